@@ -0,0 +1,1222 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::config::Config;
+use crate::de::StreamDeserializer;
+use crate::persistence;
+use crate::rdb::{self, RdbContents};
+use crate::server::Server;
+use crate::store::EntryValue;
+use crate::{arg_str, Command, Error, Value};
+
+/// When appended commands are flushed to disk, see `appendfsync`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    /// After every command.
+    Always,
+    /// Once a second, from a background task.
+    EverySec,
+    /// Whenever the OS decides to.
+    No,
+}
+
+impl AppendFsync {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+pub fn parse_fsync(policy: &str) -> Result<AppendFsync, Error> {
+    match policy.to_lowercase().as_str() {
+        "always" => Ok(AppendFsync::Always),
+        "everysec" => Ok(AppendFsync::EverySec),
+        "no" => Ok(AppendFsync::No),
+        _ => Err(Error::InvalidCommand(
+            "appendfsync must be one of always, everysec or no",
+        )),
+    }
+}
+
+/// Where the AOF lives: files named after `appendfilename` in the
+/// `appenddirname` directory, listed by a manifest.
+#[derive(Debug, Clone)]
+pub struct AofDir {
+    pub dir: PathBuf,
+    pub filename: String,
+}
+
+impl AofDir {
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.path(&format!("{}.manifest", self.filename))
+    }
+
+    fn base_name(&self, seq: u64, rdb_preamble: bool) -> String {
+        let extension = if rdb_preamble { "rdb" } else { "aof" };
+        format!("{}.{}.base.{}", self.filename, seq, extension)
+    }
+
+    fn incr_name(&self, seq: u64) -> String {
+        format!("{}.{}.incr.aof", self.filename, seq)
+    }
+
+    /// Reads the manifest, or returns `None` if there is none yet.
+    fn read_manifest(&self) -> io::Result<Option<Manifest>> {
+        match fs::read_to_string(self.manifest_path()) {
+            Ok(text) => Manifest::parse(&text).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the manifest through a renamed temporary file, so a crash
+    /// leaves either the old or the new one.
+    fn write_manifest(&self, manifest: &Manifest) -> io::Result<()> {
+        write_atomically(
+            &self.manifest_path(),
+            &self.path(&format!("temp-{}.manifest", self.filename)),
+            manifest.to_string().as_bytes(),
+        )
+    }
+}
+
+/// Writes `data` to `temp` and renames it over `path` once it is on disk.
+fn write_atomically(path: &Path, temp: &Path, data: &[u8]) -> io::Result<()> {
+    let result = File::create(temp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(temp, path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(temp);
+    }
+    result
+}
+
+/// A file listed in the manifest.
+#[derive(Debug, Clone, PartialEq)]
+struct AofInfo {
+    name: String,
+    seq: u64,
+}
+
+/// The files making up the AOF: a base holding the dataset as of the last
+/// rewrite, then the incremental files logging writes since, oldest first.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Manifest {
+    base: Option<AofInfo>,
+    incrs: Vec<AofInfo>,
+}
+
+impl Manifest {
+    /// Parses lines of `file <name> seq <seq> type <b|i|h>`. History files,
+    /// left over from a rewrite, aren't part of the dataset.
+    fn parse(text: &str) -> io::Result<Manifest> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid AOF manifest line: {}", line),
+            )
+        };
+        let mut manifest = Manifest::default();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let mut name = None;
+            let mut seq = None;
+            let mut kind = None;
+            for pair in fields.chunks(2) {
+                match pair {
+                    ["file", value] => name = Some(value.to_string()),
+                    ["seq", value] => seq = value.parse::<u64>().ok(),
+                    ["type", value] => kind = Some(*value),
+                    // Unknown fields are allowed, for newer versions.
+                    [_, _] => {}
+                    _ => return Err(invalid(line)),
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(invalid(line));
+            };
+            let info = AofInfo { name, seq };
+            match kind {
+                "b" if manifest.base.is_none() => manifest.base = Some(info),
+                "i" if manifest.incrs.last().is_none_or(|last| last.seq < seq) => {
+                    manifest.incrs.push(info)
+                }
+                "h" => {}
+                _ => return Err(invalid(line)),
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn next_base_seq(&self) -> u64 {
+        self.base.as_ref().map_or(1, |base| base.seq + 1)
+    }
+
+    fn next_incr_seq(&self) -> u64 {
+        self.incrs.last().map_or(1, |incr| incr.seq + 1)
+    }
+}
+
+impl std::fmt::Display for Manifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(base) = &self.base {
+            writeln!(f, "file {} seq {} type b", base.name, base.seq)?;
+        }
+        for incr in self.incrs.iter() {
+            writeln!(f, "file {} seq {} type i", incr.name, incr.seq)?;
+        }
+        Ok(())
+    }
+}
+
+/// The AOF, once opened at startup: writes go to the newest incremental
+/// file.
+#[derive(Debug)]
+pub struct AofFile {
+    file: File,
+    fsync: AppendFsync,
+    /// Database the logged commands currently apply to.
+    db: Option<usize>,
+    dir: AofDir,
+    manifest: Manifest,
+    rdb_preamble: bool,
+    /// Nesting of the groups started with `begin_atomic` and not ended yet.
+    atomic_depth: usize,
+    /// Whether MULTI was logged for the current group.
+    in_multi: bool,
+}
+
+impl AofFile {
+    /// Logs `command`, run against database `db`. It must already have
+    /// succeeded. Under `appendfsync always` returns a second handle to the
+    /// file, for the caller to sync with `sync_data` once it has let go of
+    /// the writer.
+    pub fn append(&mut self, db: usize, command: &[Value]) -> io::Result<Option<File>> {
+        let mut out = Vec::new();
+        if self.atomic_depth > 0 && !self.in_multi {
+            encode_command(&mut out, &[], "MULTI");
+            self.in_multi = true;
+        }
+        if self.db != Some(db) {
+            encode_command(
+                &mut out,
+                &[Value::BulkString(Some(db.to_string()))],
+                "SELECT",
+            );
+            self.db = Some(db);
+        }
+        let (name, args) = command.split_first().expect("commands have a name");
+        let name = name.str_value().unwrap_or_default();
+        encode_command(&mut out, &absolute_expiry(name, args), name);
+        self.write(&out)
+    }
+
+    /// Starts a group of commands, a transaction or a script, logged inside
+    /// MULTI ... EXEC so that replaying the AOF applies all of it or none.
+    /// Groups nest, for scripts run by a transaction; MULTI is only logged
+    /// with the first command.
+    fn begin_atomic(&mut self) {
+        self.atomic_depth += 1;
+    }
+
+    /// Ends a group started with `begin_atomic`, logging EXEC if it logged
+    /// anything. Returns like `append`.
+    fn end_atomic(&mut self) -> io::Result<Option<File>> {
+        // The AOF may have been turned on after the group started.
+        self.atomic_depth = self.atomic_depth.saturating_sub(1);
+        if self.atomic_depth > 0 || !self.in_multi {
+            return Ok(None);
+        }
+        self.in_multi = false;
+        let mut out = Vec::new();
+        encode_command(&mut out, &[], "EXEC");
+        self.write(&out)
+    }
+
+    fn write(&mut self, out: &[u8]) -> io::Result<Option<File>> {
+        self.file.write_all(out)?;
+        match self.fsync {
+            AppendFsync::Always => self.file.try_clone().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Moves logging to a new incremental file, which the rewrite keeps
+    /// while dropping every older file.
+    fn start_rewrite(&mut self) -> io::Result<Rewrite> {
+        let seq = self.manifest.next_incr_seq();
+        let incr = AofInfo {
+            name: self.dir.incr_name(seq),
+            seq,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.path(&incr.name))?;
+        let mut manifest = self.manifest.clone();
+        manifest.incrs.push(incr);
+        self.dir.write_manifest(&manifest)?;
+        // A group under way is closed in the old file and goes on in a new
+        // MULTI in the new one.
+        if self.in_multi {
+            let mut out = Vec::new();
+            encode_command(&mut out, &[], "EXEC");
+            self.file.write_all(&out)?;
+            self.in_multi = false;
+        }
+        self.file.sync_data()?;
+
+        self.manifest = manifest;
+        self.file = file;
+        self.db = None;
+        let base_seq = self.manifest.next_base_seq();
+        Ok(Rewrite {
+            base: AofInfo {
+                name: self.dir.base_name(base_seq, self.rdb_preamble),
+                seq: base_seq,
+            },
+            first_incr: seq,
+            dir: self.dir.clone(),
+            rdb_preamble: self.rdb_preamble,
+        })
+    }
+
+    /// Swaps in the base written by `rewrite` and deletes the files it
+    /// replaces.
+    fn finish_rewrite(&mut self, rewrite: Rewrite) -> io::Result<()> {
+        let mut manifest = self.manifest.clone();
+        let mut replaced: Vec<AofInfo> = manifest.base.replace(rewrite.base).into_iter().collect();
+        let first_kept = manifest
+            .incrs
+            .iter()
+            .position(|incr| incr.seq >= rewrite.first_incr)
+            .unwrap_or(manifest.incrs.len());
+        replaced.extend(manifest.incrs.drain(..first_kept));
+        self.dir.write_manifest(&manifest)?;
+        self.manifest = manifest;
+        for file in replaced {
+            if let Err(e) = fs::remove_file(self.dir.path(&file.name)) {
+                eprintln!("Can't remove the old AOF file {}: {}", file.name, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A BGREWRITEAOF under way: the base file it writes and the first
+/// incremental file logging writes made since it started.
+#[derive(Debug)]
+struct Rewrite {
+    base: AofInfo,
+    first_incr: u64,
+    dir: AofDir,
+    rdb_preamble: bool,
+}
+
+/// Writes `dataset` as the base file at `path`: an RDB file, or the
+/// commands recreating it without `aof-use-rdb-preamble`.
+fn write_base(
+    path: &Path,
+    dataset: &RdbContents,
+    rdb_preamble: bool,
+    compression: bool,
+) -> io::Result<()> {
+    let data = match rdb_preamble {
+        true => rdb::write_aof_preamble(dataset, compression),
+        false => base_commands(dataset, compression),
+    };
+    let temp = path.with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+    write_atomically(path, &temp, &data)
+}
+
+/// The commands recreating `dataset`. Strings are SET; the other types
+/// have no command here to build them, so they are RESTOREd from a DUMP
+/// payload.
+fn base_commands(dataset: &RdbContents, compression: bool) -> Vec<u8> {
+    let arg = |s: &str| Value::BulkString(Some(s.to_string()));
+    let mut out = Vec::new();
+    for code in dataset.functions.iter() {
+        encode_command(&mut out, &[arg("LOAD"), arg(code)], "FUNCTION");
+    }
+    for (db, entries) in dataset.databases.iter() {
+        encode_command(&mut out, &[arg(&db.to_string())], "SELECT");
+        for (key, entry) in entries {
+            let unix_ms = entry.expires_at().map(|expires_at| {
+                expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis()
+                    // A TTL of 0 would mean no expiry to RESTORE.
+                    .max(1)
+                    .to_string()
+            });
+            match entry.get_value() {
+                EntryValue::String(value) => {
+                    let mut args = vec![arg(key), arg(value)];
+                    if let Some(unix_ms) = unix_ms {
+                        args.extend([arg("PXAT"), arg(&unix_ms)]);
+                    }
+                    encode_command(&mut out, &args, "SET");
+                }
+                value => {
+                    let mut args = vec![
+                        arg(key),
+                        arg(unix_ms.as_deref().unwrap_or("0")),
+                        Value::Binary(rdb::dump_value(value, compression)),
+                    ];
+                    if unix_ms.is_some() {
+                        args.push(arg("ABSTTL"));
+                    }
+                    encode_command(&mut out, &args, "RESTORE");
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Appends `name args...` in RESP.
+pub(crate) fn encode_command(out: &mut Vec<u8>, args: &[Value], name: &str) {
+    out.extend_from_slice(format!("*{}\r\n", args.len() + 1).as_bytes());
+    let mut write_arg = |arg: &[u8]| {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    };
+    write_arg(name.as_bytes());
+    for arg in args {
+        match arg {
+            Value::Integer(n) => write_arg(n.to_string().as_bytes()),
+            arg => write_arg(arg.bytes_value().unwrap_or_default()),
+        }
+    }
+}
+
+/// Turns relative expiries into absolute ones, so replaying the command
+/// later doesn't extend them: `SET key value PX ms` gets PXAT instead, and
+/// `RESTORE key ttl ...` gets ABSTTL.
+fn absolute_expiry(name: &str, args: &[Value]) -> Vec<Value> {
+    let mut args = args.to_vec();
+    let unix_ms = |millis: u64| {
+        let expires_at = SystemTime::now() + Duration::from_millis(millis);
+        let unix_ms = expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Value::BulkString(Some(unix_ms.to_string()))
+    };
+    let is_arg = |arg: &Value, option: &str| {
+        arg.str_value()
+            .is_some_and(|arg| arg.eq_ignore_ascii_case(option))
+    };
+    let millis = |arg: &Value| arg.str_value().and_then(|ms| ms.parse::<u64>().ok());
+
+    if name.eq_ignore_ascii_case("set") && args.len() == 4 && is_arg(&args[2], "px") {
+        if let Some(millis) = millis(&args[3]) {
+            args[2] = Value::BulkString(Some("PXAT".to_string()));
+            args[3] = unix_ms(millis);
+        }
+    }
+    if name.eq_ignore_ascii_case("restore") && !args[3..].iter().any(|arg| is_arg(arg, "absttl")) {
+        if let Some(millis) = millis(&args[1]).filter(|&millis| millis > 0) {
+            args[1] = unix_ms(millis);
+            args.push(Value::BulkString(Some("ABSTTL".to_string())));
+        }
+    }
+    args
+}
+
+/// The AOF of the server; nothing is logged unless `appendonly` is on.
+#[derive(Debug, Default)]
+pub struct Aof {
+    file: OnceLock<Mutex<AofFile>>,
+    fsync: OnceLock<AppendFsync>,
+    rewrite_in_progress: AtomicBool,
+    last_rewrite_failed: AtomicBool,
+}
+
+impl Aof {
+    pub fn is_enabled(&self) -> bool {
+        self.file.get().is_some()
+    }
+
+    /// The fsync policy, once the file is open.
+    pub fn fsync(&self) -> Option<AppendFsync> {
+        self.fsync.get().copied()
+    }
+
+    pub fn is_rewrite_in_progress(&self) -> bool {
+        self.rewrite_in_progress.load(Ordering::Relaxed)
+    }
+
+    pub fn last_rewrite_ok(&self) -> bool {
+        !self.last_rewrite_failed.load(Ordering::Relaxed)
+    }
+
+    /// Exclusive access to the file, held while a logged command runs so
+    /// commands are logged in the order they took effect. `None` when
+    /// appendonly is off.
+    pub async fn writer(&self) -> Option<MutexGuard<'_, AofFile>> {
+        match self.file.get() {
+            Some(file) => Some(file.lock().await),
+            None => None,
+        }
+    }
+
+    /// See `AofFile::begin_atomic`. Nothing to do when appendonly is off.
+    pub async fn begin_atomic(&self) {
+        if let Some(mut writer) = self.writer().await {
+            writer.begin_atomic();
+        }
+    }
+
+    /// See `AofFile::end_atomic`.
+    pub async fn end_atomic(&self) {
+        let Some(mut writer) = self.writer().await else {
+            return;
+        };
+        let written = writer.end_atomic();
+        drop(writer);
+        finish_write(written).await;
+    }
+
+    /// Opens the AOF in `dir` for appending. Without a manifest, a single
+    /// file AOF at `legacy` becomes the base, or else a base is written
+    /// from `dataset`, so it holds everything loaded at startup.
+    pub fn open(
+        &self,
+        dir: AofDir,
+        legacy: &Path,
+        fsync: AppendFsync,
+        rdb_preamble: bool,
+        compression: bool,
+        dataset: &RdbContents,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&dir.dir)?;
+        let mut manifest = match dir.read_manifest()? {
+            Some(manifest) => manifest,
+            None if legacy.is_file() => {
+                fs::rename(legacy, dir.path(&dir.filename))?;
+                Manifest {
+                    base: Some(AofInfo {
+                        name: dir.filename.clone(),
+                        seq: 1,
+                    }),
+                    incrs: Vec::new(),
+                }
+            }
+            None => {
+                let name = dir.base_name(1, rdb_preamble);
+                write_base(&dir.path(&name), dataset, rdb_preamble, compression)?;
+                Manifest {
+                    base: Some(AofInfo { name, seq: 1 }),
+                    incrs: Vec::new(),
+                }
+            }
+        };
+        if manifest.incrs.is_empty() {
+            manifest.incrs.push(AofInfo {
+                name: dir.incr_name(1),
+                seq: 1,
+            });
+        }
+        dir.write_manifest(&manifest)?;
+
+        let incr = manifest
+            .incrs
+            .last()
+            .expect("an incremental file was added");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.path(&incr.name))?;
+        let aof_file = AofFile {
+            file,
+            fsync,
+            db: None,
+            dir,
+            manifest,
+            rdb_preamble,
+            atomic_depth: 0,
+            in_multi: false,
+        };
+        let already_open = || io::Error::new(io::ErrorKind::AlreadyExists, "AOF already open");
+        self.fsync.set(fsync).map_err(|_| already_open())?;
+        self.file
+            .set(Mutex::new(aof_file))
+            .map_err(|_| already_open())
+    }
+}
+
+/// BGREWRITEAOF: replaces the AOF with a base file holding the current
+/// dataset. Writes go to a new incremental file from here on, so they are
+/// kept while the base is written in the background. The caller holds the
+/// execution lock exclusively while the dataset is forked.
+pub async fn bgrewriteaof(server: &Arc<Server>) -> Result<Value, Error> {
+    let aof = &server.aof;
+    let Some(mut writer) = aof.writer().await else {
+        return Err(Error::InvalidCommand(
+            "Background append only file rewriting needs appendonly on",
+        ));
+    };
+    if aof
+        .rewrite_in_progress
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return Err(Error::InvalidCommand(
+            "Background append only file rewriting already in progress",
+        ));
+    }
+    let rewrite = match writer.start_rewrite() {
+        Ok(rewrite) => rewrite,
+        Err(e) => {
+            eprintln!("Can't start rewriting the AOF: {}", e);
+            aof.last_rewrite_failed.store(true, Ordering::Relaxed);
+            aof.rewrite_in_progress.store(false, Ordering::Release);
+            return Err(Error::InvalidCommand(
+                "Can't rewrite the append only file, see the server logs",
+            ));
+        }
+    };
+    // Still holding the writer, so nothing logged to the new incremental
+    // file is in the fork too.
+    let dataset = persistence::fork(server).await;
+    drop(writer);
+    let compression = server.persistence.rdb_compression();
+
+    let server = server.clone();
+    tokio::task::spawn_blocking(move || {
+        let path = rewrite.dir.path(&rewrite.base.name);
+        let result = write_base(
+            &path,
+            &dataset.contents(),
+            rewrite.rdb_preamble,
+            compression,
+        )
+        .and_then(|()| {
+            let mut writer = server
+                .aof
+                .file
+                .get()
+                .expect("the AOF is open")
+                .blocking_lock();
+            writer.finish_rewrite(rewrite)
+        });
+        let aof = &server.aof;
+        if let Err(e) = &result {
+            eprintln!("Background AOF rewrite failed: {}", e);
+        }
+        aof.last_rewrite_failed
+            .store(result.is_err(), Ordering::Relaxed);
+        aof.rewrite_in_progress.store(false, Ordering::Release);
+    });
+    Ok(Value::SimpleString(
+        "Background append only file rewriting started".to_string(),
+    ))
+}
+
+/// Completes a write to the AOF once the writer is let go of: syncs it if
+/// `appendfsync always` asked for it, and reports failures.
+pub async fn finish_write(written: io::Result<Option<File>>) {
+    let result = match written {
+        Ok(Some(file)) => sync_data(file).await,
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("Error writing to the AOF: {}", e);
+    }
+}
+
+/// Flushes `file` to disk on a blocking thread.
+pub async fn sync_data(file: File) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || file.sync_data())
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+/// Flushes the AOF to disk every second under `appendfsync everysec`, for
+/// as long as the server runs.
+pub async fn run_everysec_fsync(server: Arc<Server>) {
+    if server.aof.fsync() != Some(AppendFsync::EverySec) {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let Some(writer) = server.aof.writer().await else {
+            return;
+        };
+        // A second handle to the current file, so syncing doesn't hold up
+        // logging.
+        let file = writer.file.try_clone();
+        drop(writer);
+        let result = match file {
+            Ok(file) => sync_data(file).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Error syncing the AOF: {}", e);
+        }
+    }
+}
+
+/// An AOF read at startup: the dataset of its base, if it is an RDB file
+/// or starts with an RDB preamble, and the commands logged after it.
+#[derive(Debug, Default)]
+pub struct LoadedAof {
+    pub base: RdbContents,
+    parts: Vec<AofPart>,
+}
+
+/// One file of a loaded AOF.
+#[derive(Debug)]
+struct AofPart {
+    path: PathBuf,
+    data: Vec<u8>,
+    /// Where the commands start in `data`.
+    commands: usize,
+}
+
+/// Reads the AOF in `dir`, following its manifest, or the single file AOF
+/// at `legacy` if there is no manifest yet. Returns `None` if there is
+/// neither.
+pub fn read(dir: &AofDir, legacy: &Path) -> io::Result<Option<LoadedAof>> {
+    let paths: Vec<PathBuf> = match dir.read_manifest()? {
+        Some(manifest) => manifest
+            .base
+            .iter()
+            .chain(manifest.incrs.iter())
+            .map(|file| dir.path(&file.name))
+            .collect(),
+        None if legacy.is_file() => vec![legacy.to_path_buf()],
+        None => return Ok(None),
+    };
+
+    let mut loaded = LoadedAof::default();
+    for path in paths {
+        let data = fs::read(&path).map_err(|e| {
+            io::Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e))
+        })?;
+        let commands = if loaded.parts.is_empty() && data.starts_with(b"REDIS") {
+            let (base, consumed) = rdb::parse_rdb_prefix(&data).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bad RDB preamble in {}: {}", path.display(), e),
+                )
+            })?;
+            loaded.base = base;
+            consumed
+        } else {
+            0
+        };
+        loaded.parts.push(AofPart {
+            path,
+            data,
+            commands,
+        });
+    }
+    Ok(Some(loaded))
+}
+
+/// Runs the commands of `loaded` while clients are still answered with
+/// -LOADING, counting its bytes into the loading progress. A command cut
+/// short at the end of the last file, as left by a crash, is dropped and
+/// cut off the file when `load_truncated` is set; otherwise loading fails.
+/// Returns how many commands ran.
+pub async fn replay(
+    loaded: &LoadedAof,
+    server: &Arc<Server>,
+    config: &Config,
+    load_truncated: bool,
+) -> Result<usize, Error> {
+    let persistence = &server.persistence;
+    persistence.add_loading_total(loaded.parts.iter().map(|part| part.data.len() as u64).sum());
+    // The RDB preamble was loaded already.
+    persistence.add_loaded(loaded.parts.first().map_or(0, |part| part.commands as u64));
+
+    let mut count = 0;
+    for (i, part) in loaded.parts.iter().enumerate() {
+        let is_last = i + 1 == loaded.parts.len();
+        count += replay_part(part, server, config, load_truncated && is_last).await?;
+    }
+    Ok(count)
+}
+
+async fn replay_part(
+    part: &AofPart,
+    server: &Arc<Server>,
+    config: &Config,
+    load_truncated: bool,
+) -> Result<usize, Error> {
+    let mut cursor = Cursor::new(&part.data[..]);
+    cursor.set_position(part.commands as u64);
+    let mut db = 0;
+    let mut count = 0;
+    let mut transaction: Option<PendingTransaction> = None;
+
+    loop {
+        let start = cursor.position();
+        if start as usize == part.data.len() {
+            return match transaction {
+                // A transaction cut short is dropped whole.
+                Some(pending) if load_truncated => {
+                    truncate_part(part, pending.start)?;
+                    Ok(pending.count)
+                }
+                Some(_) => Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Unexpected end of file inside MULTI ... EXEC",
+                ))),
+                None => Ok(count),
+            };
+        }
+        let args = match StreamDeserializer::new(&mut cursor).decode_next().await {
+            Ok(Value::Array(args)) if !args.is_empty() => args,
+            Ok(_) => {
+                return Err(Error::InvalidCommand(
+                    "Bad file format reading the append only file",
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && load_truncated => {
+                // A transaction cut short is dropped whole.
+                let (end, count) =
+                    transaction.map_or((start, count), |pending| (pending.start, pending.count));
+                truncate_part(part, end)?;
+                return Ok(count);
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Err(Error::InvalidCommand(
+                    "Bad file format reading the append only file",
+                ))
+            }
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let command = Command::from_str(arg_str(&args[0])?)?;
+        match (command, transaction.as_mut()) {
+            (Command::SELECT, _) => db = crate::db_index(&args[1], &server.databases)?,
+            (Command::MULTI, None) => {
+                transaction = Some(PendingTransaction {
+                    start,
+                    count,
+                    commands: Vec::new(),
+                })
+            }
+            (Command::EXEC, Some(_)) => {
+                let pending = transaction.take().expect("matched above");
+                for (db, command, args) in pending.commands {
+                    run_logged(server, config, db, command, args).await;
+                }
+            }
+            (Command::MULTI | Command::EXEC, _) => {
+                return Err(Error::InvalidCommand(
+                    "Bad file format reading the append only file",
+                ))
+            }
+            (command, Some(pending)) => pending.commands.push((db, command, args)),
+            (command, None) => run_logged(server, config, db, command, args).await,
+        }
+        server.persistence.add_loaded(cursor.position() - start);
+        count += 1;
+    }
+}
+
+/// A MULTI read from the AOF whose EXEC has not been reached yet.
+struct PendingTransaction {
+    /// Where MULTI starts in the file.
+    start: u64,
+    /// Commands replayed before MULTI.
+    count: usize,
+    /// The commands read since, with their database, run at EXEC.
+    commands: Vec<(usize, Command, Vec<Value>)>,
+}
+
+/// Runs a command read from the AOF against database `db`.
+async fn run_logged(
+    server: &Arc<Server>,
+    config: &Config,
+    db: usize,
+    command: Command,
+    args: Vec<Value>,
+) {
+    let store = server
+        .databases
+        .get(db)
+        .expect("SELECT checks the index")
+        .clone();
+    // Errors were already reported to the client that sent them.
+    let _ = command
+        .construct_response(args, store, server, config)
+        .await;
+}
+
+/// Cuts `part` down to `len` bytes, dropping a command or transaction left
+/// unfinished at its end.
+fn truncate_part(part: &AofPart, len: u64) -> io::Result<()> {
+    eprintln!(
+        "!!! Warning: short read while loading the AOF {}, truncating it to {} bytes",
+        part.path.display(),
+        len
+    );
+    OpenOptions::new()
+        .write(true)
+        .open(&part.path)?
+        .set_len(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sorted_set::SortedSet;
+
+    #[test]
+    fn test_encoding() {
+        let arg = |s: &str| Value::BulkString(Some(s.to_string()));
+        let mut out = Vec::new();
+        encode_command(&mut out, &[arg("k"), Value::Binary(vec![0xff])], "SET");
+        assert_eq!(out, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\n\xff\r\n");
+
+        let args = absolute_expiry("set", &[arg("k"), arg("v"), arg("px"), arg("1000")]);
+        assert_eq!(args[2], arg("PXAT"));
+        let unix_ms: u128 = args[3].str_value().unwrap().parse().unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(unix_ms > now.as_millis() && unix_ms <= now.as_millis() + 1000);
+
+        let payload = Value::Binary(vec![0]);
+        let args = absolute_expiry("RESTORE", &[arg("k"), arg("1000"), payload.clone()]);
+        assert_eq!(args[3], arg("ABSTTL"));
+        let unix_ms: u128 = args[1].str_value().unwrap().parse().unwrap();
+        assert!(unix_ms > now.as_millis() && unix_ms <= now.as_millis() + 1000);
+        for args in [
+            vec![arg("k"), arg("0"), payload.clone()],
+            vec![arg("k"), arg("5"), payload.clone(), arg("absttl")],
+        ] {
+            assert_eq!(absolute_expiry("restore", &args), args);
+        }
+
+        assert_eq!(parse_fsync("EVERYSEC").unwrap(), AppendFsync::EverySec);
+        assert!(parse_fsync("sometimes").is_err());
+    }
+
+    fn new_server() -> Arc<Server> {
+        let pubsub = Arc::new(crate::pubsub::PubSub::new());
+        let databases = crate::store::Databases::new(16, Default::default(), pubsub.clone());
+        Arc::new(Server::new(databases, pubsub))
+    }
+
+    async fn run(server: &Arc<Server>, db: usize, cmd: &str) {
+        let args: Vec<Value> = cmd
+            .split_whitespace()
+            .map(|arg| Value::BulkString(Some(arg.to_string())))
+            .collect();
+        let command = Command::from_str(arg_str(&args[0]).unwrap()).unwrap();
+        let store = server.databases.get(db).unwrap().clone();
+        let config = Config::new(String::new(), None, None, 16);
+        command
+            .construct_response(args, store, server, &config)
+            .await
+            .unwrap();
+    }
+
+    fn temp_dir(name: &str) -> AofDir {
+        let dir = std::env::temp_dir().join(format!("aof-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        AofDir {
+            dir,
+            filename: "appendonly.aof".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_manifest() {
+        let text = "file appendonly.aof.2.base.rdb seq 2 type b\n\
+                    file appendonly.aof.1.incr.aof seq 1 type h\n\
+                    file appendonly.aof.2.incr.aof seq 2 type i\n\
+                    file appendonly.aof.3.incr.aof seq 3 type i startoffset 0\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 2);
+        assert_eq!(manifest.incrs.len(), 2);
+        assert_eq!(manifest.next_base_seq(), 3);
+        assert_eq!(manifest.next_incr_seq(), 4);
+        assert_eq!(Manifest::parse(&manifest.to_string()).unwrap(), manifest);
+
+        assert!(Manifest::parse("file a seq x type b").is_err());
+        assert!(Manifest::parse("file a seq 1 type q").is_err());
+        assert!(Manifest::parse("file a seq 2 type i\nfile b seq 1 type i").is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let dir = temp_dir("replay");
+            let legacy = dir.dir.with_extension("aof");
+            let config = Config::new(String::new(), None, None, 16);
+            let now = std::time::Instant::now();
+
+            let server = new_server();
+            server
+                .aof
+                .open(
+                    dir.clone(),
+                    &legacy,
+                    AppendFsync::Always,
+                    true,
+                    true,
+                    &RdbContents::default(),
+                )
+                .unwrap();
+            run(&server, 0, "SET a 1").await;
+            run(&server, 0, "GET a").await;
+            run(&server, 2, "SET b 2 PX 100000").await;
+            run(&server, 2, "SET c 3").await;
+            run(&server, 2, "DEL c").await;
+
+            assert_eq!(
+                fs::read_to_string(dir.manifest_path()).unwrap(),
+                "file appendonly.aof.1.base.rdb seq 1 type b\n\
+                 file appendonly.aof.1.incr.aof seq 1 type i\n"
+            );
+            let incr = dir.path("appendonly.aof.1.incr.aof");
+            let data = fs::read(&incr).unwrap();
+            let tail = String::from_utf8_lossy(&data);
+            assert!(!tail.contains("GET"));
+            assert!(tail.contains("PXAT"));
+
+            let loaded = read(&dir, &legacy).unwrap().unwrap();
+            let replayed = new_server();
+            let count = replay(&loaded, &replayed, &config, false).await.unwrap();
+            assert_eq!(count, 6);
+            let db = |index| replayed.databases.get(index).unwrap().clone();
+            assert_eq!(db(0).get("a", now).await.unwrap(), Some("1".to_string()));
+            assert_eq!(db(2).get("b", now).await.unwrap(), Some("2".to_string()));
+            assert!(!db(2).contains_key("c", now).await);
+
+            // A command cut short is refused, or dropped along with the end
+            // of the file.
+            fs::write(&incr, &data[..data.len() - 3]).unwrap();
+            let loaded = read(&dir, &legacy).unwrap().unwrap();
+            assert!(replay(&loaded, &new_server(), &config, false)
+                .await
+                .is_err());
+            let count = replay(&loaded, &new_server(), &config, true).await.unwrap();
+            assert_eq!(count, 5);
+            let truncated = fs::read(&incr).unwrap();
+            assert!(truncated.ends_with(b"SET\r\n$1\r\nc\r\n$1\r\n3\r\n"));
+
+            // Broken framing is a bad file, however the AOF may be truncated.
+            for corrupt in [&b"*1\r\n$3\r\nDELxx"[..], b"*x\r\n"] {
+                fs::write(&incr, [&truncated[..], corrupt].concat()).unwrap();
+                let loaded = read(&dir, &legacy).unwrap().unwrap();
+                assert!(matches!(
+                    replay(&loaded, &new_server(), &config, true).await,
+                    Err(Error::InvalidCommand(
+                        "Bad file format reading the append only file"
+                    ))
+                ));
+            }
+            fs::remove_dir_all(&dir.dir).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_replay_transaction() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let dir = temp_dir("replay-transaction");
+            let legacy = dir.dir.with_extension("aof");
+            let config = Config::new(String::new(), None, None, 16);
+            let now = std::time::Instant::now();
+
+            let server = new_server();
+            server
+                .aof
+                .open(
+                    dir.clone(),
+                    &legacy,
+                    AppendFsync::No,
+                    true,
+                    true,
+                    &RdbContents::default(),
+                )
+                .unwrap();
+            // A group without writes logs nothing.
+            server.aof.begin_atomic().await;
+            run(&server, 0, "GET x").await;
+            server.aof.end_atomic().await;
+            // Nested groups, like a script run by a transaction, log one
+            // MULTI ... EXEC.
+            server.aof.begin_atomic().await;
+            run(&server, 0, "SET x 1").await;
+            server.aof.begin_atomic().await;
+            run(&server, 3, "SET y 2").await;
+            server.aof.end_atomic().await;
+            server.aof.end_atomic().await;
+            run(&server, 0, "SET z 3").await;
+
+            let incr = dir.path("appendonly.aof.1.incr.aof");
+            let data = fs::read(&incr).unwrap();
+            let text = String::from_utf8_lossy(&data);
+            assert_eq!(text.matches("MULTI").count(), 1);
+            assert_eq!(text.matches("EXEC").count(), 1);
+            let multi = text.find("*1\r\n$5\r\nMULTI").unwrap();
+            let exec = text.find("*1\r\n$4\r\nEXEC").unwrap();
+            assert!(multi < text.find("SELECT").unwrap());
+            assert!(exec < text.find("z").unwrap());
+
+            let loaded = read(&dir, &legacy).unwrap().unwrap();
+            let replayed = new_server();
+            let count = replay(&loaded, &replayed, &config, false).await.unwrap();
+            assert_eq!(count, 8);
+            let db = |index| replayed.databases.get(index).unwrap().clone();
+            assert_eq!(db(0).get("x", now).await.unwrap(), Some("1".to_string()));
+            assert_eq!(db(3).get("y", now).await.unwrap(), Some("2".to_string()));
+            assert_eq!(db(0).get("z", now).await.unwrap(), Some("3".to_string()));
+
+            // A transaction cut short is refused, or dropped whole along with
+            // the end of the file.
+            for end in [exec, exec + 3] {
+                fs::write(&incr, &data[..end]).unwrap();
+                let loaded = read(&dir, &legacy).unwrap().unwrap();
+                assert!(replay(&loaded, &new_server(), &config, false)
+                    .await
+                    .is_err());
+                let replayed = new_server();
+                let count = replay(&loaded, &replayed, &config, true).await.unwrap();
+                assert_eq!(count, 0);
+                let db = |index| replayed.databases.get(index).unwrap().clone();
+                assert!(!db(0).contains_key("x", now).await);
+                assert!(!db(3).contains_key("y", now).await);
+                assert_eq!(fs::read(&incr).unwrap(), &data[..multi]);
+            }
+
+            // EXEC without MULTI is a bad file.
+            fs::write(&incr, [&data[..multi], &data[exec..]].concat()).unwrap();
+            let loaded = read(&dir, &legacy).unwrap().unwrap();
+            assert!(matches!(
+                replay(&loaded, &new_server(), &config, true).await,
+                Err(Error::InvalidCommand(
+                    "Bad file format reading the append only file"
+                ))
+            ));
+            fs::remove_dir_all(&dir.dir).unwrap();
+        })
+    }
+
+    /// Loads the AOF in `dir` the way the server does at startup.
+    async fn load(dir: &AofDir, legacy: &Path) -> Arc<Server> {
+        let mut loaded = read(dir, legacy).unwrap().unwrap();
+        let base = std::mem::take(&mut loaded.base);
+        let pubsub = Arc::new(crate::pubsub::PubSub::new());
+        let databases = crate::store::Databases::new(16, base.databases, pubsub.clone());
+        let server = Arc::new(Server::new(databases, pubsub));
+        let config = Config::new(String::new(), None, None, 16);
+        replay(&loaded, &server, &config, false).await.unwrap();
+        server
+    }
+
+    async fn rewrite(server: &Arc<Server>) {
+        let _guard = server.databases.lock_exclusive().await;
+        bgrewriteaof(server).await.unwrap();
+        drop(_guard);
+        while server.aof.is_rewrite_in_progress() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(server.aof.last_rewrite_ok());
+    }
+
+    #[test]
+    fn test_rewrite() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let now = std::time::Instant::now();
+            for rdb_preamble in [true, false] {
+                let dir = temp_dir(&format!("rewrite-{}", rdb_preamble));
+                let legacy = dir.dir.with_extension("aof");
+                // A single file AOF from before manifests becomes the base.
+                let mut old = Vec::new();
+                encode_command(
+                    &mut old,
+                    &[Value::BulkString(Some("a".into())), Value::Integer(1)],
+                    "SET",
+                );
+                fs::write(&legacy, &old).unwrap();
+
+                let server = load(&dir, &legacy).await;
+                server
+                    .aof
+                    .open(
+                        dir.clone(),
+                        &legacy,
+                        AppendFsync::No,
+                        rdb_preamble,
+                        true,
+                        &RdbContents::default(),
+                    )
+                    .unwrap();
+                assert!(!legacy.exists());
+                run(&server, 0, "SET a 2").await;
+                run(&server, 3, "SET b 3 PX 100000").await;
+                run(&server, 0, "GEOADD g 13.361389 38.115556 Palermo").await;
+
+                rewrite(&server).await;
+                run(&server, 3, "SET c 4").await;
+
+                let extension = if rdb_preamble { "rdb" } else { "aof" };
+                assert_eq!(
+                    fs::read_to_string(dir.manifest_path()).unwrap(),
+                    format!(
+                        "file appendonly.aof.2.base.{} seq 2 type b\n\
+                         file appendonly.aof.2.incr.aof seq 2 type i\n",
+                        extension
+                    )
+                );
+                let mut files: Vec<_> = fs::read_dir(&dir.dir)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                    .collect();
+                files.sort();
+                assert_eq!(
+                    files,
+                    [
+                        format!("appendonly.aof.2.base.{}", extension),
+                        "appendonly.aof.2.incr.aof".to_string(),
+                        "appendonly.aof.manifest".to_string(),
+                    ]
+                );
+
+                let replayed = load(&dir, &legacy).await;
+                let db = |index| replayed.databases.get(index).unwrap().clone();
+                assert_eq!(db(0).get("a", now).await.unwrap(), Some("2".to_string()));
+                assert_eq!(db(3).get("b", now).await.unwrap(), Some("3".to_string()));
+                let snapshot = replayed.databases.snapshot(now).await;
+                assert!(snapshot[&3]["b"].expires_at().is_some());
+                assert_eq!(db(3).get("c", now).await.unwrap(), Some("4".to_string()));
+                // Sorted sets come back without the RDB preamble too.
+                let score = |zset: &SortedSet| zset.score("Palermo");
+                let original = server.databases.get(0).unwrap().clone();
+                let expected = original.read_sorted_set("g", now, score).await.unwrap();
+                assert!(expected.flatten().is_some());
+                assert_eq!(
+                    db(0).read_sorted_set("g", now, score).await.unwrap(),
+                    expected
+                );
+                fs::remove_dir_all(&dir.dir).unwrap();
+            }
+        })
+    }
+}
@@ -0,0 +1,5 @@
+use redis_starter_rust::check_rdb;
+
+fn main() {
+    std::process::exit(check_rdb::run(std::env::args()));
+}
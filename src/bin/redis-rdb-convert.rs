@@ -0,0 +1,5 @@
+use redis_starter_rust::rdb_convert;
+
+fn main() {
+    std::process::exit(rdb_convert::run(std::env::args()));
+}
@@ -0,0 +1,182 @@
+//! redis-check-rdb: reads an RDB file through and reports what it holds,
+//! or the first place it is broken. `src/bin/redis-check-rdb.rs` runs it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::time::SystemTime;
+
+use crate::rdb::{self, RdbError, RdbMetadata, RdbReader};
+
+/// Keys of a database, and how many of them expire.
+#[derive(Debug, Default, PartialEq)]
+pub struct DbStats {
+    pub keys: u64,
+    pub expires: u64,
+    /// Keys whose expiry time had passed when checked.
+    pub already_expired: u64,
+}
+
+/// What checking an RDB file found.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub metadata: RdbMetadata,
+    pub databases: BTreeMap<usize, DbStats>,
+    /// Keys of each type.
+    pub types: BTreeMap<&'static str, u64>,
+    /// Bytes read, up to the error if there is one.
+    pub bytes: u64,
+    pub error: Option<RdbError>,
+}
+
+/// Reads the RDB data in `input` through, keeping only counts.
+pub fn check(input: impl Read) -> Report {
+    let mut report = Report::default();
+    let mut reader = RdbReader::new(input);
+    let now = SystemTime::now();
+    let (databases, types) = (&mut report.databases, &mut report.types);
+    let result = rdb::read_rdb(&mut reader, &mut report.metadata, |db, _key, entry| {
+        let stats = databases.entry(db).or_default();
+        stats.keys += 1;
+        if let Some(expires_at) = entry.expires_at() {
+            stats.expires += 1;
+            stats.already_expired += (expires_at <= now) as u64;
+        }
+        *types.entry(entry.get_value().type_name()).or_default() += 1;
+        Ok(())
+    });
+    report.bytes = reader.offset();
+    report.error = result.err();
+    report
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metadata = &self.metadata;
+        if metadata.version > 0 {
+            writeln!(f, "[info] RDB version {}", metadata.version)?;
+        }
+        for (key, value) in metadata.aux.iter() {
+            writeln!(f, "[info] AUX FIELD {} = '{}'", key, value)?;
+        }
+        if !metadata.functions.is_empty() {
+            writeln!(f, "[info] {} function libraries", metadata.functions.len())?;
+        }
+        for (db, stats) in self.databases.iter() {
+            writeln!(
+                f,
+                "[info] db{}: keys={}, expires={}, already_expired={}",
+                db, stats.keys, stats.expires, stats.already_expired
+            )?;
+        }
+        let keys: u64 = self.databases.values().map(|stats| stats.keys).sum();
+        writeln!(f, "[info] {} keys read", keys)?;
+        for (name, count) in self.types.iter() {
+            writeln!(f, "[info] {} {} keys", count, name)?;
+        }
+        let expires: u64 = self.databases.values().map(|stats| stats.expires).sum();
+        let expired: u64 = self
+            .databases
+            .values()
+            .map(|stats| stats.already_expired)
+            .sum();
+        writeln!(f, "[info] {} expires", expires)?;
+        writeln!(f, "[info] {} already expired", expired)?;
+
+        match &self.error {
+            Some(e) => {
+                writeln!(f, "--- RDB ERROR DETECTED ---")?;
+                writeln!(f, "{}", e)
+            }
+            None => writeln!(f, "[offset {}] \\o/ RDB looks OK! \\o/", self.bytes),
+        }
+    }
+}
+
+/// Runs the checker on the file named by the only argument after the
+/// program name. Returns the exit status: 0 when the file is fine.
+pub fn run(mut args: impl Iterator<Item = String>) -> i32 {
+    let (Some(path), None) = (args.nth(1), args.next()) else {
+        eprintln!("Usage: redis-check-rdb <rdb-file-name>");
+        return 1;
+    };
+    println!("[offset 0] Checking RDB file {}", path);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Cannot open RDB file {}: {}", path, e);
+            return 1;
+        }
+    };
+    let report = check(BufReader::new(file));
+    print!("{}", report);
+    report.error.is_some() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdb::RdbContents;
+    use crate::store::{Entry, EntryValue};
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    #[test]
+    fn test_check() {
+        let now = Instant::now();
+        let string =
+            |value: &str, ttl| Entry::new(EntryValue::String(value.into()), ttl, None, now);
+        let contents = RdbContents {
+            databases: BTreeMap::from([
+                (
+                    0,
+                    HashMap::from([
+                        ("a".to_string(), string("1", None)),
+                        ("b".to_string(), string("2", Some(60_000))),
+                        ("c".to_string(), string("3", Some(0))),
+                    ]),
+                ),
+                (
+                    2,
+                    HashMap::from([(
+                        "l".to_string(),
+                        Entry::new(EntryValue::List(["x".into()].into()), None, None, now),
+                    )]),
+                ),
+            ]),
+            functions: Vec::new(),
+        };
+        let data = rdb::write_rdb(&contents, false);
+
+        let report = check(&data[..]);
+        assert!(report.error.is_none());
+        assert_eq!(report.bytes, data.len() as u64);
+        assert_eq!(report.metadata.version, rdb::RDB_VERSION);
+        assert!(report
+            .metadata
+            .aux
+            .iter()
+            .any(|(key, _)| key == "redis-ver"));
+        assert_eq!(
+            report.databases[&0],
+            DbStats {
+                keys: 3,
+                expires: 2,
+                already_expired: 1
+            }
+        );
+        assert_eq!(report.databases[&2].keys, 1);
+        assert_eq!(report.types, BTreeMap::from([("list", 1), ("string", 3)]));
+        let text = report.to_string();
+        assert!(text.contains("[info] db0: keys=3, expires=2, already_expired=1\n"));
+        assert!(text.ends_with("RDB looks OK! \\o/\n"));
+
+        // What was read before the error is still reported.
+        let report = check(&data[..data.len() - 20]);
+        let e = report.error.as_ref().unwrap();
+        assert_eq!(e.reason, "Unexpected end of file");
+        assert_eq!(report.metadata.aux.len(), 4);
+        assert!(report.to_string().contains("--- RDB ERROR DETECTED ---\n"));
+    }
+}
@@ -0,0 +1,682 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+
+use crate::config::Config;
+use crate::pubsub::SubscriptionKind;
+use crate::server::Server;
+use crate::store::Store;
+use crate::tracking::{self, TrackingOptions};
+use crate::{arg_str, db_index, Command, Error, Value, REDIS_VERSION};
+
+pub type ClientId = u64;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How many push messages may wait for a connection before it is considered
+/// too slow and disconnected, so publishers never wait on subscribers.
+const PUSH_QUEUE_LIMIT: usize = 4096;
+
+/// What other connections hold to deliver push messages to a client.
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    id: ClientId,
+    /// RESP version, which decides how pushes are framed for this client.
+    protocol: Arc<AtomicU8>,
+    pushes: mpsc::Sender<Value>,
+    kill: Arc<Notify>,
+}
+
+impl ClientHandle {
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol.load(Ordering::Relaxed)
+    }
+
+    /// Queues `value` for the client without waiting. A client whose queue
+    /// is full gets disconnected instead.
+    pub fn push(&self, value: Value) {
+        match self.pushes.try_send(value) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(_)) => self.kill.notify_one(),
+        }
+    }
+}
+
+/// Commands queued between MULTI and EXEC on one connection.
+#[derive(Debug, Default)]
+struct Transaction {
+    queued: Vec<(Command, Vec<Value>)>,
+    /// Set when a command could not be queued; EXEC then discards everything.
+    aborted: bool,
+}
+
+/// Turns command errors into error replies, keeping the connection open.
+pub(crate) fn reply_or_error(result: Result<Value, Error>) -> Result<Value, Error> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(e) => e.to_reply().ok_or(e),
+    }
+}
+
+/// Keys WATCHed by one connection, with the database each belongs to, the
+/// version it had at WATCH time and whether it existed then. Unregisters
+/// itself from the databases on drop.
+#[derive(Default)]
+struct WatchedKeys {
+    keys: Vec<(Arc<Store>, String, u64, bool)>,
+}
+
+impl WatchedKeys {
+    async fn watch(&mut self, store: &Arc<Store>, key: &str) {
+        if self
+            .keys
+            .iter()
+            .any(|(db, watched, _, _)| Arc::ptr_eq(db, store) && watched == key)
+        {
+            return;
+        }
+        let version = store.watch(key);
+        let existed = store.contains_key(key, Instant::now()).await;
+        self.keys
+            .push((store.clone(), key.to_string(), version, existed));
+    }
+
+    fn clear(&mut self) {
+        for (store, key, _, _) in self.keys.drain(..) {
+            store.unwatch(&key);
+        }
+    }
+
+    /// Whether any watched key was modified, deleted or has expired since
+    /// it was watched.
+    async fn is_dirty(&self) -> bool {
+        let now = Instant::now();
+        for (store, key, version, existed) in self.keys.iter() {
+            if store.key_version(key) != *version {
+                return true;
+            }
+            if *existed && !store.contains_key(key, now).await {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Drop for WatchedKeys {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Per-connection state: protocol version, transaction, watched keys,
+/// subscriptions and client side caching mode.
+pub struct Client {
+    handle: ClientHandle,
+    transaction: Option<Transaction>,
+    watched: WatchedKeys,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+    shard_channels: BTreeSet<String>,
+    /// CLIENT CACHING answer for the next command in OPTIN/OPTOUT mode.
+    caching: Option<bool>,
+    closing: bool,
+    /// The database selected with SELECT.
+    store: Arc<Store>,
+    server: Arc<Server>,
+    config: Config,
+}
+
+impl Client {
+    /// Creates the state for a new connection, along with the receiving end
+    /// of its push queue and the signal raised when it has to be dropped.
+    pub fn new(server: Arc<Server>, config: Config) -> (Self, mpsc::Receiver<Value>, Arc<Notify>) {
+        let (pushes, receiver) = mpsc::channel(PUSH_QUEUE_LIMIT);
+        let kill = Arc::new(Notify::new());
+        let handle = ClientHandle {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: Arc::new(AtomicU8::new(2)),
+            pushes,
+            kill: kill.clone(),
+        };
+
+        let client = Self {
+            handle,
+            transaction: None,
+            watched: WatchedKeys::default(),
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            shard_channels: BTreeSet::new(),
+            caching: None,
+            closing: false,
+            store: server.databases.get(0).expect("no databases").clone(),
+            server,
+            config,
+        };
+        client.store.tracking().connect(&client.handle);
+        (client, receiver, kill)
+    }
+
+    pub fn id(&self) -> ClientId {
+        self.handle.id
+    }
+
+    /// RESP version negotiated with HELLO.
+    pub fn protocol(&self) -> u8 {
+        self.handle.protocol()
+    }
+
+    /// Whether the connection should be closed once the replies are sent.
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// The count reported in (un)subscribe replies: shard channels are
+    /// counted on their own, like in Redis.
+    fn subscription_count(&self, kind: SubscriptionKind) -> usize {
+        match kind {
+            SubscriptionKind::Channel | SubscriptionKind::Pattern => {
+                self.channels.len() + self.patterns.len()
+            }
+            SubscriptionKind::ShardChannel => self.shard_channels.len(),
+        }
+    }
+
+    fn subscriptions_mut(&mut self, kind: SubscriptionKind) -> &mut BTreeSet<String> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+
+    /// RESP2 connections with subscriptions can only manage them.
+    fn in_subscribed_mode(&self) -> bool {
+        self.protocol() == 2
+            && !(self.channels.is_empty()
+                && self.patterns.is_empty()
+                && self.shard_channels.is_empty())
+    }
+
+    /// Runs every queued command while holding the store's exclusive
+    /// execution lock, so no other client can run in between.
+    async fn exec_transaction(&mut self, transaction: Transaction) -> Result<Value, Error> {
+        if transaction.aborted {
+            self.watched.clear();
+            return Err(Error::ExecAbort);
+        }
+
+        // The lock is shared by all databases, and SELECT may change
+        // `self.store` along the way.
+        let store = self.store.clone();
+        let _guard = store.lock_exclusive().await;
+        let dirty = self.watched.is_dirty().await;
+        self.watched.clear();
+        if dirty {
+            return Ok(Value::NullArray);
+        }
+
+        // The writes are logged to the AOF inside MULTI ... EXEC.
+        self.server.aof.begin_atomic().await;
+        let replies = self.run_queued(transaction.queued).await;
+        self.server.aof.end_atomic().await;
+        Ok(Value::Array(replies?))
+    }
+
+    async fn run_queued(
+        &mut self,
+        queued: Vec<(Command, Vec<Value>)>,
+    ) -> Result<Vec<Value>, Error> {
+        let mut replies = Vec::with_capacity(queued.len());
+        for (command, data) in queued {
+            // A queued SELECT switches the database for the commands after it.
+            let result = if command == Command::SELECT {
+                self.select(&data)
+            } else {
+                self.track_reads(&command, &data);
+                command
+                    .construct_response(data, self.store.clone(), &self.server, &self.config)
+                    .await
+            };
+            replies.push(reply_or_error(result)?);
+        }
+        Ok(replies)
+    }
+
+    fn subscribe(&mut self, kind: SubscriptionKind, names: &[Value]) -> Result<Vec<Value>, Error> {
+        let mut replies = Vec::with_capacity(names.len());
+        for name in names {
+            let name = arg_str(name)?;
+            if self.subscriptions_mut(kind).insert(name.to_string()) {
+                self.server.pubsub.subscribe(kind, name, &self.handle);
+            }
+            replies.push(subscription_reply(
+                kind.subscribe_reply(),
+                Some(name.to_string()),
+                self.subscription_count(kind),
+            ));
+        }
+        Ok(replies)
+    }
+
+    /// Unsubscribes from `names`, or from everything of `kind` when empty.
+    fn unsubscribe(
+        &mut self,
+        kind: SubscriptionKind,
+        names: &[Value],
+    ) -> Result<Vec<Value>, Error> {
+        let names: Vec<String> = if names.is_empty() {
+            self.subscriptions_mut(kind).iter().cloned().collect()
+        } else {
+            names
+                .iter()
+                .map(|name| arg_str(name).map(str::to_string))
+                .collect::<Result<_, _>>()?
+        };
+
+        if names.is_empty() {
+            return Ok(vec![subscription_reply(
+                kind.unsubscribe_reply(),
+                None,
+                self.subscription_count(kind),
+            )]);
+        }
+
+        let mut replies = Vec::with_capacity(names.len());
+        for name in names {
+            if self.subscriptions_mut(kind).remove(&name) {
+                self.server.pubsub.unsubscribe(kind, &name, self.id());
+            }
+            replies.push(subscription_reply(
+                kind.unsubscribe_reply(),
+                Some(name),
+                self.subscription_count(kind),
+            ));
+        }
+        Ok(replies)
+    }
+
+    /// SELECT index
+    fn select(&mut self, data: &[Value]) -> Result<Value, Error> {
+        let index = db_index(&data[1], &self.server.databases)?;
+        self.store = self.server.databases.get(index).unwrap().clone();
+        Ok(Value::SimpleString("OK".to_string()))
+    }
+
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    fn hello(&mut self, data: &[Value]) -> Result<Value, Error> {
+        if let Some(version) = data.get(1) {
+            let protocol = match arg_str(version)?.parse::<i64>() {
+                Ok(2) => 2,
+                Ok(3) => 3,
+                Ok(_) => return Err(Error::NoProto),
+                Err(_) => {
+                    return Err(Error::InvalidCommand(
+                        "Protocol version is not an integer or out of range",
+                    ))
+                }
+            };
+            self.handle.protocol.store(protocol, Ordering::Relaxed);
+        }
+
+        let mut idx = 2;
+        while idx < data.len() {
+            let remaining = data.len() - idx - 1;
+            match arg_str(&data[idx])?.to_lowercase().as_str() {
+                "auth" if remaining >= 2 => idx += 2,
+                "setname" if remaining >= 1 => idx += 1,
+                _ => return Err(Error::InvalidCommand("syntax error")),
+            }
+            idx += 1;
+        }
+
+        let field = |s: &str| Value::BulkString(Some(s.to_string()));
+        Ok(Value::Map(vec![
+            (field("server"), field("redis")),
+            (field("version"), field(REDIS_VERSION)),
+            (field("proto"), Value::Integer(self.protocol() as i64)),
+            (field("id"), Value::Integer(self.id() as i64)),
+            (field("mode"), field("standalone")),
+            (field("role"), field("master")),
+            (field("modules"), Value::Array(Vec::new())),
+        ]))
+    }
+
+    /// Remembers the keys a read-only command is about to read, when the
+    /// client tracks keys in default mode and wants this command cached.
+    fn track_reads(&self, command: &Command, data: &[Value]) {
+        let keys = command.read_keys(data);
+        if keys.is_empty() {
+            return;
+        }
+        let tracking = self.store.tracking();
+        let Some(options) = tracking.options(self.id()) else {
+            return;
+        };
+
+        let cache = if options.bcast {
+            false
+        } else if options.optin {
+            self.caching == Some(true)
+        } else if options.optout {
+            self.caching != Some(false)
+        } else {
+            true
+        };
+        if cache {
+            tracking.remember_keys(self.id(), &keys);
+        }
+    }
+
+    /// CLIENT ID | GETREDIR | CACHING YES|NO
+    ///   | TRACKING ON|OFF [REDIRECT id] [PREFIX prefix ...] [BCAST] [OPTIN]
+    ///     [OPTOUT] [NOLOOP]
+    fn client_command(&mut self, data: &[Value]) -> Result<Value, Error> {
+        let subcommand = arg_str(&data[1])?.to_lowercase();
+        let tracking = self.store.tracking();
+
+        match (subcommand.as_str(), data.len()) {
+            ("id", 2) => Ok(Value::Integer(self.id() as i64)),
+            ("getredir", 2) => Ok(Value::Integer(match tracking.options(self.id()) {
+                Some(options) => options.redirect.map_or(0, |id| id as i64),
+                None => -1,
+            })),
+            ("caching", 3) => {
+                let options = tracking
+                    .options(self.id())
+                    .filter(|options| options.optin || options.optout)
+                    .ok_or(Error::InvalidCommand(
+                        "CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled",
+                    ))?;
+                match arg_str(&data[2])?.to_lowercase().as_str() {
+                    "yes" if options.optin => self.caching = Some(true),
+                    "yes" => return Err(Error::InvalidCommand(
+                        "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.",
+                    )),
+                    "no" if options.optout => self.caching = Some(false),
+                    "no" => return Err(Error::InvalidCommand(
+                        "CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.",
+                    )),
+                    _ => return Err(Error::InvalidCommand("syntax error")),
+                }
+                Ok(Value::SimpleString("OK".to_string()))
+            }
+            ("tracking", 3..) => {
+                let on = match arg_str(&data[2])?.to_lowercase().as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(Error::InvalidCommand("syntax error")),
+                };
+
+                let mut options = TrackingOptions::default();
+                let mut idx = 3;
+                while idx < data.len() {
+                    let remaining = data.len() - idx - 1;
+                    match arg_str(&data[idx])?.to_lowercase().as_str() {
+                        "redirect" if remaining >= 1 => {
+                            let id =
+                                arg_str(&data[idx + 1])?.parse::<ClientId>().map_err(|_| {
+                                    Error::InvalidCommand("value is not an integer or out of range")
+                                })?;
+                            options.redirect = Some(id);
+                            idx += 1;
+                        }
+                        "prefix" if remaining >= 1 => {
+                            options.prefixes.push(arg_str(&data[idx + 1])?.to_string());
+                            idx += 1;
+                        }
+                        "bcast" => options.bcast = true,
+                        "optin" => options.optin = true,
+                        "optout" => options.optout = true,
+                        "noloop" => options.noloop = true,
+                        _ => return Err(Error::InvalidCommand("syntax error")),
+                    }
+                    idx += 1;
+                }
+
+                if !on {
+                    tracking.disable(self.id());
+                    self.caching = None;
+                    return Ok(Value::SimpleString("OK".to_string()));
+                }
+                if !options.prefixes.is_empty() && !options.bcast {
+                    return Err(Error::InvalidCommand(
+                        "PREFIX option requires BCAST mode to be enabled",
+                    ));
+                }
+                if options.optin && options.optout {
+                    return Err(Error::InvalidCommand("You can't use both OPTIN and OPTOUT"));
+                }
+                if options.bcast && (options.optin || options.optout) {
+                    return Err(Error::InvalidCommand(
+                        "OPTIN and OPTOUT are not compatible with BCAST",
+                    ));
+                }
+                tracking.enable(self.id(), options)?;
+                Ok(Value::SimpleString("OK".to_string()))
+            }
+            _ => Err(Error::InvalidCommand(
+                "unknown subcommand or wrong number of arguments for 'client' command",
+            )),
+        }
+    }
+
+    /// Handles one request and returns the frames to send back, which is
+    /// more than one for (UN)SUBSCRIBE with several channels.
+    pub async fn handle_request(&mut self, data: Vec<Value>) -> Result<Vec<Value>, Error> {
+        let id = self.id();
+        tracking::with_current_client(id, self.dispatch(data)).await
+    }
+
+    async fn dispatch(&mut self, data: Vec<Value>) -> Result<Vec<Value>, Error> {
+        let cmd_part = data[0].str_value().ok_or(Error::InvalidCommand(
+            "Expected Command to be parseable as string",
+        ))?;
+
+        let command = Command::from_str(cmd_part)
+            .and_then(|command| command.check_arity(&data).map(|_| command));
+        let command = match command {
+            Ok(command) => command,
+            Err(e) => {
+                if let Some(transaction) = self.transaction.as_mut() {
+                    transaction.aborted = true;
+                }
+                return Err(e);
+            }
+        };
+
+        let subscribed = self.in_subscribed_mode();
+        if subscribed
+            && !matches!(
+                command,
+                Command::SUBSCRIBE
+                    | Command::UNSUBSCRIBE
+                    | Command::PSUBSCRIBE
+                    | Command::PUNSUBSCRIBE
+                    | Command::SSUBSCRIBE
+                    | Command::SUNSUBSCRIBE
+                    | Command::PING
+                    | Command::QUIT
+            )
+        {
+            return Err(Error::SubscribedMode(command.name()));
+        }
+
+        if self.server.persistence.is_loading() && !command.allowed_while_loading() {
+            return Err(Error::Loading);
+        }
+
+        // Only SCRIPT KILL can help while a script is hogging the server.
+        let kills_script = matches!(command, Command::SCRIPT | Command::FUNCTION)
+            && data
+                .get(1)
+                .and_then(Value::str_value)
+                .is_some_and(|subcommand| subcommand.eq_ignore_ascii_case("kill"));
+        if !kills_script && self.server.scripting.is_busy() {
+            return Err(Error::Busy);
+        }
+
+        // The CLIENT CACHING answer holds for the next command, or for the
+        // whole transaction.
+        let keeps_caching = command == Command::CLIENT;
+
+        let reply = match (command, self.transaction.as_mut()) {
+            (Command::MULTI, Some(_)) => {
+                Err(Error::InvalidCommand("MULTI calls can not be nested"))
+            }
+            (Command::MULTI, None) => {
+                self.transaction = Some(Transaction::default());
+                Ok(vec![Value::SimpleString("OK".to_string())])
+            }
+            (Command::EXEC, Some(_)) => {
+                let queued = self.transaction.take().unwrap_or_default();
+                self.exec_transaction(queued).await.map(|reply| vec![reply])
+            }
+            (Command::EXEC, None) => Err(Error::InvalidCommand("EXEC without MULTI")),
+            (Command::DISCARD, Some(_)) => {
+                self.transaction = None;
+                self.watched.clear();
+                Ok(vec![Value::SimpleString("OK".to_string())])
+            }
+            (Command::DISCARD, None) => Err(Error::InvalidCommand("DISCARD without MULTI")),
+            (Command::WATCH, Some(_)) => {
+                Err(Error::InvalidCommand("WATCH inside MULTI is not allowed"))
+            }
+            (Command::WATCH, None) => {
+                for key in data[1..].iter() {
+                    self.watched.watch(&self.store, arg_str(key)?).await;
+                }
+                Ok(vec![Value::SimpleString("OK".to_string())])
+            }
+            (Command::UNWATCH, None) => {
+                self.watched.clear();
+                Ok(vec![Value::SimpleString("OK".to_string())])
+            }
+            (Command::SELECT, None) => self.select(&data).map(|reply| vec![reply]),
+            (
+                Command::SUBSCRIBE
+                | Command::UNSUBSCRIBE
+                | Command::PSUBSCRIBE
+                | Command::PUNSUBSCRIBE
+                | Command::SSUBSCRIBE
+                | Command::SUNSUBSCRIBE
+                | Command::HELLO
+                | Command::CLIENT
+                | Command::QUIT,
+                Some(transaction),
+            ) => {
+                transaction.aborted = true;
+                Err(Error::InvalidCommand(
+                    "Command not allowed inside a transaction",
+                ))
+            }
+            (Command::SUBSCRIBE, None) => self.subscribe(SubscriptionKind::Channel, &data[1..]),
+            (Command::UNSUBSCRIBE, None) => self.unsubscribe(SubscriptionKind::Channel, &data[1..]),
+            (Command::PSUBSCRIBE, None) => self.subscribe(SubscriptionKind::Pattern, &data[1..]),
+            (Command::PUNSUBSCRIBE, None) => {
+                self.unsubscribe(SubscriptionKind::Pattern, &data[1..])
+            }
+            (Command::SSUBSCRIBE, None) => {
+                self.subscribe(SubscriptionKind::ShardChannel, &data[1..])
+            }
+            (Command::SUNSUBSCRIBE, None) => {
+                self.unsubscribe(SubscriptionKind::ShardChannel, &data[1..])
+            }
+            (Command::HELLO, None) => self.hello(&data).map(|reply| vec![reply]),
+            (Command::CLIENT, None) => self.client_command(&data).map(|reply| vec![reply]),
+            (Command::QUIT, None) => {
+                self.closing = true;
+                Ok(vec![Value::SimpleString("OK".to_string())])
+            }
+            // In subscribed mode PING answers in the shape of a message.
+            (Command::PING, None) if subscribed => {
+                let payload = match data.get(1) {
+                    Some(payload) => arg_str(payload)?.to_string(),
+                    None => String::new(),
+                };
+                Ok(vec![Value::Array(vec![
+                    Value::BulkString(Some("pong".to_string())),
+                    Value::BulkString(Some(payload)),
+                ])])
+            }
+            (command, Some(transaction)) => {
+                transaction.queued.push((command, data));
+                Ok(vec![Value::SimpleString("QUEUED".to_string())])
+            }
+            // Scripts run atomically, and saves and AOF rewrites copy the
+            // dataset while nothing else runs. SCRIPT must not wait for
+            // them so it can kill scripts.
+            (
+                command @ (Command::EVAL
+                | Command::EVALSHA
+                | Command::FCALL
+                | Command::FCALLRO
+                | Command::SAVE
+                | Command::BGSAVE
+                | Command::BGREWRITEAOF),
+                None,
+            ) => {
+                let _guard = self.store.lock_exclusive().await;
+                command
+                    .construct_response(data, self.store.clone(), &self.server, &self.config)
+                    .await
+                    .map(|reply| vec![reply])
+            }
+            (command @ (Command::SCRIPT | Command::FUNCTION), None) => command
+                .construct_response(data, self.store.clone(), &self.server, &self.config)
+                .await
+                .map(|reply| vec![reply]),
+            (command, None) => {
+                self.track_reads(&command, &data);
+                let _guard = self.store.lock_shared().await;
+                command
+                    .construct_response(data, self.store.clone(), &self.server, &self.config)
+                    .await
+                    .map(|reply| vec![reply])
+            }
+        };
+
+        if !keeps_caching && self.transaction.is_none() {
+            self.caching = None;
+        }
+        reply
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.store.tracking().disconnect(self.handle.id);
+        for channel in self.channels.iter() {
+            self.server
+                .pubsub
+                .unsubscribe(SubscriptionKind::Channel, channel, self.handle.id);
+        }
+        for pattern in self.patterns.iter() {
+            self.server
+                .pubsub
+                .unsubscribe(SubscriptionKind::Pattern, pattern, self.handle.id);
+        }
+        for channel in self.shard_channels.iter() {
+            self.server
+                .pubsub
+                .unsubscribe(SubscriptionKind::ShardChannel, channel, self.handle.id);
+        }
+    }
+}
+
+/// The `[kind, channel, count]` frame confirming a (un)subscription.
+fn subscription_reply(kind: &str, channel: Option<String>, count: usize) -> Value {
+    Value::Push(vec![
+        Value::BulkString(Some(kind.to_string())),
+        Value::BulkString(channel),
+        Value::Integer(count as i64),
+    ])
+}
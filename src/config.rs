@@ -3,14 +3,21 @@ pub struct Config {
     addr: String,
     rdb_dir: Option<String>,
     rdb_file: Option<String>,
+    databases: usize,
 }
 
 impl Config {
-    pub fn new(addr: String, rdb_dir: Option<String>, rdb_file: Option<String>) -> Self {
+    pub fn new(
+        addr: String,
+        rdb_dir: Option<String>,
+        rdb_file: Option<String>,
+        databases: usize,
+    ) -> Self {
         Self {
             addr,
             rdb_dir,
             rdb_file,
+            databases,
         }
     }
 
@@ -26,12 +33,17 @@ impl Config {
         self.rdb_dir.clone()
     }
 
+    /// Number of databases, selectable with SELECT.
+    pub fn get_databases(&self) -> usize {
+        self.databases
+    }
+
     pub fn get_rdb_path(&self) -> Option<String> {
         match self.rdb_dir.clone() {
-            Some(dir) => match self.rdb_file.clone() {
-                Some(fname) => Some(format!("{}/{}", dir, fname)),
-                None => None,
-            },
+            Some(dir) => self
+                .rdb_file
+                .clone()
+                .map(|fname| format!("{}/{}", dir, fname)),
             None => None,
         }
     }
@@ -3,6 +3,10 @@ use std::io;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncReadExt;
 
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
 pub struct StreamDeserializer<S> {
     stream: S,
 }
@@ -33,7 +37,7 @@ where
 
         let lf = self.stream.read_u8().await? as char;
         if lf != '\n' {
-            panic!("Unexpected terminator after CR");
+            return Err(invalid_data("Unexpected terminator after CR".to_string()));
         }
 
         Ok(())
@@ -47,10 +51,7 @@ where
             None
         } else {
             Some(s.parse::<usize>().map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Integer value: {} couldn't be parsed, {}", s, e),
-                )
+                invalid_data(format!("Integer value: {} couldn't be parsed, {}", s, e))
             })?)
         })
     }
@@ -73,20 +74,18 @@ where
                     let lf = self.stream.read_u8().await? as char;
 
                     if cr != '\r' {
-                        panic!("Expected CR at end of string");
+                        return Err(invalid_data("Expected CR at end of string".to_string()));
                     }
                     if lf != '\n' {
-                        panic!("Expected LF at end of string after \\r");
+                        return Err(invalid_data(
+                            "Expected LF at end of string after \\r".to_string(),
+                        ));
                     }
 
-                    let str_value = String::from_utf8(buffer).map_err(|e| {
-                        io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!("Couldn't convert u8 buffer into valid string, {}", e),
-                        )
-                    })?;
-
-                    Ok(Value::BulkString(Some(str_value)))
+                    match String::from_utf8(buffer) {
+                        Ok(str_value) => Ok(Value::BulkString(Some(str_value))),
+                        Err(e) => Ok(Value::Binary(e.into_bytes())),
+                    }
                 } else {
                     Ok(Value::None)
                 }
@@ -102,11 +101,14 @@ where
 
                 let s_int = s
                     .parse::<i64>()
-                    .expect(&format!("Unable to parse string: {} as i64", s));
+                    .map_err(|_| invalid_data(format!("Unable to parse string: {} as i64", s)))?;
 
                 Ok(Value::Integer(s_int))
             }
-            _ => panic!("Unexpected first character: {}", first_char),
+            _ => Err(invalid_data(format!(
+                "Unexpected first character: {}",
+                first_char
+            ))),
         }
     }
 
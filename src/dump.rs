@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::rdb;
+use crate::server::Server;
+use crate::store::{Entry, Store};
+use crate::{arg_str, Error, Value};
+
+/// DUMP key
+pub async fn dump(args: &[Value], store: &Store, server: &Server) -> Result<Value, Error> {
+    let key = arg_str(&args[1])?;
+    let compression = server.persistence.rdb_compression();
+    let payload = store
+        .view(Instant::now(), |view| {
+            view.get(key)
+                .map(|value| rdb::dump_value(value, compression))
+        })
+        .await;
+    Ok(payload.map_or(Value::BulkString(None), Value::Binary))
+}
+
+/// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds]
+/// [FREQ frequency]
+///
+/// IDLETIME and FREQ are checked, but keys carry no access time or
+/// frequency here for them to set.
+pub async fn restore(args: &[Value], store: &Store) -> Result<Value, Error> {
+    let key = arg_str(&args[1])?;
+    let payload = args[3].bytes_value().ok_or(Error::InvalidCommand(
+        "Argument couldn't be parsed as string",
+    ))?;
+
+    let (mut replace, mut absttl) = (false, false);
+    let (mut idletime, mut freq) = (None, None);
+    let mut idx = 4;
+    while idx < args.len() {
+        let remaining = args.len() - idx - 1;
+        match arg_str(&args[idx])?.to_lowercase().as_str() {
+            "replace" => replace = true,
+            "absttl" => absttl = true,
+            "idletime" if remaining >= 1 && freq.is_none() => {
+                let seconds = integer(&args[idx + 1])?;
+                if seconds < 0 {
+                    return Err(Error::InvalidCommand(
+                        "Invalid IDLETIME value, must be >= 0",
+                    ));
+                }
+                idletime = Some(seconds);
+                idx += 1;
+            }
+            "freq" if remaining >= 1 && idletime.is_none() => {
+                let frequency = integer(&args[idx + 1])?;
+                if !(0..=255).contains(&frequency) {
+                    return Err(Error::InvalidCommand(
+                        "Invalid FREQ value, must be >= 0 and <= 255",
+                    ));
+                }
+                freq = Some(frequency);
+                idx += 1;
+            }
+            _ => return Err(Error::InvalidCommand("syntax error")),
+        }
+        idx += 1;
+    }
+
+    let now = Instant::now();
+    if !replace && store.contains_key(key, now).await {
+        return Err(Error::BusyKey);
+    }
+    let ttl = integer(&args[2])?;
+    if ttl < 0 {
+        return Err(Error::InvalidCommand("Invalid TTL value, must be >= 0"));
+    }
+    if !absttl && now.checked_add(Duration::from_millis(ttl as u64)).is_none() {
+        return Err(Error::InvalidCommand(
+            "invalid expire time in 'restore' command",
+        ));
+    }
+    let value = rdb::restore_value(payload)?;
+    if value.is_empty() {
+        return Err(Error::InvalidCommand("Bad data format"));
+    }
+
+    // A TTL of 0 means the key doesn't expire.
+    let entry = match (ttl as u64, absttl) {
+        (0, _) => Some(Entry::new(value, None, None, now)),
+        (unix_ms, true) => {
+            let expires_at = UNIX_EPOCH + Duration::from_millis(unix_ms);
+            (expires_at > SystemTime::now()).then(|| Entry::new(value, None, Some(expires_at), now))
+        }
+        (ttl, false) => Some(Entry::new(value, Some(ttl), None, now)),
+    };
+    store.restore(key, entry, replace).await?;
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+fn integer(value: &Value) -> Result<i64, Error> {
+    arg_str(value)?
+        .parse()
+        .map_err(|_| Error::InvalidCommand("value is not an integer or out of range"))
+}
@@ -0,0 +1,514 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mlua::{HookTriggers, IntoLuaMulti, Lua, Variadic, VmState};
+
+use crate::config::Config;
+use crate::glob::glob_match;
+use crate::rdb;
+use crate::scripting::{self, Scripting};
+use crate::server::Server;
+use crate::store::Store;
+use crate::{arg_str, Error, Value};
+
+/// Flags `redis.register_function` accepts.
+const FUNCTION_FLAGS: [&str; 5] = [
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+/// How long running a library's code to register its functions may take.
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A function as a library registered it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: Vec<&'static str>,
+}
+
+impl FunctionInfo {
+    /// Functions flagged `no-writes` can run from FCALL_RO.
+    pub fn is_read_only(&self) -> bool {
+        self.flags.contains(&"no-writes")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Library {
+    pub name: String,
+    /// The code as loaded, including the `#!lua name=...` line.
+    pub code: String,
+    pub functions: Vec<FunctionInfo>,
+}
+
+/// The loaded function libraries, by name.
+#[derive(Debug, Default)]
+pub struct Functions {
+    libraries: Mutex<BTreeMap<String, Library>>,
+}
+
+impl Functions {
+    /// FUNCTION LOAD: runs `code` to learn its functions and adds the
+    /// library, returning its name.
+    pub fn load(&self, code: &str, replace: bool) -> Result<String, Error> {
+        let library = compile_library(code)?;
+        let mut libraries = self.libraries.lock().unwrap();
+        add_library(&mut libraries, library, replace)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), Error> {
+        let mut libraries = self.libraries.lock().unwrap();
+        libraries
+            .remove(name)
+            .map(|_| ())
+            .ok_or(Error::InvalidCommand("Library not found"))
+    }
+
+    pub fn flush(&self) {
+        self.libraries.lock().unwrap().clear();
+    }
+
+    pub fn libraries(&self) -> Vec<Library> {
+        let libraries = self.libraries.lock().unwrap();
+        libraries.values().cloned().collect()
+    }
+
+    /// The function called `name`, with the code of its library.
+    pub fn find(&self, name: &str) -> Option<(String, FunctionInfo)> {
+        let libraries = self.libraries.lock().unwrap();
+        libraries.values().find_map(|library| {
+            library
+                .functions
+                .iter()
+                .find(|function| function.name == name)
+                .map(|function| (library.code.clone(), function.clone()))
+        })
+    }
+
+    /// FUNCTION DUMP: every library in a DUMP payload.
+    pub fn dump(&self) -> Vec<u8> {
+        let libraries = self.libraries.lock().unwrap();
+        let mut payload = Vec::new();
+        rdb::write_functions(
+            &mut payload,
+            libraries.values().map(|library| library.code.as_str()),
+        );
+        rdb::seal_payload(payload)
+    }
+
+    /// FUNCTION RESTORE: adds the libraries of a DUMP payload, all of them
+    /// or none.
+    pub fn restore(&self, payload: &[u8], policy: RestorePolicy) -> Result<(), Error> {
+        let codes = rdb::parse_functions(rdb::open_payload(payload)?)?;
+        let restored = codes
+            .iter()
+            .map(|code| compile_library(code))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut libraries = self.libraries.lock().unwrap();
+        let mut updated = match policy {
+            RestorePolicy::Flush => BTreeMap::new(),
+            RestorePolicy::Append | RestorePolicy::Replace => libraries.clone(),
+        };
+        for library in restored {
+            add_library(&mut updated, library, policy == RestorePolicy::Replace)?;
+        }
+        *libraries = updated;
+        Ok(())
+    }
+}
+
+/// What FUNCTION RESTORE does with the libraries already loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestorePolicy {
+    /// Drop them first.
+    Flush,
+    /// Keep them, failing on a library name already in use.
+    Append,
+    /// Keep them, unless a restored library has the same name.
+    Replace,
+}
+
+fn add_library(
+    libraries: &mut BTreeMap<String, Library>,
+    library: Library,
+    replace: bool,
+) -> Result<String, Error> {
+    if !replace && libraries.contains_key(&library.name) {
+        return Err(Error::Script(format!(
+            "ERR Library '{}' already exists",
+            library.name
+        )));
+    }
+    for other in libraries
+        .values()
+        .filter(|other| other.name != library.name)
+    {
+        if let Some(function) = library
+            .functions
+            .iter()
+            .find(|function| other.functions.iter().any(|f| f.name == function.name))
+        {
+            return Err(Error::Script(format!(
+                "ERR Function {} already exists",
+                function.name
+            )));
+        }
+    }
+
+    let name = library.name.clone();
+    libraries.insert(name.clone(), library);
+    Ok(name)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits the `#!<engine> name=<library>` line off a library's code.
+fn parse_metadata(code: &str) -> Result<(String, &str), Error> {
+    let rest = code
+        .strip_prefix("#!")
+        .ok_or(Error::InvalidCommand("Missing library metadata"))?;
+    let (shebang, body) = rest.split_once('\n').unwrap_or((rest, ""));
+
+    let mut parts = shebang.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(Error::Script(format!("ERR Engine '{}' not found", engine)));
+    }
+
+    let mut name = None;
+    for part in parts {
+        match part.strip_prefix("name=") {
+            Some(value) => name = Some(value),
+            None => {
+                return Err(Error::Script(format!(
+                    "ERR Invalid metadata value given: {}",
+                    part
+                )))
+            }
+        }
+    }
+    let name = name.ok_or(Error::InvalidCommand("Library name was not given"))?;
+    if !is_valid_name(name) {
+        return Err(Error::InvalidCommand(
+            "Library names can only contain letters, numbers, or underscores(_) and must be at least one character long",
+        ));
+    }
+    Ok((name.to_string(), body))
+}
+
+/// Checks a library loads, without keeping its Lua state.
+fn compile_library(code: &str) -> Result<Library, Error> {
+    let lua = scripting::new_lua()?;
+    let (name, registered) = load_library(&lua, code)?;
+    Ok(Library {
+        name,
+        code: code.to_string(),
+        functions: registered.into_iter().map(|(info, _)| info).collect(),
+    })
+}
+
+type Registered = Vec<(FunctionInfo, mlua::Function)>;
+
+/// Runs a library's code in `lua`, returning the library name and the
+/// functions it registered.
+fn load_library(lua: &Lua, code: &str) -> Result<(String, Registered), Error> {
+    let (name, body) = parse_metadata(code)?;
+    // The metadata line is not Lua, but keep line numbers right.
+    let chunk = lua
+        .load(format!("\n{}", body))
+        .set_name("@user_function")
+        .into_function()
+        .map_err(|e| {
+            let message = match e {
+                mlua::Error::SyntaxError { message, .. } => message,
+                e => e.to_string(),
+            };
+            Error::Script(format!("ERR Error compiling function: {}", message))
+        })?;
+
+    let registered = Arc::new(Mutex::new(Registered::new()));
+    let setup = || -> mlua::Result<()> {
+        let redis = lua.create_table()?;
+        let functions = registered.clone();
+        redis.set(
+            "register_function",
+            lua.create_function(move |_, args: Variadic<mlua::Value>| {
+                let function = parse_registration(args)?;
+                let mut functions = functions.lock().unwrap();
+                if functions.iter().any(|(f, _)| f.name == function.0.name) {
+                    return Err(mlua::Error::runtime(
+                        "Function already exists in the library",
+                    ));
+                }
+                functions.push(function);
+                Ok(())
+            })?,
+        )?;
+        scripting::add_log(lua, &redis)?;
+        lua.globals().set("redis", redis)?;
+
+        let started = Instant::now();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(1000),
+            move |_, _| {
+                if started.elapsed() > LOAD_TIMEOUT {
+                    return Err(mlua::Error::runtime("FUNCTION LOAD timeout"));
+                }
+                Ok(VmState::Continue)
+            },
+        )
+    };
+    setup().map_err(|e| Error::Script(format!("ERR {}", e)))?;
+
+    let loaded = chunk.call::<()>(());
+    lua.remove_hook();
+    if let Err(e) = loaded {
+        return Err(Error::Script(format!(
+            "ERR Error registering functions: {}",
+            scripting::error_message(&e)
+        )));
+    }
+
+    let registered = std::mem::take(&mut *registered.lock().unwrap());
+    if registered.is_empty() {
+        return Err(Error::InvalidCommand("No functions registered"));
+    }
+    Ok((name, registered))
+}
+
+/// Arguments of `redis.register_function`: a name and a callback, or a
+/// table with `function_name`, `callback`, `flags` and `description`.
+fn parse_registration(args: Variadic<mlua::Value>) -> mlua::Result<(FunctionInfo, mlua::Function)> {
+    let (name, callback, flags, description) = match (args.len(), args.first()) {
+        (1, Some(mlua::Value::Table(table))) => (
+            table.get::<Option<String>>("function_name")?,
+            table.get::<Option<mlua::Function>>("callback")?,
+            table
+                .get::<Option<Vec<String>>>("flags")?
+                .unwrap_or_default(),
+            table.get::<Option<String>>("description")?,
+        ),
+        (2, Some(mlua::Value::String(name))) => (
+            Some(name.to_str()?.to_string()),
+            args[1].as_function().cloned(),
+            Vec::new(),
+            None,
+        ),
+        _ => {
+            return Err(mlua::Error::runtime(
+                "wrong number of arguments to redis.register_function",
+            ))
+        }
+    };
+
+    let name = name
+        .filter(|name| is_valid_name(name))
+        .ok_or(mlua::Error::runtime(
+            "Function names can only contain letters, numbers, or underscores(_) and must be at least one character long",
+        ))?;
+    let callback = callback.ok_or(mlua::Error::runtime("callback must be a function"))?;
+    let flags = flags
+        .iter()
+        .map(|flag| {
+            FUNCTION_FLAGS
+                .iter()
+                .find(|known| **known == flag.as_str())
+                .copied()
+                .ok_or(mlua::Error::runtime("unknown flag given"))
+        })
+        .collect::<mlua::Result<_>>()?;
+
+    let info = FunctionInfo {
+        name,
+        description,
+        flags,
+    };
+    Ok((info, callback))
+}
+
+/// FCALL function numkeys [key ...] [arg ...], or FCALL_RO with
+/// `read_only`.
+pub async fn fcall(
+    request_content: &[Value],
+    store: Arc<Store>,
+    server: &Arc<Server>,
+    config: &Config,
+    read_only: bool,
+) -> Result<Value, Error> {
+    let name = arg_str(&request_content[1])?;
+    let (keys, argv) = scripting::split_keys(&request_content[2..])?;
+
+    let (code, function) = server
+        .functions
+        .find(name)
+        .ok_or(Error::InvalidCommand("Function not found"))?;
+    if read_only && !function.is_read_only() {
+        return Err(Error::InvalidCommand(
+            "Can not execute a script with write flag using *_ro command.",
+        ));
+    }
+
+    let lua = scripting::new_lua()?;
+    let (_, registered) = load_library(&lua, &code)?;
+    let callback = registered
+        .into_iter()
+        .find(|(info, _)| info.name == function.name)
+        .map(|(_, callback)| callback)
+        .ok_or(Error::InvalidCommand("Function not found"))?;
+
+    let args = (|| {
+        (
+            lua.create_sequence_from(keys)?,
+            lua.create_sequence_from(argv)?,
+        )
+            .into_lua_multi(&lua)
+    })()
+    .map_err(|e| Error::Script(format!("ERR {}", e)))?;
+    scripting::run(
+        &lua,
+        callback,
+        args,
+        store,
+        server,
+        config,
+        function.is_read_only(),
+        name,
+    )
+    .await
+}
+
+/// FUNCTION LOAD | DELETE | FLUSH | LIST | DUMP | RESTORE | KILL.
+pub fn function(
+    request_content: &[Value],
+    functions: &Functions,
+    scripting: &Scripting,
+) -> Result<Value, Error> {
+    let subcommand = arg_str(&request_content[1])?.to_lowercase();
+    let args = &request_content[2..];
+    let ok = || Ok(Value::SimpleString("OK".to_string()));
+
+    match subcommand.as_str() {
+        "load" if !args.is_empty() && args.len() <= 2 => {
+            let replace = match args.len() {
+                2 if arg_str(&args[0])?.eq_ignore_ascii_case("replace") => true,
+                2 => {
+                    return Err(Error::InvalidCommand(
+                        "Unknown option given: only REPLACE is supported",
+                    ))
+                }
+                _ => false,
+            };
+            let name = functions.load(arg_str(&args[args.len() - 1])?, replace)?;
+            Ok(Value::BulkString(Some(name)))
+        }
+        "delete" if args.len() == 1 => {
+            functions.delete(arg_str(&args[0])?)?;
+            ok()
+        }
+        "flush" if args.len() <= 1 => {
+            if let Some(mode) = args.first() {
+                let mode = arg_str(mode)?.to_lowercase();
+                if mode != "sync" && mode != "async" {
+                    return Err(Error::InvalidCommand(
+                        "FUNCTION FLUSH only supports SYNC|ASYNC option",
+                    ));
+                }
+            }
+            functions.flush();
+            ok()
+        }
+        "list" => list(args, functions),
+        "dump" if args.is_empty() => Ok(Value::Binary(functions.dump())),
+        "restore" if !args.is_empty() && args.len() <= 2 => {
+            let payload = args[0].bytes_value().ok_or(Error::InvalidCommand(
+                "Argument couldn't be parsed as string",
+            ))?;
+            let policy = match args.get(1).map(arg_str).transpose()? {
+                None => RestorePolicy::Append,
+                Some(policy) => match policy.to_lowercase().as_str() {
+                    "flush" => RestorePolicy::Flush,
+                    "append" => RestorePolicy::Append,
+                    "replace" => RestorePolicy::Replace,
+                    _ => {
+                        return Err(Error::InvalidCommand(
+                            "Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.",
+                        ))
+                    }
+                },
+            };
+            functions.restore(payload, policy)?;
+            ok()
+        }
+        "kill" if args.is_empty() => {
+            scripting.kill()?;
+            ok()
+        }
+        _ => Err(Error::InvalidCommand(
+            "unknown subcommand or wrong number of arguments for 'function' command",
+        )),
+    }
+}
+
+/// FUNCTION LIST [LIBRARYNAME pattern] [WITHCODE].
+fn list(args: &[Value], functions: &Functions) -> Result<Value, Error> {
+    let mut pattern = None;
+    let mut with_code = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg_str(arg)?.to_lowercase().as_str() {
+            "withcode" => with_code = true,
+            "libraryname" => {
+                let name = args
+                    .next()
+                    .ok_or(Error::InvalidCommand("library name argument was not given"))?;
+                pattern = Some(arg_str(name)?);
+            }
+            _ => return Err(Error::InvalidCommand("Unknown argument")),
+        }
+    }
+
+    let bulk = |s: &str| Value::BulkString(Some(s.to_string()));
+    let libraries = functions
+        .libraries()
+        .into_iter()
+        .filter(|library| pattern.is_none_or(|pattern| glob_match(pattern, &library.name)))
+        .map(|library| {
+            let functions = library
+                .functions
+                .iter()
+                .map(|function| {
+                    Value::Map(vec![
+                        (bulk("name"), bulk(&function.name)),
+                        (
+                            bulk("description"),
+                            Value::BulkString(function.description.clone()),
+                        ),
+                        (
+                            bulk("flags"),
+                            Value::Array(function.flags.iter().map(|flag| bulk(flag)).collect()),
+                        ),
+                    ])
+                })
+                .collect();
+            let mut fields = vec![
+                (bulk("library_name"), bulk(&library.name)),
+                (bulk("engine"), bulk("LUA")),
+                (bulk("functions"), Value::Array(functions)),
+            ];
+            if with_code {
+                fields.push((bulk("library_code"), bulk(&library.code)));
+            }
+            Value::Map(fields)
+        })
+        .collect();
+    Ok(Value::Array(libraries))
+}
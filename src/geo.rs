@@ -0,0 +1,1024 @@
+use std::time::Instant;
+
+use crate::notify::NOTIFY_ZSET;
+use crate::sorted_set::SortedSet;
+use crate::store::{EntryValue, Store};
+use crate::{arg_str, Error, Value};
+
+// Limits from EPSG:900913 / EPSG:3785 / OSGEO:41001, the same ones Redis uses
+// so that scores are interchangeable with a real server.
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+
+const GEO_STEP_MAX: u8 = 26; // 26 * 2 = 52 bits
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+
+const GEO_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Range {
+    min: f64,
+    max: f64,
+}
+
+const LONG_RANGE: Range = Range {
+    min: GEO_LONG_MIN,
+    max: GEO_LONG_MAX,
+};
+
+const LAT_RANGE: Range = Range {
+    min: GEO_LAT_MIN,
+    max: GEO_LAT_MAX,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct GeoHash {
+    bits: u64,
+    step: u8,
+}
+
+impl GeoHash {
+    const ZERO: GeoHash = GeoHash { bits: 0, step: 0 };
+
+    fn is_zero(&self) -> bool {
+        self.bits == 0 && self.step == 0
+    }
+
+    /// Left-aligns the hash to 52 bits so it can be compared with scores.
+    fn align_52_bits(&self) -> u64 {
+        self.bits << (GEO_STEP_MAX * 2 - self.step * 2)
+    }
+
+    fn move_x(&mut self, d: i8) {
+        let x = self.bits & 0xaaaaaaaaaaaaaaaa;
+        let y = self.bits & 0x5555555555555555;
+        let zz = 0x5555555555555555u64 >> (64 - self.step as u32 * 2);
+
+        let x = if d > 0 {
+            x.wrapping_add(zz + 1)
+        } else {
+            (x | zz).wrapping_sub(zz + 1)
+        };
+
+        let x = x & (0xaaaaaaaaaaaaaaaau64 >> (64 - self.step as u32 * 2));
+        self.bits = x | y;
+    }
+
+    fn move_y(&mut self, d: i8) {
+        let x = self.bits & 0xaaaaaaaaaaaaaaaa;
+        let y = self.bits & 0x5555555555555555;
+        let zz = 0xaaaaaaaaaaaaaaaau64 >> (64 - self.step as u32 * 2);
+
+        let y = if d > 0 {
+            y.wrapping_add(zz + 1)
+        } else {
+            (y | zz).wrapping_sub(zz + 1)
+        };
+
+        let y = y & (0x5555555555555555u64 >> (64 - self.step as u32 * 2));
+        self.bits = x | y;
+    }
+
+    fn moved(&self, dx: i8, dy: i8) -> GeoHash {
+        let mut hash = *self;
+        if dx != 0 {
+            hash.move_x(dx);
+        }
+        if dy != 0 {
+            hash.move_y(dy);
+        }
+        hash
+    }
+
+    /// Center followed by N, S, E, W, NE, NW, SE, SW.
+    fn with_neighbors(&self) -> [GeoHash; 9] {
+        [
+            *self,
+            self.moved(0, 1),
+            self.moved(0, -1),
+            self.moved(1, 0),
+            self.moved(-1, 0),
+            self.moved(1, 1),
+            self.moved(-1, 1),
+            self.moved(1, -1),
+            self.moved(-1, -1),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GeoArea {
+    longitude: Range,
+    latitude: Range,
+}
+
+/// Spreads the bits of `x` over the even positions and `y` over the odd ones.
+fn interleave64(x: u32, y: u32) -> u64 {
+    fn spread(v: u32) -> u64 {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000FFFF0000FFFF;
+        v = (v | (v << 8)) & 0x00FF00FF00FF00FF;
+        v = (v | (v << 4)) & 0x0F0F0F0F0F0F0F0F;
+        v = (v | (v << 2)) & 0x3333333333333333;
+        (v | (v << 1)) & 0x5555555555555555
+    }
+
+    spread(x) | (spread(y) << 1)
+}
+
+/// Inverse of `interleave64`.
+fn deinterleave64(v: u64) -> (u32, u32) {
+    fn squash(v: u64) -> u32 {
+        let mut v = v & 0x5555555555555555;
+        v = (v | (v >> 1)) & 0x3333333333333333;
+        v = (v | (v >> 2)) & 0x0F0F0F0F0F0F0F0F;
+        v = (v | (v >> 4)) & 0x00FF00FF00FF00FF;
+        v = (v | (v >> 8)) & 0x0000FFFF0000FFFF;
+        v = (v | (v >> 16)) & 0x00000000FFFFFFFF;
+        v as u32
+    }
+
+    (squash(v), squash(v >> 1))
+}
+
+fn encode(long_range: Range, lat_range: Range, longitude: f64, latitude: f64, step: u8) -> GeoHash {
+    let lat_offset = (latitude - lat_range.min) / (lat_range.max - lat_range.min);
+    let long_offset = (longitude - long_range.min) / (long_range.max - long_range.min);
+
+    let cells = (1u64 << step) as f64;
+    GeoHash {
+        bits: interleave64((lat_offset * cells) as u32, (long_offset * cells) as u32),
+        step,
+    }
+}
+
+fn decode(hash: GeoHash, long_range: Range, lat_range: Range) -> GeoArea {
+    let (ilato, ilono) = deinterleave64(hash.bits);
+    let cells = (1u64 << hash.step) as f64;
+
+    let lat_scale = lat_range.max - lat_range.min;
+    let long_scale = long_range.max - long_range.min;
+
+    GeoArea {
+        latitude: Range {
+            min: lat_range.min + (ilato as f64 / cells) * lat_scale,
+            max: lat_range.min + ((ilato as f64 + 1.0) / cells) * lat_scale,
+        },
+        longitude: Range {
+            min: long_range.min + (ilono as f64 / cells) * long_scale,
+            max: long_range.min + ((ilono as f64 + 1.0) / cells) * long_scale,
+        },
+    }
+}
+
+/// Encodes a coordinate pair into the 52-bit score stored in the sorted set.
+pub fn encode_score(longitude: f64, latitude: f64) -> f64 {
+    encode(LONG_RANGE, LAT_RANGE, longitude, latitude, GEO_STEP_MAX).bits as f64
+}
+
+/// Decodes a sorted set score back into the center of its geohash cell.
+pub fn decode_score(score: f64) -> (f64, f64) {
+    let hash = GeoHash {
+        bits: score as u64,
+        step: GEO_STEP_MAX,
+    };
+    let area = decode(hash, LONG_RANGE, LAT_RANGE);
+
+    let longitude =
+        ((area.longitude.min + area.longitude.max) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let latitude = ((area.latitude.min + area.latitude.max) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (longitude, latitude)
+}
+
+/// The standard 11 character geohash string, which uses a [-90, 90]
+/// latitude range instead of the mercator limits used for scores.
+pub fn geohash_string(score: f64) -> String {
+    let (longitude, latitude) = decode_score(score);
+    let hash = encode(
+        LONG_RANGE,
+        Range {
+            min: -90.0,
+            max: 90.0,
+        },
+        longitude,
+        latitude,
+        GEO_STEP_MAX,
+    );
+
+    (0..11)
+        .map(|i| {
+            // The last character only has 2 bits left, Redis pads it with 0.
+            let idx = if i == 10 {
+                0
+            } else {
+                (hash.bits >> (52 - (i + 1) * 5)) & 0x1f
+            };
+            GEO_ALPHABET[idx as usize] as char
+        })
+        .collect()
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (lat2.to_radians() - lat1.to_radians()).abs()
+}
+
+/// Haversine distance in meters.
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let lat1r = lat1.to_radians();
+    let lat2r = lat2.to_radians();
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+
+    // Same longitude: skip the expensive part of the formula.
+    if v == 0.0 {
+        return lat_distance(lat1, lat2);
+    }
+
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+fn estimate_steps_by_radius(range_meters: f64, latitude: f64) -> u8 {
+    if range_meters == 0.0 {
+        return GEO_STEP_MAX;
+    }
+
+    let mut range_meters = range_meters;
+    let mut step: i32 = 1;
+    while range_meters < MERCATOR_MAX {
+        range_meters *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+
+    // Cells get narrower towards the poles, so more of them are needed.
+    if !(-66.0..=66.0).contains(&latitude) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&latitude) {
+            step -= 1;
+        }
+    }
+
+    step.clamp(1, GEO_STEP_MAX as i32) as u8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SearchArea {
+    longitude: f64,
+    latitude: f64,
+    shape: Shape,
+    /// Meters per unit requested by the client.
+    conversion: f64,
+}
+
+impl SearchArea {
+    /// Returns (min_lon, min_lat, max_lon, max_lat).
+    fn bounding_box(&self) -> (f64, f64, f64, f64) {
+        let (width, height) = match self.shape {
+            Shape::Radius(radius) => (radius, radius),
+            Shape::Box { width, height } => (width / 2.0, height / 2.0),
+        };
+        let width = width * self.conversion;
+        let height = height * self.conversion;
+
+        let lat_delta = (height / EARTH_RADIUS_IN_METERS).to_degrees();
+        let long_delta_top =
+            (width / EARTH_RADIUS_IN_METERS / (self.latitude + lat_delta).to_radians().cos())
+                .to_degrees();
+        let long_delta_bottom =
+            (width / EARTH_RADIUS_IN_METERS / (self.latitude - lat_delta).to_radians().cos())
+                .to_degrees();
+
+        // The widest part of the box is the edge closest to the equator.
+        let long_delta = if self.latitude < 0.0 {
+            long_delta_bottom
+        } else {
+            long_delta_top
+        };
+
+        (
+            self.longitude - long_delta,
+            self.latitude - lat_delta,
+            self.longitude + long_delta,
+            self.latitude + lat_delta,
+        )
+    }
+
+    fn radius_meters(&self) -> f64 {
+        let radius = match self.shape {
+            Shape::Radius(radius) => radius,
+            Shape::Box { width, height } => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+        };
+        radius * self.conversion
+    }
+
+    /// Geohash cells (center and neighbors) that together cover the area.
+    /// Cells that cannot contain matches are zeroed out.
+    fn covering_cells(&self) -> [GeoHash; 9] {
+        let (min_lon, min_lat, max_lon, max_lat) = self.bounding_box();
+        let radius_meters = self.radius_meters();
+
+        let mut steps = estimate_steps_by_radius(radius_meters, self.latitude);
+        let mut hash = encode(LONG_RANGE, LAT_RANGE, self.longitude, self.latitude, steps);
+        let mut cells = hash.with_neighbors();
+        let mut area = decode(hash, LONG_RANGE, LAT_RANGE);
+
+        // The estimate can be one step too fine when the area touches the
+        // edge of the neighboring cells; in that case zoom out once.
+        let north = decode(cells[1], LONG_RANGE, LAT_RANGE);
+        let south = decode(cells[2], LONG_RANGE, LAT_RANGE);
+        let east = decode(cells[3], LONG_RANGE, LAT_RANGE);
+        let west = decode(cells[4], LONG_RANGE, LAT_RANGE);
+
+        let decrease_step = distance(
+            self.longitude,
+            self.latitude,
+            self.longitude,
+            north.latitude.max,
+        ) < radius_meters
+            || distance(
+                self.longitude,
+                self.latitude,
+                self.longitude,
+                south.latitude.min,
+            ) < radius_meters
+            || distance(
+                self.longitude,
+                self.latitude,
+                east.longitude.max,
+                self.latitude,
+            ) < radius_meters
+            || distance(
+                self.longitude,
+                self.latitude,
+                west.longitude.min,
+                self.latitude,
+            ) < radius_meters;
+
+        if steps > 1 && decrease_step {
+            steps -= 1;
+            hash = encode(LONG_RANGE, LAT_RANGE, self.longitude, self.latitude, steps);
+            cells = hash.with_neighbors();
+            area = decode(hash, LONG_RANGE, LAT_RANGE);
+        }
+
+        if steps >= 2 {
+            // Indices into `cells`: 1 N, 2 S, 3 E, 4 W, 5 NE, 6 NW, 7 SE, 8 SW.
+            let mut exclude = |indices: [usize; 3]| {
+                for i in indices {
+                    cells[i] = GeoHash::ZERO;
+                }
+            };
+            if area.latitude.min < min_lat {
+                exclude([2, 7, 8]);
+            }
+            if area.latitude.max > max_lat {
+                exclude([1, 5, 6]);
+            }
+            if area.longitude.min < min_lon {
+                exclude([4, 8, 6]);
+            }
+            if area.longitude.max > max_lon {
+                exclude([3, 7, 5]);
+            }
+        }
+
+        cells
+    }
+
+    /// Distance in meters from the center if the point lies within the shape.
+    fn distance_if_inside(&self, longitude: f64, latitude: f64) -> Option<f64> {
+        match self.shape {
+            Shape::Radius(radius) => {
+                let dist = distance(self.longitude, self.latitude, longitude, latitude);
+                (dist <= radius * self.conversion).then_some(dist)
+            }
+            Shape::Box { width, height } => {
+                // Latitude distance is cheaper, so check it first.
+                if lat_distance(latitude, self.latitude) > height * self.conversion / 2.0 {
+                    return None;
+                }
+                if distance(longitude, latitude, self.longitude, latitude)
+                    > width * self.conversion / 2.0
+                {
+                    return None;
+                }
+                Some(distance(self.longitude, self.latitude, longitude, latitude))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct GeoPoint {
+    member: String,
+    /// Distance from the search center, in the requested unit.
+    dist: f64,
+    score: f64,
+    longitude: f64,
+    latitude: f64,
+}
+
+/// Collects members of `zset` inside `area`, stopping after `limit` matches.
+fn search(zset: &SortedSet, area: &SearchArea, limit: Option<usize>) -> Vec<GeoPoint> {
+    let mut points = Vec::new();
+    let mut previous: Option<GeoHash> = None;
+
+    for cell in area.covering_cells() {
+        if cell.is_zero() {
+            continue;
+        }
+        // At low steps neighbors can wrap around to the same cell.
+        if previous == Some(cell) {
+            continue;
+        }
+        previous = Some(cell);
+
+        let min = cell.align_52_bits();
+        let max = GeoHash {
+            bits: cell.bits + 1,
+            step: cell.step,
+        }
+        .align_52_bits();
+
+        for (member, score) in zset.range_by_score(min as f64, max as f64) {
+            let (longitude, latitude) = decode_score(score);
+            if let Some(dist) = area.distance_if_inside(longitude, latitude) {
+                points.push(GeoPoint {
+                    member: member.to_string(),
+                    dist: dist / area.conversion,
+                    score,
+                    longitude,
+                    latitude,
+                });
+
+                if limit.is_some_and(|limit| points.len() >= limit) {
+                    return points;
+                }
+            }
+        }
+    }
+
+    points
+}
+
+fn arg_f64(value: &Value) -> Result<f64, Error> {
+    let f = arg_str(value)?
+        .parse::<f64>()
+        .map_err(|_| Error::InvalidCommand("value is not a valid float"))?;
+    if f.is_nan() {
+        return Err(Error::InvalidCommand("value is not a valid float"));
+    }
+    Ok(f)
+}
+
+fn unit_conversion(value: &Value) -> Result<f64, Error> {
+    match arg_str(value)?.to_lowercase().as_str() {
+        "m" => Ok(1.0),
+        "km" => Ok(1000.0),
+        "ft" => Ok(0.3048),
+        "mi" => Ok(1609.34),
+        _ => Err(Error::InvalidCommand(
+            "unsupported unit provided. please use M, KM, FT, MI",
+        )),
+    }
+}
+
+fn validate_coordinates(longitude: f64, latitude: f64) -> Result<(), Error> {
+    if !(GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude)
+        || !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+    {
+        return Err(Error::InvalidCommand("invalid longitude,latitude pair"));
+    }
+    Ok(())
+}
+
+/// Redis prints distances with 4 decimals.
+fn format_distance(dist: f64) -> String {
+    format!("{:.4}", dist)
+}
+
+/// Matches Redis' "human" long double formatting: 17 decimals with trailing
+/// zeros removed.
+fn format_coordinate(coordinate: f64) -> String {
+    let s = format!("{:.17}", coordinate);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    s.to_string()
+}
+
+fn coordinates_reply(longitude: f64, latitude: f64) -> Value {
+    Value::Array(vec![
+        Value::BulkString(Some(format_coordinate(longitude))),
+        Value::BulkString(Some(format_coordinate(latitude))),
+    ])
+}
+
+/// GEOADD key [NX | XX] [CH] longitude latitude member [...]
+pub async fn geoadd(request_content: &[Value], store: &Store) -> Result<Value, Error> {
+    if request_content.len() < 5 {
+        return Err(Error::InvalidCommand(
+            "wrong number of arguments for 'geoadd' command",
+        ));
+    }
+    let key = arg_str(&request_content[1])?;
+
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut idx = 2;
+    while idx < request_content.len() {
+        match arg_str(&request_content[idx])?.to_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "ch" => ch = true,
+            _ => break,
+        }
+        idx += 1;
+    }
+
+    if nx && xx {
+        return Err(Error::InvalidCommand(
+            "XX and NX options at the same time are not compatible",
+        ));
+    }
+
+    let triplets = &request_content[idx..];
+    if triplets.is_empty() || !triplets.len().is_multiple_of(3) {
+        return Err(Error::InvalidCommand("syntax error"));
+    }
+
+    let mut members = Vec::with_capacity(triplets.len() / 3);
+    for triplet in triplets.chunks(3) {
+        let longitude = arg_f64(&triplet[0])?;
+        let latitude = arg_f64(&triplet[1])?;
+        validate_coordinates(longitude, latitude)?;
+
+        let member = arg_str(&triplet[2])?.to_string();
+        members.push((member, encode_score(longitude, latitude)));
+    }
+
+    let (count, modified) = store
+        .write_sorted_set(key, Instant::now(), |zset| {
+            let (mut count, mut modified) = (0, false);
+            for (member, score) in members {
+                let existing = zset.score(&member);
+                if (nx && existing.is_some()) || (xx && existing.is_none()) {
+                    continue;
+                }
+
+                let changed = existing.is_some_and(|old| old != score);
+                let added = zset.insert(member, score);
+                modified |= added || changed;
+                if added || (ch && changed) {
+                    count += 1;
+                }
+            }
+            (count, modified)
+        })
+        .await?;
+
+    if modified {
+        store.notify(NOTIFY_ZSET, "zadd", key);
+    }
+    Ok(Value::Integer(count))
+}
+
+/// GEODIST key member1 member2 [M | KM | FT | MI]
+pub async fn geodist(request_content: &[Value], store: &Store) -> Result<Value, Error> {
+    if request_content.len() != 4 && request_content.len() != 5 {
+        return Err(Error::InvalidCommand(
+            "wrong number of arguments for 'geodist' command",
+        ));
+    }
+    let key = arg_str(&request_content[1])?;
+    let member1 = arg_str(&request_content[2])?;
+    let member2 = arg_str(&request_content[3])?;
+    let conversion = match request_content.get(4) {
+        Some(unit) => unit_conversion(unit)?,
+        None => 1.0,
+    };
+
+    let dist = store
+        .read_sorted_set(key, Instant::now(), |zset| {
+            let (lon1, lat1) = decode_score(zset.score(member1)?);
+            let (lon2, lat2) = decode_score(zset.score(member2)?);
+            Some(distance(lon1, lat1, lon2, lat2) / conversion)
+        })
+        .await?
+        .flatten();
+
+    Ok(Value::BulkString(dist.map(format_distance)))
+}
+
+/// GEOHASH key [member [member ...]]
+pub async fn geohash(request_content: &[Value], store: &Store) -> Result<Value, Error> {
+    if request_content.len() < 2 {
+        return Err(Error::InvalidCommand(
+            "wrong number of arguments for 'geohash' command",
+        ));
+    }
+    let key = arg_str(&request_content[1])?;
+    let members = request_content[2..]
+        .iter()
+        .map(arg_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let hashes = store
+        .read_sorted_set(key, Instant::now(), |zset| {
+            members
+                .iter()
+                .map(|member| zset.score(member).map(geohash_string))
+                .collect::<Vec<_>>()
+        })
+        .await?
+        .unwrap_or_else(|| vec![None; members.len()]);
+
+    Ok(Value::Array(
+        hashes.into_iter().map(Value::BulkString).collect(),
+    ))
+}
+
+/// GEOPOS key [member [member ...]]
+pub async fn geopos(request_content: &[Value], store: &Store) -> Result<Value, Error> {
+    if request_content.len() < 2 {
+        return Err(Error::InvalidCommand(
+            "wrong number of arguments for 'geopos' command",
+        ));
+    }
+    let key = arg_str(&request_content[1])?;
+    let members = request_content[2..]
+        .iter()
+        .map(arg_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let positions = store
+        .read_sorted_set(key, Instant::now(), |zset| {
+            members
+                .iter()
+                .map(|member| zset.score(member).map(decode_score))
+                .collect::<Vec<_>>()
+        })
+        .await?
+        .unwrap_or_else(|| vec![None; members.len()]);
+
+    Ok(Value::Array(
+        positions
+            .into_iter()
+            .map(|position| match position {
+                Some((longitude, latitude)) => coordinates_reply(longitude, latitude),
+                None => Value::NullArray,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sort {
+    None,
+    Asc,
+    Desc,
+}
+
+enum Origin<'a> {
+    Member(&'a str),
+    LonLat(f64, f64),
+}
+
+struct SearchOptions<'a> {
+    origin: Origin<'a>,
+    shape: Shape,
+    conversion: f64,
+    sort: Sort,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+/// Parses everything after the source key of GEOSEARCH / GEOSEARCHSTORE.
+fn parse_search_options(args: &[Value], is_store: bool) -> Result<SearchOptions<'_>, Error> {
+    let mut origin = None;
+    let mut shape = None;
+    let mut conversion = 1.0;
+    let mut sort = Sort::None;
+    let mut count = None;
+    let mut any = false;
+    let (mut with_coord, mut with_dist, mut with_hash, mut store_dist) =
+        (false, false, false, false);
+
+    let mut idx = 0;
+    while idx < args.len() {
+        let remaining = args.len() - idx - 1;
+        match arg_str(&args[idx])?.to_lowercase().as_str() {
+            "frommember" if remaining >= 1 => {
+                if origin.is_some() {
+                    return Err(Error::InvalidCommand(
+                        "exactly one of FROMMEMBER or FROMLONLAT can be specified",
+                    ));
+                }
+                origin = Some(Origin::Member(arg_str(&args[idx + 1])?));
+                idx += 1;
+            }
+            "fromlonlat" if remaining >= 2 => {
+                if origin.is_some() {
+                    return Err(Error::InvalidCommand(
+                        "exactly one of FROMMEMBER or FROMLONLAT can be specified",
+                    ));
+                }
+                let longitude = arg_f64(&args[idx + 1])?;
+                let latitude = arg_f64(&args[idx + 2])?;
+                validate_coordinates(longitude, latitude)?;
+                origin = Some(Origin::LonLat(longitude, latitude));
+                idx += 2;
+            }
+            "byradius" if remaining >= 2 => {
+                if shape.is_some() {
+                    return Err(Error::InvalidCommand(
+                        "exactly one of BYRADIUS and BYBOX can be specified",
+                    ));
+                }
+                let radius = arg_f64(&args[idx + 1])?;
+                if radius < 0.0 {
+                    return Err(Error::InvalidCommand("radius cannot be negative"));
+                }
+                conversion = unit_conversion(&args[idx + 2])?;
+                shape = Some(Shape::Radius(radius));
+                idx += 2;
+            }
+            "bybox" if remaining >= 3 => {
+                if shape.is_some() {
+                    return Err(Error::InvalidCommand(
+                        "exactly one of BYRADIUS and BYBOX can be specified",
+                    ));
+                }
+                let width = arg_f64(&args[idx + 1])?;
+                let height = arg_f64(&args[idx + 2])?;
+                if width < 0.0 || height < 0.0 {
+                    return Err(Error::InvalidCommand("height or width cannot be negative"));
+                }
+                conversion = unit_conversion(&args[idx + 3])?;
+                shape = Some(Shape::Box { width, height });
+                idx += 3;
+            }
+            "asc" => sort = Sort::Asc,
+            "desc" => sort = Sort::Desc,
+            "count" if remaining >= 1 => {
+                let n = arg_str(&args[idx + 1])?.parse::<i64>().map_err(|_| {
+                    Error::InvalidCommand("value is not an integer or out of range")
+                })?;
+                if n <= 0 {
+                    return Err(Error::InvalidCommand("COUNT must be > 0"));
+                }
+                count = Some(n as usize);
+                idx += 1;
+                if args
+                    .get(idx + 1)
+                    .and_then(|v| v.str_value())
+                    .is_some_and(|s| s.eq_ignore_ascii_case("any"))
+                {
+                    any = true;
+                    idx += 1;
+                }
+            }
+            "withcoord" if !is_store => with_coord = true,
+            "withdist" if !is_store => with_dist = true,
+            "withhash" if !is_store => with_hash = true,
+            "storedist" if is_store => store_dist = true,
+            _ => return Err(Error::InvalidCommand("syntax error")),
+        }
+        idx += 1;
+    }
+
+    let origin = origin.ok_or(Error::InvalidCommand(
+        "exactly one of FROMMEMBER or FROMLONLAT can be specified",
+    ))?;
+    let shape = shape.ok_or(Error::InvalidCommand(
+        "exactly one of BYRADIUS and BYBOX can be specified",
+    ))?;
+
+    Ok(SearchOptions {
+        origin,
+        shape,
+        conversion,
+        sort,
+        count,
+        any,
+        with_coord,
+        with_dist,
+        with_hash,
+        store_dist,
+    })
+}
+
+/// Runs the search described by `options` against `zset`, returning the
+/// matches already sorted and truncated.
+fn run_search(zset: &SortedSet, options: &SearchOptions) -> Result<Vec<GeoPoint>, Error> {
+    let (longitude, latitude) = match options.origin {
+        Origin::LonLat(longitude, latitude) => (longitude, latitude),
+        Origin::Member(member) => decode_score(zset.score(member).ok_or(Error::InvalidCommand(
+            "could not decode requested zset member",
+        ))?),
+    };
+
+    let area = SearchArea {
+        longitude,
+        latitude,
+        shape: options.shape,
+        conversion: options.conversion,
+    };
+
+    let limit = if options.any { options.count } else { None };
+    let mut points = search(zset, &area, limit);
+
+    // COUNT without ANY needs the closest matches, so sorting is implied.
+    let sort = match options.sort {
+        Sort::None if options.count.is_some() && !options.any => Sort::Asc,
+        sort => sort,
+    };
+    match sort {
+        Sort::Asc => points.sort_by(|a, b| a.dist.total_cmp(&b.dist)),
+        Sort::Desc => points.sort_by(|a, b| b.dist.total_cmp(&a.dist)),
+        Sort::None => {}
+    }
+
+    if let Some(count) = options.count {
+        points.truncate(count);
+    }
+    Ok(points)
+}
+
+/// GEOSEARCH key <FROMMEMBER member | FROMLONLAT longitude latitude>
+///   <BYRADIUS radius unit | BYBOX width height unit> [ASC | DESC]
+///   [COUNT count [ANY]] [WITHCOORD] [WITHDIST] [WITHHASH]
+pub async fn geosearch(request_content: &[Value], store: &Store) -> Result<Value, Error> {
+    if request_content.len() < 2 {
+        return Err(Error::InvalidCommand(
+            "wrong number of arguments for 'geosearch' command",
+        ));
+    }
+    let key = arg_str(&request_content[1])?;
+    let options = parse_search_options(&request_content[2..], false)?;
+
+    let points = store
+        .read_sorted_set(key, Instant::now(), |zset| run_search(zset, &options))
+        .await?
+        .transpose()?
+        .unwrap_or_default();
+
+    let with_any = options.with_coord || options.with_dist || options.with_hash;
+    let reply = points
+        .into_iter()
+        .map(|point| {
+            if !with_any {
+                return Value::BulkString(Some(point.member));
+            }
+
+            let mut item = vec![Value::BulkString(Some(point.member))];
+            if options.with_dist {
+                item.push(Value::BulkString(Some(format_distance(point.dist))));
+            }
+            if options.with_hash {
+                item.push(Value::Integer(point.score as i64));
+            }
+            if options.with_coord {
+                item.push(coordinates_reply(point.longitude, point.latitude));
+            }
+            Value::Array(item)
+        })
+        .collect();
+
+    Ok(Value::Array(reply))
+}
+
+/// GEOSEARCHSTORE destination source <FROMMEMBER member | FROMLONLAT
+///   longitude latitude> <BYRADIUS radius unit | BYBOX width height unit>
+///   [ASC | DESC] [COUNT count [ANY]] [STOREDIST]
+pub async fn geosearchstore(request_content: &[Value], store: &Store) -> Result<Value, Error> {
+    if request_content.len() < 3 {
+        return Err(Error::InvalidCommand(
+            "wrong number of arguments for 'geosearchstore' command",
+        ));
+    }
+    let destination = arg_str(&request_content[1])?;
+    let source = arg_str(&request_content[2])?;
+    let options = parse_search_options(&request_content[3..], true)?;
+
+    // Searched and stored under one lock, so the source can't change in
+    // between.
+    let (count, stored) = store
+        .store_computed(destination, Instant::now(), |view| {
+            let points = match view.get(source) {
+                Some(EntryValue::SortedSet(zset)) => run_search(zset, &options)?,
+                Some(_) => return Err(Error::WrongType),
+                None => Vec::new(),
+            };
+            let mut result = SortedSet::new();
+            for point in points {
+                let score = if options.store_dist {
+                    point.dist
+                } else {
+                    point.score
+                };
+                result.insert(point.member, score);
+            }
+            let count = result.len() as i64;
+            Ok((EntryValue::SortedSet(result), count))
+        })
+        .await?;
+    if stored {
+        store.notify(NOTIFY_ZSET, "geosearchstore", destination);
+    }
+    Ok(Value::Integer(count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave_roundtrip() {
+        let v = interleave64(0b1011, 0b0110);
+        assert_eq!(v, 0b110_1101);
+        assert_eq!(deinterleave64(v), (0b1011, 0b0110));
+        assert_eq!(deinterleave64(interleave64(u32::MAX, 7)), (u32::MAX, 7));
+    }
+
+    #[test]
+    fn test_score_roundtrip() {
+        // Palermo, as in the Redis documentation.
+        let score = encode_score(13.361389, 38.115556);
+        assert_eq!(score, 3479099956230698.0);
+
+        let (longitude, latitude) = decode_score(score);
+        assert_eq!(format_coordinate(longitude), "13.36138933897018433");
+        assert_eq!(format_coordinate(latitude), "38.11555639549629859");
+    }
+
+    #[test]
+    fn test_geohash_string() {
+        assert_eq!(
+            geohash_string(encode_score(13.361389, 38.115556)),
+            "sqc8b49rny0"
+        );
+        assert_eq!(
+            geohash_string(encode_score(15.087269, 37.502669)),
+            "sqdtr74hyu0"
+        );
+    }
+
+    #[test]
+    fn test_distance() {
+        let palermo = decode_score(encode_score(13.361389, 38.115556));
+        let catania = decode_score(encode_score(15.087269, 37.502669));
+        let dist = distance(palermo.0, palermo.1, catania.0, catania.1);
+        assert_eq!(format_distance(dist), "166274.1516");
+        assert_eq!(format_distance(dist / 1000.0), "166.2742");
+    }
+
+    #[test]
+    fn test_search() {
+        let mut zset = SortedSet::new();
+        zset.insert("Palermo".to_string(), encode_score(13.361389, 38.115556));
+        zset.insert("Catania".to_string(), encode_score(15.087269, 37.502669));
+        zset.insert("edge1".to_string(), encode_score(12.758489, 38.788135));
+        zset.insert("edge2".to_string(), encode_score(17.241510, 38.788135));
+
+        let area = SearchArea {
+            longitude: 15.0,
+            latitude: 37.0,
+            shape: Shape::Radius(200.0),
+            conversion: 1000.0,
+        };
+        let mut members: Vec<String> = search(&zset, &area, None)
+            .into_iter()
+            .map(|p| p.member)
+            .collect();
+        members.sort();
+        assert_eq!(members, vec!["Catania", "Palermo"]);
+
+        let area = SearchArea {
+            longitude: 15.0,
+            latitude: 37.0,
+            shape: Shape::Box {
+                width: 400.0,
+                height: 400.0,
+            },
+            conversion: 1000.0,
+        };
+        let mut members: Vec<String> = search(&zset, &area, None)
+            .into_iter()
+            .map(|p| p.member)
+            .collect();
+        members.sort();
+        assert_eq!(members, vec!["Catania", "Palermo", "edge1", "edge2"]);
+    }
+}
@@ -0,0 +1,119 @@
+/// Glob-style matching with the same rules as Redis' `stringmatchlen`:
+/// `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape.
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    match_bytes(pattern.as_bytes(), string.as_bytes())
+}
+
+fn match_bytes(mut pattern: &[u8], mut string: &[u8]) -> bool {
+    while let Some(&p) = pattern.first() {
+        match p {
+            b'*' => {
+                // Collapse consecutive stars.
+                while pattern.len() > 1 && pattern[1] == b'*' {
+                    pattern = &pattern[1..];
+                }
+                if pattern.len() == 1 {
+                    return true;
+                }
+                for start in 0..=string.len() {
+                    if match_bytes(&pattern[1..], &string[start..]) {
+                        return true;
+                    }
+                }
+                return false;
+            }
+            b'?' => {
+                if string.is_empty() {
+                    return false;
+                }
+                string = &string[1..];
+            }
+            b'[' => {
+                let Some(&c) = string.first() else {
+                    return false;
+                };
+                pattern = &pattern[1..];
+                let negate = pattern.first() == Some(&b'^');
+                if negate {
+                    pattern = &pattern[1..];
+                }
+
+                let mut matched = false;
+                loop {
+                    match pattern {
+                        [] => break,
+                        [b']', ..] => break,
+                        [b'\\', escaped, ..] => {
+                            if *escaped == c {
+                                matched = true;
+                            }
+                            pattern = &pattern[2..];
+                        }
+                        [start, b'-', end, ..] => {
+                            let (start, end) = (*start.min(end), *start.max(end));
+                            if (start..=end).contains(&c) {
+                                matched = true;
+                            }
+                            pattern = &pattern[3..];
+                        }
+                        [other, ..] => {
+                            if *other == c {
+                                matched = true;
+                            }
+                            pattern = &pattern[1..];
+                        }
+                    }
+                }
+
+                if matched == negate {
+                    return false;
+                }
+                string = &string[1..];
+                // `pattern` points at the closing bracket (or is exhausted).
+                if pattern.is_empty() {
+                    continue;
+                }
+            }
+            b'\\' if pattern.len() >= 2 => {
+                pattern = &pattern[1..];
+                if string.first().is_none_or(|&c| c != pattern[0]) {
+                    return false;
+                }
+                string = &string[1..];
+            }
+            _ => {
+                if string.first().is_none_or(|&c| c != p) {
+                    return false;
+                }
+                string = &string[1..];
+            }
+        }
+        pattern = &pattern[1..];
+    }
+
+    string.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("orders.*", "orders.created"));
+        assert!(!glob_match("orders.*", "order.created"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-c]llo", "hbllo"));
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("a*b*c", "aXXbYY"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt::Write;
+
+use crate::server::Server;
+use crate::{arg_str, Error, Value};
+
+/// Sections INFO knows, in the order they are reported.
+const SECTIONS: [&str; 1] = ["persistence"];
+
+/// INFO [section ...]: `field:value` lines grouped in sections, every
+/// section when none is asked for.
+pub fn info(request_content: &[Value], server: &Server) -> Result<Value, Error> {
+    let requested = request_content[1..]
+        .iter()
+        .map(|section| arg_str(section).map(str::to_lowercase))
+        .collect::<Result<Vec<_>, _>>()?;
+    let everything = requested.is_empty()
+        || requested
+            .iter()
+            .any(|section| matches!(section.as_str(), "all" | "default" | "everything"));
+
+    let sections: Vec<String> = SECTIONS
+        .iter()
+        .filter(|name| everything || requested.iter().any(|section| section == *name))
+        .map(|name| match *name {
+            "persistence" => persistence(server),
+            _ => unreachable!(),
+        })
+        .collect();
+    Ok(Value::BulkString(Some(sections.join("\r\n"))))
+}
+
+fn persistence(server: &Server) -> String {
+    let persistence = &server.persistence;
+    let mut section = String::from("# Persistence\r\n");
+    let mut field = |name: &str, value: &dyn std::fmt::Display| {
+        let _ = write!(section, "{}:{}\r\n", name, value);
+    };
+    let loading = persistence.is_loading();
+    field("loading", &(loading as u8));
+    if loading {
+        field("loading_start_time", &persistence.loading_start_time());
+        field("loading_total_bytes", &persistence.loading_total_bytes());
+        field("loading_loaded_bytes", &persistence.loading_loaded_bytes());
+        field(
+            "loading_loaded_perc",
+            &format!("{:.2}", persistence.loading_loaded_perc()),
+        );
+    }
+    field("rdb_changes_since_last_save", &server.databases.dirty());
+    field(
+        "rdb_bgsave_in_progress",
+        &(persistence.is_bgsave_in_progress() as u8),
+    );
+    field("rdb_last_save_time", &persistence.lastsave());
+    field(
+        "rdb_last_bgsave_status",
+        &if persistence.last_bgsave_ok() {
+            "ok"
+        } else {
+            "err"
+        },
+    );
+    field("aof_enabled", &(server.aof.is_enabled() as u8));
+    field(
+        "aof_rewrite_in_progress",
+        &(server.aof.is_rewrite_in_progress() as u8),
+    );
+    field(
+        "aof_last_bgrewrite_status",
+        &if server.aof.last_rewrite_ok() {
+            "ok"
+        } else {
+            "err"
+        },
+    );
+    section
+}
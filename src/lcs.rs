@@ -0,0 +1,256 @@
+use std::time::Instant;
+
+use crate::store::Store;
+use crate::{arg_str, Error, Value};
+
+/// Most memory the LCS table may take, like Redis, which limits it to
+/// proto-max-bulk-len.
+const MAX_TABLE_BYTES: usize = 512 * 1024 * 1024;
+
+/// A common substring found while walking back the LCS table, as inclusive
+/// byte ranges into each string.
+#[derive(Debug, PartialEq)]
+struct Match {
+    a: (usize, usize),
+    b: (usize, usize),
+}
+
+impl Match {
+    fn len(&self) -> usize {
+        self.a.1 - self.a.0 + 1
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Lcs {
+    sequence: Vec<u8>,
+    /// Matching ranges, from the end of the strings towards the start.
+    matches: Vec<Match>,
+}
+
+/// Computes the longest common subsequence of `a` and `b` with the classic
+/// dynamic programming table, walking it backwards like Redis does so that
+/// the reported ranges are identical.
+fn longest_common_subsequence(a: &[u8], b: &[u8]) -> Lcs {
+    let (alen, blen) = (a.len(), b.len());
+    let width = blen + 1;
+    let mut table = vec![0u32; (alen + 1) * width];
+    let at = |i: usize, j: usize| i * width + j;
+
+    for i in 1..=alen {
+        for j in 1..=blen {
+            table[at(i, j)] = if a[i - 1] == b[j - 1] {
+                table[at(i - 1, j - 1)] + 1
+            } else {
+                table[at(i - 1, j)].max(table[at(i, j - 1)])
+            };
+        }
+    }
+
+    let mut sequence = vec![0u8; table[at(alen, blen)] as usize];
+    let mut idx = sequence.len();
+    let mut matches = Vec::new();
+
+    // `current` holds the range being extended backwards, if any.
+    let mut current: Option<Match> = None;
+    let (mut i, mut j) = (alen, blen);
+
+    while i > 0 && j > 0 {
+        let mut emit = false;
+
+        if a[i - 1] == b[j - 1] {
+            sequence[idx - 1] = a[i - 1];
+
+            match current.as_mut() {
+                None => {
+                    current = Some(Match {
+                        a: (i - 1, i - 1),
+                        b: (j - 1, j - 1),
+                    })
+                }
+                Some(range) if range.a.0 == i && range.b.0 == j => {
+                    range.a.0 -= 1;
+                    range.b.0 -= 1;
+                }
+                Some(_) => emit = true,
+            }
+
+            // Matched the first byte of one of the strings, nothing is left.
+            if current
+                .as_ref()
+                .is_some_and(|range| range.a.0 == 0 || range.b.0 == 0)
+            {
+                emit = true;
+            }
+
+            idx -= 1;
+            i -= 1;
+            j -= 1;
+        } else {
+            if table[at(i - 1, j)] > table[at(i, j - 1)] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            emit = current.is_some();
+        }
+
+        if emit {
+            matches.extend(current.take());
+        }
+    }
+
+    Lcs { sequence, matches }
+}
+
+/// Refuses strings whose LCS table would take more than `MAX_TABLE_BYTES`.
+fn check_table_size(alen: usize, blen: usize) -> Result<(), Error> {
+    let bytes = (alen + 1)
+        .checked_mul(blen + 1)
+        .and_then(|cells| cells.checked_mul(size_of::<u32>()));
+    match bytes {
+        Some(bytes) if bytes <= MAX_TABLE_BYTES => Ok(()),
+        _ => Err(Error::InvalidCommand(
+            "Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len",
+        )),
+    }
+}
+
+/// The common subsequence as a reply. It can end in the middle of a
+/// multi-byte character, so it is only text if it is still valid UTF-8.
+fn sequence_value(sequence: Vec<u8>) -> Value {
+    match String::from_utf8(sequence) {
+        Ok(text) => Value::BulkString(Some(text)),
+        Err(e) => Value::Binary(e.into_bytes()),
+    }
+}
+
+async fn get_string(store: &Store, key: &str) -> Result<String, Error> {
+    match store.get(key, Instant::now()).await {
+        Ok(value) => Ok(value.unwrap_or_default()),
+        Err(Error::WrongType) => Err(Error::InvalidCommand(
+            "The specified keys must contain string values",
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// LCS key1 key2 [LEN] [IDX] [MINMATCHLEN min-match-len] [WITHMATCHLEN]
+pub async fn lcs(request_content: &[Value], store: &Store) -> Result<Value, Error> {
+    if request_content.len() < 3 {
+        return Err(Error::InvalidCommand(
+            "wrong number of arguments for 'lcs' command",
+        ));
+    }
+
+    let (mut get_len, mut get_idx, mut with_match_len) = (false, false, false);
+    let mut min_match_len = 0;
+
+    let mut idx = 3;
+    while idx < request_content.len() {
+        match arg_str(&request_content[idx])?.to_lowercase().as_str() {
+            "len" => get_len = true,
+            "idx" => get_idx = true,
+            "withmatchlen" => with_match_len = true,
+            "minmatchlen" if idx + 1 < request_content.len() => {
+                let n = arg_str(&request_content[idx + 1])?
+                    .parse::<i64>()
+                    .map_err(|_| {
+                        Error::InvalidCommand("value is not an integer or out of range")
+                    })?;
+                min_match_len = n.max(0) as usize;
+                idx += 1;
+            }
+            _ => return Err(Error::InvalidCommand("syntax error")),
+        }
+        idx += 1;
+    }
+
+    if get_len && get_idx {
+        return Err(Error::InvalidCommand(
+            "If you want both the length and indexes, please just use IDX.",
+        ));
+    }
+
+    let a = get_string(store, arg_str(&request_content[1])?).await?;
+    let b = get_string(store, arg_str(&request_content[2])?).await?;
+    check_table_size(a.len(), b.len())?;
+    let result = longest_common_subsequence(a.as_bytes(), b.as_bytes());
+
+    if get_len {
+        return Ok(Value::Integer(result.sequence.len() as i64));
+    }
+
+    if !get_idx {
+        return Ok(sequence_value(result.sequence));
+    }
+
+    let matches = result
+        .matches
+        .iter()
+        .filter(|m| min_match_len == 0 || m.len() >= min_match_len)
+        .map(|m| {
+            let mut item = vec![
+                Value::Array(vec![
+                    Value::Integer(m.a.0 as i64),
+                    Value::Integer(m.a.1 as i64),
+                ]),
+                Value::Array(vec![
+                    Value::Integer(m.b.0 as i64),
+                    Value::Integer(m.b.1 as i64),
+                ]),
+            ];
+            if with_match_len {
+                item.push(Value::Integer(m.len() as i64));
+            }
+            Value::Array(item)
+        })
+        .collect();
+
+    Ok(Value::Array(vec![
+        Value::BulkString(Some("matches".to_string())),
+        Value::Array(matches),
+        Value::BulkString(Some("len".to_string())),
+        Value::Integer(result.sequence.len() as i64),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lcs() {
+        let result = longest_common_subsequence(b"ohmytext", b"mynewtext");
+        assert_eq!(result.sequence, b"mytext");
+        assert_eq!(
+            result.matches,
+            vec![
+                Match {
+                    a: (4, 7),
+                    b: (5, 8)
+                },
+                Match {
+                    a: (2, 3),
+                    b: (0, 1)
+                },
+            ]
+        );
+
+        let result = longest_common_subsequence(b"", b"abc");
+        assert!(result.sequence.is_empty());
+        assert!(result.matches.is_empty());
+
+        // Only the first byte of "é" and "è" is common.
+        let result = longest_common_subsequence("é".as_bytes(), "è".as_bytes());
+        assert_eq!(sequence_value(result.sequence), Value::Binary(vec![0xC3]));
+        assert_eq!(
+            sequence_value(b"text".to_vec()),
+            Value::BulkString(Some("text".to_string()))
+        );
+
+        assert!(check_table_size(10_000, 10_000).is_ok());
+        assert!(check_table_size(100_000, 100_000).is_err());
+        assert!(check_table_size(usize::MAX - 1, 2).is_err());
+    }
+}
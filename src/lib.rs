@@ -1,31 +1,75 @@
+pub mod aof;
+pub mod check_rdb;
+pub mod client;
+pub mod cluster;
 pub mod config;
 pub mod de;
+pub mod dump;
+pub mod functions;
+pub mod geo;
+pub mod glob;
+pub mod info;
+pub mod lcs;
+pub mod lzf;
+pub mod notify;
+pub mod persistence;
+pub mod pubsub;
 pub mod rdb;
+pub mod rdb_convert;
+pub mod scripting;
 pub mod se;
+pub mod server;
+pub mod sort;
+pub mod sorted_set;
 pub mod store;
+pub mod stream;
+pub mod tracking;
 
-use std::{sync::Arc, time::Instant};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
 
 use crate::de::StreamDeserializer;
+use client::{reply_or_error, Client};
 use config::Config;
 use se::StreamSerializer;
-use store::Store;
+use server::Server;
+use store::{Databases, Store};
+use tokio::io::{BufReader, BufWriter};
+use tokio::net::tcp::OwnedWriteHalf;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Redis version reported to clients, which use it to pick features.
+pub const REDIS_VERSION: &str = "7.2.0";
 
 const CRLF: &str = "\r\n";
 
 const INTEGER_PREFIX: char = ':';
 const SIMPLE_STRING_PREFIX: char = '+';
+const SIMPLE_ERROR_PREFIX: char = '-';
 const BULK_STRING_PREFIX: char = '$';
 const ARRAY_PREFIX: char = '*';
+const NULL_PREFIX: char = '_';
+const MAP_PREFIX: char = '%';
+const PUSH_PREFIX: char = '>';
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     None,
     SimpleString(String),
     BulkString(Option<String>),
     Array(Vec<Value>),
     Integer(i64),
+    Error(String),
+    NullArray,
+    /// Out-of-band data such as pub/sub messages; a plain array in RESP2.
+    Push(Vec<Value>),
+    /// Key-value pairs; a flat array in RESP2.
+    Map(Vec<(Value, Value)>),
+    /// A bulk string that isn't valid UTF-8, such as a DUMP payload.
+    Binary(Vec<u8>),
 }
 
 impl Value {
@@ -40,13 +84,19 @@ impl Value {
         }
     }
 
+    /// The raw bytes of a bulk string, valid UTF-8 or not.
+    pub fn bytes_value(&self) -> Option<&[u8]> {
+        match self {
+            Self::BulkString(Some(s)) => Some(s.as_bytes()),
+            Self::Binary(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
     pub fn int_value(&self) -> Option<i64> {
         match self {
             Self::Integer(x) => Some(x.to_owned()),
-            Self::SimpleString(s) => match s.parse::<i64>() {
-                Ok(x) => Some(x),
-                Err(_) => None,
-            },
+            Self::SimpleString(s) => s.parse::<i64>().ok(),
             Self::BulkString(opt_s) => {
                 let s = opt_s.as_ref().expect("Unexpected NONE in BulkString");
                 Some(s.parse::<i64>().expect("Unable to parse as i64"))
@@ -56,15 +106,111 @@ impl Value {
     }
 }
 
+/// Reads a command argument that has to be a string.
+pub(crate) fn arg_str(value: &Value) -> Result<&str, Error> {
+    value.str_value().ok_or(Error::InvalidCommand(
+        "Argument couldn't be parsed as string",
+    ))
+}
+
+/// Parses a database number, which has to be below the configured count.
+pub(crate) fn db_index(value: &Value, databases: &Databases) -> Result<usize, Error> {
+    let index = arg_str(value)?
+        .parse::<i64>()
+        .map_err(|_| Error::InvalidCommand("value is not an integer or out of range"))?;
+    if index < 0 || index as usize >= databases.count() {
+        return Err(Error::InvalidCommand("DB index is out of range"));
+    }
+    Ok(index as usize)
+}
+
+/// The optional ASYNC|SYNC argument of FLUSHALL and FLUSHDB; true for ASYNC.
+fn flush_mode(request_content: &[Value]) -> Result<bool, Error> {
+    if request_content.len() > 2 {
+        return Err(Error::InvalidCommand("syntax error"));
+    }
+    match request_content.get(1) {
+        Some(mode) => match arg_str(mode)?.to_lowercase().as_str() {
+            "async" => Ok(true),
+            "sync" => Ok(false),
+            _ => Err(Error::InvalidCommand("syntax error")),
+        },
+        None => Ok(false),
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Invalid command received: {0}")]
     InvalidCommand(&'static str),
 
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+
+    #[error("wrong number of arguments for '{0}' command")]
+    WrongArity(&'static str),
+
+    #[error("EXECABORT Transaction discarded because of previous errors.")]
+    ExecAbort,
+
+    #[error("Can't execute '{0}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context")]
+    SubscribedMode(&'static str),
+
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+
+    #[error(
+        "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE."
+    )]
+    Busy,
+
+    #[error("LOADING Redis is loading the dataset in memory")]
+    Loading,
+
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+
+    #[error("NOSCRIPT No matching script. Please use EVAL.")]
+    NoScript,
+
+    #[error("NOTBUSY No scripts in execution right now.")]
+    NotBusy,
+
+    #[error("UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.")]
+    Unkillable,
+
+    /// An error raised by a script, already carrying its error code.
+    #[error("{0}")]
+    Script(String),
+
     #[error(transparent)]
     Io(#[from] std::io::Error),
 }
 
+impl Error {
+    /// The error reply sent to the client, or `None` for errors that should
+    /// close the connection instead.
+    pub fn to_reply(&self) -> Option<Value> {
+        match self {
+            Error::InvalidCommand(msg) => Some(Value::Error(format!("ERR {}", msg))),
+            Error::WrongArity(_) | Error::SubscribedMode(_) => {
+                Some(Value::Error(format!("ERR {}", self)))
+            }
+            Error::WrongType
+            | Error::ExecAbort
+            | Error::NoProto
+            | Error::Busy
+            | Error::Loading
+            | Error::BusyKey
+            | Error::NoScript
+            | Error::NotBusy
+            | Error::Unkillable
+            | Error::Script(_) => Some(Value::Error(self.to_string())),
+            Error::Io(_) => None,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Command {
     PING,
@@ -73,9 +219,55 @@ pub enum Command {
     SET,
     CONFIG,
     KEYS,
+    GEOADD,
+    GEODIST,
+    GEOHASH,
+    GEOPOS,
+    GEOSEARCH,
+    GEOSEARCHSTORE,
+    LCS,
+    SORT,
+    SORTRO,
+    MULTI,
+    EXEC,
+    DISCARD,
+    WATCH,
+    UNWATCH,
+    DEL,
+    DUMP,
+    RESTORE,
+    FLUSHALL,
+    FLUSHDB,
+    SELECT,
+    MOVE,
+    SWAPDB,
+    SAVE,
+    BGSAVE,
+    BGREWRITEAOF,
+    LASTSAVE,
+    INFO,
+    SUBSCRIBE,
+    UNSUBSCRIBE,
+    PSUBSCRIBE,
+    PUNSUBSCRIBE,
+    SSUBSCRIBE,
+    SUNSUBSCRIBE,
+    SPUBLISH,
+    PUBLISH,
+    PUBSUB,
+    HELLO,
+    CLIENT,
+    QUIT,
+    EVAL,
+    EVALSHA,
+    SCRIPT,
+    FCALL,
+    FCALLRO,
+    FUNCTION,
 }
 
 impl Command {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<Command, Error> {
         match s.to_lowercase().as_str() {
             "ping" => Ok(Command::PING),
@@ -84,16 +276,322 @@ impl Command {
             "get" => Ok(Command::GET),
             "config" => Ok(Command::CONFIG),
             "keys" => Ok(Command::KEYS),
+            "geoadd" => Ok(Command::GEOADD),
+            "geodist" => Ok(Command::GEODIST),
+            "geohash" => Ok(Command::GEOHASH),
+            "geopos" => Ok(Command::GEOPOS),
+            "geosearch" => Ok(Command::GEOSEARCH),
+            "geosearchstore" => Ok(Command::GEOSEARCHSTORE),
+            "lcs" => Ok(Command::LCS),
+            "sort" => Ok(Command::SORT),
+            "sort_ro" => Ok(Command::SORTRO),
+            "multi" => Ok(Command::MULTI),
+            "exec" => Ok(Command::EXEC),
+            "discard" => Ok(Command::DISCARD),
+            "watch" => Ok(Command::WATCH),
+            "unwatch" => Ok(Command::UNWATCH),
+            "del" => Ok(Command::DEL),
+            "dump" => Ok(Command::DUMP),
+            "restore" => Ok(Command::RESTORE),
+            "flushall" => Ok(Command::FLUSHALL),
+            "flushdb" => Ok(Command::FLUSHDB),
+            "select" => Ok(Command::SELECT),
+            "move" => Ok(Command::MOVE),
+            "swapdb" => Ok(Command::SWAPDB),
+            "save" => Ok(Command::SAVE),
+            "bgsave" => Ok(Command::BGSAVE),
+            "bgrewriteaof" => Ok(Command::BGREWRITEAOF),
+            "lastsave" => Ok(Command::LASTSAVE),
+            "info" => Ok(Command::INFO),
+            "subscribe" => Ok(Command::SUBSCRIBE),
+            "unsubscribe" => Ok(Command::UNSUBSCRIBE),
+            "psubscribe" => Ok(Command::PSUBSCRIBE),
+            "punsubscribe" => Ok(Command::PUNSUBSCRIBE),
+            "ssubscribe" => Ok(Command::SSUBSCRIBE),
+            "sunsubscribe" => Ok(Command::SUNSUBSCRIBE),
+            "spublish" => Ok(Command::SPUBLISH),
+            "publish" => Ok(Command::PUBLISH),
+            "pubsub" => Ok(Command::PUBSUB),
+            "hello" => Ok(Command::HELLO),
+            "client" => Ok(Command::CLIENT),
+            "quit" => Ok(Command::QUIT),
+            "eval" => Ok(Command::EVAL),
+            "evalsha" => Ok(Command::EVALSHA),
+            "script" => Ok(Command::SCRIPT),
+            "fcall" => Ok(Command::FCALL),
+            "fcall_ro" => Ok(Command::FCALLRO),
+            "function" => Ok(Command::FUNCTION),
             _ => Err(Error::InvalidCommand(
                 "Invalid command / Command has not been implemented",
             )),
         }
     }
 
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::PING => "ping",
+            Command::ECHO => "echo",
+            Command::GET => "get",
+            Command::SET => "set",
+            Command::CONFIG => "config",
+            Command::KEYS => "keys",
+            Command::GEOADD => "geoadd",
+            Command::GEODIST => "geodist",
+            Command::GEOHASH => "geohash",
+            Command::GEOPOS => "geopos",
+            Command::GEOSEARCH => "geosearch",
+            Command::GEOSEARCHSTORE => "geosearchstore",
+            Command::LCS => "lcs",
+            Command::SORT => "sort",
+            Command::SORTRO => "sort_ro",
+            Command::MULTI => "multi",
+            Command::EXEC => "exec",
+            Command::DISCARD => "discard",
+            Command::WATCH => "watch",
+            Command::UNWATCH => "unwatch",
+            Command::DEL => "del",
+            Command::DUMP => "dump",
+            Command::RESTORE => "restore",
+            Command::FLUSHALL => "flushall",
+            Command::FLUSHDB => "flushdb",
+            Command::SELECT => "select",
+            Command::MOVE => "move",
+            Command::SWAPDB => "swapdb",
+            Command::SAVE => "save",
+            Command::BGSAVE => "bgsave",
+            Command::BGREWRITEAOF => "bgrewriteaof",
+            Command::LASTSAVE => "lastsave",
+            Command::INFO => "info",
+            Command::SUBSCRIBE => "subscribe",
+            Command::UNSUBSCRIBE => "unsubscribe",
+            Command::PSUBSCRIBE => "psubscribe",
+            Command::PUNSUBSCRIBE => "punsubscribe",
+            Command::SSUBSCRIBE => "ssubscribe",
+            Command::SUNSUBSCRIBE => "sunsubscribe",
+            Command::SPUBLISH => "spublish",
+            Command::PUBLISH => "publish",
+            Command::PUBSUB => "pubsub",
+            Command::HELLO => "hello",
+            Command::CLIENT => "client",
+            Command::QUIT => "quit",
+            Command::EVAL => "eval",
+            Command::EVALSHA => "evalsha",
+            Command::SCRIPT => "script",
+            Command::FCALL => "fcall",
+            Command::FCALLRO => "fcall_ro",
+            Command::FUNCTION => "function",
+        }
+    }
+
+    /// Number of arguments including the command name, as in Redis' command
+    /// table: a negative arity means "at least that many".
+    pub fn arity(&self) -> i64 {
+        match self {
+            Command::PING => -1,
+            Command::ECHO => 2,
+            Command::GET => 2,
+            Command::SET => -3,
+            Command::CONFIG => -2,
+            Command::KEYS => 2,
+            Command::GEOADD => -5,
+            Command::GEODIST => -4,
+            Command::GEOHASH => -2,
+            Command::GEOPOS => -2,
+            Command::GEOSEARCH => -7,
+            Command::GEOSEARCHSTORE => -8,
+            Command::LCS => -3,
+            Command::SORT => -2,
+            Command::SORTRO => -2,
+            Command::MULTI => 1,
+            Command::EXEC => 1,
+            Command::DISCARD => 1,
+            Command::WATCH => -2,
+            Command::UNWATCH => 1,
+            Command::DEL => -2,
+            Command::DUMP => 2,
+            Command::RESTORE => -4,
+            Command::FLUSHALL => -1,
+            Command::FLUSHDB => -1,
+            Command::SELECT => 2,
+            Command::MOVE => 3,
+            Command::SWAPDB => 3,
+            Command::SAVE => 1,
+            Command::BGSAVE => -1,
+            Command::BGREWRITEAOF => 1,
+            Command::LASTSAVE => 1,
+            Command::INFO => -1,
+            Command::SUBSCRIBE => -2,
+            Command::UNSUBSCRIBE => -1,
+            Command::PSUBSCRIBE => -2,
+            Command::PUNSUBSCRIBE => -1,
+            Command::SSUBSCRIBE => -2,
+            Command::SUNSUBSCRIBE => -1,
+            Command::SPUBLISH => 3,
+            Command::PUBLISH => 3,
+            Command::PUBSUB => -2,
+            Command::HELLO => -1,
+            Command::CLIENT => -2,
+            Command::QUIT => -1,
+            Command::EVAL => -3,
+            Command::EVALSHA => -3,
+            Command::SCRIPT => -2,
+            Command::FCALL => -3,
+            Command::FCALLRO => -3,
+            Command::FUNCTION => -2,
+        }
+    }
+
+    /// Whether the command may modify the dataset, which makes a script
+    /// that ran it unkillable. SORT only writes with STORE.
+    pub fn is_write(&self, request_content: &[Value]) -> bool {
+        match self {
+            Command::SORT => sort::stores(request_content),
+            _ => matches!(
+                self,
+                Command::SET
+                    | Command::GEOADD
+                    | Command::GEOSEARCHSTORE
+                    | Command::DEL
+                    | Command::RESTORE
+                    | Command::FLUSHALL
+                    | Command::FLUSHDB
+                    | Command::MOVE
+                    | Command::SWAPDB
+            ),
+        }
+    }
+
+    /// Connection-level commands, pub/sub and server introspection still
+    /// work while the dataset loads; the rest get -LOADING.
+    pub fn allowed_while_loading(&self) -> bool {
+        matches!(
+            self,
+            Command::CONFIG
+                | Command::SELECT
+                | Command::LASTSAVE
+                | Command::INFO
+                | Command::SUBSCRIBE
+                | Command::UNSUBSCRIBE
+                | Command::PSUBSCRIBE
+                | Command::PUNSUBSCRIBE
+                | Command::SSUBSCRIBE
+                | Command::SUNSUBSCRIBE
+                | Command::SPUBLISH
+                | Command::PUBLISH
+                | Command::PUBSUB
+                | Command::HELLO
+                | Command::CLIENT
+                | Command::QUIT
+        )
+    }
+
+    /// Connection-level commands and scripting itself can't run from a
+    /// script.
+    pub fn allowed_in_script(&self) -> bool {
+        !matches!(
+            self,
+            Command::MULTI
+                | Command::EXEC
+                | Command::DISCARD
+                | Command::WATCH
+                | Command::UNWATCH
+                | Command::SELECT
+                | Command::SUBSCRIBE
+                | Command::UNSUBSCRIBE
+                | Command::PSUBSCRIBE
+                | Command::PUNSUBSCRIBE
+                | Command::SSUBSCRIBE
+                | Command::SUNSUBSCRIBE
+                | Command::HELLO
+                | Command::CLIENT
+                | Command::QUIT
+                | Command::EVAL
+                | Command::EVALSHA
+                | Command::SCRIPT
+                | Command::FCALL
+                | Command::FCALLRO
+                | Command::FUNCTION
+                | Command::SAVE
+                | Command::BGSAVE
+                | Command::BGREWRITEAOF
+        )
+    }
+
+    pub fn check_arity(&self, request_content: &[Value]) -> Result<(), Error> {
+        let arity = self.arity();
+        let argc = request_content.len() as i64;
+        if (arity > 0 && argc != arity) || argc < -arity {
+            return Err(Error::WrongArity(self.name()));
+        }
+        Ok(())
+    }
+
+    /// Keys read by a read-only command, which CLIENT TRACKING remembers
+    /// for the client. Empty for every other command.
+    pub fn read_keys<'a>(&self, request_content: &'a [Value]) -> Vec<&'a str> {
+        let positions: &[usize] = match self {
+            Command::GET
+            | Command::GEODIST
+            | Command::GEOHASH
+            | Command::GEOPOS
+            | Command::GEOSEARCH
+            | Command::SORTRO
+            | Command::DUMP => &[1],
+            Command::LCS => &[1, 2],
+            _ => &[],
+        };
+        positions
+            .iter()
+            .filter_map(|&idx| request_content.get(idx).and_then(Value::str_value))
+            .collect()
+    }
+
+    /// Whether the command changes the dataset or the function libraries,
+    /// which makes it go to the AOF.
+    pub fn is_logged(&self, request_content: &[Value]) -> bool {
+        match self {
+            Command::FUNCTION => request_content
+                .get(1)
+                .and_then(Value::str_value)
+                .is_some_and(|subcommand| {
+                    ["load", "delete", "flush", "restore"]
+                        .iter()
+                        .any(|logged| subcommand.eq_ignore_ascii_case(logged))
+                }),
+            _ => self.is_write(request_content),
+        }
+    }
+
+    /// Runs the command against `store`, the client's selected database,
+    /// logging it to the AOF if it changed anything.
     pub async fn construct_response(
         &self,
         request_content: Vec<Value>,
         store: Arc<Store>,
+        server: &Arc<Server>,
+        config: &Config,
+    ) -> Result<Value, Error> {
+        let aof = match self.is_logged(&request_content) {
+            true => server.aof.writer().await,
+            false => None,
+        };
+        let logged = aof.as_ref().map(|_| request_content.clone());
+        let db = store.index();
+        let result = self.execute(request_content, store, server, config).await;
+        if let (Some(mut aof), Some(command), Ok(_)) = (aof, logged, &result) {
+            let appended = aof.append(db, &command);
+            // Other commands can be logged while this one is synced.
+            drop(aof);
+            aof::finish_write(appended).await;
+        }
+        result
+    }
+
+    async fn execute(
+        &self,
+        request_content: Vec<Value>,
+        store: Arc<Store>,
+        server: &Arc<Server>,
         config: &Config,
     ) -> Result<Value, Error> {
         let store = store.clone();
@@ -110,7 +608,7 @@ impl Command {
 
                     Ok(Value::BulkString(Some(pong_value)))
                 }
-                _ => return Err(Error::InvalidCommand("Expected either 0 or 1 arguments")),
+                _ => Err(Error::InvalidCommand("Expected either 0 or 1 arguments")),
             },
             Command::ECHO => {
                 if request_content.len() != 2 {
@@ -130,9 +628,10 @@ impl Command {
             Command::SET => {
                 // SET KEY VALUE
                 // SET KEY VALUE PX xxx
+                // SET KEY VALUE PXAT unix-time-ms
                 if request_content.len() != 3 && request_content.len() != 5 {
                     return Err(Error::InvalidCommand(
-                        "SET command expects exactly 2 arguments: KEY and VALUE and one optional PX (set expiry)",
+                        "SET command expects exactly 2 arguments: KEY and VALUE and one optional PX or PXAT (set expiry)",
                     ));
                 }
 
@@ -144,27 +643,29 @@ impl Command {
                     "VALUE value to SET cmd couldn't be parsed as string",
                 ))?;
 
-                let expires_in = if request_content.len() == 5 {
-                    if request_content[3]
+                let (expires_in, expires_at) = if request_content.len() == 5 {
+                    let millis = request_content[4].int_value().unwrap() as u64;
+                    match request_content[3]
                         .str_value()
                         .map(|s| s.to_lowercase())
-                        .as_ref()
-                        .map(|s| s.as_str())
-                        != Some("px")
+                        .as_deref()
                     {
-                        return Err(Error::InvalidCommand(
-                            "Expected either PX or px to specify expiry in ms for data",
-                        ));
+                        Some("px") => (Some(millis), None),
+                        Some("pxat") => (None, Some(UNIX_EPOCH + Duration::from_millis(millis))),
+                        _ => {
+                            return Err(Error::InvalidCommand(
+                                "Expected either PX or PXAT to specify expiry in ms for data",
+                            ))
+                        }
                     }
-
-                    Some(request_content[4].int_value().unwrap() as u64)
                 } else {
-                    None
+                    (None, None)
                 };
 
                 store
-                    .insert(key.to_string(), value.to_string(), expires_in, None)
+                    .insert(key.to_string(), value.to_string(), expires_in, expires_at)
                     .await;
+                store.notify(notify::NOTIFY_STRING, "set", key);
 
                 Ok(Value::SimpleString("OK".to_string()))
             }
@@ -179,52 +680,82 @@ impl Command {
                     "KEY passed for GET cmd couldn;t be parsed as string",
                 ))?;
 
-                match store.get(key, Instant::now()).await {
+                match store.get(key, Instant::now()).await? {
                     Some(v) => Ok(Value::BulkString(Some(v))),
                     None => Ok(Value::BulkString(None)),
                 }
             }
             Command::CONFIG => {
-                // CONFIG GET
-                // CONFIG GET dir
-                // CONFIG GET dbfilename
-                if request_content.len() != 3 {
-                    return Err(Error::InvalidCommand(
-                        "CONFIG commands should have exactly 2 arguments. CONFIG GET <CONFIG_KEY>",
-                    ));
-                }
-
-                if request_content[1]
-                    .str_value()
-                    .ok_or(Error::InvalidCommand(
-                        "Second argument passed couldn't be parsed as String",
-                    ))?
-                    .to_lowercase()
-                    != "get"
-                {
-                    return Err(Error::InvalidCommand(
-                        "CONFIG command expected GET as second arg.",
-                    ));
-                }
+                // CONFIG GET <CONFIG_KEY>
+                // CONFIG SET <CONFIG_KEY> <VALUE>
+                let subcommand = arg_str(&request_content[1])?.to_lowercase();
+                match (subcommand.as_str(), request_content.len()) {
+                    ("get", 3) => {
+                        let key = request_content[2].str_value().ok_or(Error::InvalidCommand(
+                            "Error during parsing KEY passed to CONFIG GET",
+                        ))?;
 
-                let key = request_content[2].str_value().ok_or(Error::InvalidCommand(
-                    "Error during parsing KEY passed to CONFIG GET",
-                ))?;
-
-                match key {
-                    "dir" => Ok(Value::Array(vec![
-                        Value::BulkString(Some("dir".to_string())),
-                        Value::BulkString(Some(
-                            config.get_rdb_dir().ok_or("".to_string()).unwrap(),
-                        )),
-                    ])),
-                    "dbfilename" => Ok(Value::Array(vec![
-                        Value::BulkString(Some("dbfilename".to_string())),
-                        Value::BulkString(Some(
-                            config.get_rdb_file().ok_or("".to_string()).unwrap(),
-                        )),
-                    ])),
-                    _ => Err(Error::InvalidCommand("Invalid CONFIG key requested")),
+                        let value = match key {
+                            "dir" => config.get_rdb_dir().unwrap_or_default(),
+                            "dbfilename" => config.get_rdb_file().unwrap_or_default(),
+                            "databases" => config.get_databases().to_string(),
+                            "save" => {
+                                persistence::save_rules_to_string(&server.persistence.save_rules())
+                            }
+                            "rdbcompression" => if server.persistence.rdb_compression() {
+                                "yes"
+                            } else {
+                                "no"
+                            }
+                            .to_string(),
+                            "appendonly" => {
+                                if server.aof.is_enabled() { "yes" } else { "no" }.to_string()
+                            }
+                            "appendfsync" => server
+                                .aof
+                                .fsync()
+                                .unwrap_or(aof::AppendFsync::EverySec)
+                                .name()
+                                .to_string(),
+                            "notify-keyspace-events" => {
+                                notify::flags_to_string(store.notify_flags())
+                            }
+                            _ => return Err(Error::InvalidCommand("Invalid CONFIG key requested")),
+                        };
+                        Ok(Value::Array(vec![
+                            Value::BulkString(Some(key.to_string())),
+                            Value::BulkString(Some(value)),
+                        ]))
+                    }
+                    ("set", 4) => {
+                        let key = arg_str(&request_content[2])?.to_lowercase();
+                        let value = arg_str(&request_content[3])?;
+                        match key.as_str() {
+                            "notify-keyspace-events" => server
+                                .databases
+                                .set_notify_flags(notify::parse_flags(value)?),
+                            "save" => server
+                                .persistence
+                                .set_save_rules(persistence::parse_save_rules(value)?),
+                            "rdbcompression" => server.persistence.set_rdb_compression(match value
+                                .to_lowercase()
+                                .as_str()
+                            {
+                                "yes" => true,
+                                "no" => false,
+                                _ => {
+                                    return Err(Error::InvalidCommand(
+                                        "argument must be 'yes' or 'no'",
+                                    ))
+                                }
+                            }),
+                            _ => return Err(Error::InvalidCommand("Unsupported CONFIG parameter")),
+                        }
+                        Ok(Value::SimpleString("OK".to_string()))
+                    }
+                    _ => Err(Error::InvalidCommand(
+                        "CONFIG expects either GET <CONFIG_KEY> or SET <CONFIG_KEY> <VALUE>",
+                    )),
                 }
             }
             Command::KEYS => {
@@ -250,44 +781,191 @@ impl Command {
                     Err(Error::InvalidCommand("not implemented yet"))
                 }
             }
+            Command::GEOADD => geo::geoadd(&request_content, &store).await,
+            Command::GEODIST => geo::geodist(&request_content, &store).await,
+            Command::GEOHASH => geo::geohash(&request_content, &store).await,
+            Command::GEOPOS => geo::geopos(&request_content, &store).await,
+            Command::GEOSEARCH => geo::geosearch(&request_content, &store).await,
+            Command::GEOSEARCHSTORE => geo::geosearchstore(&request_content, &store).await,
+            Command::LCS => lcs::lcs(&request_content, &store).await,
+            Command::SORT => sort::sort(&request_content, &store, false).await,
+            Command::SORTRO => sort::sort(&request_content, &store, true).await,
+            Command::DEL => {
+                let keys = request_content[1..]
+                    .iter()
+                    .map(arg_str)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let removed = store.remove(&keys, Instant::now()).await;
+                Ok(Value::Integer(removed as i64))
+            }
+            Command::DUMP => dump::dump(&request_content, &store, server).await,
+            Command::RESTORE => dump::restore(&request_content, &store).await,
+            Command::FLUSHALL => {
+                let lazy = flush_mode(&request_content)?;
+                server.databases.flush_all(lazy).await;
+                Ok(Value::SimpleString("OK".to_string()))
+            }
+            Command::FLUSHDB => {
+                let lazy = flush_mode(&request_content)?;
+                store.flush(lazy).await;
+                Ok(Value::SimpleString("OK".to_string()))
+            }
+            Command::MOVE => {
+                let key = arg_str(&request_content[1])?;
+                let db = db_index(&request_content[2], &server.databases)?;
+                if db == store.index() {
+                    return Err(Error::InvalidCommand(
+                        "source and destination objects are the same",
+                    ));
+                }
+                let moved = server
+                    .databases
+                    .move_key(key, store.index(), db, Instant::now())
+                    .await;
+                Ok(Value::Integer(moved as i64))
+            }
+            Command::SWAPDB => {
+                let first = db_index(&request_content[1], &server.databases)
+                    .map_err(|_| Error::InvalidCommand("invalid first DB index"))?;
+                let second = db_index(&request_content[2], &server.databases)
+                    .map_err(|_| Error::InvalidCommand("invalid second DB index"))?;
+                server.databases.swap(first, second).await;
+                Ok(Value::SimpleString("OK".to_string()))
+            }
+            Command::SAVE => persistence::save(server, config).await,
+            Command::BGSAVE => persistence::bgsave(&request_content, server, config).await,
+            Command::BGREWRITEAOF => aof::bgrewriteaof(server).await,
+            Command::LASTSAVE => Ok(Value::Integer(server.persistence.lastsave() as i64)),
+            Command::INFO => info::info(&request_content, server),
+            // Watched keys are already released by EXEC before queued
+            // commands run, so a queued UNWATCH has nothing left to do.
+            Command::UNWATCH => Ok(Value::SimpleString("OK".to_string())),
+            Command::PUBLISH => {
+                let channel = arg_str(&request_content[1])?;
+                let message = arg_str(&request_content[2])?;
+                let receivers = server.pubsub.publish(channel, message);
+                Ok(Value::Integer(receivers as i64))
+            }
+            Command::SPUBLISH => {
+                let channel = arg_str(&request_content[1])?;
+                let message = arg_str(&request_content[2])?;
+                let receivers = server.pubsub.spublish(channel, message);
+                Ok(Value::Integer(receivers as i64))
+            }
+            Command::PUBSUB => pubsub::pubsub(&request_content, &server.pubsub),
+            Command::EVAL => scripting::eval(&request_content, store, server, config, false).await,
+            Command::EVALSHA => {
+                scripting::eval(&request_content, store, server, config, true).await
+            }
+            Command::SCRIPT => scripting::script(&request_content, &server.scripting),
+            Command::FCALL => {
+                functions::fcall(&request_content, store, server, config, false).await
+            }
+            Command::FCALLRO => {
+                functions::fcall(&request_content, store, server, config, true).await
+            }
+            Command::FUNCTION => {
+                functions::function(&request_content, &server.functions, &server.scripting)
+            }
+            // Transactions and subscriptions are per-connection state, see
+            // `client::Client`.
+            Command::MULTI
+            | Command::EXEC
+            | Command::DISCARD
+            | Command::WATCH
+            | Command::SELECT
+            | Command::SUBSCRIBE
+            | Command::UNSUBSCRIBE
+            | Command::PSUBSCRIBE
+            | Command::PUNSUBSCRIBE
+            | Command::SSUBSCRIBE
+            | Command::SUNSUBSCRIBE
+            | Command::HELLO
+            | Command::CLIENT
+            | Command::QUIT => Err(Error::InvalidCommand(
+                "Command is only allowed on a client connection",
+            )),
         }
     }
 }
 
 pub async fn handle_stream(
-    mut stream: TcpStream,
-    store: Arc<Store>,
+    stream: TcpStream,
+    server: Arc<Server>,
     config: Config,
 ) -> Result<(), Error> {
-    let (read, write) = stream.split();
-    let mut input_deserializer = StreamDeserializer::new(read);
-    let mut output_serializer = StreamSerializer::new(write);
+    let (read, write) = stream.into_split();
 
-    loop {
-        let store = store.clone();
-        let request = input_deserializer.decode_next().await?;
+    // Decoding a request is not cancel-safe, so it runs in its own task and
+    // the loop below can wait on requests and push messages at once.
+    let (requests_tx, mut requests) = mpsc::channel(1);
+    let reader = tokio::spawn(async move {
+        let mut input_deserializer = StreamDeserializer::new(BufReader::new(read));
+        loop {
+            let request = input_deserializer.decode_next().await;
+            let failed = request.is_err();
+            if requests_tx.send(request).await.is_err() || failed {
+                break;
+            }
+        }
+    });
 
-        match request {
-            Value::Array(data) => {
-                if data.len() < 1 {
-                    return Err(Error::InvalidCommand(
-                        "Expected an array but received 0 bytes",
-                    ));
-                }
+    let result = serve(&mut requests, write, server, config).await;
+    reader.abort();
+    result
+}
 
-                let cmd_part = data[0].str_value().ok_or(Error::InvalidCommand(
-                    "Expected Command to be parseable as string",
-                ))?;
+async fn serve(
+    requests: &mut mpsc::Receiver<std::io::Result<Value>>,
+    write: OwnedWriteHalf,
+    server: Arc<Server>,
+    config: Config,
+) -> Result<(), Error> {
+    let mut output_serializer = StreamSerializer::new(BufWriter::new(write));
+    let (mut client, mut pushes, kill) = Client::new(server, config);
 
-                let command = Command::from_str(cmd_part)?;
-                let response = command.construct_response(data, store, &config).await?;
+    loop {
+        tokio::select! {
+            // Messages queued before a request was read go out before its
+            // reply, so clients see them in the order they happened.
+            biased;
 
-                output_serializer.write(response).await?;
+            _ = kill.notified() => {
+                eprintln!("Disconnecting client {}: too many pending messages", client.id());
+                return Ok(());
             }
-            _ => {
-                return Err(Error::InvalidCommand(
-                    "Unrecognizable request..... Type of Value doesnt exist....",
-                ));
+            Some(push) = pushes.recv() => output_serializer.write(push).await?,
+            request = requests.recv() => {
+                let Some(request) = request else {
+                    return Ok(());
+                };
+
+                let data = match request? {
+                    Value::Array(data) if !data.is_empty() => data,
+                    Value::Array(_) => {
+                        return Err(Error::InvalidCommand(
+                            "Expected an array but received 0 bytes",
+                        ));
+                    }
+                    _ => {
+                        return Err(Error::InvalidCommand(
+                            "Unrecognizable request..... Type of Value doesnt exist....",
+                        ));
+                    }
+                };
+
+                let replies = match client.handle_request(data).await {
+                    Ok(replies) => replies,
+                    Err(e) => vec![reply_or_error(Err(e))?],
+                };
+                output_serializer.set_protocol(client.protocol());
+                for reply in replies {
+                    output_serializer.write(reply).await?;
+                }
+
+                if client.is_closing() {
+                    return Ok(());
+                }
             }
         }
     }
@@ -295,11 +973,12 @@ pub async fn handle_stream(
 
 #[cfg(test)]
 mod tests {
-    use std::{future::Future, io::Cursor};
+    use std::{collections::BTreeMap, future::Future, io::Cursor, time::SystemTime};
 
     use tokio::runtime::Runtime;
 
     use super::*;
+    use crate::pubsub::PubSub;
 
     fn run_async_tests<F: Future>(f: F) {
         let runtime = Runtime::new().unwrap();
@@ -334,6 +1013,19 @@ mod tests {
             );
 
             assert_eq!(&encode(Value::BulkString(None)).await, b"$-1\r\n");
+
+            assert_eq!(&encode(Value::Integer(-42)).await, b":-42\r\n");
+
+            assert_eq!(&encode(Value::Binary(vec![0xff])).await, b"$1\r\n\xff\r\n");
+
+            assert_eq!(
+                &encode(Value::Array(vec![
+                    Value::BulkString(Some("foo".into())),
+                    Value::Array(vec![Value::Integer(1)]),
+                ]))
+                .await,
+                b"*2\r\n$3\r\nfoo\r\n*1\r\n:1\r\n"
+            );
         })
     }
 
@@ -353,10 +1045,1269 @@ mod tests {
                 Value::SimpleString("hello".to_string())
             );
 
-            assert_eq!(decode(b":10\r\n").await, Value::Integer(10 as i64))
+            assert_eq!(decode(b":10\r\n").await, Value::Integer(10_i64));
+
+            assert_eq!(
+                decode(b"$2\r\n\xff\x00\r\n").await,
+                Value::Binary(vec![0xff, 0])
+            )
         })
     }
 
+    async fn spawn_server() -> std::net::SocketAddr {
+        spawn_server_saving_to(None).await
+    }
+
+    /// Like `spawn_server`, saving RDB files as `dump.rdb` in `rdb_dir`.
+    async fn spawn_server_saving_to(rdb_dir: Option<String>) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pubsub = Arc::new(PubSub::new());
+        let databases = Databases::new(16, BTreeMap::new(), pubsub.clone());
+        let server = Arc::new(Server::new(databases, pubsub));
+        let rdb_file = rdb_dir.as_ref().map(|_| "dump.rdb".to_string());
+        let config = Config::new(addr.to_string(), rdb_dir, rdb_file, 16);
+        tokio::spawn(persistence::run_save_rules(server.clone(), config.clone()));
+        serve(listener, server, config);
+        addr
+    }
+
+    /// Accepts clients of `server` in the background.
+    fn serve(listener: tokio::net::TcpListener, server: Arc<Server>, config: Config) {
+        tokio::spawn(async move {
+            loop {
+                let (tcp_stream, _) = listener.accept().await.unwrap();
+                let (server, config) = (server.clone(), config.clone());
+                tokio::spawn(handle_stream(tcp_stream, server, config));
+            }
+        });
+    }
+
+    /// Sends `cmd` (split on whitespace) and checks the raw reply bytes.
+    async fn roundtrip(stream: &mut TcpStream, cmd: &str, expected: &[u8]) {
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+        roundtrip_args(stream, &args, expected).await;
+    }
+
+    /// Like `roundtrip`, for arguments that contain whitespace.
+    async fn roundtrip_args(stream: &mut TcpStream, args: &[&str], expected: &[u8]) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let request = Value::Array(
+            args.iter()
+                .map(|arg| Value::BulkString(Some(arg.to_string())))
+                .collect(),
+        );
+        stream.write_all(&encode(request).await).await.unwrap();
+
+        let mut reply = vec![0u8; expected.len()];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&reply),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    #[test]
+    fn test_transaction() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut client = TcpStream::connect(addr).await.unwrap();
+
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "SET foo bar", b"+QUEUED\r\n").await;
+            roundtrip(&mut client, "GEOADD foo 0 0 member", b"+QUEUED\r\n").await;
+            roundtrip(&mut client, "GET foo", b"+QUEUED\r\n").await;
+            roundtrip(
+                &mut client,
+                "EXEC",
+                b"*3\r\n+OK\r\n-WRONGTYPE Operation against a key holding the wrong kind of value\r\n$3\r\nbar\r\n",
+            )
+            .await;
+
+            roundtrip(&mut client, "EXEC", b"-ERR EXEC without MULTI\r\n").await;
+
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "SET foo baz", b"+QUEUED\r\n").await;
+            roundtrip(
+                &mut client,
+                "GET",
+                b"-ERR wrong number of arguments for 'get' command\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                "EXEC",
+                b"-EXECABORT Transaction discarded because of previous errors.\r\n",
+            )
+            .await;
+
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "SET foo baz", b"+QUEUED\r\n").await;
+            roundtrip(&mut client, "DISCARD", b"+OK\r\n").await;
+            roundtrip(&mut client, "GET foo", b"$3\r\nbar\r\n").await;
+        })
+    }
+
+    #[test]
+    fn test_watch() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let mut other = TcpStream::connect(addr).await.unwrap();
+
+            // Untouched watched keys let EXEC through.
+            roundtrip(&mut client, "WATCH foo", b"+OK\r\n").await;
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "SET foo 1", b"+QUEUED\r\n").await;
+            roundtrip(&mut client, "EXEC", b"*1\r\n+OK\r\n").await;
+
+            // Modified by another client.
+            roundtrip(&mut client, "WATCH foo", b"+OK\r\n").await;
+            roundtrip(&mut other, "SET foo 2", b"+OK\r\n").await;
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "SET foo 3", b"+QUEUED\r\n").await;
+            roundtrip(&mut client, "EXEC", b"*-1\r\n").await;
+            roundtrip(&mut client, "GET foo", b"$1\r\n2\r\n").await;
+
+            // Deleted by FLUSHALL.
+            roundtrip(&mut client, "WATCH foo", b"+OK\r\n").await;
+            roundtrip(&mut other, "FLUSHALL", b"+OK\r\n").await;
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "EXEC", b"*-1\r\n").await;
+
+            // Expired in between, without anyone touching it.
+            roundtrip(&mut client, "SET foo 4 PX 50", b"+OK\r\n").await;
+            roundtrip(&mut client, "WATCH foo", b"+OK\r\n").await;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "EXEC", b"*-1\r\n").await;
+
+            // A write that fails on the wrong type leaves the key untouched.
+            roundtrip(&mut client, "SET bar 1", b"+OK\r\n").await;
+            roundtrip(&mut client, "WATCH bar", b"+OK\r\n").await;
+            roundtrip(
+                &mut other,
+                "GEOADD bar 13.361389 38.115556 Palermo",
+                b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "EXEC", b"*0\r\n").await;
+
+            // So does one that changes nothing.
+            roundtrip(
+                &mut client,
+                "GEOADD geo 13.361389 38.115556 Palermo",
+                b":1\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "WATCH geo", b"+OK\r\n").await;
+            roundtrip(&mut other, "GEOADD geo NX 15 37 Palermo", b":0\r\n").await;
+            roundtrip(&mut other, "GEOADD geo XX 15 37 Catania", b":0\r\n").await;
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "EXEC", b"*0\r\n").await;
+
+            // UNWATCH forgets about earlier modifications.
+            roundtrip(&mut client, "WATCH foo", b"+OK\r\n").await;
+            roundtrip(&mut other, "DEL foo", b":0\r\n").await;
+            roundtrip(&mut other, "SET foo 5", b"+OK\r\n").await;
+            roundtrip(&mut client, "UNWATCH", b"+OK\r\n").await;
+            roundtrip(&mut client, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut client, "DEL foo", b"+QUEUED\r\n").await;
+            roundtrip(&mut client, "EXEC", b"*1\r\n:1\r\n").await;
+        })
+    }
+
+    #[test]
+    fn test_pubsub() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut subscriber = TcpStream::connect(addr).await.unwrap();
+            let mut publisher = TcpStream::connect(addr).await.unwrap();
+
+            roundtrip(
+                &mut subscriber,
+                "SUBSCRIBE news sport",
+                b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$5\r\nsport\r\n:2\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut subscriber,
+                "GET foo",
+                b"-ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n",
+            )
+            .await;
+            roundtrip(&mut subscriber, "PING", b"*2\r\n$4\r\npong\r\n$0\r\n\r\n").await;
+
+            roundtrip(&mut publisher, "PUBLISH news hello", b":1\r\n").await;
+            roundtrip(&mut publisher, "PUBLISH weather rain", b":0\r\n").await;
+            roundtrip(
+                &mut publisher,
+                "PUBSUB CHANNELS s*",
+                b"*1\r\n$5\r\nsport\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut publisher,
+                "PUBSUB NUMSUB news weather",
+                b"*4\r\n$4\r\nnews\r\n:1\r\n$7\r\nweather\r\n:0\r\n",
+            )
+            .await;
+
+            roundtrip(
+                &mut subscriber,
+                "UNSUBSCRIBE news",
+                b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:1\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut subscriber,
+                "UNSUBSCRIBE",
+                b"*3\r\n$11\r\nunsubscribe\r\n$5\r\nsport\r\n:0\r\n",
+            )
+            .await;
+            roundtrip(&mut subscriber, "GET foo", b"$-1\r\n").await;
+
+            // RESP3 clients get push frames and may keep running commands.
+            roundtrip(&mut subscriber, "HELLO 3", b"%7\r\n$6\r\nserver\r\n").await;
+            // Skip the rest of the map up to the trailing empty module list.
+            let mut hello = Vec::new();
+            while !hello.ends_with(b"*0\r\n") {
+                hello.push(
+                    tokio::io::AsyncReadExt::read_u8(&mut subscriber)
+                        .await
+                        .unwrap(),
+                );
+            }
+            roundtrip(
+                &mut subscriber,
+                "SUBSCRIBE news",
+                b">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n",
+            )
+            .await;
+            roundtrip(&mut subscriber, "GET foo", b"_\r\n").await;
+            roundtrip(&mut publisher, "PUBLISH news hi", b":1\r\n").await;
+            roundtrip(
+                &mut subscriber,
+                "PING",
+                b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n+PONG\r\n",
+            )
+            .await;
+
+            roundtrip(&mut subscriber, "QUIT", b"+OK\r\n").await;
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            roundtrip(&mut publisher, "PUBLISH news bye", b":0\r\n").await;
+        })
+    }
+
+    #[test]
+    fn test_pattern_subscriptions() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut subscriber = TcpStream::connect(addr).await.unwrap();
+            let mut publisher = TcpStream::connect(addr).await.unwrap();
+
+            roundtrip(
+                &mut subscriber,
+                "PSUBSCRIBE orders.*",
+                b"*3\r\n$10\r\npsubscribe\r\n$8\r\norders.*\r\n:1\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut subscriber,
+                "SUBSCRIBE orders.created",
+                b"*3\r\n$9\r\nsubscribe\r\n$14\r\norders.created\r\n:2\r\n",
+            )
+            .await;
+            roundtrip(&mut publisher, "PUBSUB NUMPAT", b":1\r\n").await;
+
+            // Delivered once per matching subscription.
+            roundtrip(&mut publisher, "PUBLISH orders.created 42", b":2\r\n").await;
+            roundtrip(&mut publisher, "PUBLISH users.created 7", b":0\r\n").await;
+            roundtrip(
+                &mut subscriber,
+                "PUNSUBSCRIBE",
+                b"*3\r\n$7\r\nmessage\r\n$14\r\norders.created\r\n$2\r\n42\r\n\
+                  *4\r\n$8\r\npmessage\r\n$8\r\norders.*\r\n$14\r\norders.created\r\n$2\r\n42\r\n\
+                  *3\r\n$12\r\npunsubscribe\r\n$8\r\norders.*\r\n:1\r\n",
+            )
+            .await;
+            roundtrip(&mut publisher, "PUBSUB NUMPAT", b":0\r\n").await;
+            roundtrip(&mut publisher, "PUBLISH orders.paid 1", b":0\r\n").await;
+        })
+    }
+
+    #[test]
+    fn test_sharded_pubsub() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut subscriber = TcpStream::connect(addr).await.unwrap();
+            let mut publisher = TcpStream::connect(addr).await.unwrap();
+
+            roundtrip(
+                &mut subscriber,
+                "SSUBSCRIBE {user1}.feed",
+                b"*3\r\n$10\r\nssubscribe\r\n$12\r\n{user1}.feed\r\n:1\r\n",
+            )
+            .await;
+            // Shard subscriptions are counted apart from global ones.
+            roundtrip(
+                &mut subscriber,
+                "SUBSCRIBE {user1}.feed",
+                b"*3\r\n$9\r\nsubscribe\r\n$12\r\n{user1}.feed\r\n:1\r\n",
+            )
+            .await;
+
+            roundtrip(&mut publisher, "SPUBLISH {user1}.feed hi", b":1\r\n").await;
+            roundtrip(&mut publisher, "SPUBLISH {user1}.other hi", b":0\r\n").await;
+            roundtrip(
+                &mut publisher,
+                "PUBSUB SHARDCHANNELS",
+                b"*1\r\n$12\r\n{user1}.feed\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut publisher,
+                "PUBSUB SHARDNUMSUB {user1}.feed",
+                b"*2\r\n$12\r\n{user1}.feed\r\n:1\r\n",
+            )
+            .await;
+
+            roundtrip(
+                &mut subscriber,
+                "SUNSUBSCRIBE",
+                b"*3\r\n$8\r\nsmessage\r\n$12\r\n{user1}.feed\r\n$2\r\nhi\r\n\
+                  *3\r\n$12\r\nsunsubscribe\r\n$12\r\n{user1}.feed\r\n:0\r\n",
+            )
+            .await;
+            roundtrip(&mut publisher, "SPUBLISH {user1}.feed again", b":0\r\n").await;
+            roundtrip(&mut publisher, "PUBLISH {user1}.feed global", b":1\r\n").await;
+        })
+    }
+
+    #[test]
+    fn test_databases() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let mut other = TcpStream::connect(addr).await.unwrap();
+
+            roundtrip(&mut client, "SET foo 0", b"+OK\r\n").await;
+            roundtrip(&mut client, "SELECT 1", b"+OK\r\n").await;
+            roundtrip(&mut client, "GET foo", b"$-1\r\n").await;
+            roundtrip(&mut client, "SET foo 1", b"+OK\r\n").await;
+            roundtrip(&mut other, "GET foo", b"$1\r\n0\r\n").await;
+            roundtrip(
+                &mut client,
+                "SELECT 16",
+                b"-ERR DB index is out of range\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                "SELECT x",
+                b"-ERR value is not an integer or out of range\r\n",
+            )
+            .await;
+
+            // MOVE only moves keys missing from the destination.
+            roundtrip(&mut client, "MOVE foo 0", b":0\r\n").await;
+            roundtrip(&mut client, "MOVE bar 0", b":0\r\n").await;
+            roundtrip(&mut client, "MOVE foo 2", b":1\r\n").await;
+            roundtrip(&mut client, "GET foo", b"$-1\r\n").await;
+            roundtrip(
+                &mut client,
+                "MOVE foo 1",
+                b"-ERR source and destination objects are the same\r\n",
+            )
+            .await;
+
+            // SWAPDB is seen by every client, and touches watched keys.
+            roundtrip(&mut other, "WATCH foo", b"+OK\r\n").await;
+            roundtrip(&mut client, "SWAPDB 0 2", b"+OK\r\n").await;
+            roundtrip(&mut other, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut other, "GET foo", b"+QUEUED\r\n").await;
+            roundtrip(&mut other, "EXEC", b"*-1\r\n").await;
+            roundtrip(&mut other, "GET foo", b"$1\r\n1\r\n").await;
+            roundtrip(
+                &mut client,
+                "SWAPDB 0 x",
+                b"-ERR invalid second DB index\r\n",
+            )
+            .await;
+
+            // A queued SELECT switches the database for the commands after it.
+            roundtrip(&mut other, "MULTI", b"+OK\r\n").await;
+            roundtrip(&mut other, "SELECT 2", b"+QUEUED\r\n").await;
+            roundtrip(&mut other, "GET foo", b"+QUEUED\r\n").await;
+            roundtrip(&mut other, "EXEC", b"*2\r\n+OK\r\n$1\r\n0\r\n").await;
+
+            roundtrip(&mut other, "FLUSHDB ASYNC", b"+OK\r\n").await;
+            roundtrip(&mut other, "GET foo", b"$-1\r\n").await;
+            roundtrip(&mut client, "SELECT 0", b"+OK\r\n").await;
+            roundtrip(&mut client, "GET foo", b"$1\r\n1\r\n").await;
+            roundtrip(&mut client, "FLUSHALL SYNC", b"+OK\r\n").await;
+            roundtrip(&mut client, "GET foo", b"$-1\r\n").await;
+            roundtrip(&mut client, "FLUSHDB LATER", b"-ERR syntax error\r\n").await;
+            roundtrip(
+                &mut client,
+                "CONFIG GET databases",
+                b"*2\r\n$9\r\ndatabases\r\n$2\r\n16\r\n",
+            )
+            .await;
+        })
+    }
+
+    #[test]
+    fn test_save() {
+        run_async_tests(async {
+            let dir = std::env::temp_dir().join(format!("rdb-save-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let dir = dir.to_str().unwrap().to_string();
+            let config = Config::new(
+                String::new(),
+                Some(dir.clone()),
+                Some("dump.rdb".into()),
+                16,
+            );
+
+            let addr = spawn_server_saving_to(Some(dir.clone())).await;
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            roundtrip(&mut client, "SET foo bar", b"+OK\r\n").await;
+            roundtrip(&mut client, "SELECT 3", b"+OK\r\n").await;
+            roundtrip(&mut client, "SET n 12 PX 100000", b"+OK\r\n").await;
+            roundtrip(&mut client, "SAVE", b"+OK\r\n").await;
+
+            let saved = rdb::read_rdb_file(&config).unwrap().unwrap();
+            assert!(saved.databases[&0].contains_key("foo"));
+            let n = &saved.databases[&3]["n"];
+            assert!(matches!(n.get_value(), store::EntryValue::String(v) if v == "12"));
+            assert!(n.expires_at().is_some());
+
+            roundtrip(&mut client, "DEL n", b":1\r\n").await;
+            roundtrip(&mut client, "BGSAVE", b"+Background saving started\r\n").await;
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            let saved = rdb::read_rdb_file(&config).unwrap().unwrap();
+            assert_eq!(saved.databases.keys().collect::<Vec<_>>(), [&0]);
+
+            let now = SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let lastsave = request_line(&mut client, "LASTSAVE").await;
+            let lastsave: u64 = lastsave.strip_prefix(':').unwrap().parse().unwrap();
+            assert!(now - lastsave <= 1);
+
+            // Save rules kick in once enough keys changed.
+            roundtrip_args(&mut client, &["CONFIG", "SET", "save", "0 2"], b"+OK\r\n").await;
+            roundtrip(
+                &mut client,
+                "CONFIG GET save",
+                b"*2\r\n$4\r\nsave\r\n$3\r\n0 2\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "SET a 1", b"+OK\r\n").await;
+            assert!(info(&mut client)
+                .await
+                .contains("rdb_changes_since_last_save:1\r\n"));
+            roundtrip(&mut client, "SET b 1", b"+OK\r\n").await;
+            for _ in 0..30 {
+                if info(&mut client)
+                    .await
+                    .contains("rdb_changes_since_last_save:0\r\n")
+                {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            let persistence = info(&mut client).await;
+            assert!(persistence.starts_with("# Persistence\r\n"));
+            assert!(persistence.contains("rdb_changes_since_last_save:0\r\n"));
+            assert!(persistence.contains("rdb_last_bgsave_status:ok\r\n"));
+            let saved = rdb::read_rdb_file(&config).unwrap().unwrap();
+            assert!(saved.databases[&3].contains_key("b"));
+
+            // Only the renamed file is left behind.
+            let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+            assert_eq!(files.len(), 1);
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_loading() {
+        run_async_tests(async {
+            let dir = std::env::temp_dir().join(format!("rdb-load-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("dump.rdb").to_str().unwrap().to_string();
+            let entry = |value: &str| {
+                let value = store::EntryValue::String(value.to_string());
+                store::Entry::new(value, None, None, std::time::Instant::now())
+            };
+            let contents = rdb::RdbContents {
+                databases: BTreeMap::from([
+                    (
+                        0,
+                        std::collections::HashMap::from([("a".to_string(), entry("1"))]),
+                    ),
+                    (
+                        5,
+                        std::collections::HashMap::from([("b".to_string(), entry("2"))]),
+                    ),
+                ]),
+                functions: Vec::new(),
+            };
+            std::fs::write(&path, rdb::write_rdb(&contents, true)).unwrap();
+
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let pubsub = Arc::new(PubSub::new());
+            let databases = Databases::new(16, BTreeMap::new(), pubsub.clone());
+            let server = Arc::new(Server::new(databases, pubsub));
+            let config = Config::new(addr.to_string(), None, None, 16);
+            server.persistence.start_loading();
+            serve(listener, server.clone(), config);
+
+            // Only a few commands are answered until the dataset is loaded.
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            roundtrip(
+                &mut client,
+                "GET a",
+                b"-LOADING Redis is loading the dataset in memory\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "SELECT 5", b"+OK\r\n").await;
+            assert!(info(&mut client).await.contains("loading:1\r\n"));
+
+            let loader = server.clone();
+            let loaded = tokio::task::spawn_blocking(move || {
+                persistence::load_rdb(&loader, &path).unwrap().unwrap()
+            })
+            .await
+            .unwrap();
+            assert!(loaded.is_empty());
+            let persistence = info(&mut client).await;
+            assert!(persistence.contains("loading_loaded_perc:100.00\r\n"));
+            server.persistence.finish_loading();
+            assert!(info(&mut client).await.contains("loading:0\r\n"));
+            roundtrip(&mut client, "GET b", b"$1\r\n2\r\n").await;
+            roundtrip(&mut client, "SELECT 0", b"+OK\r\n").await;
+            roundtrip(&mut client, "GET a", b"$1\r\n1\r\n").await;
+
+            // Databases the server doesn't have can't be loaded.
+            let pubsub = Arc::new(PubSub::new());
+            let small = Server::new(Databases::new(4, BTreeMap::new(), pubsub.clone()), pubsub);
+            let path = dir.join("dump.rdb").to_str().unwrap().to_string();
+            let e = tokio::task::spawn_blocking(move || persistence::load_rdb(&small, &path))
+                .await
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(e.opcode, Some(0));
+            assert!(e.reason.contains("configured with 4 databases"));
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+    }
+
+    #[test]
+    fn test_keyspace_notifications() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut subscriber = TcpStream::connect(addr).await.unwrap();
+            let mut client = TcpStream::connect(addr).await.unwrap();
+
+            roundtrip(
+                &mut client,
+                "CONFIG SET notify-keyspace-events Kgx$",
+                b"+OK\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                "CONFIG GET notify-keyspace-events",
+                b"*2\r\n$22\r\nnotify-keyspace-events\r\n$4\r\ng$xK\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut subscriber,
+                "SUBSCRIBE __keyspace@0__:foo",
+                b"*3\r\n$9\r\nsubscribe\r\n$18\r\n__keyspace@0__:foo\r\n:1\r\n",
+            )
+            .await;
+
+            roundtrip(&mut client, "SET foo 1", b"+OK\r\n").await;
+            roundtrip(&mut client, "DEL foo", b":1\r\n").await;
+            // Zset events are not enabled.
+            roundtrip(&mut client, "GEOADD foo 0 0 member", b":1\r\n").await;
+            roundtrip(&mut client, "DEL foo", b":1\r\n").await;
+            roundtrip(&mut client, "SET foo 2 PX 10", b"+OK\r\n").await;
+            tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+            roundtrip(&mut client, "GET foo", b"$-1\r\n").await;
+
+            let message = |event: &str| {
+                format!(
+                    "*3\r\n$7\r\nmessage\r\n$18\r\n__keyspace@0__:foo\r\n${}\r\n{}\r\n",
+                    event.len(),
+                    event
+                )
+            };
+            let expected: String = ["set", "del", "del", "set", "expired"]
+                .iter()
+                .map(|event| message(event))
+                .collect();
+            roundtrip(
+                &mut subscriber,
+                "UNSUBSCRIBE",
+                format!(
+                    "{}*3\r\n$11\r\nunsubscribe\r\n$18\r\n__keyspace@0__:foo\r\n:0\r\n",
+                    expected
+                )
+                .as_bytes(),
+            )
+            .await;
+
+            roundtrip(
+                &mut client,
+                "CONFIG SET notify-keyspace-events Kw",
+                b"-ERR Invalid event class character. Use 'Ag$lshzxeKEtmn'.\r\n",
+            )
+            .await;
+        })
+    }
+
+    /// Sends `cmd` and returns the first reply line, for replies whose
+    /// exact bytes are not known in advance.
+    async fn request_line(stream: &mut TcpStream, cmd: &str) -> String {
+        use tokio::io::AsyncWriteExt;
+
+        let request = Value::Array(
+            cmd.split_whitespace()
+                .map(|arg| Value::BulkString(Some(arg.to_string())))
+                .collect(),
+        );
+        stream.write_all(&encode(request).await).await.unwrap();
+        read_line(stream).await
+    }
+
+    /// The persistence section of INFO.
+    async fn info(stream: &mut TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let header = request_line(stream, "INFO persistence").await;
+        let len: usize = header.strip_prefix('$').unwrap().parse().unwrap();
+        let mut section = vec![0u8; len + 2];
+        stream.read_exact(&mut section).await.unwrap();
+        section.truncate(len);
+        String::from_utf8(section).unwrap()
+    }
+
+    /// Reads one line of a reply, without its CRLF.
+    async fn read_line(stream: &mut TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            line.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(line).unwrap().trim_end().to_string()
+    }
+
+    #[test]
+    fn test_client_tracking() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut cache = TcpStream::connect(addr).await.unwrap();
+            let mut writer = TcpStream::connect(addr).await.unwrap();
+
+            // Default mode: keys read are invalidated once.
+            request_line(&mut cache, "HELLO 3").await;
+            let mut hello = Vec::new();
+            while !hello.ends_with(b"*0\r\n") {
+                hello.push(tokio::io::AsyncReadExt::read_u8(&mut cache).await.unwrap());
+            }
+            roundtrip(&mut cache, "CLIENT TRACKING ON", b"+OK\r\n").await;
+            roundtrip(&mut cache, "GET foo", b"_\r\n").await;
+            roundtrip(&mut writer, "SET foo 1", b"+OK\r\n").await;
+            roundtrip(&mut writer, "SET foo 2", b"+OK\r\n").await;
+            roundtrip(
+                &mut cache,
+                "PING",
+                b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nfoo\r\n+PONG\r\n",
+            )
+            .await;
+
+            // NOLOOP skips the client's own writes.
+            roundtrip(&mut cache, "CLIENT TRACKING ON NOLOOP", b"+OK\r\n").await;
+            roundtrip(&mut cache, "GET foo", b"$1\r\n2\r\n").await;
+            roundtrip(&mut cache, "SET foo 3", b"+OK\r\n").await;
+            roundtrip(&mut cache, "PING", b"+PONG\r\n").await;
+
+            // OPTIN only caches reads right after CLIENT CACHING YES.
+            roundtrip(&mut cache, "CLIENT TRACKING OFF", b"+OK\r\n").await;
+            roundtrip(&mut cache, "CLIENT TRACKING ON OPTIN", b"+OK\r\n").await;
+            roundtrip(&mut cache, "GET bar", b"_\r\n").await;
+            roundtrip(&mut cache, "CLIENT CACHING YES", b"+OK\r\n").await;
+            roundtrip(&mut cache, "GET baz", b"_\r\n").await;
+            roundtrip(&mut writer, "SET bar 1", b"+OK\r\n").await;
+            roundtrip(&mut writer, "SET baz 1", b"+OK\r\n").await;
+            roundtrip(
+                &mut cache,
+                "PING",
+                b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nbaz\r\n+PONG\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut cache,
+                "CLIENT CACHING NO",
+                b"-ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut cache,
+                "CLIENT TRACKING ON BCAST",
+                b"-ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.\r\n",
+            )
+            .await;
+            roundtrip(&mut cache, "CLIENT TRACKING OFF", b"+OK\r\n").await;
+
+            // RESP2 clients receive invalidations through a redirection.
+            let mut listener = TcpStream::connect(addr).await.unwrap();
+            let id = request_line(&mut listener, "CLIENT ID").await;
+            roundtrip(
+                &mut listener,
+                "SUBSCRIBE __redis__:invalidate",
+                b"*3\r\n$9\r\nsubscribe\r\n$20\r\n__redis__:invalidate\r\n:1\r\n",
+            )
+            .await;
+            let mut resp2 = TcpStream::connect(addr).await.unwrap();
+            roundtrip(
+                &mut resp2,
+                &format!(
+                    "CLIENT TRACKING ON REDIRECT {} BCAST PREFIX user:",
+                    &id[1..]
+                ),
+                b"+OK\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut resp2,
+                "CLIENT GETREDIR",
+                format!("{}\r\n", id).as_bytes(),
+            )
+            .await;
+            roundtrip(&mut writer, "SET user:1 x", b"+OK\r\n").await;
+            roundtrip(&mut writer, "SET order:1 x", b"+OK\r\n").await;
+            roundtrip(&mut writer, "FLUSHALL", b"+OK\r\n").await;
+            roundtrip(
+                &mut listener,
+                "PING",
+                b"*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$6\r\nuser:1\r\n\
+                  *3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n$-1\r\n\
+                  *2\r\n$4\r\npong\r\n$0\r\n\r\n",
+            )
+            .await;
+        })
+    }
+
+    #[test]
+    fn test_scripting() {
+        run_async_tests(async {
+            let addr = spawn_server().await;
+            let mut client = TcpStream::connect(addr).await.unwrap();
+
+            roundtrip_args(
+                &mut client,
+                &[
+                    "EVAL",
+                    "return {KEYS[1], ARGV[1], 3.7, true, false, 'x'}",
+                    "1",
+                    "k",
+                    "a",
+                ],
+                b"*6\r\n$1\r\nk\r\n$1\r\na\r\n:3\r\n:1\r\n$-1\r\n$1\r\nx\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &[
+                    "EVAL",
+                    "redis.call('SET', KEYS[1], ARGV[1]) return redis.call('GET', KEYS[1])",
+                    "1",
+                    "foo",
+                    "bar",
+                ],
+                b"$3\r\nbar\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["EVAL", "return redis.call('SET', 'foo', 'baz')", "0"],
+                b"+OK\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["EVAL", "return redis.call('GET', 'missing') == false", "0"],
+                b":1\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &[
+                    "EVAL",
+                    "return redis.pcall('GEOADD', 'foo', 0, 0, 'm')",
+                    "0",
+                ],
+                b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &[
+                    "EVAL",
+                    "local r = redis.pcall('GEOADD', 'foo', 0, 0, 'm') return type(r.err)",
+                    "0",
+                ],
+                b"$6\r\nstring\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &[
+                    "EVAL",
+                    "redis.call('GEOADD', 'foo', 0, 0, 'm') return 1",
+                    "0",
+                ],
+                b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["EVAL", "return redis.error_reply('MY error')", "0"],
+                b"-MY error\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["EVAL", "return redis.call('MULTI')", "0"],
+                b"-ERR This Redis command is not allowed from script\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                "EVAL return -1",
+                b"-ERR Number of keys can't be negative\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                "EVAL return 2 k",
+                b"-ERR Number of keys can't be greater than number of args\r\n",
+            )
+            .await;
+
+            let sha = scripting::sha1hex("return ARGV[1]");
+            roundtrip(
+                &mut client,
+                &format!("EVALSHA {} 0 x", sha),
+                b"-NOSCRIPT No matching script. Please use EVAL.\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["SCRIPT", "LOAD", "return ARGV[1]"],
+                format!("$40\r\n{}\r\n", sha).as_bytes(),
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                &format!("EVALSHA {} 0 x", sha.to_uppercase()),
+                b"$1\r\nx\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                &format!("SCRIPT EXISTS {} nope", sha),
+                b"*2\r\n:1\r\n:0\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "SCRIPT FLUSH", b"+OK\r\n").await;
+            roundtrip(
+                &mut client,
+                &format!("SCRIPT EXISTS {}", sha),
+                b"*1\r\n:0\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                "SCRIPT KILL",
+                b"-NOTBUSY No scripts in execution right now.\r\n",
+            )
+            .await;
+            assert!(request_line(&mut client, "SCRIPT LOAD return+")
+                .await
+                .starts_with("-ERR Error compiling script (new function): user_script:1:"));
+        });
+    }
+
+    #[test]
+    fn test_script_kill() {
+        run_async_tests(async {
+            use tokio::io::AsyncWriteExt;
+
+            let addr = spawn_server().await;
+            let mut looping = TcpStream::connect(addr).await.unwrap();
+            let mut client = TcpStream::connect(addr).await.unwrap();
+
+            let request = Value::Array(
+                ["EVAL", "while true do end", "0"]
+                    .iter()
+                    .map(|arg| Value::BulkString(Some(arg.to_string())))
+                    .collect(),
+            );
+            looping.write_all(&encode(request).await).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+            roundtrip(&mut client, "SCRIPT KILL", b"+OK\r\n").await;
+            assert_eq!(
+                request_line(&mut looping, "PING").await,
+                "-ERR Script killed by user with SCRIPT KILL..."
+            );
+            roundtrip(&mut looping, "PING", b"+PONG\r\n").await;
+
+            let request = Value::Array(
+                ["EVAL", "redis.call('SET', 'k', 'v') while true do end", "0"]
+                    .iter()
+                    .map(|arg| Value::BulkString(Some(arg.to_string())))
+                    .collect(),
+            );
+            looping.write_all(&encode(request).await).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            assert!(request_line(&mut client, "SCRIPT KILL")
+                .await
+                .starts_with("-UNKILLABLE"));
+        });
+    }
+
+    #[test]
+    fn test_functions() {
+        run_async_tests(async {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let addr = spawn_server().await;
+            let mut client = TcpStream::connect(addr).await.unwrap();
+
+            let library = "#!lua name=mylib
+                redis.register_function('setget', function(keys, args)
+                    redis.call('SET', keys[1], args[1])
+                    return redis.call('GET', keys[1])
+                end)
+                redis.register_function{
+                    function_name = 'peek',
+                    callback = function(keys) return redis.call('GET', keys[1]) end,
+                    flags = {'no-writes', 'allow-stale'},
+                }
+                redis.register_function{
+                    function_name = 'sneaky',
+                    callback = function(keys) return redis.call('SET', keys[1], 'x') end,
+                    flags = {'no-writes'},
+                }";
+            roundtrip_args(
+                &mut client,
+                &["FUNCTION", "LOAD", library],
+                b"$5\r\nmylib\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["FUNCTION", "LOAD", library],
+                b"-ERR Library 'mylib' already exists\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["FUNCTION", "LOAD", "REPLACE", library],
+                b"$5\r\nmylib\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &[
+                    "FUNCTION",
+                    "LOAD",
+                    "#!lua name=other\nredis.register_function('peek', print)",
+                ],
+                b"-ERR Function peek already exists\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["FUNCTION", "LOAD", "return 1"],
+                b"-ERR Missing library metadata\r\n",
+            )
+            .await;
+            roundtrip_args(
+                &mut client,
+                &["FUNCTION", "LOAD", "#!lua name=empty\nlocal x = 1"],
+                b"-ERR No functions registered\r\n",
+            )
+            .await;
+            assert!(request_line(&mut client, "FUNCTION LOAD #!js")
+                .await
+                .starts_with("-ERR Engine 'js' not found"));
+
+            roundtrip(&mut client, "FCALL setget 1 foo bar", b"$3\r\nbar\r\n").await;
+            roundtrip(&mut client, "FCALL_RO peek 1 foo", b"$3\r\nbar\r\n").await;
+            roundtrip(
+                &mut client,
+                "FCALL_RO setget 1 foo baz",
+                b"-ERR Can not execute a script with write flag using *_ro command.\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                "FCALL sneaky 1 foo",
+                b"-ERR Write commands are not allowed from read-only scripts.\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "FCALL nope 0", b"-ERR Function not found\r\n").await;
+
+            roundtrip(
+                &mut client,
+                "FUNCTION LIST LIBRARYNAME my*",
+                b"*1\r\n*6\r\n$12\r\nlibrary_name\r\n$5\r\nmylib\r\n$6\r\nengine\r\n$3\r\nLUA\r\n\
+                  $9\r\nfunctions\r\n*3\r\n\
+                  *6\r\n$4\r\nname\r\n$6\r\nsetget\r\n$11\r\ndescription\r\n$-1\r\n$5\r\nflags\r\n*0\r\n\
+                  *6\r\n$4\r\nname\r\n$4\r\npeek\r\n$11\r\ndescription\r\n$-1\r\n$5\r\nflags\r\n\
+                  *2\r\n$9\r\nno-writes\r\n$11\r\nallow-stale\r\n\
+                  *6\r\n$4\r\nname\r\n$6\r\nsneaky\r\n$11\r\ndescription\r\n$-1\r\n$5\r\nflags\r\n\
+                  *1\r\n$9\r\nno-writes\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "FUNCTION LIST LIBRARYNAME x*", b"*0\r\n").await;
+
+            // DUMP, then RESTORE into an empty server.
+            let header = request_line(&mut client, "FUNCTION DUMP").await;
+            let len: usize = header[1..].parse().unwrap();
+            let mut payload = vec![0u8; len + 2];
+            client.read_exact(&mut payload).await.unwrap();
+            payload.truncate(len);
+
+            let restore = |policy: Option<&str>, payload: Vec<u8>| {
+                let mut args = vec![
+                    Value::BulkString(Some("FUNCTION".to_string())),
+                    Value::BulkString(Some("RESTORE".to_string())),
+                    Value::Binary(payload),
+                ];
+                args.extend(policy.map(|policy| Value::BulkString(Some(policy.to_string()))));
+                Value::Array(args)
+            };
+            client
+                .write_all(&encode(restore(None, payload.clone())).await)
+                .await
+                .unwrap();
+            assert_eq!(
+                read_line(&mut client).await,
+                "-ERR Library 'mylib' already exists"
+            );
+
+            roundtrip(&mut client, "FUNCTION DELETE mylib", b"+OK\r\n").await;
+            roundtrip(
+                &mut client,
+                "FUNCTION DELETE mylib",
+                b"-ERR Library not found\r\n",
+            )
+            .await;
+            roundtrip(
+                &mut client,
+                "FCALL setget 1 foo bar",
+                b"-ERR Function not found\r\n",
+            )
+            .await;
+
+            let mut corrupted = payload.clone();
+            corrupted[2] ^= 1;
+            client
+                .write_all(&encode(restore(Some("FLUSH"), corrupted)).await)
+                .await
+                .unwrap();
+            assert_eq!(
+                read_line(&mut client).await,
+                "-ERR DUMP payload version or checksum are wrong"
+            );
+            client
+                .write_all(&encode(restore(Some("FLUSH"), payload)).await)
+                .await
+                .unwrap();
+            assert_eq!(read_line(&mut client).await, "+OK");
+            roundtrip(&mut client, "FCALL_RO peek 1 foo", b"$3\r\nbar\r\n").await;
+
+            // SORT only counts as a write when it stores its result.
+            let library = "#!lua name=sorting
+                redis.register_function{
+                    function_name = 'sorted',
+                    callback = function(keys, args) return redis.call('SORT', keys[1], unpack(args)) end,
+                    flags = {'no-writes'},
+                }";
+            roundtrip_args(
+                &mut client,
+                &["FUNCTION", "LOAD", library],
+                b"$7\r\nsorting\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "FCALL_RO sorted 1 nolist LIMIT 0 1", b"*0\r\n").await;
+            roundtrip(
+                &mut client,
+                "FCALL_RO sorted 1 nolist STORE dst",
+                b"-ERR Write commands are not allowed from read-only scripts.\r\n",
+            )
+            .await;
+            roundtrip(&mut client, "FUNCTION FLUSH", b"+OK\r\n").await;
+            roundtrip(&mut client, "FUNCTION LIST", b"*0\r\n").await;
+        });
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        run_async_tests(async {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let addr = spawn_server().await;
+            let mut client = TcpStream::connect(addr).await.unwrap();
+
+            async fn dump(client: &mut TcpStream, key: &str) -> Vec<u8> {
+                let header = request_line(client, &format!("DUMP {}", key)).await;
+                let len: usize = header[1..].parse().unwrap();
+                let mut payload = vec![0u8; len + 2];
+                client.read_exact(&mut payload).await.unwrap();
+                payload.truncate(len);
+                payload
+            }
+            // RESTORE key ttl payload options...
+            async fn restore(client: &mut TcpStream, args: &str, payload: &[u8]) -> String {
+                let mut args = args.split_whitespace();
+                let mut request = vec![Value::BulkString(Some("RESTORE".to_string()))];
+                request.extend(
+                    args.by_ref()
+                        .take(2)
+                        .map(|arg| Value::BulkString(Some(arg.to_string()))),
+                );
+                request.push(Value::Binary(payload.to_vec()));
+                request.extend(args.map(|arg| Value::BulkString(Some(arg.to_string()))));
+                client
+                    .write_all(&encode(Value::Array(request)).await)
+                    .await
+                    .unwrap();
+                read_line(client).await
+            }
+
+            roundtrip(&mut client, "SET foo bar", b"+OK\r\n").await;
+            roundtrip(&mut client, "DUMP missing", b"$-1\r\n").await;
+            let payload = dump(&mut client, "foo").await;
+            assert_eq!(
+                payload[payload.len() - 10..][..2],
+                rdb::RDB_VERSION.to_le_bytes()
+            );
+
+            assert_eq!(
+                restore(&mut client, "foo 0", &payload).await,
+                "-BUSYKEY Target key name already exists."
+            );
+            assert_eq!(restore(&mut client, "copy 0", &payload).await, "+OK");
+            roundtrip(&mut client, "GET copy", b"$3\r\nbar\r\n").await;
+            assert_eq!(
+                restore(&mut client, "foo 0", &payload).await,
+                "-BUSYKEY Target key name already exists."
+            );
+            assert_eq!(
+                restore(&mut client, "foo 0 REPLACE IDLETIME 10", &payload).await,
+                "+OK"
+            );
+            assert_eq!(
+                restore(&mut client, "foo 0 replace freq 255", &payload).await,
+                "+OK"
+            );
+
+            for (args, error) in [
+                ("x -1", "-ERR Invalid TTL value, must be >= 0"),
+                ("x ten", "-ERR value is not an integer or out of range"),
+                (
+                    "x 0 IDLETIME -1",
+                    "-ERR Invalid IDLETIME value, must be >= 0",
+                ),
+                (
+                    "x 0 FREQ 256",
+                    "-ERR Invalid FREQ value, must be >= 0 and <= 255",
+                ),
+                ("x 0 IDLETIME 1 FREQ 1", "-ERR syntax error"),
+                ("x 0 FREQ", "-ERR syntax error"),
+                ("x 0 LATER", "-ERR syntax error"),
+            ] {
+                assert_eq!(
+                    restore(&mut client, args, &payload).await,
+                    error,
+                    "{}",
+                    args
+                );
+            }
+            let mut corrupted = payload.clone();
+            corrupted[1] ^= 1;
+            assert_eq!(
+                restore(&mut client, "x 0", &corrupted).await,
+                "-ERR DUMP payload version or checksum are wrong"
+            );
+            // A payload from a newer RDB version, properly checksummed.
+            let mut newer = payload[..payload.len() - 10].to_vec();
+            newer.extend_from_slice(&(rdb::RDB_VERSION + 1).to_le_bytes());
+            newer.extend_from_slice(&rdb::crc64(0, &newer).to_le_bytes());
+            assert_eq!(
+                restore(&mut client, "x 0", &newer).await,
+                "-ERR DUMP payload version or checksum are wrong"
+            );
+            let mut bad = vec![0, 5, b'a'];
+            bad.extend_from_slice(&rdb::RDB_VERSION.to_le_bytes());
+            bad.extend_from_slice(&rdb::crc64(0, &bad).to_le_bytes());
+            assert_eq!(
+                restore(&mut client, "x 0", &bad).await,
+                "-ERR Bad data format"
+            );
+            // Module values, which there is no module to load.
+            for module_type in [6, 7] {
+                let mut module = vec![module_type, 0];
+                module.extend_from_slice(&rdb::RDB_VERSION.to_le_bytes());
+                module.extend_from_slice(&rdb::crc64(0, &module).to_le_bytes());
+                assert_eq!(
+                    restore(&mut client, "x 0", &module).await,
+                    "-ERR Bad data format"
+                );
+            }
+            roundtrip(&mut client, "GET x", b"$-1\r\n").await;
+
+            // A TTL already in the past only deletes the key being replaced.
+            assert_eq!(
+                restore(&mut client, "copy 1 ABSTTL REPLACE", &payload).await,
+                "+OK"
+            );
+            roundtrip(&mut client, "GET copy", b"$-1\r\n").await;
+            assert_eq!(restore(&mut client, "soon 50", &payload).await, "+OK");
+            roundtrip(&mut client, "GET soon", b"$3\r\nbar\r\n").await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            roundtrip(&mut client, "GET soon", b"$-1\r\n").await;
+
+            // Values of other types come back identical.
+            roundtrip(
+                &mut client,
+                "GEOADD places 13.361389 38.115556 Palermo 15.087269 37.502669 Catania",
+                b":2\r\n",
+            )
+            .await;
+            let payload = dump(&mut client, "places").await;
+            assert_eq!(restore(&mut client, "places2 0", &payload).await, "+OK");
+            assert_eq!(dump(&mut client, "places2").await, payload);
+            roundtrip(&mut client, "GET places2", b"-WRONGTYPE").await;
+        });
+    }
+
     // #[test]
     // fn test_command() {
     //     let store = Arc::new(RwLock::new(Store::new()));
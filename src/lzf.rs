@@ -0,0 +1,225 @@
+//! LZF, the compression of long strings in RDB files. `compress` follows
+//! liblzf as Redis builds it, with its hash table cleared, so it produces
+//! the same bytes Redis does.
+
+/// Bits of the hash table index.
+const HLOG: u32 = 16;
+const HSIZE: usize = 1 << HLOG;
+/// Longest literal run.
+const MAX_LIT: usize = 1 << 5;
+/// Furthest a back reference reaches.
+const MAX_OFF: usize = 1 << 13;
+/// Longest back reference.
+const MAX_REF: usize = (1 << 8) + (1 << 3);
+/// Most bytes a byte of compressed data expands to, reached by back
+/// references of the longest length, which take 3 bytes.
+pub const MAX_EXPANSION: usize = MAX_REF / 3;
+
+/// Hash table slot of the three bytes in `hval`.
+fn idx(hval: u32) -> usize {
+    ((hval >> (3 * 8 - HLOG)).wrapping_sub(hval.wrapping_mul(5)) as usize) & (HSIZE - 1)
+}
+
+/// Compresses `input` into at most `out_len` bytes, or returns `None` if it
+/// doesn't fit, in which case the string is better stored as is.
+pub fn compress(input: &[u8], out_len: usize) -> Option<Vec<u8>> {
+    if input.is_empty() || out_len == 0 {
+        return None;
+    }
+    let in_end = input.len();
+    let mut out = vec![0; out_len];
+    // Last position with each hash. Position 0 is never referenced, as in
+    // liblzf, so 0 stands for none.
+    let mut htab = vec![0_usize; HSIZE];
+    let first =
+        |ip: usize| (input[ip] as u32) << 8 | input.get(ip + 1).copied().unwrap_or(0) as u32;
+    let next = |hval: u32, ip: usize| hval << 8 | input[ip + 2] as u32;
+
+    let mut ip = 0;
+    // Each literal run starts with a byte holding its length, filled in
+    // once the run ends.
+    let mut op = 1;
+    let mut lit = 0;
+    let mut hval = first(ip);
+    while ip + 2 < in_end {
+        hval = next(hval, ip);
+        let slot = idx(hval);
+        let reference = htab[slot];
+        htab[slot] = ip;
+        let off = ip.wrapping_sub(reference).wrapping_sub(1);
+
+        if off < MAX_OFF && reference > 0 && input[reference..reference + 3] == input[ip..ip + 3] {
+            let mut len = 2;
+            let maxlen = (in_end - ip - len).min(MAX_REF);
+            if op + 3 + 1 >= out_len && op - (lit == 0) as usize + 3 + 1 >= out_len {
+                return None;
+            }
+            // End the literal run, or drop it if empty.
+            out[op - lit - 1] = (lit as u8).wrapping_sub(1);
+            op -= (lit == 0) as usize;
+
+            // Like liblzf, 16 bytes are compared unchecked first when the
+            // match may be long, which can run one past `maxlen`.
+            'compare: {
+                if maxlen > 16 {
+                    for _ in 0..16 {
+                        len += 1;
+                        if input[reference + len] != input[ip + len] {
+                            break 'compare;
+                        }
+                    }
+                }
+                loop {
+                    len += 1;
+                    if !(len < maxlen && input[reference + len] == input[ip + len]) {
+                        break;
+                    }
+                }
+            }
+
+            len -= 2;
+            ip += 1;
+            if len < 7 {
+                out[op] = ((off >> 8) + (len << 5)) as u8;
+                op += 1;
+            } else {
+                out[op] = ((off >> 8) + (7 << 5)) as u8;
+                out[op + 1] = (len - 7) as u8;
+                op += 2;
+            }
+            out[op] = off as u8;
+            op += 2;
+            lit = 0;
+
+            ip += len + 1;
+            if ip + 2 >= in_end {
+                break;
+            }
+            // Hash the last two positions of the match too.
+            ip -= 2;
+            hval = first(ip);
+            for _ in 0..2 {
+                hval = next(hval, ip);
+                htab[idx(hval)] = ip;
+                ip += 1;
+            }
+        } else {
+            if op >= out_len {
+                return None;
+            }
+            lit += 1;
+            out[op] = input[ip];
+            op += 1;
+            ip += 1;
+            if lit == MAX_LIT {
+                out[op - lit - 1] = (lit - 1) as u8;
+                lit = 0;
+                op += 1;
+            }
+        }
+    }
+
+    // At most two bytes are left, and the start of a run.
+    if op + 3 > out_len {
+        return None;
+    }
+    while ip < in_end {
+        lit += 1;
+        out[op] = input[ip];
+        op += 1;
+        ip += 1;
+        if lit == MAX_LIT {
+            out[op - lit - 1] = (lit - 1) as u8;
+            lit = 0;
+            op += 1;
+        }
+    }
+    out[op - lit - 1] = (lit as u8).wrapping_sub(1);
+    op -= (lit == 0) as usize;
+    out.truncate(op);
+    Some(out)
+}
+
+/// Decompresses `input`, which must expand to exactly `out_len` bytes.
+/// Returns `None` for corrupt input.
+pub fn decompress(input: &[u8], out_len: usize) -> Option<Vec<u8>> {
+    // The length comes from the file, so it is only trusted as far as the
+    // input can expand.
+    if out_len > input.len().saturating_mul(MAX_EXPANSION) {
+        return None;
+    }
+    let mut out = Vec::with_capacity(out_len);
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < 1 << 5 {
+            // A run of ctrl + 1 literal bytes.
+            let run = input.get(ip..ip + ctrl + 1)?;
+            if out.len() + run.len() > out_len {
+                return None;
+            }
+            out.extend_from_slice(run);
+            ip += run.len();
+        } else {
+            // A back reference: length in the top 3 bits, extended by a
+            // byte when they are all set, and a 13 bit offset.
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(ip)? as usize;
+                ip += 1;
+            }
+            let off = ((ctrl & 0x1f) << 8) + *input.get(ip)? as usize + 1;
+            ip += 1;
+            len += 2;
+            if off > out.len() || out.len() + len > out_len {
+                return None;
+            }
+            // The reference may overlap what it produces, so it is copied
+            // a byte at a time.
+            let start = out.len() - off;
+            for i in 0..len {
+                out.push(out[start + i]);
+            }
+        }
+    }
+    (out.len() == out_len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lzf() {
+        // Two literals, a back reference one byte behind of 20 bytes, then
+        // two more literals.
+        let compressed = [0x01, b'a', b'a', 0xE0, 0x0B, 0x00, 0x01, b'a', b'a'];
+        assert_eq!(compress(&[b'a'; 24], 20).unwrap(), compressed);
+        assert_eq!(decompress(&compressed, 24).unwrap(), [b'a'; 24]);
+        assert!(decompress(&compressed, 23).is_none());
+        assert!(decompress(&compressed[..4], 24).is_none());
+        // References can't point before the start.
+        assert!(decompress(&[0x20, 0x05], 3).is_none());
+        // Lengths no input this short expands to.
+        assert!(decompress(&compressed, usize::MAX).is_none());
+        assert!(decompress(&[0xE0, 0xFF, 0x00], MAX_EXPANSION * 3 + 1).is_none());
+
+        // Too little room, or nothing repeated, compresses to nothing.
+        assert!(compress(&[b'a'; 24], 8).is_none());
+        assert!(compress(b"abcdefghijklmnopqrstuvwxyz", 22).is_none());
+
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(200);
+        let mut inputs = vec![text.into_bytes(), vec![0; 70_000]];
+        inputs.push(
+            (0..5000_u32)
+                .flat_map(|i| (i * 7919 % 251).to_le_bytes())
+                .collect(),
+        );
+        for input in inputs {
+            let compressed = compress(&input, input.len()).unwrap();
+            assert!(compressed.len() < input.len());
+            assert_eq!(decompress(&compressed, input.len()).unwrap(), input);
+        }
+    }
+}
@@ -1,7 +1,12 @@
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::sync::Arc;
 
 use clap::Parser;
-use redis_starter_rust::{config::Config, handle_stream, rdb::read_rdb_file, store::Store};
+use redis_starter_rust::{
+    aof, config::Config, handle_stream, notify, persistence, pubsub::PubSub, server::Server,
+    store::Databases,
+};
 use tokio::net::TcpListener;
 
 #[derive(Parser, Debug)]
@@ -15,29 +20,197 @@ struct Args {
 
     #[arg(long = "dbfilename")]
     rdb_file: Option<String>,
+
+    /// Number of databases, numbered from 0 and chosen with SELECT.
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+    databases: u32,
+
+    /// Keyspace event classes to publish, e.g. "KEA" (see CONFIG SET).
+    #[arg(long = "notify-keyspace-events", default_value = "")]
+    notify_keyspace_events: String,
+
+    /// Automatic save rules as "<seconds> <changes> ...", "" to disable.
+    #[arg(long, default_value = persistence::DEFAULT_SAVE_RULES)]
+    save: String,
+
+    /// LZF compress long strings in RDB files.
+    #[arg(
+        long,
+        default_value = "yes",
+        value_parser = yes_no,
+        action = clap::ArgAction::Set
+    )]
+    rdbcompression: bool,
+
+    /// Log every write to an append only file and load it at startup.
+    #[arg(long, default_value = "no", value_parser = yes_no, action = clap::ArgAction::Set)]
+    appendonly: bool,
+
+    #[arg(long, default_value = "appendonly.aof")]
+    appendfilename: String,
+
+    /// Directory, under --dir, holding the AOF files and their manifest.
+    #[arg(long, default_value = "appendonlydir")]
+    appenddirname: String,
+
+    /// Write AOF base files as RDB rather than as commands.
+    #[arg(
+        long = "aof-use-rdb-preamble",
+        default_value = "yes",
+        value_parser = yes_no,
+        action = clap::ArgAction::Set
+    )]
+    aof_use_rdb_preamble: bool,
+
+    /// When the AOF is flushed to disk: always, everysec or no.
+    #[arg(long, default_value = "everysec", value_parser = aof::parse_fsync)]
+    appendfsync: aof::AppendFsync,
+
+    /// Load an AOF whose last command was cut short, dropping that command.
+    #[arg(
+        long = "aof-load-truncated",
+        default_value = "yes",
+        value_parser = yes_no,
+        action = clap::ArgAction::Set
+    )]
+    aof_load_truncated: bool,
+
+    /// What to do when the RDB file can't be read: refuse to start, or
+    /// start without its data.
+    #[arg(long = "rdb-load-error", value_enum, default_value_t = RdbLoadError::Refuse)]
+    rdb_load_error: RdbLoadError,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum RdbLoadError {
+    Refuse,
+    Empty,
+}
+
+fn yes_no(value: &str) -> Result<bool, String> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("expected yes or no".to_string()),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let config = Config::new(args.addr, args.rdb_dir, args.rdb_file);
+    let notify_flags = notify::parse_flags(&args.notify_keyspace_events)?;
+    let save_rules = persistence::parse_save_rules(&args.save)?;
+    let databases = args.databases as usize;
+    let dir = Path::new(args.rdb_dir.as_deref().unwrap_or("."));
+    let legacy_aof = dir.join(&args.appendfilename);
+    let aof_dir = aof::AofDir {
+        dir: dir.join(&args.appenddirname),
+        filename: args.appendfilename.clone(),
+    };
+    let config = Config::new(args.addr, args.rdb_dir, args.rdb_file, databases);
 
-    // Read data from RDB file into a HASHMAP
-    // then add it to state store
-    let rdb_kv_data = read_rdb_file(&config);
-    println!("{:?}", rdb_kv_data);
+    let pubsub = Arc::new(PubSub::new());
+    let databases = Databases::new(databases, BTreeMap::new(), pubsub.clone());
+    databases.set_notify_flags(notify_flags);
+    let server = Arc::new(Server::new(databases, pubsub));
+    server.persistence.set_save_rules(save_rules);
+    server.persistence.set_rdb_compression(args.rdbcompression);
 
+    // Clients can connect while the dataset loads, and are answered with
+    // -LOADING until it has.
+    server.persistence.start_loading();
     let listener = TcpListener::bind(config.get_addr_string()).await?;
-    let store = Arc::new(Store::new(rdb_kv_data));
+    let accepting = tokio::spawn(accept_clients(listener, server.clone(), config.clone()));
+
+    // With appendonly on, the AOF holds the dataset if there is one;
+    // otherwise the RDB file does.
+    let mut loaded_aof = match args.appendonly {
+        true => aof::read(&aof_dir, &legacy_aof)?,
+        false => None,
+    };
+    let functions = match loaded_aof.as_mut() {
+        Some(loaded) => {
+            let base = std::mem::take(&mut loaded.base);
+            let loader = server.clone();
+            tokio::task::spawn_blocking(move || persistence::load_contents(&loader, base)).await??
+        }
+        None => load_rdb(&server, &config, args.rdb_load_error).await?,
+    };
+    for code in functions.iter() {
+        server.functions.load(code, false)?;
+    }
+    if let Some(loaded) = loaded_aof {
+        let count = aof::replay(&loaded, &server, &config, args.aof_load_truncated).await?;
+        println!("[!] Replayed {} commands from the AOF", count);
+    }
+    if args.appendonly {
+        let dataset = persistence::snapshot(&server).await;
+        server.aof.open(
+            aof_dir,
+            &legacy_aof,
+            args.appendfsync,
+            args.aof_use_rdb_preamble,
+            server.persistence.rdb_compression(),
+            &dataset,
+        )?;
+        tokio::spawn(aof::run_everysec_fsync(server.clone()));
+    }
+    server.persistence.finish_loading();
+    tokio::spawn(persistence::run_save_rules(server.clone(), config.clone()));
+
+    accepting.await??;
+    Ok(())
+}
+
+/// Loads the RDB file, if there is one, into `server`, or leaves it empty
+/// when the file can't be read and `on_error` allows it. Returns the
+/// function libraries of the file.
+async fn load_rdb(
+    server: &Arc<Server>,
+    config: &Config,
+    on_error: RdbLoadError,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let Some(path) = config.get_rdb_path() else {
+        return Ok(Vec::new());
+    };
+    let loader = server.clone();
+    let loaded = tokio::task::spawn_blocking(move || persistence::load_rdb(&loader, &path)).await?;
+    match loaded {
+        Ok(Some(functions)) => Ok(functions),
+        Ok(None) => {
+            println!("Couldn't find RDB file so skipping reading RDB file content into state");
+            Ok(Vec::new())
+        }
+        Err(e) if on_error == RdbLoadError::Empty => {
+            eprintln!(
+                "!!! Warning: starting empty, can't read the RDB file: {}. Saving will overwrite it.",
+                e
+            );
+            // Keys read before the error go too.
+            server.databases.flush_all(false).await;
+            Ok(Vec::new())
+        }
+        Err(e) => Err(format!(
+            "Can't read the RDB file: {}. Fix it or start with --rdb-load-error empty",
+            e
+        )
+        .into()),
+    }
+}
 
+async fn accept_clients(
+    listener: TcpListener,
+    server: Arc<Server>,
+    config: Config,
+) -> std::io::Result<()> {
     loop {
         let (tcp_stream, _) = listener.accept().await?;
-        let store_clone = store.clone();
+        let server_clone = server.clone();
         let config_clone = config.clone();
         println!("[*] Accepted new client.");
 
         tokio::spawn(async move {
-            if let Err(e) = handle_stream(tcp_stream, store_clone, config_clone).await {
+            if let Err(e) = handle_stream(tcp_stream, server_clone, config_clone).await {
                 eprintln!("Error during handling stream: {}", e);
             }
         });
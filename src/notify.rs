@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::pubsub::PubSub;
+use crate::Error;
+
+// Event classes, as in Redis' notify-keyspace-events.
+pub const NOTIFY_KEYSPACE: u32 = 1 << 0; // K
+pub const NOTIFY_KEYEVENT: u32 = 1 << 1; // E
+pub const NOTIFY_GENERIC: u32 = 1 << 2; // g
+pub const NOTIFY_STRING: u32 = 1 << 3; // $
+pub const NOTIFY_LIST: u32 = 1 << 4; // l
+pub const NOTIFY_SET: u32 = 1 << 5; // s
+pub const NOTIFY_HASH: u32 = 1 << 6; // h
+pub const NOTIFY_ZSET: u32 = 1 << 7; // z
+pub const NOTIFY_EXPIRED: u32 = 1 << 8; // x
+pub const NOTIFY_EVICTED: u32 = 1 << 9; // e
+pub const NOTIFY_STREAM: u32 = 1 << 10; // t
+pub const NOTIFY_KEY_MISS: u32 = 1 << 11; // m
+pub const NOTIFY_NEW: u32 = 1 << 12; // n
+
+/// Everything `A` stands for; key misses and new keys have to be asked for
+/// explicitly.
+pub const NOTIFY_ALL: u32 = NOTIFY_GENERIC
+    | NOTIFY_STRING
+    | NOTIFY_LIST
+    | NOTIFY_SET
+    | NOTIFY_HASH
+    | NOTIFY_ZSET
+    | NOTIFY_EXPIRED
+    | NOTIFY_EVICTED
+    | NOTIFY_STREAM;
+
+const CLASS_CHARS: [(char, u32); 9] = [
+    ('g', NOTIFY_GENERIC),
+    ('$', NOTIFY_STRING),
+    ('l', NOTIFY_LIST),
+    ('s', NOTIFY_SET),
+    ('h', NOTIFY_HASH),
+    ('z', NOTIFY_ZSET),
+    ('x', NOTIFY_EXPIRED),
+    ('e', NOTIFY_EVICTED),
+    ('t', NOTIFY_STREAM),
+];
+
+/// Parses a notify-keyspace-events string such as `"KEA"` or `"Kx"`.
+pub fn parse_flags(classes: &str) -> Result<u32, Error> {
+    classes.chars().try_fold(0, |flags, c| {
+        let class = match c {
+            'A' => NOTIFY_ALL,
+            'K' => NOTIFY_KEYSPACE,
+            'E' => NOTIFY_KEYEVENT,
+            'm' => NOTIFY_KEY_MISS,
+            'n' => NOTIFY_NEW,
+            _ => CLASS_CHARS
+                .iter()
+                .find(|(class_char, _)| *class_char == c)
+                .map(|(_, class)| *class)
+                .ok_or(Error::InvalidCommand(
+                    "Invalid event class character. Use 'Ag$lshzxeKEtmn'.",
+                ))?,
+        };
+        Ok(flags | class)
+    })
+}
+
+/// The canonical string for `flags`, as CONFIG GET reports it.
+pub fn flags_to_string(flags: u32) -> String {
+    let mut classes = String::new();
+    if flags & NOTIFY_ALL == NOTIFY_ALL {
+        classes.push('A');
+    } else {
+        for (c, class) in CLASS_CHARS {
+            if flags & class != 0 {
+                classes.push(c);
+            }
+        }
+    }
+    for (c, class) in [
+        ('K', NOTIFY_KEYSPACE),
+        ('E', NOTIFY_KEYEVENT),
+        ('m', NOTIFY_KEY_MISS),
+        ('n', NOTIFY_NEW),
+    ] {
+        if flags & class != 0 {
+            classes.push(c);
+        }
+    }
+    classes
+}
+
+/// Publishes keyspace events of a database to Pub/Sub, filtered by the
+/// configured classes.
+#[derive(Debug, Default)]
+pub struct KeyspaceNotifier {
+    pubsub: Option<Arc<PubSub>>,
+    flags: AtomicU32,
+    db: usize,
+}
+
+impl KeyspaceNotifier {
+    pub fn new(pubsub: Arc<PubSub>, db: usize) -> Self {
+        Self {
+            pubsub: Some(pubsub),
+            flags: AtomicU32::new(0),
+            db,
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags.load(Ordering::Relaxed)
+    }
+
+    pub fn set_flags(&self, flags: u32) {
+        self.flags.store(flags, Ordering::Relaxed);
+    }
+
+    /// Sends `__keyspace@<db>__:<key>` with the event name and
+    /// `__keyevent@<db>__:<event>` with the key, if `class` is enabled.
+    pub fn notify(&self, class: u32, event: &str, key: &str) {
+        let flags = self.flags();
+        let Some(pubsub) = self.pubsub.as_ref() else {
+            return;
+        };
+        if flags & class == 0 {
+            return;
+        }
+
+        if flags & NOTIFY_KEYSPACE != 0 {
+            pubsub.publish(&format!("__keyspace@{}__:{}", self.db, key), event);
+        }
+        if flags & NOTIFY_KEYEVENT != 0 {
+            pubsub.publish(&format!("__keyevent@{}__:{}", self.db, event), key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(parse_flags("").unwrap(), 0);
+        assert_eq!(parse_flags("Kx").unwrap(), NOTIFY_KEYSPACE | NOTIFY_EXPIRED);
+        assert_eq!(flags_to_string(parse_flags("KEA").unwrap()), "AKE");
+        assert_eq!(flags_to_string(parse_flags("Eg$lshzxet").unwrap()), "AE");
+        assert_eq!(flags_to_string(parse_flags("nmzK").unwrap()), "zKmn");
+        assert!(parse_flags("Kq").is_err());
+    }
+}
@@ -0,0 +1,506 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::rdb::{self, RdbContents, RdbError, RdbMetadata, RdbReader};
+use crate::server::Server;
+use crate::store::{DatabasesFork, Entry};
+use crate::{Error, Value};
+
+/// How often the `save` rules are checked.
+const SAVE_RULES_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Seconds to wait before retrying a failed scheduled save.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+/// Save rules Redis starts with.
+pub const DEFAULT_SAVE_RULES: &str = "3600 1 300 100 60 10000";
+
+/// A `save <seconds> <changes>` rule: save once at least `changes` writes
+/// happened and more than `seconds` passed since the last save.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Parses the `save` config: pairs of seconds and changes, or an empty
+/// string for no automatic saves.
+pub fn parse_save_rules(rules: &str) -> Result<Vec<SaveRule>, Error> {
+    const INVALID: Error = Error::InvalidCommand("Invalid save parameters");
+
+    let numbers = rules
+        .split_whitespace()
+        .map(|n| n.parse::<u64>().map_err(|_| INVALID))
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() % 2 != 0 {
+        return Err(INVALID);
+    }
+    Ok(numbers
+        .chunks(2)
+        .map(|pair| SaveRule {
+            seconds: pair[0],
+            changes: pair[1],
+        })
+        .collect())
+}
+
+/// The `save` config as CONFIG GET reports it.
+pub fn save_rules_to_string(rules: &[SaveRule]) -> String {
+    rules
+        .iter()
+        .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// State of RDB snapshotting: the `save` rules, when the dataset was last
+/// saved and whether a background save is running, and the progress of
+/// loading the dataset at startup.
+#[derive(Debug)]
+pub struct Persistence {
+    save_rules: Mutex<Vec<SaveRule>>,
+    /// Unix time of the last successful save, or of startup.
+    lastsave: AtomicU64,
+    /// Unix time the last background save was started.
+    last_bgsave_try: AtomicU64,
+    bgsave_in_progress: AtomicBool,
+    last_bgsave_ok: AtomicBool,
+    /// Whether long strings are LZF compressed, see `rdbcompression`.
+    rdb_compression: AtomicBool,
+    loading: AtomicBool,
+    /// Unix time loading started.
+    loading_start_time: AtomicU64,
+    loading_total_bytes: AtomicU64,
+    loading_loaded_bytes: AtomicU64,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self {
+            save_rules: Mutex::new(Vec::new()),
+            lastsave: AtomicU64::new(unix_time()),
+            last_bgsave_try: AtomicU64::new(0),
+            bgsave_in_progress: AtomicBool::new(false),
+            last_bgsave_ok: AtomicBool::new(true),
+            rdb_compression: AtomicBool::new(true),
+            loading: AtomicBool::new(false),
+            loading_start_time: AtomicU64::new(0),
+            loading_total_bytes: AtomicU64::new(0),
+            loading_loaded_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Persistence {
+    pub fn save_rules(&self) -> Vec<SaveRule> {
+        self.save_rules.lock().unwrap().clone()
+    }
+
+    pub fn set_save_rules(&self, rules: Vec<SaveRule>) {
+        *self.save_rules.lock().unwrap() = rules;
+    }
+
+    pub fn rdb_compression(&self) -> bool {
+        self.rdb_compression.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rdb_compression(&self, compression: bool) {
+        self.rdb_compression.store(compression, Ordering::Relaxed);
+    }
+
+    /// Whether a `save` rule is met with `dirty` unsaved changes.
+    fn should_save(&self, dirty: u64) -> bool {
+        if self.is_bgsave_in_progress() {
+            return false;
+        }
+        let now = unix_time();
+        // A failing save is only retried every few seconds.
+        if !self.last_bgsave_ok()
+            && now.saturating_sub(self.last_bgsave_try.load(Ordering::Relaxed))
+                <= BGSAVE_RETRY_DELAY
+        {
+            return false;
+        }
+        let since_save = now.saturating_sub(self.lastsave());
+        self.save_rules
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|rule| dirty >= rule.changes && since_save > rule.seconds)
+    }
+
+    pub fn lastsave(&self) -> u64 {
+        self.lastsave.load(Ordering::Relaxed)
+    }
+
+    pub fn is_bgsave_in_progress(&self) -> bool {
+        self.bgsave_in_progress.load(Ordering::Relaxed)
+    }
+
+    pub fn last_bgsave_ok(&self) -> bool {
+        self.last_bgsave_ok.load(Ordering::Relaxed)
+    }
+
+    fn saved(&self) {
+        self.lastsave.store(unix_time(), Ordering::Relaxed);
+    }
+
+    /// Marks the dataset as loading, which clients are answered with
+    /// -LOADING for until `finish_loading`.
+    pub fn start_loading(&self) {
+        self.loading_start_time
+            .store(unix_time(), Ordering::Relaxed);
+        self.loading_total_bytes.store(0, Ordering::Relaxed);
+        self.loading_loaded_bytes.store(0, Ordering::Relaxed);
+        self.loading.store(true, Ordering::Release);
+    }
+
+    pub fn finish_loading(&self) {
+        self.loading.store(false, Ordering::Release);
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.loading.load(Ordering::Acquire)
+    }
+
+    /// Adds the size of a file about to be loaded to the bytes to load.
+    pub fn add_loading_total(&self, bytes: u64) {
+        self.loading_total_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_loaded(&self, bytes: u64) {
+        self.loading_loaded_bytes
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn loading_start_time(&self) -> u64 {
+        self.loading_start_time.load(Ordering::Relaxed)
+    }
+
+    pub fn loading_total_bytes(&self) -> u64 {
+        self.loading_total_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn loading_loaded_bytes(&self) -> u64 {
+        self.loading_loaded_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Percentage of the bytes to load loaded so far.
+    pub fn loading_loaded_perc(&self) -> f64 {
+        match self.loading_total_bytes() {
+            0 => 0.0,
+            total => (self.loading_loaded_bytes() as f64 * 100.0 / total as f64).min(100.0),
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The dataset at one point, to be turned into `RdbContents` for saving
+/// off the async runtime.
+#[derive(Debug)]
+pub struct DatasetFork {
+    databases: DatabasesFork,
+    functions: Vec<String>,
+    at: Instant,
+}
+
+impl DatasetFork {
+    pub fn contents(&self) -> RdbContents {
+        RdbContents {
+            databases: self.databases.live_keys(self.at),
+            functions: self.functions.clone(),
+        }
+    }
+}
+
+/// Forks the dataset for saving, without copying it. The caller holds the
+/// execution lock exclusively, so no command runs while it is taken.
+pub async fn fork(server: &Server) -> DatasetFork {
+    DatasetFork {
+        databases: server.databases.fork().await,
+        functions: server
+            .functions
+            .libraries()
+            .into_iter()
+            .map(|library| library.code)
+            .collect(),
+        at: Instant::now(),
+    }
+}
+
+/// Copies the dataset for saving, see `fork`.
+pub async fn snapshot(server: &Server) -> RdbContents {
+    fork(server).await.contents()
+}
+
+/// Counts the bytes read from a file being loaded into the loading progress.
+struct Progress<'a, R> {
+    inner: R,
+    persistence: &'a Persistence,
+}
+
+impl<R: Read> Read for Progress<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.persistence.add_loaded(read as u64);
+        Ok(read)
+    }
+}
+
+/// Loads the RDB file at `path` into the databases of `server`, each key
+/// stored as soon as it is read. Returns the function libraries for the
+/// caller to load, or `None` when there is no file. Blocks, so it runs
+/// outside the async runtime.
+pub fn load_rdb(server: &Server, path: &str) -> Result<Option<Vec<String>>, RdbError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(RdbError::new(0, e.to_string())),
+    };
+    let persistence = &server.persistence;
+    persistence.add_loading_total(file.metadata().map_or(0, |metadata| metadata.len()));
+    let mut reader = RdbReader::new(BufReader::new(Progress {
+        inner: file,
+        persistence,
+    }));
+    let mut metadata = RdbMetadata::default();
+    rdb::read_rdb(&mut reader, &mut metadata, |db, key, entry| {
+        load_key(server, db, key, entry)
+    })?;
+    Ok(Some(metadata.functions))
+}
+
+/// Loads `contents` already read into memory, such as the RDB preamble of
+/// an AOF, into the databases of `server`. Returns the function libraries
+/// for the caller to load. Blocks like `load_rdb`.
+pub fn load_contents(server: &Server, contents: RdbContents) -> Result<Vec<String>, String> {
+    for (db, entries) in contents.databases {
+        for (key, entry) in entries {
+            load_key(server, db, key, entry)?;
+        }
+    }
+    Ok(contents.functions)
+}
+
+fn load_key(server: &Server, db: usize, key: String, entry: Entry) -> Result<(), String> {
+    let store = server.databases.get(db).ok_or_else(|| {
+        format!(
+            "Database {} is loaded but the server is configured with {} databases",
+            db,
+            server.databases.count()
+        )
+    })?;
+    Arc::make_mut(&mut store.blocking_write()).insert(key, entry);
+    Ok(())
+}
+
+fn rdb_path(config: &Config) -> Result<String, Error> {
+    config.get_rdb_path().ok_or(Error::InvalidCommand(
+        "No RDB file configured, start the server with --dir and --dbfilename",
+    ))
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over
+/// `path`, so a failed save never leaves a truncated RDB file behind.
+fn write_file(path: &str, contents: &RdbContents, compression: bool) -> io::Result<()> {
+    let temp = Path::new(path).with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let result = File::create(&temp).and_then(|mut file| {
+        file.write_all(&rdb::write_rdb(contents, compression))?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// SAVE: writes the RDB file before replying, blocking every client.
+pub async fn save(server: &Server, config: &Config) -> Result<Value, Error> {
+    let path = rdb_path(config)?;
+    if server.persistence.is_bgsave_in_progress() {
+        return Err(Error::InvalidCommand("Background save already in progress"));
+    }
+
+    let dirty = server.databases.dirty();
+    let contents = snapshot(server).await;
+    let compression = server.persistence.rdb_compression();
+    if let Err(e) = write_file(&path, &contents, compression) {
+        eprintln!("Failed saving the DB to {}: {}", path, e);
+        return Err(Error::InvalidCommand(
+            "Error saving the DB, see the server logs",
+        ));
+    }
+    server.databases.saved_dirty(dirty);
+    server.persistence.saved();
+    Ok(Value::SimpleString("OK".to_string()))
+}
+
+/// BGSAVE: forks the dataset and writes it out in the background, so
+/// clients only wait for the fork.
+pub async fn bgsave(
+    request_content: &[Value],
+    server: &Arc<Server>,
+    config: &Config,
+) -> Result<Value, Error> {
+    if request_content.len() > 1 {
+        return Err(Error::InvalidCommand("syntax error"));
+    }
+    start_bgsave(server, config).await?;
+    Ok(Value::SimpleString("Background saving started".to_string()))
+}
+
+/// Starts a background save. The caller holds the execution lock
+/// exclusively while the dataset is forked.
+async fn start_bgsave(server: &Arc<Server>, config: &Config) -> Result<(), Error> {
+    let path = rdb_path(config)?;
+    let persistence = &server.persistence;
+    if persistence
+        .bgsave_in_progress
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return Err(Error::InvalidCommand("Background save already in progress"));
+    }
+    persistence
+        .last_bgsave_try
+        .store(unix_time(), Ordering::Relaxed);
+
+    let dirty = server.databases.dirty();
+    let dataset = fork(server).await;
+    let compression = persistence.rdb_compression();
+    let server = server.clone();
+    tokio::task::spawn_blocking(move || {
+        let result = write_file(&path, &dataset.contents(), compression);
+        let persistence = &server.persistence;
+        match &result {
+            Ok(()) => {
+                server.databases.saved_dirty(dirty);
+                persistence.saved();
+            }
+            Err(e) => eprintln!("Background save to {} failed: {}", path, e),
+        }
+        persistence
+            .last_bgsave_ok
+            .store(result.is_ok(), Ordering::Relaxed);
+        persistence
+            .bgsave_in_progress
+            .store(false, Ordering::Release);
+    });
+    Ok(())
+}
+
+/// Starts background saves whenever a `save` rule is met, for as long as
+/// the server runs. Does nothing without an RDB file to save to.
+pub async fn run_save_rules(server: Arc<Server>, config: Config) {
+    if config.get_rdb_path().is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(SAVE_RULES_INTERVAL);
+    loop {
+        interval.tick().await;
+        if !server.persistence.should_save(server.databases.dirty()) {
+            continue;
+        }
+        let _guard = server.databases.lock_exclusive().await;
+        if let Err(e) = start_bgsave(&server, &config).await {
+            eprintln!("Scheduled background save failed to start: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::EntryValue;
+
+    #[test]
+    fn test_save_rules() {
+        let rules = parse_save_rules(DEFAULT_SAVE_RULES).unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(
+            rules[1],
+            SaveRule {
+                seconds: 300,
+                changes: 100
+            }
+        );
+        assert_eq!(save_rules_to_string(&rules), DEFAULT_SAVE_RULES);
+        assert_eq!(parse_save_rules("").unwrap(), []);
+        assert!(parse_save_rules("3600").is_err());
+        assert!(parse_save_rules("3600 -1").is_err());
+
+        let persistence = Persistence::default();
+        persistence.set_save_rules(parse_save_rules("0 2").unwrap());
+        persistence
+            .lastsave
+            .store(unix_time() - 1, Ordering::Relaxed);
+        assert!(!persistence.should_save(1));
+        assert!(persistence.should_save(2));
+
+        persistence
+            .bgsave_in_progress
+            .store(true, Ordering::Relaxed);
+        assert!(!persistence.should_save(2));
+        persistence
+            .bgsave_in_progress
+            .store(false, Ordering::Relaxed);
+
+        // Failed saves wait before being retried.
+        persistence.last_bgsave_ok.store(false, Ordering::Relaxed);
+        persistence
+            .last_bgsave_try
+            .store(unix_time(), Ordering::Relaxed);
+        assert!(!persistence.should_save(2));
+    }
+
+    #[test]
+    fn test_fork() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let pubsub = Arc::new(crate::pubsub::PubSub::new());
+            let databases = crate::store::Databases::new(2, Default::default(), pubsub.clone());
+            let server = Server::new(databases, pubsub);
+            let now = Instant::now();
+            let db = server.databases.get(0).unwrap().clone();
+            db.insert("a".to_string(), "1".to_string(), None, None)
+                .await;
+            db.write_sorted_set("z", now, |zset| ((), zset.insert("m".to_string(), 1.0)))
+                .await
+                .unwrap();
+
+            // Writes after the fork, in place or replacing whole keyspaces,
+            // don't show in it.
+            let dataset = fork(&server).await;
+            db.insert("a".to_string(), "2".to_string(), None, None)
+                .await;
+            db.write_sorted_set("z", now, |zset| {
+                zset.insert("m".to_string(), 2.0);
+                ((), true)
+            })
+            .await
+            .unwrap();
+            server.databases.swap(0, 1).await;
+            server.databases.flush_all(false).await;
+
+            let contents = dataset.contents();
+            assert_eq!(contents.databases.len(), 1);
+            let keys = &contents.databases[&0];
+            assert!(matches!(keys["a"].get_value(), EntryValue::String(value) if value == "1"));
+            let EntryValue::SortedSet(zset) = keys["z"].get_value() else {
+                panic!("not a sorted set");
+            };
+            assert_eq!(zset.score("m"), Some(1.0));
+        })
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::client::{ClientHandle, ClientId};
+use crate::cluster::key_hash_slot;
+use crate::glob::glob_match;
+use crate::{arg_str, Error, Value};
+
+/// What a client can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionKind {
+    Channel,
+    /// A glob pattern matched against the channel of every message.
+    Pattern,
+    /// A channel bound to the hash slot of its name, as in Redis Cluster.
+    ShardChannel,
+}
+
+impl SubscriptionKind {
+    pub fn subscribe_reply(&self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "subscribe",
+            SubscriptionKind::Pattern => "psubscribe",
+            SubscriptionKind::ShardChannel => "ssubscribe",
+        }
+    }
+
+    pub fn unsubscribe_reply(&self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "unsubscribe",
+            SubscriptionKind::Pattern => "punsubscribe",
+            SubscriptionKind::ShardChannel => "sunsubscribe",
+        }
+    }
+}
+
+type Subscribers = HashMap<String, HashMap<ClientId, ClientHandle>>;
+
+fn add_subscriber(subscribers: &mut Subscribers, name: &str, client: &ClientHandle) {
+    subscribers
+        .entry(name.to_string())
+        .or_default()
+        .insert(client.id(), client.clone());
+}
+
+fn remove_subscriber(subscribers: &mut Subscribers, name: &str, id: ClientId) {
+    if let Some(clients) = subscribers.get_mut(name) {
+        clients.remove(&id);
+        if clients.is_empty() {
+            subscribers.remove(name);
+        }
+    }
+}
+
+/// Registry of channel, pattern and shard channel subscriptions shared by
+/// all connections.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    channels: RwLock<Subscribers>,
+    /// Keyed by pattern, so each pattern is matched once per message no
+    /// matter how many clients use it.
+    patterns: RwLock<Subscribers>,
+    /// Shard channels grouped by hash slot, kept apart from global channels.
+    shard_channels: RwLock<HashMap<u16, Subscribers>>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&self, kind: SubscriptionKind, name: &str, client: &ClientHandle) {
+        match kind {
+            SubscriptionKind::Channel => {
+                add_subscriber(&mut self.channels.write().unwrap(), name, client)
+            }
+            SubscriptionKind::Pattern => {
+                add_subscriber(&mut self.patterns.write().unwrap(), name, client)
+            }
+            SubscriptionKind::ShardChannel => {
+                let mut slots = self.shard_channels.write().unwrap();
+                add_subscriber(slots.entry(key_hash_slot(name)).or_default(), name, client);
+            }
+        }
+    }
+
+    pub fn unsubscribe(&self, kind: SubscriptionKind, name: &str, id: ClientId) {
+        match kind {
+            SubscriptionKind::Channel => {
+                remove_subscriber(&mut self.channels.write().unwrap(), name, id)
+            }
+            SubscriptionKind::Pattern => {
+                remove_subscriber(&mut self.patterns.write().unwrap(), name, id)
+            }
+            SubscriptionKind::ShardChannel => {
+                let slot = key_hash_slot(name);
+                let mut slots = self.shard_channels.write().unwrap();
+                if let Some(subscribers) = slots.get_mut(&slot) {
+                    remove_subscriber(subscribers, name, id);
+                    if subscribers.is_empty() {
+                        slots.remove(&slot);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Queues `message` for every subscriber of `channel` and every matching
+    /// pattern subscriber, returning how many deliveries were made. Never
+    /// waits on a subscriber.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let bulk = |s: &str| Value::BulkString(Some(s.to_string()));
+        let mut receivers = 0;
+
+        if let Some(clients) = self.channels.read().unwrap().get(channel) {
+            let frame = Value::Push(vec![bulk("message"), bulk(channel), bulk(message)]);
+            for client in clients.values() {
+                client.push(frame.clone());
+            }
+            receivers += clients.len();
+        }
+
+        let patterns = self.patterns.read().unwrap();
+        for (pattern, clients) in patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let frame = Value::Push(vec![
+                bulk("pmessage"),
+                bulk(pattern),
+                bulk(channel),
+                bulk(message),
+            ]);
+            for client in clients.values() {
+                client.push(frame.clone());
+            }
+            receivers += clients.len();
+        }
+
+        receivers
+    }
+
+    /// Channels with at least one subscriber, optionally filtered by a glob.
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        let channels = self.channels.read().unwrap();
+        channels
+            .keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn num_subscribers(&self, channel: &str) -> usize {
+        let channels = self.channels.read().unwrap();
+        channels.get(channel).map_or(0, |clients| clients.len())
+    }
+
+    /// Queues `message` for the subscribers of shard channel `channel`,
+    /// returning how many there were.
+    pub fn spublish(&self, channel: &str, message: &str) -> usize {
+        let slots = self.shard_channels.read().unwrap();
+        let Some(clients) = slots
+            .get(&key_hash_slot(channel))
+            .and_then(|subscribers| subscribers.get(channel))
+        else {
+            return 0;
+        };
+
+        let frame = Value::Push(vec![
+            Value::BulkString(Some("smessage".to_string())),
+            Value::BulkString(Some(channel.to_string())),
+            Value::BulkString(Some(message.to_string())),
+        ]);
+        for client in clients.values() {
+            client.push(frame.clone());
+        }
+        clients.len()
+    }
+
+    /// Shard channels with at least one subscriber, optionally filtered by
+    /// a glob.
+    pub fn shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        let slots = self.shard_channels.read().unwrap();
+        slots
+            .values()
+            .flat_map(|subscribers| subscribers.keys())
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn num_shard_subscribers(&self, channel: &str) -> usize {
+        let slots = self.shard_channels.read().unwrap();
+        slots
+            .get(&key_hash_slot(channel))
+            .and_then(|subscribers| subscribers.get(channel))
+            .map_or(0, |clients| clients.len())
+    }
+
+    /// Number of distinct patterns subscribed to by any client.
+    pub fn num_patterns(&self) -> usize {
+        self.patterns.read().unwrap().len()
+    }
+}
+
+/// PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT
+///   | SHARDCHANNELS [pattern] | SHARDNUMSUB [shardchannel ...]
+pub fn pubsub(request_content: &[Value], pubsub: &PubSub) -> Result<Value, Error> {
+    let subcommand = arg_str(&request_content[1])?.to_lowercase();
+    let args = &request_content[2..];
+
+    let list = |channels: Vec<String>| {
+        Value::Array(
+            channels
+                .into_iter()
+                .map(|channel| Value::BulkString(Some(channel)))
+                .collect(),
+        )
+    };
+    let counts = |count: &dyn Fn(&str) -> usize| {
+        let pairs = args
+            .iter()
+            .map(|channel| {
+                let channel = arg_str(channel)?;
+                Ok((
+                    Value::BulkString(Some(channel.to_string())),
+                    Value::Integer(count(channel) as i64),
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Value::Map(pairs))
+    };
+    let pattern = || args.first().map(arg_str).transpose();
+
+    match subcommand.as_str() {
+        "channels" if args.len() <= 1 => Ok(list(pubsub.channels(pattern()?))),
+        "numsub" => counts(&|channel| pubsub.num_subscribers(channel)),
+        "numpat" if args.is_empty() => Ok(Value::Integer(pubsub.num_patterns() as i64)),
+        "shardchannels" if args.len() <= 1 => Ok(list(pubsub.shard_channels(pattern()?))),
+        "shardnumsub" => counts(&|channel| pubsub.num_shard_subscribers(channel)),
+        _ => Err(Error::InvalidCommand(
+            "unknown subcommand or wrong number of arguments for 'pubsub' command",
+        )),
+    }
+}
@@ -1,8 +1,19 @@
-use std::fs;
-use std::time::{Duration, Instant};
-use std::{collections::HashMap, time::UNIX_EPOCH};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::UNIX_EPOCH,
+};
 
-use crate::{config::Config, store::Entry, Error};
+use crate::{
+    config::Config,
+    lzf,
+    sorted_set::SortedSet,
+    store::{Entry, EntryValue},
+    stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId},
+    Error, REDIS_VERSION,
+};
 
 const EOF: u8 = 0xFF;
 const SELECT_DB: u8 = 0xFE;
@@ -10,6 +21,15 @@ const EXPIRE_TIME: u8 = 0xFD;
 const EXPIRE_TIME_MS: u8 = 0xFC;
 const RESIZE_DB: u8 = 0xFB;
 const AUXILLARY_FIELDS: u8 = 0xFA;
+const FREQ: u8 = 0xF9;
+const IDLE: u8 = 0xF8;
+const MODULE_AUX: u8 = 0xF7;
+/// Function libraries in the format of Redis 7.0 release candidates.
+const FUNCTION_PRE_GA: u8 = 0xF6;
+const FUNCTION: u8 = 0xF5;
+
+/// RDB format version written by this server, that of Redis 7.2.
+pub const RDB_VERSION: u16 = 11;
 
 #[derive(Debug, PartialEq)]
 enum LengthEncodingType {
@@ -23,354 +43,2122 @@ enum EncodingFormat {
     Compressed,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Value {
     String = 0,
     List = 1,
     Set = 2,
     SortedSet = 3,
     Hash = 4,
+    /// Sorted set with binary double scores.
+    SortedSet2 = 5,
     Zipmap = 9,
     Ziplist = 10,
     Intset = 11,
     SortedSetInZiplist = 12,
     HashmapInZiplist = 13,
+    /// Module value only its module can read.
+    Module = 6,
+    /// Module value in self-describing opcodes.
+    Module2 = 7,
     ListInQuicklist = 14,
+    HashInListpack = 16,
+    SortedSetInListpack = 17,
+    /// Quicklist of listpacks, or of single large elements.
+    ListInQuicklist2 = 18,
+    StreamListpacks = 15,
+    /// Streams with first and max deleted IDs and group read counters.
+    StreamListpacks2 = 19,
+    SetInListpack = 20,
+    /// Streams with consumer active times.
+    StreamListpacks3 = 21,
 }
 
 impl TryFrom<u8> for Value {
     type Error = Error;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        println!("---------- try_from : {value}");
         match value {
             0 => Ok(Value::String),
             1 => Ok(Value::List),
             2 => Ok(Value::Set),
             3 => Ok(Value::SortedSet),
             4 => Ok(Value::Hash),
+            5 => Ok(Value::SortedSet2),
+            6 => Ok(Value::Module),
+            7 => Ok(Value::Module2),
             9 => Ok(Value::Zipmap),
             10 => Ok(Value::Ziplist),
             11 => Ok(Value::Intset),
             12 => Ok(Value::SortedSetInZiplist),
             13 => Ok(Value::HashmapInZiplist),
             14 => Ok(Value::ListInQuicklist),
+            15 => Ok(Value::StreamListpacks),
+            16 => Ok(Value::HashInListpack),
+            17 => Ok(Value::SortedSetInListpack),
+            18 => Ok(Value::ListInQuicklist2),
+            19 => Ok(Value::StreamListpacks2),
+            20 => Ok(Value::SetInListpack),
+            21 => Ok(Value::StreamListpacks3),
             _ => Err(Error::InvalidCommand("Unrecognized value for Value type")),
         }
     }
 }
 
-pub fn read_rdb_file(config: &Config) -> Option<HashMap<String, Entry>> {
-    if let Some(file_path) = config.get_rdb_path() {
-        let file = fs::read(file_path);
+/// What an RDB file holds: the keyspace of each database and the function
+/// libraries.
+#[derive(Debug, Default)]
+pub struct RdbContents {
+    pub databases: BTreeMap<usize, HashMap<String, Entry>>,
+    /// Source code of each function library.
+    pub functions: Vec<String>,
+}
+
+/// What RDB data holds besides its keys.
+#[derive(Debug, Default)]
+pub struct RdbMetadata {
+    /// RDB format version of the file.
+    pub version: u16,
+    /// Auxiliary fields, such as the version of Redis that wrote the file.
+    pub aux: Vec<(String, String)>,
+    /// Source code of each function library.
+    pub functions: Vec<String>,
+}
+
+/// Why RDB data couldn't be read: what was wrong, the offset of the byte
+/// where it was found, and the opcode or value type of the record it is in.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("{reason} at offset {offset}{}", describe_opcode(*.opcode))]
+pub struct RdbError {
+    pub offset: u64,
+    pub opcode: Option<u8>,
+    pub reason: String,
+}
+
+fn describe_opcode(opcode: Option<u8>) -> String {
+    opcode
+        .map(|opcode| format!(" (opcode 0x{:02X})", opcode))
+        .unwrap_or_default()
+}
+
+impl RdbError {
+    pub fn new(offset: u64, reason: impl Into<String>) -> Self {
+        Self {
+            offset,
+            opcode: None,
+            reason: reason.into(),
+        }
+    }
+
+    /// Moves an error found decoding a string's contents, with an offset
+    /// into them, to the string at `start` in the file. As contents may be
+    /// compressed, offsets into them count from where the string starts.
+    fn inside(self, start: u64) -> Self {
+        Self {
+            offset: start + self.offset,
+            ..self
+        }
+    }
+
+    fn in_record(self, opcode: u8) -> Self {
+        Self {
+            opcode: self.opcode.or(Some(opcode)),
+            ..self
+        }
+    }
+}
+
+/// Reads RDB data from any byte source, counting the bytes read for error
+/// offsets and checksumming them for the trailer after EOF.
+pub struct RdbReader<R> {
+    inner: R,
+    offset: u64,
+    crc: u64,
+}
+
+impl<R: Read> RdbReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            offset: 0,
+            crc: 0,
+        }
+    }
+
+    /// Bytes read so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// CRC64 of the bytes read so far.
+    pub fn checksum(&self) -> u64 {
+        self.crc
+    }
+
+    /// An error at the byte `back` bytes before the current offset.
+    fn error_before(&self, back: u64, reason: impl Into<String>) -> RdbError {
+        RdbError::new(self.offset.saturating_sub(back), reason)
+    }
+
+    fn advance(&mut self, bytes: &[u8]) {
+        self.offset += bytes.len() as u64;
+        self.crc = crc64(self.crc, bytes);
+    }
+
+    fn io_error(&self, start: u64, e: io::Error) -> RdbError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => RdbError::new(start, "Unexpected end of file"),
+            _ => RdbError::new(start, e.to_string()),
+        }
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        let mut bytes = [0; N];
+        self.inner
+            .read_exact(&mut bytes)
+            .map_err(|e| self.io_error(self.offset, e))?;
+        self.advance(&bytes);
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    /// Reads `len` bytes. Memory grows with what is actually read, as a
+    /// corrupt length can be anything.
+    fn read_vec(&mut self, len: usize) -> Result<Vec<u8>, RdbError> {
+        let start = self.offset;
+        let mut bytes = Vec::new();
+        let result = (&mut self.inner).take(len as u64).read_to_end(&mut bytes);
+        self.advance(&bytes);
+        match result {
+            Err(e) => Err(self.io_error(self.offset, e)),
+            Ok(read) if read < len => Err(RdbError::new(start, "Unexpected end of file")),
+            Ok(_) => Ok(bytes),
+        }
+    }
 
-        match file {
-            Ok(file_content) => {
-                // println!("{:?}", file_content);
-                Some(rdb_parser(&file_content[..]))
+    fn read_length_encoding(&mut self) -> Result<LengthEncodingType, RdbError> {
+        let start = self.offset;
+        let first_byte = self.read_u8()?;
+        let length = match first_byte >> 6 {
+            0b00 => (first_byte & 0x3f) as usize,
+            0b01 => u16::from_be_bytes([first_byte & 0x3f, self.read_u8()?]) as usize,
+            0b10 => match first_byte {
+                0x80 => u32::from_be_bytes(self.read_array()?) as usize,
+                0x81 => u64::from_be_bytes(self.read_array()?) as usize,
+                _ => {
+                    return Err(RdbError::new(
+                        start,
+                        "Unexpected 32 or 64 bit length marker",
+                    ))
+                }
+            },
+            _ => {
+                let format = match first_byte & 0x3f {
+                    0 => EncodingFormat::Integer(1),
+                    1 => EncodingFormat::Integer(2),
+                    2 => EncodingFormat::Integer(4),
+                    3 => EncodingFormat::Compressed,
+                    _ => return Err(RdbError::new(start, "Unknown string encoding")),
+                };
+                return Ok(LengthEncodingType::Special(format));
             }
-            Err(_) => {
-                println!("Couldn't find RDB file so skipping reading RDB file content into state");
-                None
+        };
+        Ok(LengthEncodingType::Length(length))
+    }
+
+    /// Reads a length, refusing the special string encodings.
+    fn read_length(&mut self) -> Result<usize, RdbError> {
+        let start = self.offset;
+        match self.read_length_encoding()? {
+            LengthEncodingType::Length(len) => Ok(len),
+            _ => Err(RdbError::new(start, "Expected a length")),
+        }
+    }
+
+    /// Reads a string as raw bytes, for the binary blobs of compact
+    /// encodings.
+    fn read_bytes(&mut self) -> Result<Vec<u8>, RdbError> {
+        match self.read_length_encoding()? {
+            LengthEncodingType::Length(len) => self.read_vec(len),
+            LengthEncodingType::Special(EncodingFormat::Integer(n)) => {
+                Ok(signed_le(&self.read_vec(n)?).to_string().into_bytes())
+            }
+            LengthEncodingType::Special(EncodingFormat::Compressed) => {
+                let compressed_len = self.read_length()?;
+                let len = self.read_length()?;
+                let start = self.offset;
+                let invalid = || RdbError::new(start, "Invalid LZF compressed string");
+                if len > compressed_len.saturating_mul(lzf::MAX_EXPANSION) {
+                    return Err(invalid());
+                }
+                lzf::decompress(&self.read_vec(compressed_len)?, len).ok_or_else(invalid)
             }
         }
-    } else {
-        None
-    }
+    }
+
+    fn read_string(&mut self) -> Result<String, RdbError> {
+        let start = self.offset;
+        utf8(self.read_bytes()?, start)
+    }
 }
 
-fn rdb_parser(data: &[u8]) -> HashMap<String, Entry> {
-    if &data[..5] != b"REDIS" {
-        panic!("Expected magic string (5 bytes) to have value 'REDIS'");
-    }
-    println!("[!] parsed MAGIC STRING: read 5 bytes");
-
-    let rdb_version = String::from_utf8_lossy(&data[5..9]);
-    println!("[!] RDB file version: {}, read 4 bytes", rdb_version);
+/// Reads the whole RDB file the config points at into memory. A missing
+/// file isn't an error, there is just nothing to read. The server loads its
+/// file with `persistence::load_rdb` instead, key by key.
+pub fn read_rdb_file(config: &Config) -> Result<Option<RdbContents>, RdbError> {
+    let Some(file_path) = config.get_rdb_path() else {
+        return Ok(None);
+    };
+    match File::open(file_path) {
+        Ok(file) => collect_rdb(&mut RdbReader::new(BufReader::new(file))).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(RdbError::new(0, e.to_string())),
+    }
+}
 
-    let mut data = &data[9..];
-    let mut hm: HashMap<String, Entry> = HashMap::new();
+/// Parses the RDB image at the start of `data`, returning its contents and
+/// its length including the checksum, for files that continue past it such
+/// as AOFs with an RDB preamble.
+pub fn parse_rdb_prefix(data: &[u8]) -> Result<(RdbContents, usize), RdbError> {
+    let mut reader = RdbReader::new(data);
+    let contents = collect_rdb(&mut reader)?;
+    Ok((contents, reader.offset() as usize))
+}
 
-    while !data.is_empty() {
-        match data[0] {
-            EOF => {
-                println!("[!] Reached EOF");
-                data = &data[data.len()..]; // exit while loop
-            }
-            SELECT_DB => {
-                println!("[!] Reached SELECT_DB");
-                // Skip two bytes: OP_CODE <DB_selector_value>
-                // Assuming DB_selector_value is only 1 byte.... should be atleast for our usecase
-                let db_selector = data[1];
-                println!(
-                    "---- DB selector value: {}, parsed bytes = 2 (OPCODE & DB_selector_value)",
-                    db_selector
-                );
-                data = &data[2..];
+fn collect_rdb<R: Read>(reader: &mut RdbReader<R>) -> Result<RdbContents, RdbError> {
+    let mut databases: BTreeMap<usize, HashMap<String, Entry>> = BTreeMap::new();
+    let mut metadata = RdbMetadata::default();
+    read_rdb(reader, &mut metadata, |db, key, entry| {
+        databases.entry(db).or_default().insert(key, entry);
+        Ok(())
+    })?;
+    Ok(RdbContents {
+        databases,
+        functions: metadata.functions,
+    })
+}
+
+/// Reads RDB data up to the end of its checksum: the magic string and
+/// version, then records until the EOF opcode. Each key is handed to
+/// `on_key` with its database as soon as it is read, and can be refused
+/// with the reason why. The rest goes to `metadata` as it is read, so what
+/// came before an error is kept.
+pub fn read_rdb<R: Read>(
+    reader: &mut RdbReader<R>,
+    metadata: &mut RdbMetadata,
+    mut on_key: impl FnMut(usize, String, Entry) -> Result<(), String>,
+) -> Result<(), RdbError> {
+    let header: [u8; 9] = reader.read_array()?;
+    if &header[..5] != b"REDIS" {
+        return Err(RdbError::new(0, "Wrong signature, not an RDB file"));
+    }
+    let version = std::str::from_utf8(&header[5..])
+        .ok()
+        .and_then(|version| version.parse::<u16>().ok())
+        .filter(|version| (1..=RDB_VERSION).contains(version))
+        .ok_or_else(|| {
+            RdbError::new(
+                5,
+                format!("Can't handle RDB format version {}", lossy(&header[5..])),
+            )
+        })?;
+    metadata.version = version;
+
+    // Keys before the first SELECT_DB belong to database 0.
+    let mut db = 0;
+    let mut expires_at = None;
+    loop {
+        let opcode = reader.read_u8()?;
+        if opcode == EOF {
+            break;
+        }
+        let state = (&mut *metadata, &mut db, &mut expires_at);
+        read_record(reader, opcode, state, &mut on_key).map_err(|e| e.in_record(opcode))?;
+    }
+
+    // Checksums were added in version 5. Writers with them turned off
+    // leave zeros.
+    if version >= 5 {
+        let computed = reader.checksum();
+        let offset = reader.offset();
+        let expected = u64::from_le_bytes(reader.read_array().map_err(|e| e.in_record(EOF))?);
+        if expected != 0 && expected != computed {
+            let reason = format!(
+                "Wrong RDB checksum, expected {:016x} but got {:016x}",
+                expected, computed
+            );
+            return Err(RdbError::new(offset, reason).in_record(EOF));
+        }
+    }
+    Ok(())
+}
+
+/// Where reading RDB data is at between records: the metadata so far, the
+/// selected database, and the expiry time of the next key.
+type RecordState<'a> = (
+    &'a mut RdbMetadata,
+    &'a mut usize,
+    &'a mut Option<SystemTime>,
+);
+
+/// Reads the record following `opcode`, which is either an opcode or the
+/// value type of a key. Expiry times apply to the key after them.
+fn read_record<R: Read>(
+    reader: &mut RdbReader<R>,
+    opcode: u8,
+    (metadata, db, expires_at): RecordState,
+    on_key: &mut impl FnMut(usize, String, Entry) -> Result<(), String>,
+) -> Result<(), RdbError> {
+    match opcode {
+        SELECT_DB => *db = reader.read_length()?,
+        RESIZE_DB => {
+            // Hash table sizes, only hints.
+            let _db_size = reader.read_length()?;
+            let _expires_size = reader.read_length()?;
+        }
+        AUXILLARY_FIELDS => {
+            let key = reader.read_string()?;
+            let value = reader.read_string()?;
+            metadata.aux.push((key, value));
+        }
+        FUNCTION => metadata.functions.push(reader.read_string()?),
+        FUNCTION_PRE_GA => {
+            return Err(
+                reader.error_before(1, "Functions of the pre-release format aren't supported")
+            )
+        }
+        MODULE_AUX => {
+            let module = skip_module_value(reader)?;
+            eprintln!(
+                "!!! Warning: skipping auxiliary data of module type {} which isn't loaded",
+                module
+            );
+        }
+        IDLE => {
+            let _idle = reader.read_length()?;
+        }
+        FREQ => {
+            let _freq = reader.read_u8()?;
+        }
+        EXPIRE_TIME => {
+            let secs = u32::from_le_bytes(reader.read_array()?);
+            *expires_at = Some(UNIX_EPOCH + Duration::from_secs(secs as u64));
+        }
+        EXPIRE_TIME_MS => {
+            let millis = u64::from_le_bytes(reader.read_array()?);
+            *expires_at = Some(UNIX_EPOCH + Duration::from_millis(millis));
+        }
+        value_type => {
+            let start = reader.offset() - 1;
+            let (key, value) = read_key_value(reader, value_type)?;
+            let expires_at = expires_at.take();
+            if let Some(value) = value {
+                let entry = Entry::new(value, None, expires_at, Instant::now());
+                on_key(*db, key, entry).map_err(|reason| RdbError::new(start, reason))?;
             }
-            RESIZE_DB => {
-                println!("[!] Reached RESIZE_DB");
-                // Jump over OP_CODE
-                // println!("-------------- DEBUG: current byte: {}", data[0]);
-                // println!("-------------- DEBUG: second byte: {}", data[1]);
-                // println!("-------------- DEBUG: thrid byte: {}", data[2]);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the key and value of a record of type `value_type`, as found after
+/// any expiry. Module values have no module here to load them, so they are
+/// skipped and come back as `None`.
+fn read_key_value<R: Read>(
+    reader: &mut RdbReader<R>,
+    value_type: u8,
+) -> Result<(String, Option<EntryValue>), RdbError> {
+    let value_type = Value::try_from(value_type)
+        .map_err(|_| reader.error_before(1, format!("Unknown value type {}", value_type)))?;
+    let key = reader.read_string()?;
 
-                // data = &data[1..];
+    if value_type == Value::Module2 {
+        let module = skip_module_value(reader)?;
+        eprintln!(
+            "!!! Warning: skipping key '{}', a value of module type {} which isn't loaded",
+            key, module
+        );
+        return Ok((key, None));
+    }
+    let value = read_value(reader, value_type)?;
+    Ok((key, Some(value)))
+}
+
+/// Reads a string and decodes its contents with `decode`, placing errors
+/// from it within the file.
+fn read_encoded<R: Read, T>(
+    reader: &mut RdbReader<R>,
+    decode: impl FnOnce(&[u8]) -> Result<T, RdbError>,
+) -> Result<T, RdbError> {
+    let start = reader.offset();
+    let bytes = reader.read_bytes()?;
+    decode(&bytes).map_err(|e| e.inside(start))
+}
+
+/// Reads a value of type `value_type`.
+fn read_value<R: Read>(
+    reader: &mut RdbReader<R>,
+    value_type: Value,
+) -> Result<EntryValue, RdbError> {
+    let value = match value_type {
+        Value::String => EntryValue::String(reader.read_string()?),
+        Value::List => EntryValue::List(
+            (0..reader.read_length()?)
+                .map(|_| reader.read_string())
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Set => EntryValue::Set(
+            (0..reader.read_length()?)
+                .map(|_| reader.read_string())
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::SortedSet | Value::SortedSet2 => {
+            let mut zset = SortedSet::new();
+            for _ in 0..reader.read_length()? {
+                let member = reader.read_string()?;
+                let score = match value_type {
+                    Value::SortedSet => read_string_score(reader)?,
+                    _ => f64::from_le_bytes(reader.read_array()?),
+                };
+                zset.insert(member, score);
+            }
+            EntryValue::SortedSet(zset)
+        }
+        Value::Hash => {
+            let mut hash = HashMap::new();
+            for _ in 0..reader.read_length()? {
+                let field = reader.read_string()?;
+                let value = reader.read_string()?;
+                hash.insert(field, value);
+            }
+            EntryValue::Hash(hash)
+        }
+        Value::Module => {
+            return Err(reader.error_before(
+                0,
+                "Module values of the pre-release format can't be skipped",
+            ))
+        }
+        // Files skip these, see `read_key_value`; anywhere else there is no
+        // module to hand the value to.
+        Value::Module2 => {
+            return Err(reader.error_before(0, "Module values can't be loaded without their module"))
+        }
+        Value::StreamListpacks | Value::StreamListpacks2 | Value::StreamListpacks3 => {
+            EntryValue::Stream(read_stream(reader, value_type)?)
+        }
+        Value::ListInQuicklist | Value::ListInQuicklist2 => {
+            let mut list = VecDeque::new();
+            for _ in 0..reader.read_length()? {
+                let container = match value_type {
+                    Value::ListInQuicklist2 => reader.read_length()?,
+                    _ => QUICKLIST_NODE_PACKED,
+                };
+                match (value_type == Value::ListInQuicklist, container) {
+                    (true, _) => list.extend(read_encoded(reader, ziplist_entries)?),
+                    (false, QUICKLIST_NODE_PLAIN) => list.push_back(reader.read_string()?),
+                    (false, QUICKLIST_NODE_PACKED) => {
+                        list.extend(read_encoded(reader, listpack_entries)?)
+                    }
+                    _ => return Err(reader.error_before(0, "Unknown quicklist node container")),
+                }
+            }
+            EntryValue::List(list)
+        }
+        Value::Zipmap => {
+            EntryValue::Hash(read_encoded(reader, zipmap_entries)?.into_iter().collect())
+        }
+        Value::Ziplist => EntryValue::List(read_encoded(reader, ziplist_entries)?.into()),
+        Value::Intset => {
+            EntryValue::Set(read_encoded(reader, intset_entries)?.into_iter().collect())
+        }
+        Value::SetInListpack => EntryValue::Set(
+            read_encoded(reader, listpack_entries)?
+                .into_iter()
+                .collect(),
+        ),
+        Value::HashmapInZiplist => {
+            EntryValue::Hash(read_encoded(reader, |blob| pairs(ziplist_entries(blob)?))?)
+        }
+        Value::HashInListpack => {
+            EntryValue::Hash(read_encoded(reader, |blob| pairs(listpack_entries(blob)?))?)
+        }
+        Value::SortedSetInZiplist => EntryValue::SortedSet(read_encoded(reader, |blob| {
+            scored(pairs(ziplist_entries(blob)?)?)
+        })?),
+        Value::SortedSetInListpack => EntryValue::SortedSet(read_encoded(reader, |blob| {
+            scored(pairs(listpack_entries(blob)?)?)
+        })?),
+    };
+    Ok(value)
+}
 
-                // let (hash_table_size, bytes_read) = parse_string(&data).unwrap();
-                // println!("===================> INFO: hash_table_size = {hash_table_size}");
-                // data = &data[bytes_read..];
+/// Skips a module value, returning the name and version of its module
+/// type: a 64-bit module type ID, then opcodes each followed by a value,
+/// up to an EOF opcode.
+fn skip_module_value<R: Read>(reader: &mut RdbReader<R>) -> Result<String, RdbError> {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
 
-                // let (_expire_hash_table_size, bytes_read) = parse_string(&data).unwrap();
-                // data = &data[bytes_read..];
-                data = &data[3..];
+    let id = reader.read_length()? as u64;
+    loop {
+        let start = reader.offset();
+        match reader.read_length()? {
+            MODULE_OPCODE_EOF => break,
+            MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                reader.read_length()?;
+            }
+            MODULE_OPCODE_FLOAT => {
+                reader.read_array::<4>()?;
             }
-            AUXILLARY_FIELDS => {
-                println!("[!] Reached AUXILLARY_FIELDS");
-                data = &data[1..];
+            MODULE_OPCODE_DOUBLE => {
+                reader.read_array::<8>()?;
+            }
+            MODULE_OPCODE_STRING => {
+                reader.read_bytes()?;
+            }
+            _ => return Err(RdbError::new(start, "Unknown module value opcode")),
+        }
+    }
+
+    // Nine characters of six bits each, then a ten bit version.
+    let name: String = (0..9)
+        .map(|i| CHARSET[(id >> (58 - 6 * i) & 63) as usize] as char)
+        .collect();
+    Ok(format!("{} version {}", name, id & 1023))
+}
+
+const MODULE_OPCODE_EOF: usize = 0;
+const MODULE_OPCODE_SINT: usize = 1;
+const MODULE_OPCODE_UINT: usize = 2;
+const MODULE_OPCODE_FLOAT: usize = 3;
+const MODULE_OPCODE_DOUBLE: usize = 4;
+const MODULE_OPCODE_STRING: usize = 5;
+
+/// Stream entry flag: deleted, kept until its listpack is compacted.
+const STREAM_ITEM_DELETED: i64 = 1;
+/// Stream entry flag: has the fields of its listpack's master entry.
+const STREAM_ITEM_SAMEFIELDS: i64 = 2;
+/// Entries written to each listpack of a stream.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+/// Read counter of a group that doesn't know how many entries it read.
+const STREAM_ENTRIES_READ_INVALID: u64 = u64::MAX;
+
+fn read_id<R: Read>(reader: &mut RdbReader<R>) -> Result<StreamId, RdbError> {
+    Ok(StreamId {
+        ms: reader.read_length()? as u64,
+        seq: reader.read_length()? as u64,
+    })
+}
+
+/// Reads a 16 byte big-endian ID, as in listpack keys and PELs.
+fn read_raw_id<R: Read>(reader: &mut RdbReader<R>) -> Result<StreamId, RdbError> {
+    Ok(StreamId::from_be_bytes(reader.read_array()?))
+}
 
-                let (key, bytes_read) = parse_string(&data).unwrap();
-                data = &data[bytes_read..];
+fn read_millis<R: Read>(reader: &mut RdbReader<R>) -> Result<u64, RdbError> {
+    Ok(u64::from_le_bytes(reader.read_array()?))
+}
+
+/// Reads a stream: its entries in listpacks, each keyed by the ID its
+/// entries are relative to, then its metadata and consumer groups. Later
+/// versions of the type add fields, see `Value`.
+fn read_stream<R: Read>(reader: &mut RdbReader<R>, value_type: Value) -> Result<Stream, RdbError> {
+    let mut stream = Stream::default();
+
+    for _ in 0..reader.read_length()? {
+        let master = read_encoded(reader, |key| {
+            key.try_into()
+                .map(StreamId::from_be_bytes)
+                .map_err(|_| RdbError::new(0, "Invalid stream listpack key"))
+        })?;
+        read_encoded(reader, |listpack| {
+            read_stream_listpack(master, &listpack_entries(listpack)?, &mut stream)
+        })?;
+    }
 
-                let (value, bytes_read) = parse_string(&data).unwrap();
-                data = &data[bytes_read..];
+    let _length = reader.read_length()?;
+    stream.last_id = read_id(reader)?;
+    if value_type == Value::StreamListpacks {
+        stream.first_id = stream.entries.keys().next().copied().unwrap_or_default();
+        stream.entries_added = stream.entries.len() as u64;
+    } else {
+        stream.first_id = read_id(reader)?;
+        stream.max_deleted_id = read_id(reader)?;
+        stream.entries_added = reader.read_length()? as u64;
+    }
+
+    for _ in 0..reader.read_length()? {
+        let mut group = ConsumerGroup {
+            name: reader.read_string()?,
+            last_id: read_id(reader)?,
+            ..Default::default()
+        };
+        if value_type != Value::StreamListpacks {
+            group.entries_read = Some(reader.read_length()? as u64)
+                .filter(|&read| read != STREAM_ENTRIES_READ_INVALID);
+        }
+
+        for _ in 0..reader.read_length()? {
+            let id = read_raw_id(reader)?;
+            let pending = PendingEntry {
+                consumer: String::new(),
+                delivery_time: read_millis(reader)?,
+                delivery_count: reader.read_length()? as u64,
+            };
+            group.pending.insert(id, pending);
+        }
 
-                println!(
-                    "===================> INFO: Parsed aux field ----> {} : {}",
-                    key, value
-                );
+        // Each consumer lists its pending entries, which the group PEL has.
+        for _ in 0..reader.read_length()? {
+            let name = reader.read_string()?;
+            let seen_time = read_millis(reader)?;
+            let active_time = match value_type {
+                Value::StreamListpacks3 => read_millis(reader)?,
+                _ => seen_time,
+            };
+            for _ in 0..reader.read_length()? {
+                let id = read_raw_id(reader)?;
+                let pending = group.pending.get_mut(&id).ok_or_else(|| {
+                    reader.error_before(16, "Consumer pending entry missing from the group PEL")
+                })?;
+                pending.consumer = name.clone();
             }
-            EXPIRE_TIME => {
-                // Jump op-code
-                data = &data[1..];
+            group.consumers.push(Consumer {
+                name,
+                seen_time,
+                active_time,
+            });
+        }
+        stream.groups.push(group);
+    }
+    Ok(stream)
+}
+
+/// Reads the entries of a stream listpack: a master entry with the entry
+/// counts and the fields entries share, then each entry with its flags, ID
+/// relative to `master`, fields unless it shares the master's, values and
+/// element count.
+fn read_stream_listpack(
+    master: StreamId,
+    elements: &[String],
+    stream: &mut Stream,
+) -> Result<(), RdbError> {
+    let invalid = || RdbError::new(0, "Invalid stream listpack");
+
+    let mut pos = 0;
+    let mut next = || -> Result<&String, RdbError> {
+        let element = elements.get(pos).ok_or_else(invalid)?;
+        pos += 1;
+        Ok(element)
+    };
+    let int = |element: &String| element.parse::<i64>().map_err(|_| invalid());
+
+    let count = int(next()?)?;
+    let deleted = int(next()?)?;
+    let master_fields = (0..int(next()?)?)
+        .map(|_| next().cloned())
+        .collect::<Result<Vec<_>, _>>()?;
+    if int(next()?)? != 0 {
+        return Err(invalid());
+    }
+
+    for _ in 0..count.saturating_add(deleted) {
+        let flags = int(next()?)?;
+        let id = StreamId {
+            ms: master.ms.wrapping_add(int(next()?)? as u64),
+            seq: master.seq.wrapping_add(int(next()?)? as u64),
+        };
+        let fields = if flags & STREAM_ITEM_SAMEFIELDS != 0 {
+            master_fields
+                .iter()
+                .map(|field| Ok((field.clone(), next()?.clone())))
+                .collect::<Result<Vec<_>, RdbError>>()?
+        } else {
+            (0..int(next()?)?)
+                .map(|_| Ok((next()?.clone(), next()?.clone())))
+                .collect::<Result<Vec<_>, RdbError>>()?
+        };
+        let _lp_count = next()?;
+        if flags & STREAM_ITEM_DELETED == 0 {
+            stream.entries.insert(id, fields);
+        }
+    }
+    Ok(())
+}
+
+/// Quicklist2 node holding a single element as is.
+const QUICKLIST_NODE_PLAIN: usize = 1;
+/// Quicklist2 node holding a listpack of elements.
+const QUICKLIST_NODE_PACKED: usize = 2;
 
-                // read 4 byte unsigned integer
-                let raw_data = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as u64;
-                data = &data[4..];
+/// `len` bytes of `buf` from `start`, or an error if it is cut short.
+fn take(buf: &[u8], start: usize, len: usize) -> Result<&[u8], RdbError> {
+    buf.get(start..start.saturating_add(len))
+        .ok_or_else(|| RdbError::new(start as u64, "Unexpected end of encoded value"))
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).to_string()
+}
 
-                let timestamp = UNIX_EPOCH + Duration::from_secs(raw_data);
-                // println!(
-                //     "----------------------- INFO: EXPIRE TIME SEC: {:?}",
-                //     timestamp
-                // );
-                // let expiring_at = std::time::UNIX_EPOCH + raw_data;
+/// The string in `bytes`, found at `offset`. Values are held as text, so
+/// binary ones are refused rather than changed.
+fn utf8(bytes: Vec<u8>, offset: u64) -> Result<String, RdbError> {
+    String::from_utf8(bytes).map_err(|_| RdbError::new(offset, "Non-UTF-8 string, unsupported"))
+}
 
-                // read KV
-                let (key, value, parsed_bytes) = read_key_string_value(&data).unwrap();
-                data = &data[parsed_bytes..];
+/// Reads a sorted set score of the old zset type: a length byte followed by
+/// the score as text, with 253, 254 and 255 standing for nan, inf and -inf.
+fn read_string_score<R: Read>(reader: &mut RdbReader<R>) -> Result<f64, RdbError> {
+    match reader.read_u8()? {
+        253 => Ok(f64::NAN),
+        254 => Ok(f64::INFINITY),
+        255 => Ok(f64::NEG_INFINITY),
+        len => {
+            let start = reader.offset();
+            let score = reader.read_vec(len as usize)?;
+            parse_score(&lossy(&score)).map_err(|e| e.inside(start))
+        }
+    }
+}
+
+fn parse_score(score: &str) -> Result<f64, RdbError> {
+    score
+        .parse()
+        .map_err(|_| RdbError::new(0, "Invalid sorted set score"))
+}
+
+/// Groups alternating fields and values.
+fn pairs(elements: Vec<String>) -> Result<HashMap<String, String>, RdbError> {
+    if !elements.len().is_multiple_of(2) {
+        return Err(RdbError::new(0, "Odd number of elements in a hash"));
+    }
+    let mut elements = elements.into_iter();
+    let mut hash = HashMap::new();
+    while let (Some(field), Some(value)) = (elements.next(), elements.next()) {
+        hash.insert(field, value);
+    }
+    Ok(hash)
+}
 
-                let entry = Entry::new(value, None, Some(timestamp), Instant::now());
-                hm.insert(key, entry);
+/// Builds a sorted set from members and their scores as text.
+fn scored(members: HashMap<String, String>) -> Result<SortedSet, RdbError> {
+    let mut zset = SortedSet::new();
+    for (member, score) in members {
+        zset.insert(member, parse_score(&score)?);
+    }
+    Ok(zset)
+}
+
+/// Sign-extends the little-endian integer in `bytes`, at most 8 long.
+fn signed_le(bytes: &[u8]) -> i64 {
+    let value = bytes
+        .iter()
+        .enumerate()
+        .fold(0_u64, |value, (i, &byte)| value | (byte as u64) << (8 * i));
+    let unused = 64 - 8 * bytes.len() as u32;
+    ((value << unused) as i64) >> unused
+}
+
+fn first_byte(buf: &[u8], pos: usize) -> Result<u8, RdbError> {
+    Ok(take(buf, pos, 1)?[0])
+}
+
+/// Elements of a ziplist: a header of total bytes, tail offset and count,
+/// then entries each prefixed by the previous entry's length and their own
+/// encoding, then 0xFF.
+fn ziplist_entries(blob: &[u8]) -> Result<Vec<String>, RdbError> {
+    let mut pos = 10;
+    let mut elements = Vec::new();
+    loop {
+        let prevlen = first_byte(blob, pos)?;
+        if prevlen == 0xFF {
+            return Ok(elements);
+        }
+        pos += if prevlen == 0xFE { 5 } else { 1 };
+
+        let encoding = first_byte(blob, pos)?;
+        pos += 1;
+        let (header, len) = match encoding >> 6 {
+            0b00 => (0, (encoding & 0x3f) as usize),
+            0b01 => (
+                1,
+                ((encoding & 0x3f) as usize) << 8 | first_byte(blob, pos)? as usize,
+            ),
+            0b10 => (
+                4,
+                u32::from_be_bytes(take(blob, pos, 4)?.try_into().unwrap()) as usize,
+            ),
+            _ => {
+                let len = match encoding {
+                    0xC0 => 2,
+                    0xD0 => 4,
+                    0xE0 => 8,
+                    0xF0 => 3,
+                    0xFE => 1,
+                    // The value is in the encoding, 0 to 12.
+                    0xF1..=0xFD => {
+                        elements.push(((encoding & 0x0f) - 1).to_string());
+                        continue;
+                    }
+                    _ => {
+                        return Err(RdbError::new(
+                            pos as u64 - 1,
+                            "Invalid ziplist entry encoding",
+                        ))
+                    }
+                };
+                elements.push(signed_le(take(blob, pos, len)?).to_string());
+                pos += len;
+                continue;
+            }
+        };
+        elements.push(utf8(take(blob, pos + header, len)?.to_vec(), pos as u64)?);
+        pos += header + len;
+    }
+}
+
+/// Elements of a listpack: a header of total bytes and count, then entries
+/// each made of an encoding, data and its own length backwards, then 0xFF.
+fn listpack_entries(blob: &[u8]) -> Result<Vec<String>, RdbError> {
+    let mut pos = 6;
+    let mut elements = Vec::new();
+    loop {
+        let encoding = first_byte(blob, pos)?;
+        let (element, entry_len) = match encoding {
+            0xFF => return Ok(elements),
+            0x00..=0x7F => (encoding.to_string(), 1),
+            0x80..=0xBF => {
+                let len = (encoding & 0x3f) as usize;
+                (
+                    utf8(take(blob, pos + 1, len)?.to_vec(), pos as u64)?,
+                    1 + len,
+                )
+            }
+            0xC0..=0xDF => {
+                let value = ((encoding & 0x1f) as u16) << 8 | first_byte(blob, pos + 1)? as u16;
+                // 13 bit two's complement.
+                let value = ((value << 3) as i16) >> 3;
+                (value.to_string(), 2)
+            }
+            0xE0..=0xEF => {
+                let len = ((encoding & 0x0f) as usize) << 8 | first_byte(blob, pos + 1)? as usize;
+                (
+                    utf8(take(blob, pos + 2, len)?.to_vec(), pos as u64)?,
+                    2 + len,
+                )
+            }
+            0xF0 => {
+                let len = u32::from_le_bytes(take(blob, pos + 1, 4)?.try_into().unwrap()) as usize;
+                (
+                    utf8(take(blob, pos + 5, len)?.to_vec(), pos as u64)?,
+                    5 + len,
+                )
             }
-            EXPIRE_TIME_MS => {
-                // Jump op-code
-                data = &data[1..];
+            0xF1..=0xF4 => {
+                let len = match encoding {
+                    0xF1 => 2,
+                    0xF2 => 3,
+                    0xF3 => 4,
+                    _ => 8,
+                };
+                (signed_le(take(blob, pos + 1, len)?).to_string(), 1 + len)
+            }
+            _ => return Err(RdbError::new(pos as u64, "Invalid listpack entry encoding")),
+        };
+        let backlen = backlen_size(entry_len);
+        elements.push(element);
+        pos += entry_len + backlen;
+    }
+}
 
-                // read 8 byte unsigned long
-                let raw_data = u64::from_le_bytes([
-                    data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7],
-                ]) as u64;
-                data = &data[8..];
+/// Bytes taken by the backwards length of a listpack entry `entry_len`
+/// bytes long.
+fn backlen_size(entry_len: usize) -> usize {
+    match entry_len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
 
-                let timestamp = UNIX_EPOCH + Duration::from_millis(raw_data);
-                // println!(
-                //     "----------------------- INFO: EXPIRE TIME MS: {:?}",
-                //     timestamp
-                // ); // let expiring_at = std::time::UNIX_EPOCH + raw_data;
-
-                // read KV
-                let (key, value, parsed_bytes) = read_key_string_value(&data).unwrap();
-                data = &data[parsed_bytes..];
-
-                let entry = Entry::new(value, None, Some(timestamp), Instant::now());
-                hm.insert(key, entry);
+/// Builds a listpack of `elements`, integers in their integer encodings.
+fn write_listpack(elements: &[String]) -> Vec<u8> {
+    let mut out = vec![0; 6];
+    for element in elements {
+        let start = out.len();
+        match element.parse::<i64>() {
+            Ok(n) if n.to_string() == *element => {
+                if (0..128).contains(&n) {
+                    out.push(n as u8);
+                } else if let Ok(n) = i16::try_from(n) {
+                    out.push(0xF1);
+                    out.extend_from_slice(&n.to_le_bytes());
+                } else if let Ok(n) = i32::try_from(n) {
+                    out.push(0xF3);
+                    out.extend_from_slice(&n.to_le_bytes());
+                } else {
+                    out.push(0xF4);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
             }
             _ => {
-                let (key, value, bytes_read) = read_key_string_value(&data).unwrap();
-                println!("[!] Read KV pair without expiry ----> {key} : {value}");
-                data = &data[bytes_read..];
+                let len = element.len();
+                if len < 64 {
+                    out.push(0x80 | len as u8);
+                } else if len < 4096 {
+                    out.extend_from_slice(&(0xE000 | len as u16).to_be_bytes());
+                } else {
+                    out.push(0xF0);
+                    out.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                out.extend_from_slice(element.as_bytes());
+            }
+        }
 
-                let entry = Entry::new(value, None, None, Instant::now());
-                hm.insert(key, entry);
+        // The entry length again, in 7 bit groups read from the end.
+        let entry_len = out.len() - start;
+        let size = backlen_size(entry_len);
+        for i in (0..size).rev() {
+            let group = (entry_len >> (7 * i)) as u8 & 127;
+            out.push(if i == size - 1 { group } else { group | 128 });
+        }
+    }
+    out.push(0xFF);
+
+    let total = out.len() as u32;
+    out[..4].copy_from_slice(&total.to_le_bytes());
+    let count = elements.len().min(u16::MAX as usize) as u16;
+    out[4..6].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+/// Appends `stream` in the format `read_stream` reads for the newest stream
+/// type, entries grouped in listpacks sharing the fields of their first.
+fn write_stream(out: &mut Vec<u8>, stream: &Stream, compression: bool) {
+    let entries: Vec<_> = stream.entries.iter().collect();
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+    write_length(out, nodes.len());
+    for node in nodes {
+        let (master, master_fields) = node[0];
+        let mut elements = vec![
+            node.len().to_string(),
+            "0".to_string(),
+            master_fields.len().to_string(),
+        ];
+        elements.extend(master_fields.iter().map(|(field, _)| field.clone()));
+        elements.push("0".to_string());
+
+        for (id, fields) in node {
+            let same_fields = fields.len() == master_fields.len()
+                && fields
+                    .iter()
+                    .zip(master_fields.iter())
+                    .all(|((a, _), (b, _))| a == b);
+            let flags = if same_fields {
+                STREAM_ITEM_SAMEFIELDS
+            } else {
+                0
+            };
+            elements.push(flags.to_string());
+            elements.push((id.ms.wrapping_sub(master.ms) as i64).to_string());
+            elements.push((id.seq.wrapping_sub(master.seq) as i64).to_string());
+            if same_fields {
+                elements.extend(fields.iter().map(|(_, value)| value.clone()));
+                elements.push((fields.len() + 3).to_string());
+            } else {
+                elements.push(fields.len().to_string());
+                for (field, value) in fields.iter() {
+                    elements.push(field.clone());
+                    elements.push(value.clone());
+                }
+                elements.push((2 * fields.len() + 4).to_string());
             }
         }
+        write_string(out, &master.to_be_bytes());
+        write_blob(out, &write_listpack(&elements), compression);
     }
 
-    hm
+    write_length(out, stream.entries.len());
+    for id in [stream.last_id, stream.first_id, stream.max_deleted_id] {
+        write_length(out, id.ms as usize);
+        write_length(out, id.seq as usize);
+    }
+    write_length(out, stream.entries_added as usize);
+
+    write_length(out, stream.groups.len());
+    for group in stream.groups.iter() {
+        write_string(out, group.name.as_bytes());
+        write_length(out, group.last_id.ms as usize);
+        write_length(out, group.last_id.seq as usize);
+        write_length(
+            out,
+            group.entries_read.unwrap_or(STREAM_ENTRIES_READ_INVALID) as usize,
+        );
+        write_length(out, group.pending.len());
+        for (id, pending) in group.pending.iter() {
+            out.extend_from_slice(&id.to_be_bytes());
+            out.extend_from_slice(&pending.delivery_time.to_le_bytes());
+            write_length(out, pending.delivery_count as usize);
+        }
+        write_length(out, group.consumers.len());
+        for consumer in group.consumers.iter() {
+            write_string(out, consumer.name.as_bytes());
+            out.extend_from_slice(&consumer.seen_time.to_le_bytes());
+            out.extend_from_slice(&consumer.active_time.to_le_bytes());
+            let pending: Vec<_> = group
+                .pending
+                .iter()
+                .filter(|(_, pending)| pending.consumer == consumer.name)
+                .collect();
+            write_length(out, pending.len());
+            for (id, _) in pending {
+                out.extend_from_slice(&id.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Members of an intset: the width of its integers, their count, then the
+/// integers in ascending order.
+fn intset_entries(blob: &[u8]) -> Result<Vec<String>, RdbError> {
+    let width = u32::from_le_bytes(take(blob, 0, 4)?.try_into().unwrap()) as usize;
+    let count = u32::from_le_bytes(take(blob, 4, 4)?.try_into().unwrap()) as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(RdbError::new(0, "Invalid intset encoding"));
+    }
+    take(blob, 8, width.saturating_mul(count))?
+        .chunks(width)
+        .map(|int| Ok(signed_le(int).to_string()))
+        .collect()
 }
 
-fn read_key_string_value(buf: &[u8]) -> Result<(String, String, usize), Error> {
-    // read value type: for this challenge only Value::String has been implemented
-    match Value::try_from(buf[0]).unwrap() {
-        Value::String => {
-            // Jump over value type byte
-            let mut rest = &buf[1..];
-            let mut bytes_read = 1; // 1 cos we read first byte (value type)
+/// Fields and values of a zipmap: a count, then lengths and bytes of each
+/// field and value, the value followed by unused bytes, then 0xFF.
+fn zipmap_entries(blob: &[u8]) -> Result<Vec<(String, String)>, RdbError> {
+    let read_len = |pos: usize| -> Result<Option<(usize, usize)>, RdbError> {
+        match first_byte(blob, pos)? {
+            0xFF => Ok(None),
+            0xFE => {
+                let len = u32::from_le_bytes(take(blob, pos + 1, 4)?.try_into().unwrap());
+                Ok(Some((len as usize, 5)))
+            }
+            len => Ok(Some((len as usize, 1))),
+        }
+    };
+
+    let mut pos = 1;
+    let mut entries = Vec::new();
+    while let Some((len, header)) = read_len(pos)? {
+        let field = utf8(take(blob, pos + header, len)?.to_vec(), pos as u64)?;
+        pos += header + len;
+        let Some((len, header)) = read_len(pos)? else {
+            return Err(RdbError::new(pos as u64, "Invalid zipmap"));
+        };
+        let free = first_byte(blob, pos + header)? as usize;
+        let value = utf8(take(blob, pos + header + 1, len)?.to_vec(), pos as u64)?;
+        pos += header + 1 + len + free;
+        entries.push((field, value));
+    }
+    Ok(entries)
+}
 
-            let (key, parsed_bytes) = parse_string(&rest).unwrap();
-            bytes_read += parsed_bytes;
-            rest = &rest[parsed_bytes..];
+/// Appends the RDB length encoding of `len`.
+pub fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend_from_slice(&(len as u16 | 0x4000).to_be_bytes());
+    } else if len <= u32::MAX as usize {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+}
 
-            let (value, parsed_bytes) = parse_string(&rest).unwrap();
-            bytes_read += parsed_bytes;
+/// Appends a length-prefixed string.
+pub fn write_string(out: &mut Vec<u8>, s: &[u8]) {
+    write_length(out, s.len());
+    out.extend_from_slice(s);
+}
 
-            Ok((key, value, bytes_read))
+/// Appends a string, LZF compressed when `compression` is on and it is
+/// long enough to gain from it, like Redis does.
+fn write_blob(out: &mut Vec<u8>, s: &[u8], compression: bool) {
+    if compression && s.len() > 20 {
+        // Only worth it when at least 4 bytes are saved.
+        if let Some(compressed) = lzf::compress(s, s.len() - 4) {
+            out.push(0xC3);
+            write_length(out, compressed.len());
+            write_length(out, s.len());
+            out.extend_from_slice(&compressed);
+            return;
         }
-        _ => unimplemented!(),
     }
+    write_string(out, s);
 }
 
-fn parse_string(buf: &[u8]) -> Result<(String, usize), Error> {
-    let mut bytes_read: usize = 0;
+/// Appends one FUNCTION opcode per library, as RDB files and FUNCTION DUMP
+/// store them.
+pub fn write_functions<'a>(out: &mut Vec<u8>, libraries: impl IntoIterator<Item = &'a str>) {
+    for code in libraries {
+        out.push(FUNCTION);
+        write_string(out, code.as_bytes());
+    }
+}
 
-    let (length_encoding_type, parsed_bytes) = decode_length_encoding(&buf).unwrap();
+/// Appends a string, as an integer encoding when it is the canonical form
+/// of a 32-bit integer, like Redis does.
+fn write_encoded_string(out: &mut Vec<u8>, s: &str, compression: bool) {
+    match s.parse::<i32>() {
+        Ok(n) if n.to_string() == s => {
+            if let Ok(n) = i8::try_from(n) {
+                out.push(0xC0);
+                out.extend_from_slice(&n.to_le_bytes());
+            } else if let Ok(n) = i16::try_from(n) {
+                out.push(0xC1);
+                out.extend_from_slice(&n.to_le_bytes());
+            } else {
+                out.push(0xC2);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+        }
+        _ => write_blob(out, s.as_bytes(), compression),
+    }
+}
 
-    let rest = &buf[parsed_bytes..];
-    bytes_read += parsed_bytes;
+/// Appends the type byte of `value`.
+fn write_value_type(out: &mut Vec<u8>, value: &EntryValue) {
+    let value_type = match value {
+        EntryValue::String(_) => Value::String,
+        EntryValue::List(_) => Value::List,
+        EntryValue::Set(_) => Value::Set,
+        EntryValue::SortedSet(_) => Value::SortedSet2,
+        EntryValue::Hash(_) => Value::Hash,
+        EntryValue::Stream(_) => Value::StreamListpacks3,
+    };
+    out.push(value_type as u8);
+}
 
-    let data: String = match length_encoding_type {
-        LengthEncodingType::Length(length) => {
-            // println!("---- Reading {} raw bytes", length);
-            let mut parsed_string = String::new();
-            if length > 0 {
-                // println!("----------- DEBUG raw read value: {:?}", &rest[..length]);
-                parsed_string = String::from_utf8_lossy(&rest[..length]).to_string();
-                // println!("---- Parsed String: {}", parsed_string);
+/// Appends `value` in the encoding announced by `write_value_type`.
+fn write_value(out: &mut Vec<u8>, value: &EntryValue, compression: bool) {
+    match value {
+        EntryValue::String(s) => write_encoded_string(out, s, compression),
+        EntryValue::List(list) => {
+            write_length(out, list.len());
+            for element in list {
+                write_encoded_string(out, element, compression);
             }
-            bytes_read += length;
-            parsed_string
         }
-        LengthEncodingType::Special(spl_format) => match spl_format {
-            EncodingFormat::Integer(n) => {
-                bytes_read += n;
-                match n {
-                    1 => rest[0].to_string(),
-                    2 => u16::from_be_bytes([rest[0], rest[1]]).to_string(),
-                    4 => u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]).to_string(),
-                    _ => return Err(Error::InvalidCommand("Invalid length encoding type")),
-                }
+        EntryValue::Set(set) => {
+            write_length(out, set.len());
+            for member in set {
+                write_encoded_string(out, member, compression);
             }
-            _ => return Err(Error::InvalidCommand("Invalid length encoding type")),
-        },
-    };
+        }
+        EntryValue::SortedSet(zset) => {
+            write_length(out, zset.len());
+            // Highest scores first, which Redis loads fastest.
+            for (member, score) in zset.iter().rev() {
+                write_encoded_string(out, member, compression);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        EntryValue::Hash(hash) => {
+            write_length(out, hash.len());
+            for (field, value) in hash {
+                write_encoded_string(out, field, compression);
+                write_encoded_string(out, value, compression);
+            }
+        }
+        EntryValue::Stream(stream) => write_stream(out, stream, compression),
+    }
+}
 
-    // println!(
-    // "-------------- DEBUG: Bytes read at end of parsing string: {}",
-    //     bytes_read
-    // );
-    Ok((data, bytes_read))
-}
-
-fn decode_length_encoding(buf: &[u8]) -> Result<(LengthEncodingType, usize), Error> {
-    let first_byte = buf[0];
-    println!("---- length encoding byte: {:b}", first_byte);
-
-    let two_msb_value = first_byte >> 6;
-    // println!("---- MSB value: {:b}", two_msb_value);
-
-    match two_msb_value {
-        0b00 => Ok((LengthEncodingType::Length((first_byte & 0x3f) as usize), 1)),
-        0b01 => {
-            let next_byte = buf[1];
-            let length = u16::from_be_bytes([(first_byte & 0x3f), next_byte]) as usize;
-            Ok((LengthEncodingType::Length(length), 2))
-        }
-        0b10 => {
-            let length = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
-            Ok((LengthEncodingType::Length(length), 5))
-        }
-        0b11 => {
-            // Special format
-            println!("---- Special format byte: {:b}", first_byte & 0x3f);
-            match first_byte & 0x3f {
-                0b00 => {
-                    // 8 bit Integer
-                    Ok((LengthEncodingType::Special(EncodingFormat::Integer(1)), 1))
-                }
-                0b01 => {
-                    // 16 bit integer
-                    Ok((LengthEncodingType::Special(EncodingFormat::Integer(2)), 1))
-                }
-                0b10 => {
-                    // 32 bit integer
-                    Ok((LengthEncodingType::Special(EncodingFormat::Integer(4)), 1))
-                }
-                0b11 => {
-                    // Compressed
-                    Ok((LengthEncodingType::Special(EncodingFormat::Compressed), 1))
-                }
-                _ => Err(Error::InvalidCommand(
-                    "Unexpected value of remaining 6 bits for special format",
-                )),
+fn write_aux(out: &mut Vec<u8>, key: &str, value: &str) {
+    out.push(AUXILLARY_FIELDS);
+    write_string(out, key.as_bytes());
+    write_encoded_string(out, value, false);
+}
+
+/// Serializes `contents` into a complete RDB file, checksum included,
+/// with long strings LZF compressed when `compression` is on.
+pub fn write_rdb(contents: &RdbContents, compression: bool) -> Vec<u8> {
+    write_dataset(contents, compression, false)
+}
+
+/// Like `write_rdb`, for the RDB preamble of an AOF base file, which is
+/// marked as such by the `aof-base` aux field.
+pub fn write_aof_preamble(contents: &RdbContents, compression: bool) -> Vec<u8> {
+    write_dataset(contents, compression, true)
+}
+
+fn write_dataset(contents: &RdbContents, compression: bool, aof_base: bool) -> Vec<u8> {
+    let mut out = format!("REDIS{:04}", RDB_VERSION).into_bytes();
+
+    let ctime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    write_aux(&mut out, "redis-ver", REDIS_VERSION);
+    write_aux(&mut out, "redis-bits", &(usize::BITS).to_string());
+    write_aux(&mut out, "ctime", &ctime.to_string());
+    write_aux(&mut out, "aof-base", if aof_base { "1" } else { "0" });
+    write_functions(&mut out, contents.functions.iter().map(String::as_str));
+
+    for (db, entries) in contents.databases.iter() {
+        if entries.is_empty() {
+            continue;
+        }
+        out.push(SELECT_DB);
+        write_length(&mut out, *db);
+        out.push(RESIZE_DB);
+        write_length(&mut out, entries.len());
+        write_length(
+            &mut out,
+            entries
+                .values()
+                .filter(|entry| entry.expires_at().is_some())
+                .count(),
+        );
+
+        for (key, entry) in entries {
+            if let Some(expires_at) = entry.expires_at() {
+                let millis = expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64;
+                out.push(EXPIRE_TIME_MS);
+                out.extend_from_slice(&millis.to_le_bytes());
             }
+            write_value_type(&mut out, entry.get_value());
+            write_blob(&mut out, key.as_bytes(), compression);
+            write_value(&mut out, entry.get_value(), compression);
+        }
+    }
+
+    out.push(EOF);
+    let crc = crc64(0, &out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+/// Reads back what `write_functions` wrote.
+pub fn parse_functions(data: &[u8]) -> Result<Vec<String>, Error> {
+    const INVALID: Error = Error::InvalidCommand("payload is not a valid function dump");
+
+    let mut reader = RdbReader::new(data);
+    let mut libraries = Vec::new();
+    while reader.offset() < data.len() as u64 {
+        if reader.read_u8().map_err(|_| INVALID)? != FUNCTION {
+            return Err(Error::InvalidCommand("given type is not a function"));
         }
-        _ => Err(Error::InvalidCommand("Unexpected value for 2 MSBs")),
+        libraries.push(reader.read_string().map_err(|_| INVALID)?);
     }
+    Ok(libraries)
+}
+
+/// CRC-64/Jones, the checksum of RDB files and DUMP payloads.
+pub fn crc64(crc: u64, bytes: &[u8]) -> u64 {
+    const POLY: u64 = 0xad93d23594c935a9_u64.reverse_bits();
+    const TABLE: [u64; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u64;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ POLY
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    bytes.iter().fold(crc, |crc, &byte| {
+        TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Seals a DUMP payload with the RDB version and a checksum.
+pub fn seal_payload(mut payload: Vec<u8>) -> Vec<u8> {
+    payload.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let crc = crc64(0, &payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+/// Checks the trailer `seal_payload` added and returns the payload itself.
+pub fn open_payload(payload: &[u8]) -> Result<&[u8], Error> {
+    const INVALID: Error = Error::InvalidCommand("DUMP payload version or checksum are wrong");
+
+    if payload.len() < 10 {
+        return Err(INVALID);
+    }
+    let (body, crc) = payload.split_at(payload.len() - 8);
+    if crc64(0, body).to_le_bytes() != crc {
+        return Err(INVALID);
+    }
+    let (body, version) = body.split_at(body.len() - 2);
+    if u16::from_le_bytes([version[0], version[1]]) > RDB_VERSION {
+        return Err(INVALID);
+    }
+    Ok(body)
+}
+
+/// DUMP: `value` in its RDB encoding, sealed into a payload.
+pub fn dump_value(value: &EntryValue, compression: bool) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_value_type(&mut payload, value);
+    write_value(&mut payload, value, compression);
+    seal_payload(payload)
+}
+
+/// RESTORE: the value in a payload made by `dump_value`, or by Redis.
+pub fn restore_value(payload: &[u8]) -> Result<EntryValue, Error> {
+    const BAD_FORMAT: Error = Error::InvalidCommand("Bad data format");
+
+    let body = open_payload(payload)?;
+    let mut reader = RdbReader::new(body);
+    let value_type = reader.read_u8().map_err(|_| BAD_FORMAT)?;
+    let value_type = Value::try_from(value_type).map_err(|_| BAD_FORMAT)?;
+    let value = read_value(&mut reader, value_type).map_err(|_| BAD_FORMAT)?;
+    if reader.offset() != body.len() as u64 {
+        return Err(BAD_FORMAT);
+    }
+    Ok(value)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Reads a value, checking every byte is used.
+    fn read(value_type: Value, data: &[u8]) -> EntryValue {
+        let mut reader = RdbReader::new(data);
+        let value = read_value(&mut reader, value_type).unwrap();
+        assert_eq!(reader.offset(), data.len() as u64);
+        value
+    }
+
+    fn try_read(value_type: Value, data: &[u8]) -> Result<EntryValue, RdbError> {
+        read_value(&mut RdbReader::new(data), value_type)
+    }
+
+    /// Reads a key record from its type byte on, with the bytes read.
+    fn read_record_at(data: &[u8]) -> Result<(String, Option<EntryValue>, u64), RdbError> {
+        let mut reader = RdbReader::new(data);
+        let value_type = reader.read_u8()?;
+        let (key, value) = read_key_value(&mut reader, value_type)?;
+        Ok((key, value, reader.offset()))
+    }
+
+    fn parse_string(data: &[u8]) -> Result<(String, u64), RdbError> {
+        let mut reader = RdbReader::new(data);
+        let s = reader.read_string()?;
+        Ok((s, reader.offset()))
+    }
+
+    fn length_encoding(data: &[u8]) -> (LengthEncodingType, u64) {
+        let mut reader = RdbReader::new(data);
+        let encoding = reader.read_length_encoding().unwrap();
+        (encoding, reader.offset())
+    }
+
+    /// Ends RDB data with EOF and its checksum.
+    fn finish(mut data: Vec<u8>) -> Vec<u8> {
+        data.push(EOF);
+        let crc = crc64(0, &data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    fn rdb_parser(data: &[u8]) -> RdbContents {
+        let (contents, consumed) = parse_rdb_prefix(data).unwrap();
+        assert_eq!(consumed, data.len());
+        contents
+    }
+
+    /// Elements of a list, or the sorted members of a set.
+    fn elements(value: EntryValue) -> Vec<String> {
+        match value {
+            EntryValue::List(list) => list.into(),
+            EntryValue::Set(set) => {
+                let mut members: Vec<_> = set.into_iter().collect();
+                members.sort();
+                members
+            }
+            value => panic!("not a list or set: {:?}", value),
+        }
+    }
+
+    /// `value` with sets and hashes in a fixed order, for comparing.
+    fn describe(value: EntryValue) -> String {
+        match value {
+            EntryValue::SortedSet(zset) => format!("{:?}", zset.iter().collect::<Vec<_>>()),
+            EntryValue::Hash(_) => format!("{:?}", sorted_pairs(value)),
+            EntryValue::String(s) => s,
+            value => format!("{:?}", elements(value)),
+        }
+    }
+
+    fn sorted_pairs(value: EntryValue) -> Vec<(String, String)> {
+        let EntryValue::Hash(hash) = value else {
+            panic!("not a hash: {:?}", value);
+        };
+        let mut pairs: Vec<_> = hash.into_iter().collect();
+        pairs.sort();
+        pairs
+    }
+
+    /// The first `len` bytes of a ziplist or listpack, ended.
+    fn ended(bytes: &[u8], len: usize) -> Vec<u8> {
+        let mut bytes = bytes[..len].to_vec();
+        bytes.push(0xFF);
+        bytes
+    }
+
+    /// `bytes` as a length-prefixed string.
+    fn blob(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_string(&mut out, bytes);
+        out
+    }
+
     #[test]
     fn test_reading_string_kv() {
+        let (key, value, bytes_read) = read_record_at(&[0, 1, 97, 1, 98]).unwrap();
+        assert_eq!((key.as_str(), bytes_read), ("a", 5));
+        assert!(matches!(value, Some(EntryValue::String(s)) if s == "b"));
+
+        let (key, value, bytes_read) =
+            read_record_at(&[0, 3, 102, 111, 111, 4, 98, 97, 114, 115]).unwrap();
+        assert_eq!((key.as_str(), bytes_read), ("foo", 10));
+        assert!(matches!(value, Some(EntryValue::String(s)) if s == "bars"));
+    }
+
+    #[test]
+    fn test_reading_values() {
+        let mut zset = SortedSet::new();
+        zset.insert("a".to_string(), -1.5);
+        zset.insert("b".to_string(), f64::INFINITY);
+        let values = [
+            EntryValue::List(["x", "7", "y"].map(String::from).into()),
+            EntryValue::Set(["1", "m"].map(String::from).into()),
+            EntryValue::SortedSet(zset),
+            EntryValue::Hash(HashMap::from([("f".to_string(), "-2".to_string())])),
+        ];
+        for value in values {
+            let mut data = Vec::new();
+            write_value(&mut data, &value, false);
+            let mut value_type = Vec::new();
+            write_value_type(&mut value_type, &value);
+            let read = read(Value::try_from(value_type[0]).unwrap(), &data);
+            assert_eq!(describe(read), describe(value));
+        }
+
+        // The old sorted set type has scores as text.
+        let EntryValue::SortedSet(zset) = read(
+            Value::SortedSet,
+            &[2, 1, b'm', 3, b'1', b'.', b'5', 1, b'n', 255],
+        ) else {
+            panic!("not a sorted set");
+        };
+        assert_eq!(zset.score("m"), Some(1.5));
+        assert_eq!(zset.score("n"), Some(f64::NEG_INFINITY));
+    }
+
+    #[test]
+    fn test_reading_compact_encodings() {
+        let intset = [2, 0, 0, 0, 3, 0, 0, 0, 0xFE, 0xFF, 1, 0, 0x2C, 0x01];
+        assert_eq!(
+            elements(read(Value::Intset, &blob(&intset))),
+            ["-2", "1", "300"]
+        );
+
+        let mut ziplist = vec![0; 10];
+        ziplist.extend_from_slice(&[0, 0x01, b'a']);
+        ziplist.extend_from_slice(&[3, 0xF6]);
+        ziplist.extend_from_slice(&[2, 0xC0, 0xD4, 0xFE]);
+        ziplist.extend_from_slice(&[4, 0xF0, 0, 0, 0x80]);
+        ziplist.extend_from_slice(&[5, 0x40, 1, b'b', 0xFF]);
+        assert_eq!(
+            elements(read(Value::Ziplist, &blob(&ziplist))),
+            ["a", "5", "-300", "-8388608", "b"]
+        );
         assert_eq!(
-            read_key_string_value(&[0, 01, 97, 01, 98]).unwrap(),
-            (String::from("a"), String::from("b"), 5)
+            sorted_pairs(read(Value::HashmapInZiplist, &blob(&ended(&ziplist, 15)))),
+            [("a".to_string(), "5".to_string())]
         );
 
+        let mut listpack = vec![0; 6];
+        listpack.extend_from_slice(&[0x05, 1]);
+        listpack.extend_from_slice(&[0x82, b'a', b'b', 3]);
+        listpack.extend_from_slice(&[0xDF, 0xFF, 2]);
+        listpack.extend_from_slice(&[0xF1, 0xE8, 0x03, 3]);
+        listpack.push(0xFF);
         assert_eq!(
-            read_key_string_value(&[0, 03, 102, 111, 111, 04, 98, 97, 114, 115]).unwrap(),
-            (String::from("foo"), String::from("bars"), 10)
+            elements(read(Value::SetInListpack, &blob(&listpack))),
+            ["-1", "1000", "5", "ab"]
         );
+        assert_eq!(
+            sorted_pairs(read(Value::HashInListpack, &blob(&listpack))),
+            [
+                ("-1".to_string(), "1000".to_string()),
+                ("5".to_string(), "ab".to_string())
+            ]
+        );
+        // "ab" isn't a score.
+        assert!(try_read(Value::SortedSetInListpack, &blob(&listpack)).is_err());
+        let zset_listpack = [0, 0, 0, 0, 0, 0, 0x81, b'm', 2, 0x05, 1, 0xFF];
+        let EntryValue::SortedSet(zset) = read(Value::SortedSetInListpack, &blob(&zset_listpack))
+        else {
+            panic!("not a sorted set");
+        };
+        assert_eq!(zset.score("m"), Some(5.0));
+        assert!(try_read(Value::HashInListpack, &blob(&ended(&listpack, 8))).is_err());
+
+        let zipmap = [
+            2, 1, b'f', 1, 0, b'v', 2, b'g', b'g', 2, 1, b'x', b'y', 0, 0xFF,
+        ];
+        assert_eq!(
+            sorted_pairs(read(Value::Zipmap, &blob(&zipmap))),
+            [
+                ("f".to_string(), "v".to_string()),
+                ("gg".to_string(), "xy".to_string())
+            ]
+        );
+
+        let mut quicklist = vec![1];
+        quicklist.extend(blob(&ziplist));
+        assert_eq!(elements(read(Value::ListInQuicklist, &quicklist)).len(), 5);
+
+        let mut quicklist2 = vec![2, 1];
+        quicklist2.extend(blob(b"plain"));
+        quicklist2.push(2);
+        quicklist2.extend(blob(&listpack));
+        assert_eq!(
+            elements(read(Value::ListInQuicklist2, &quicklist2)),
+            ["plain", "5", "ab", "-1", "1000"]
+        );
+
+        // Cut short data is an error rather than a panic.
+        assert!(try_read(Value::Ziplist, &blob(&ziplist[..12])).is_err());
+        assert!(try_read(Value::Intset, &blob(&intset[..12])).is_err());
     }
 
     #[test]
     fn test_length_encoding() {
         assert_eq!(
-            decode_length_encoding(&[09]).unwrap(),
-            (LengthEncodingType::Length(9 as usize), 1)
+            length_encoding(&[9]),
+            (LengthEncodingType::Length(9_usize), 1)
         );
 
         assert_eq!(
-            decode_length_encoding(&[65, 01]).unwrap(),
-            (LengthEncodingType::Length(257 as usize), 2)
+            length_encoding(&[65, 1]),
+            (LengthEncodingType::Length(257_usize), 2)
         );
 
         assert_eq!(
-            decode_length_encoding(&[192]).unwrap(),
+            length_encoding(&[192]),
             (LengthEncodingType::Special(EncodingFormat::Integer(1)), 1)
         );
 
         assert_eq!(
-            decode_length_encoding(&[193]).unwrap(),
+            length_encoding(&[193]),
             (LengthEncodingType::Special(EncodingFormat::Integer(2)), 1)
         );
 
         assert_eq!(
-            decode_length_encoding(&[194]).unwrap(),
+            length_encoding(&[194]),
             (LengthEncodingType::Special(EncodingFormat::Integer(4)), 1)
         );
+
+        assert_eq!(
+            length_encoding(&[0x80, 0, 1, 0, 0]),
+            (LengthEncodingType::Length(65_536_usize), 5)
+        );
+
+        assert_eq!(
+            length_encoding(&[0x81, 0, 0, 0, 1, 0, 0, 0, 2]),
+            (LengthEncodingType::Length((1_usize << 32) + 2), 9)
+        );
+
+        let mut out = Vec::new();
+        write_length(&mut out, 1 << 40);
+        assert_eq!(
+            length_encoding(&out),
+            (LengthEncodingType::Length(1_usize << 40), 9)
+        );
+        assert!(RdbReader::new(&[0x82][..]).read_length_encoding().is_err());
+        assert!(RdbReader::new(&[0x81, 0][..])
+            .read_length_encoding()
+            .is_err());
     }
 
     #[test]
     fn test_string_parsing() {
-        assert_eq!(parse_string(&[01, 97]).unwrap(), ("a".to_string(), 2));
-        assert_eq!(parse_string(&[02, 97, 98]).unwrap(), ("ab".to_string(), 3));
+        assert_eq!(parse_string(&[1, 97]).unwrap(), ("a".to_string(), 2));
+        assert_eq!(parse_string(&[2, 97, 98]).unwrap(), ("ab".to_string(), 3));
 
-        assert_eq!(parse_string(&[192, 01]).unwrap(), ("1".to_string(), 2));
+        assert_eq!(parse_string(&[192, 1]).unwrap(), ("1".to_string(), 2));
+        assert_eq!(parse_string(&[192, 255]).unwrap(), ("-1".to_string(), 2));
+        assert_eq!(parse_string(&[193, 0, 1]).unwrap(), ("256".to_string(), 3));
         assert_eq!(
-            parse_string(&[193, 01, 00]).unwrap(),
-            ("256".to_string(), 3)
+            parse_string(&[194, 0, 0, 0, 1]).unwrap(),
+            ("16777216".to_string(), 5)
         );
+    }
+
+    fn strings(elements: &[&str]) -> Vec<String> {
+        elements.iter().map(|element| element.to_string()).collect()
+    }
+
+    #[test]
+    fn test_listpack_writing() {
+        let long = "x".repeat(200);
+        let longer = "y".repeat(5000);
+        let elements = strings(&[
+            "0",
+            "127",
+            "128",
+            "-1",
+            "70000",
+            "-9000000000",
+            "007",
+            &long,
+            &longer,
+        ]);
+        let listpack = write_listpack(&elements);
+        assert_eq!(listpack_entries(&listpack).unwrap(), elements);
         assert_eq!(
-            parse_string(&[194, 01, 00, 00, 00]).unwrap(),
-            ("16777216".to_string(), 5)
+            u32::from_le_bytes(listpack[..4].try_into().unwrap()) as usize,
+            listpack.len()
         );
+        assert_eq!(listpack[6..8], [0, 1]);
+        // Two bytes of backwards length after the 202 bytes of `long`.
+        assert_eq!(backlen_size(202), 2);
+        assert_eq!(backlen_size(16383), 3);
+
+        // Binary elements are refused, not changed.
+        let mut binary = write_listpack(&strings(&["a"]));
+        binary[7] = 0xFF;
+        let e = listpack_entries(&binary).unwrap_err();
+        assert_eq!(
+            (e.reason.as_str(), e.offset),
+            ("Non-UTF-8 string, unsupported", 6)
+        );
+    }
+
+    #[test]
+    fn test_reading_streams() {
+        let id = |ms, seq| StreamId { ms, seq };
+        // The master entry, then "a", "b" deleted, and an entry with its
+        // own fields.
+        let listpack = write_listpack(&strings(&[
+            "2", "1", "1", "f", "0", "2", "0", "0", "a", "4", "3", "5", "0", "b", "4", "0", "10",
+            "-3", "2", "x", "1", "y", "2", "8",
+        ]));
+        let mut data = vec![1];
+        write_string(&mut data, &id(1000, 3).to_be_bytes());
+        write_string(&mut data, &listpack);
+        for len in [2, 1010, 0, 1] {
+            write_length(&mut data, len);
+        }
+        write_string(&mut data, b"g");
+        data.extend_from_slice(&[0x43, 0xE8, 3, 1]);
+        data.extend_from_slice(&id(1000, 3).to_be_bytes());
+        data.extend_from_slice(&1234_u64.to_le_bytes());
+        data.push(2);
+        data.push(1);
+        write_string(&mut data, b"c");
+        data.extend_from_slice(&99_u64.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(&id(1000, 3).to_be_bytes());
+
+        let EntryValue::Stream(stream) = read(Value::StreamListpacks, &data) else {
+            panic!("not a stream");
+        };
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(f, v)| (f.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            stream.entries,
+            BTreeMap::from([
+                (id(1000, 3), pairs(&[("f", "a")])),
+                (id(1010, 0), pairs(&[("x", "1"), ("y", "2")])),
+            ])
+        );
+        assert_eq!(stream.last_id, id(1010, 0));
+        assert_eq!(stream.first_id, id(1000, 3));
+        assert_eq!(stream.entries_added, 2);
+        let group = &stream.groups[0];
+        assert_eq!(
+            (group.name.as_str(), group.last_id, group.entries_read),
+            ("g", id(1000, 3), None)
+        );
+        assert_eq!(
+            group.pending[&id(1000, 3)],
+            PendingEntry {
+                consumer: "c".to_string(),
+                delivery_time: 1234,
+                delivery_count: 2
+            }
+        );
+        assert_eq!(group.consumers[0].active_time, 99);
+
+        // Written back in the newest stream type, everything survives.
+        let mut stream = stream;
+        stream.max_deleted_id = id(1005, 0);
+        stream.entries_added = 3;
+        stream.groups[0].entries_read = Some(1);
+        stream.groups[0].consumers[0].active_time = 100;
+        stream.groups[0].consumers.push(Consumer {
+            name: "idle".to_string(),
+            ..Default::default()
+        });
+        for i in 0..150 {
+            stream
+                .entries
+                .insert(id(2000 + i, 0), pairs(&[("n", &i.to_string())]));
+        }
+        let mut data = Vec::new();
+        write_stream(&mut data, &stream, true);
+        let EntryValue::Stream(read) = read(Value::StreamListpacks3, &data) else {
+            panic!("not a stream");
+        };
+        assert_eq!(read.entries, stream.entries);
+        assert_eq!(read.max_deleted_id, id(1005, 0));
+        assert_eq!(read.entries_added, 3);
+        assert_eq!(read.groups[0].entries_read, Some(1));
+        assert_eq!(read.groups[0].pending, stream.groups[0].pending);
+        assert_eq!(read.groups[0].consumers, stream.groups[0].consumers);
+
+        // A consumer can't have entries its group doesn't.
+        let mut broken = data.clone();
+        let len = broken.len();
+        broken[len - 1] ^= 1;
+        assert!(try_read(Value::StreamListpacks3, &broken).is_err());
+    }
+
+    #[test]
+    fn test_skipping_modules() {
+        const CHARSET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let module_id = "mytype-01"
+            .chars()
+            .fold(0_u64, |id, c| id << 6 | CHARSET.find(c).unwrap() as u64)
+            << 10
+            | 3;
+
+        let mut data = b"REDIS0011".to_vec();
+        data.extend_from_slice(&[7, 1, b'm']);
+        write_length(&mut data, module_id as usize);
+        data.extend_from_slice(&[2, 5, 5]);
+        write_string(&mut data, b"abc");
+        data.push(4);
+        data.extend_from_slice(&1.5_f64.to_le_bytes());
+        data.extend_from_slice(&[3, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0, 1, b'a', 1, b'b']);
+
+        let mut reader = RdbReader::new(&data[12..]);
+        assert_eq!(
+            skip_module_value(&mut reader).unwrap(),
+            "mytype-01 version 3"
+        );
+        assert_eq!(reader.offset() as usize, data.len() - 12 - 5);
+        let contents = rdb_parser(&finish(data));
+        assert!(!contents.databases[&0].contains_key("m"));
+        assert!(contents.databases[&0].contains_key("a"));
+        assert!(read_record_at(&[6, 1, b'm', 0]).is_err());
+        // Outside of a key, a module value is an error rather than skipped.
+        let mut reader = RdbReader::new(&[0u8][..]);
+        assert!(read_value(&mut reader, Value::Module2).is_err());
+    }
+
+    #[test]
+    fn test_payload() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+
+        let mut out = Vec::new();
+        write_functions(&mut out, ["#!lua name=a\n", &"x".repeat(100)]);
+        let payload = seal_payload(out);
+        let body = open_payload(&payload).unwrap();
+        assert_eq!(
+            parse_functions(body).unwrap(),
+            vec!["#!lua name=a\n".to_string(), "x".repeat(100)]
+        );
+
+        let mut corrupted = payload.clone();
+        corrupted[3] ^= 1;
+        assert!(open_payload(&corrupted).is_err());
+
+        // DUMP of the integer 10 by Redis, RDB version 9.
+        let value = restore_value(b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n").unwrap();
+        assert_eq!(describe(value), "10");
+
+        let value = EntryValue::List(["a".into(), "b".repeat(100)].into());
+        for compression in [false, true] {
+            let payload = dump_value(&value, compression);
+            let restored = restore_value(&payload).unwrap();
+            assert_eq!(describe(restored), describe(value.clone()));
+        }
+        let mut body = vec![Value::List as u8, 1, 1, b'a', 0];
+        let with_extra = seal_payload(body.clone());
+        assert!(matches!(
+            restore_value(&with_extra),
+            Err(Error::InvalidCommand("Bad data format"))
+        ));
+        body.truncate(2);
+        assert!(restore_value(&seal_payload(body)).is_err());
+        assert!(restore_value(&seal_payload(vec![100])).is_err());
+        for module_type in [6, 7] {
+            assert!(matches!(
+                restore_value(&seal_payload(vec![module_type, 0])),
+                Err(Error::InvalidCommand("Bad data format"))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_reading_functions() {
+        let mut data = b"REDIS0011".to_vec();
+        write_functions(
+            &mut data,
+            ["#!lua name=lib\nredis.register_function('f', print)"],
+        );
+        data.extend_from_slice(&[0, 1, b'a', 1, b'b']);
+
+        let contents = rdb_parser(&finish(data));
+        assert_eq!(contents.functions.len(), 1);
+        assert!(contents.functions[0].starts_with("#!lua name=lib"));
+        assert!(contents.databases[&0].contains_key("a"));
+    }
+
+    #[test]
+    fn test_reading_databases() {
+        let mut data = b"REDIS0011".to_vec();
+        data.extend_from_slice(&[0, 1, b'a', 1, b'0']);
+        data.extend_from_slice(&[SELECT_DB, 3, RESIZE_DB, 1, 0, 0, 1, b'a', 1, b'3']);
+        data.extend_from_slice(&[SELECT_DB, 0x40, 100, 0, 1, b'b', 1, b'x']);
+        // Longer lengths, and records that only carry hints.
+        data.extend_from_slice(&[SELECT_DB, 0x80, 0, 1, 0x11, 0x70]);
+        data.extend_from_slice(&[RESIZE_DB, 0x40, 200, 0x80, 0, 0, 1, 0]);
+        data.extend_from_slice(&[IDLE, 0x40, 10, FREQ, 5, 0, 1, b'c', 1, b'y']);
+
+        let contents = rdb_parser(&finish(data));
+        let keys = |db: usize| {
+            let mut keys: Vec<_> = contents.databases[&db].keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(contents.databases.len(), 4);
+        assert_eq!(keys(0), ["a"]);
+        assert_eq!(keys(3), ["a"]);
+        assert_eq!(keys(100), ["b"]);
+        assert_eq!(keys(70_000), ["c"]);
+    }
+
+    #[test]
+    fn test_corrupt_files() {
+        let entries = HashMap::from([(
+            "key".to_string(),
+            Entry::new(
+                EntryValue::List(["a", "b"].map(String::from).into()),
+                Some(60_000),
+                None,
+                Instant::now(),
+            ),
+        )]);
+        let contents = RdbContents {
+            databases: BTreeMap::from([(0, entries)]),
+            functions: vec!["#!lua name=lib\n".to_string()],
+        };
+        let data = write_rdb(&contents, false);
+        assert_eq!(rdb_parser(&data).databases[&0].len(), 1);
+
+        // Cut anywhere, the file is an error rather than a panic.
+        for len in 0..data.len() {
+            let e = parse_rdb_prefix(&data[..len]).unwrap_err();
+            assert_eq!(e.reason, "Unexpected end of file");
+            assert!(e.offset <= len as u64);
+        }
+
+        let mut bad = data.clone();
+        bad[..5].copy_from_slice(b"RELIS");
+        let e = parse_rdb_prefix(&bad).unwrap_err();
+        assert_eq!((e.offset, e.opcode), (0, None));
+        let e = parse_rdb_prefix(b"REDIS0012\xFF").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Can't handle RDB format version 0012 at offset 5"
+        );
+
+        // A changed byte fails the checksum, found after EOF.
+        let mut bad = data.clone();
+        let len = bad.len();
+        bad[len - 12] ^= 1;
+        let e = parse_rdb_prefix(&bad).unwrap_err();
+        assert!(e.reason.starts_with("Wrong RDB checksum"));
+        assert_eq!((e.offset, e.opcode), (len as u64 - 8, Some(EOF)));
+        // Zeros stand for no checksum.
+        bad[len - 8..].fill(0);
+        assert!(parse_rdb_prefix(&bad).is_ok());
+
+        // Errors point at the byte and record they are in.
+        let mut bad = b"REDIS0011".to_vec();
+        bad.extend_from_slice(&[0, 1, b'a', 1, b'b', 8, 1, b'c']);
+        let e = parse_rdb_prefix(&finish(bad)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Unknown value type 8 at offset 14 (opcode 0x08)"
+        );
+        let mut bad = b"REDIS0011".to_vec();
+        bad.extend_from_slice(&[SELECT_DB, 0x80, 0, 0]);
+        let e = parse_rdb_prefix(&bad).unwrap_err();
+        assert_eq!((e.offset, e.opcode), (11, Some(SELECT_DB)));
+        let mut bad = b"REDIS0011".to_vec();
+        bad.push(Value::Intset as u8);
+        write_string(&mut bad, b"k");
+        write_string(&mut bad, &[3, 0, 0, 0, 1, 0, 0, 0, 7, 0, 0]);
+        let e = parse_rdb_prefix(&finish(bad)).unwrap_err();
+        assert_eq!(
+            (e.reason.as_str(), e.offset, e.opcode),
+            ("Invalid intset encoding", 12, Some(Value::Intset as u8))
+        );
+        // Strings that aren't UTF-8 can't be held, and aren't mangled.
+        let mut bad = b"REDIS0011".to_vec();
+        bad.extend_from_slice(&[0, 1, b'k', 2, b'a', 0xFF]);
+        let e = parse_rdb_prefix(&finish(bad)).unwrap_err();
+        assert_eq!(
+            (e.reason.as_str(), e.offset, e.opcode),
+            ("Non-UTF-8 string, unsupported", 12, Some(0))
+        );
+        // A compressed string claiming more than its data can expand to.
+        let mut bad = b"REDIS0011".to_vec();
+        bad.extend_from_slice(&[0, 1, b'k', 0xC3, 1, 0x81]);
+        bad.extend_from_slice(&u64::MAX.to_be_bytes());
+        bad.push(0);
+        let e = parse_rdb_prefix(&finish(bad)).unwrap_err();
+        assert_eq!(
+            (e.reason.as_str(), e.offset, e.opcode),
+            ("Invalid LZF compressed string", 23, Some(0))
+        );
+    }
+
+    #[test]
+    fn test_writing() {
+        let now = Instant::now();
+        let mut zset = crate::sorted_set::SortedSet::new();
+        zset.insert("m".to_string(), 1.5);
+        let entries = HashMap::from([
+            (
+                "str".to_string(),
+                Entry::new(EntryValue::String("-300".to_string()), None, None, now),
+            ),
+            (
+                "exp".to_string(),
+                Entry::new(EntryValue::String("v".to_string()), Some(60_000), None, now),
+            ),
+            (
+                "zset".to_string(),
+                Entry::new(EntryValue::SortedSet(zset), None, None, now),
+            ),
+        ]);
+        let contents = RdbContents {
+            databases: BTreeMap::from([(0, HashMap::new()), (7, entries)]),
+            functions: vec!["#!lua name=lib\n".to_string()],
+        };
+        let data = write_rdb(&contents, false);
+
+        assert!(data.starts_with(b"REDIS0011"));
+        let (body, crc) = data.split_at(data.len() - 8);
+        assert_eq!(crc64(0, body).to_le_bytes(), crc);
+        assert_eq!(body.last(), Some(&EOF));
+        // Empty databases are left out.
+        assert!(!body.windows(2).any(|window| window == [SELECT_DB, 0]));
+
+        let aof_base = |data: &[u8]| {
+            let mut metadata = RdbMetadata::default();
+            read_rdb(&mut RdbReader::new(data), &mut metadata, |_, _, _| Ok(())).unwrap();
+            let aux = metadata.aux.into_iter().find(|(key, _)| key == "aof-base");
+            aux.unwrap().1
+        };
+        assert_eq!(aof_base(&data), "0");
+        assert_eq!(aof_base(&write_aof_preamble(&contents, false)), "1");
+
+        let mut out = Vec::new();
+        write_encoded_string(&mut out, "-300", false);
+        write_encoded_string(&mut out, "007", false);
+        assert_eq!(out, [0xC1, 0xD4, 0xFE, 3, b'0', b'0', b'7']);
+        assert_eq!(parse_string(&out).unwrap(), ("-300".to_string(), 3));
+    }
+
+    #[test]
+    fn test_compression() {
+        // Long strings are compressed when it saves enough.
+        let long = "ab".repeat(50);
+        let mut out = Vec::new();
+        write_encoded_string(&mut out, &long, true);
+        assert_eq!(out[0], 0xC3);
+        assert!(out.len() < long.len());
+        assert_eq!(
+            parse_string(&out).unwrap(),
+            (long.clone(), out.len() as u64)
+        );
+
+        let mut out = Vec::new();
+        write_encoded_string(&mut out, "abcdefghijklmnopqrstuvwxyz", true);
+        assert_eq!(out[0], 26);
+        let mut out = Vec::new();
+        write_encoded_string(&mut out, &long, false);
+        assert_eq!(out[..2], [0x40, 100]);
+
+        let entries = HashMap::from([(
+            long.clone(),
+            Entry::new(
+                EntryValue::List([long.clone()].into()),
+                None,
+                None,
+                Instant::now(),
+            ),
+        )]);
+        let contents = RdbContents {
+            databases: BTreeMap::from([(0, entries)]),
+            functions: Vec::new(),
+        };
+        let data = write_rdb(&contents, true);
+        assert!(data.len() < 2 * long.len());
+        let read = rdb_parser(&data);
+        let EntryValue::List(list) = read.databases[&0][&long].get_value() else {
+            panic!("not a list");
+        };
+        assert_eq!(list[0], long);
+
+        // A compressed string that doesn't expand to its length is refused.
+        let mut corrupt = Vec::new();
+        write_encoded_string(&mut corrupt, &long, true);
+        corrupt[2] = 99;
+        assert!(parse_string(&corrupt).is_err());
     }
 
     #[test]
     fn test_foo() {
-        assert_eq!(Value::try_from(9 as u8).unwrap(), Value::Zipmap);
+        assert_eq!(Value::try_from(9_u8).unwrap(), Value::Zipmap);
     }
 }
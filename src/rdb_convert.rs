@@ -0,0 +1,1050 @@
+//! redis-rdb-convert: turns the keys of an RDB file into JSON lines or into
+//! the commands recreating them, and JSON lines back into an RDB file.
+//! `src/bin/redis-rdb-convert.rs` runs it.
+//!
+//! Each JSON line holds one key:
+//! `{"db":0,"key":"k","type":"list","ttl":1500,"value":["a","b"]}`, with
+//! `ttl` the milliseconds left, or null when the key doesn't expire. Values
+//! are a string, an array of strings for lists and sets, `[member, score]`
+//! pairs for sorted sets, an object for hashes, and an object with the
+//! entries, IDs and consumer groups for streams. Function libraries get a
+//! line of their own, `{"function":"#!lua name=lib ..."}`.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+
+use crate::aof::encode_command;
+use crate::rdb::{self, RdbContents, RdbMetadata, RdbReader};
+use crate::sorted_set::SortedSet;
+use crate::store::{Entry, EntryValue};
+use crate::stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId};
+use crate::Value;
+
+/// Elements added by each command recreating a list, set, sorted set or
+/// hash, as in Redis' AOF rewrite.
+const ITEMS_PER_COMMAND: usize = 64;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "redis-rdb-convert",
+    about = "Converts RDB files to JSON lines or RESP commands, and JSON lines to RDB files"
+)]
+struct ConvertArgs {
+    #[command(subcommand)]
+    conversion: Conversion,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Conversion {
+    /// Prints each key of an RDB file as a line of JSON.
+    Json { rdb: PathBuf },
+    /// Prints the commands recreating the keys of an RDB file, for
+    /// `redis-cli --pipe`.
+    Resp { rdb: PathBuf },
+    /// Writes the keys of a file of JSON lines, `-` for stdin, to an RDB
+    /// file, compressed as with the default `rdbcompression yes`.
+    Rdb { json: PathBuf, rdb: PathBuf },
+}
+
+/// Runs the conversion `args` ask for. Returns the exit status.
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let args = ConvertArgs::parse_from(args);
+    let stdout = io::stdout().lock();
+    let result = match args.conversion {
+        Conversion::Json { rdb } => File::open(&rdb)
+            .map_err(|e| format!("Can't open {}: {}", rdb.display(), e))
+            .and_then(|file| rdb_to_json(BufReader::new(file), BufWriter::new(stdout))),
+        Conversion::Resp { rdb } => File::open(&rdb)
+            .map_err(|e| format!("Can't open {}: {}", rdb.display(), e))
+            .and_then(|file| rdb_to_resp(BufReader::new(file), BufWriter::new(stdout))),
+        Conversion::Rdb { json, rdb } => {
+            let input: io::Result<Box<dyn BufRead>> = match json.to_str() {
+                Some("-") => Ok(Box::new(io::stdin().lock())),
+                _ => File::open(&json).map(|file| Box::new(BufReader::new(file)) as _),
+            };
+            input
+                .map_err(|e| format!("Can't open {}: {}", json.display(), e))
+                .and_then(json_to_rdb)
+                .and_then(|contents| {
+                    std::fs::write(&rdb, rdb::write_rdb(&contents, true))
+                        .map_err(|e| format!("Can't write {}: {}", rdb.display(), e))
+                })
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Writes each key of the RDB data in `input` to `out` as a line of JSON.
+/// Keys that have already expired are left out.
+pub fn rdb_to_json(input: impl Read, mut out: impl Write) -> Result<(), String> {
+    let now = SystemTime::now();
+    let metadata = read_keys(input, |db, key, entry| {
+        let Some(ttl) = ttl(&entry, now) else {
+            return Ok(());
+        };
+        let line = Json::Object(vec![
+            ("db".to_string(), Json::number(db)),
+            ("key".to_string(), Json::string(&key)),
+            (
+                "type".to_string(),
+                Json::string(entry.get_value().type_name()),
+            ),
+            ("ttl".to_string(), ttl.map_or(Json::Null, Json::number)),
+            ("value".to_string(), value_json(entry.get_value())),
+        ]);
+        writeln!(out, "{}", line).map_err(|e| e.to_string())
+    })?;
+    for code in metadata.functions {
+        let line = Json::Object(vec![("function".to_string(), Json::String(code))]);
+        writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+    }
+    out.flush().map_err(|e| e.to_string())
+}
+
+/// Writes the commands recreating the keys of the RDB data in `input` to
+/// `out` in RESP. Keys that have already expired are left out.
+pub fn rdb_to_resp(input: impl Read, mut out: impl Write) -> Result<(), String> {
+    let now = SystemTime::now();
+    let mut selected = None;
+    let metadata = read_keys(input, |db, key, entry| {
+        if ttl(&entry, now).is_none() {
+            return Ok(());
+        }
+        let mut commands = Vec::new();
+        if selected != Some(db) {
+            selected = Some(db);
+            encode_command(&mut commands, &[arg(&db.to_string())], "SELECT");
+        }
+        entry_commands(&mut commands, &key, &entry);
+        out.write_all(&commands).map_err(|e| e.to_string())
+    })?;
+    for code in metadata.functions.iter() {
+        let mut command = Vec::new();
+        encode_command(&mut command, &[arg("LOAD"), arg(code)], "FUNCTION");
+        out.write_all(&command).map_err(|e| e.to_string())?;
+    }
+    out.flush().map_err(|e| e.to_string())
+}
+
+/// Reads the keys and functions of the JSON lines in `input`.
+pub fn json_to_rdb(input: impl BufRead) -> Result<RdbContents, String> {
+    let mut contents = RdbContents::default();
+    let now = Instant::now();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let error = |e: String| format!("Line {}: {}", i + 1, e);
+        let json = Json::parse(&line).map_err(error)?;
+        if let Some(code) = json.get("function") {
+            contents
+                .functions
+                .push(string(code).map_err(error)?.to_string());
+            continue;
+        }
+        let (db, key, entry) = json_entry(&json, now).map_err(error)?;
+        contents.databases.entry(db).or_default().insert(key, entry);
+    }
+    Ok(contents)
+}
+
+/// Reads RDB data through, handing each key to `on_key`.
+fn read_keys(
+    input: impl Read,
+    on_key: impl FnMut(usize, String, Entry) -> Result<(), String>,
+) -> Result<RdbMetadata, String> {
+    let mut metadata = RdbMetadata::default();
+    rdb::read_rdb(&mut RdbReader::new(input), &mut metadata, on_key)
+        .map_err(|e| format!("Can't read the RDB file: {}", e))?;
+    Ok(metadata)
+}
+
+/// Milliseconds `entry` has left, `Some(None)` when it doesn't expire, or
+/// None when it has expired.
+fn ttl(entry: &Entry, now: SystemTime) -> Option<Option<u64>> {
+    match entry.expires_at() {
+        Some(expires_at) => match expires_at.duration_since(now) {
+            Ok(left) if !left.is_zero() => Some(Some(left.as_millis() as u64)),
+            _ => None,
+        },
+        None => Some(None),
+    }
+}
+
+fn arg(s: &str) -> Value {
+    Value::BulkString(Some(s.to_string()))
+}
+
+/// Appends the commands recreating `key`, those Redis' AOF rewrite uses.
+fn entry_commands(out: &mut Vec<u8>, key: &str, entry: &Entry) {
+    // Adds `items` to the key in batches, each after `name key`.
+    let mut batched = |name: &str, items: Vec<Vec<String>>| {
+        for chunk in items.chunks(ITEMS_PER_COMMAND) {
+            let mut args = vec![arg(key)];
+            args.extend(chunk.iter().flatten().map(|s| arg(s)));
+            encode_command(out, &args, name);
+        }
+    };
+    match entry.get_value() {
+        EntryValue::String(value) => encode_command(out, &[arg(key), arg(value)], "SET"),
+        EntryValue::List(list) => {
+            batched("RPUSH", list.iter().map(|e| vec![e.clone()]).collect());
+        }
+        EntryValue::Set(set) => {
+            batched("SADD", set.iter().map(|m| vec![m.clone()]).collect());
+        }
+        EntryValue::SortedSet(zset) => {
+            let pairs = zset
+                .iter()
+                .map(|(m, score)| vec![score.to_string(), m.to_string()]);
+            batched("ZADD", pairs.collect());
+        }
+        EntryValue::Hash(hash) => {
+            batched(
+                "HSET",
+                hash.iter()
+                    .map(|(f, v)| vec![f.clone(), v.clone()])
+                    .collect(),
+            );
+        }
+        EntryValue::Stream(stream) => stream_commands(out, key, stream),
+    }
+    if let Some(expires_at) = entry.expires_at() {
+        let unix_ms = expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        encode_command(out, &[arg(key), arg(&unix_ms.to_string())], "PEXPIREAT");
+    }
+}
+
+fn stream_commands(out: &mut Vec<u8>, key: &str, stream: &Stream) {
+    for (id, fields) in stream.entries.iter() {
+        let mut args = vec![arg(key), arg(&id.to_string())];
+        args.extend(fields.iter().flat_map(|(f, v)| [arg(f), arg(v)]));
+        encode_command(out, &args, "XADD");
+    }
+    if stream.entries.is_empty() {
+        // XADD can't create an empty stream, so add an entry and trim it.
+        let args = ["MAXLEN", "0", &stream.last_id.to_string(), "x", "y"];
+        let args: Vec<_> = [key].iter().chain(args.iter()).map(|s| arg(s)).collect();
+        encode_command(out, &args, "XADD");
+    }
+    let args = [
+        key,
+        &stream.last_id.to_string(),
+        "ENTRIESADDED",
+        &stream.entries_added.to_string(),
+        "MAXDELETEDID",
+        &stream.max_deleted_id.to_string(),
+    ];
+    encode_command(out, &args.map(arg), "XSETID");
+    for group in stream.groups.iter() {
+        let mut args = vec![arg("CREATE"), arg(key), arg(&group.name)];
+        args.push(arg(&group.last_id.to_string()));
+        if let Some(entries_read) = group.entries_read {
+            args.extend([arg("ENTRIESREAD"), arg(&entries_read.to_string())]);
+        }
+        encode_command(out, &args, "XGROUP");
+        for (id, pending) in group.pending.iter() {
+            let args = [
+                key,
+                &group.name,
+                &pending.consumer,
+                "0",
+                &id.to_string(),
+                "TIME",
+                &pending.delivery_time.to_string(),
+                "RETRYCOUNT",
+                &pending.delivery_count.to_string(),
+                "JUSTID",
+                "FORCE",
+            ];
+            encode_command(out, &args.map(arg), "XCLAIM");
+        }
+        let claimed: HashSet<_> = group.pending.values().map(|p| &p.consumer).collect();
+        for consumer in group.consumers.iter() {
+            if !claimed.contains(&consumer.name) {
+                let args = ["CREATECONSUMER", key, &group.name, &consumer.name];
+                encode_command(out, &args.map(arg), "XGROUP");
+            }
+        }
+    }
+}
+
+fn value_json(value: &EntryValue) -> Json {
+    let strings =
+        |items: Vec<&String>| Json::Array(items.into_iter().map(|s| Json::string(s)).collect());
+    match value {
+        EntryValue::String(s) => Json::string(s),
+        EntryValue::List(list) => strings(list.iter().collect()),
+        EntryValue::Set(set) => {
+            let mut members: Vec<_> = set.iter().collect();
+            members.sort();
+            strings(members)
+        }
+        EntryValue::SortedSet(zset) => Json::Array(
+            zset.iter()
+                .map(|(member, score)| Json::Array(vec![Json::string(member), score_json(score)]))
+                .collect(),
+        ),
+        EntryValue::Hash(hash) => {
+            let mut fields: Vec<_> = hash.iter().collect();
+            fields.sort();
+            Json::Object(
+                fields
+                    .into_iter()
+                    .map(|(field, value)| (field.clone(), Json::string(value)))
+                    .collect(),
+            )
+        }
+        EntryValue::Stream(stream) => stream_json(stream),
+    }
+}
+
+/// Scores as numbers, except infinities which JSON has no number for.
+fn score_json(score: f64) -> Json {
+    match score.is_finite() {
+        true => Json::number(score),
+        false => Json::string(&score.to_string()),
+    }
+}
+
+fn id_json(id: StreamId) -> Json {
+    Json::String(id.to_string())
+}
+
+fn stream_json(stream: &Stream) -> Json {
+    let entries = stream.entries.iter().map(|(id, fields)| {
+        let fields = fields
+            .iter()
+            .flat_map(|(f, v)| [Json::string(f), Json::string(v)]);
+        Json::Object(vec![
+            ("id".to_string(), id_json(*id)),
+            ("fields".to_string(), Json::Array(fields.collect())),
+        ])
+    });
+    let groups = stream.groups.iter().map(|group| {
+        let pending = group.pending.iter().map(|(id, pending)| {
+            Json::Object(vec![
+                ("id".to_string(), id_json(*id)),
+                ("consumer".to_string(), Json::string(&pending.consumer)),
+                (
+                    "delivery_time".to_string(),
+                    Json::number(pending.delivery_time),
+                ),
+                (
+                    "delivery_count".to_string(),
+                    Json::number(pending.delivery_count),
+                ),
+            ])
+        });
+        let consumers = group.consumers.iter().map(|consumer| {
+            Json::Object(vec![
+                ("name".to_string(), Json::string(&consumer.name)),
+                ("seen_time".to_string(), Json::number(consumer.seen_time)),
+                (
+                    "active_time".to_string(),
+                    Json::number(consumer.active_time),
+                ),
+            ])
+        });
+        Json::Object(vec![
+            ("name".to_string(), Json::string(&group.name)),
+            ("last_id".to_string(), id_json(group.last_id)),
+            (
+                "entries_read".to_string(),
+                group.entries_read.map_or(Json::Null, Json::number),
+            ),
+            ("pending".to_string(), Json::Array(pending.collect())),
+            ("consumers".to_string(), Json::Array(consumers.collect())),
+        ])
+    });
+    Json::Object(vec![
+        ("entries".to_string(), Json::Array(entries.collect())),
+        ("last_id".to_string(), id_json(stream.last_id)),
+        ("first_id".to_string(), id_json(stream.first_id)),
+        ("max_deleted_id".to_string(), id_json(stream.max_deleted_id)),
+        (
+            "entries_added".to_string(),
+            Json::number(stream.entries_added),
+        ),
+        ("groups".to_string(), Json::Array(groups.collect())),
+    ])
+}
+
+/// The database, key and entry of a JSON line.
+fn json_entry(json: &Json, now: Instant) -> Result<(usize, String, Entry), String> {
+    let db = match json.get("db") {
+        Some(db) => integer(db)? as usize,
+        None => 0,
+    };
+    let key = string(field(json, "key")?)?.to_string();
+    let ttl = match json.get("ttl") {
+        None | Some(Json::Null) => None,
+        Some(ttl) => Some(integer(ttl)?),
+    };
+    let value = field(json, "value")?;
+    let value = match string(field(json, "type")?)? {
+        "string" => EntryValue::String(string(value)?.to_string()),
+        "list" => EntryValue::List(strings(value)?.collect::<Result<VecDeque<_>, _>>()?),
+        "set" => EntryValue::Set(strings(value)?.collect::<Result<HashSet<_>, _>>()?),
+        "zset" => {
+            let mut zset = SortedSet::new();
+            for pair in array(value)? {
+                let [member, score] = array(pair)? else {
+                    return Err("Sorted set members must be [member, score] pairs".to_string());
+                };
+                zset.insert(string(member)?.to_string(), score_value(score)?);
+            }
+            EntryValue::SortedSet(zset)
+        }
+        "hash" => {
+            let Json::Object(fields) = value else {
+                return Err("Hash values must be objects".to_string());
+            };
+            let hash = fields
+                .iter()
+                .map(|(field, value)| Ok((field.clone(), string(value)?.to_string())))
+                .collect::<Result<HashMap<_, _>, String>>()?;
+            EntryValue::Hash(hash)
+        }
+        "stream" => EntryValue::Stream(json_stream(value)?),
+        other => return Err(format!("Unknown type '{}'", other)),
+    };
+    Ok((db, key, Entry::new(value, ttl, None, now)))
+}
+
+fn json_stream(json: &Json) -> Result<Stream, String> {
+    let mut stream = Stream {
+        last_id: id(field(json, "last_id")?)?,
+        first_id: id(field(json, "first_id")?)?,
+        max_deleted_id: id(field(json, "max_deleted_id")?)?,
+        entries_added: integer(field(json, "entries_added")?)?,
+        ..Stream::default()
+    };
+    for entry in array(field(json, "entries")?)? {
+        let fields: Vec<String> = strings(field(entry, "fields")?)?.collect::<Result<_, _>>()?;
+        if !fields.len().is_multiple_of(2) {
+            return Err("Stream entry fields must come in field-value pairs".to_string());
+        }
+        let fields = fields
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()));
+        stream
+            .entries
+            .insert(id(field(entry, "id")?)?, fields.collect());
+    }
+    for group in array(field(json, "groups")?)? {
+        let mut consumer_group = ConsumerGroup {
+            name: string(field(group, "name")?)?.to_string(),
+            last_id: id(field(group, "last_id")?)?,
+            entries_read: match group.get("entries_read") {
+                None | Some(Json::Null) => None,
+                Some(entries_read) => Some(integer(entries_read)?),
+            },
+            ..ConsumerGroup::default()
+        };
+        for pending in array(field(group, "pending")?)? {
+            consumer_group.pending.insert(
+                id(field(pending, "id")?)?,
+                PendingEntry {
+                    consumer: string(field(pending, "consumer")?)?.to_string(),
+                    delivery_time: integer(field(pending, "delivery_time")?)?,
+                    delivery_count: integer(field(pending, "delivery_count")?)?,
+                },
+            );
+        }
+        for consumer in array(field(group, "consumers")?)? {
+            consumer_group.consumers.push(Consumer {
+                name: string(field(consumer, "name")?)?.to_string(),
+                seen_time: integer(field(consumer, "seen_time")?)?,
+                active_time: integer(field(consumer, "active_time")?)?,
+            });
+        }
+        stream.groups.push(consumer_group);
+    }
+    Ok(stream)
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> Result<&'a Json, String> {
+    json.get(key).ok_or_else(|| format!("Missing \"{}\"", key))
+}
+
+fn string(json: &Json) -> Result<&str, String> {
+    match json {
+        Json::String(s) => Ok(s),
+        _ => Err("Expected a string".to_string()),
+    }
+}
+
+fn array(json: &Json) -> Result<&[Json], String> {
+    match json {
+        Json::Array(items) => Ok(items),
+        _ => Err("Expected an array".to_string()),
+    }
+}
+
+fn strings(json: &Json) -> Result<impl Iterator<Item = Result<String, String>> + '_, String> {
+    Ok(array(json)?.iter().map(|s| string(s).map(str::to_string)))
+}
+
+fn integer(json: &Json) -> Result<u64, String> {
+    match json {
+        Json::Number(n) => n
+            .parse()
+            .map_err(|_| format!("Expected an integer, got {}", n)),
+        _ => Err("Expected an integer".to_string()),
+    }
+}
+
+fn score_value(json: &Json) -> Result<f64, String> {
+    let score = match json {
+        Json::Number(n) => n.parse().ok(),
+        Json::String(s) => s.parse().ok(),
+        _ => None,
+    };
+    score
+        .filter(|score: &f64| !score.is_nan())
+        .ok_or_else(|| "Expected a score".to_string())
+}
+
+fn id(json: &Json) -> Result<StreamId, String> {
+    string(json)?.parse()
+}
+
+/// A JSON value, just enough of it for one key per line.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    /// Kept as written, so integers beyond the precision of f64 survive.
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    /// Fields in the order they were written.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn string(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    fn number(n: impl ToString) -> Json {
+        Json::Number(n.to_string())
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = JsonParser { text, pos: 0 };
+        let json = parser.value()?;
+        parser.skip_whitespace();
+        match parser.pos < text.len() {
+            true => Err(parser.error("Unexpected characters after the value")),
+            false => Ok(json),
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => f.write_str(n),
+            Json::String(s) => write_escaped(f, s),
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_escaped(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+struct JsonParser<'a> {
+    text: &'a str,
+    /// Byte offset of the next character.
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, reason: &str) -> String {
+        format!("{} at column {}", reason, self.pos + 1)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Unexpected end of line")),
+        }
+    }
+
+    fn literal(&mut self, word: &str, json: Json) -> Result<Json, String> {
+        match self.text[self.pos..].starts_with(word) {
+            true => {
+                self.pos += word.len();
+                Ok(json)
+            }
+            false => Err(self.error("Unexpected character")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        self.pos += 1;
+        while matches!(
+            self.peek(),
+            Some(b'0'..=b'9' | b'.' | b'e' | b'E' | b'+' | b'-')
+        ) {
+            self.pos += 1;
+        }
+        let number = &self.text[start..self.pos];
+        match number.parse::<f64>() {
+            Ok(_) => Ok(Json::number(number)),
+            Err(_) => {
+                self.pos = start;
+                Err(self.error("Invalid number"))
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("Expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.pos += 1;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("Expected a string key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if self.peek() != Some(b':') {
+                return Err(self.error("Expected ':'"));
+            }
+            self.pos += 1;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("Expected ',' or '}'")),
+            }
+        }
+    }
+
+    /// Reads the string starting at the opening quote.
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let Some(c) = self.text[self.pos..].chars().next() else {
+                return Err(self.error("Unterminated string"));
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self.peek();
+                    self.pos += 1;
+                    s.push(match escape {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => {
+                            self.pos -= 1;
+                            return Err(self.error("Invalid escape"));
+                        }
+                    });
+                }
+                c if c < ' ' => return Err(self.error("Control character in string")),
+                c => s.push(c),
+            }
+        }
+    }
+
+    /// Reads the hex digits of a `\u` escape, and the low surrogate
+    /// following a high one.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xD800..=0xDBFF => {
+                if !self.text[self.pos..].starts_with("\\u") {
+                    return Err(self.error("Unpaired surrogate"));
+                }
+                self.pos += 2;
+                match self.hex4()? {
+                    low @ 0xDC00..=0xDFFF => 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00),
+                    _ => return Err(self.error("Unpaired surrogate")),
+                }
+            }
+            code => code,
+        };
+        char::from_u32(code).ok_or_else(|| self.error("Unpaired surrogate"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("Invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    #[test]
+    fn test_json() {
+        let text = r#"{"a":[1,-2.5e3,true,false,null],"b":"q\"\\\/\né😀","c":{}}"#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(
+            json.get("a"),
+            Some(&Json::Array(vec![
+                Json::number(1),
+                Json::number("-2.5e3"),
+                Json::Bool(true),
+                Json::Bool(false),
+                Json::Null
+            ]))
+        );
+        assert_eq!(json.get("b"), Some(&Json::string("q\"\\/\né😀")));
+        assert_eq!(json.get("c"), Some(&Json::Object(vec![])));
+        assert_eq!(Json::parse(&json.to_string()), Ok(json));
+        assert_eq!(
+            Json::string("\u{1}\t").to_string(),
+            r#""\u0001\t""#.to_string()
+        );
+
+        assert_eq!(
+            Json::parse(r#"{"a" 1}"#),
+            Err("Expected ':' at column 6".to_string())
+        );
+        assert_eq!(
+            Json::parse("[1,]"),
+            Err("Unexpected character at column 4".to_string())
+        );
+        assert_eq!(
+            Json::parse(r#""\ud83d""#),
+            Err("Unpaired surrogate at column 8".to_string())
+        );
+        assert_eq!(
+            Json::parse("1 2"),
+            Err("Unexpected characters after the value at column 3".to_string())
+        );
+        assert!(Json::parse(r#""abc"#).is_err());
+        assert!(Json::parse("-").is_err());
+    }
+
+    fn contents() -> RdbContents {
+        let now = Instant::now();
+        let entry = |value, ttl| Entry::new(value, ttl, None, now);
+        let mut zset = SortedSet::new();
+        zset.insert("one".into(), 1.5);
+        zset.insert("low".into(), f64::NEG_INFINITY);
+        let mut stream = Stream {
+            last_id: StreamId { ms: 5, seq: 1 },
+            first_id: StreamId { ms: 1, seq: 0 },
+            entries_added: 3,
+            ..Stream::default()
+        };
+        stream.entries.insert(
+            StreamId { ms: 1, seq: 0 },
+            vec![("f".into(), "v".into()), ("g".into(), "w".into())],
+        );
+        stream.groups.push(ConsumerGroup {
+            name: "group".into(),
+            last_id: StreamId { ms: 1, seq: 0 },
+            entries_read: Some(1),
+            pending: BTreeMap::from([(
+                StreamId { ms: 1, seq: 0 },
+                PendingEntry {
+                    consumer: "alice".into(),
+                    delivery_time: 1_700_000_000_000,
+                    delivery_count: 2,
+                },
+            )]),
+            consumers: vec![
+                Consumer {
+                    name: "alice".into(),
+                    seen_time: 1,
+                    active_time: 2,
+                },
+                Consumer {
+                    name: "bob".into(),
+                    seen_time: 3,
+                    active_time: 4,
+                },
+            ],
+        });
+        RdbContents {
+            databases: BTreeMap::from([
+                (
+                    0,
+                    HashMap::from([
+                        (
+                            "s".to_string(),
+                            entry(EntryValue::String("a \"b\"\n".into()), Some(60_000)),
+                        ),
+                        (
+                            "l".to_string(),
+                            entry(EntryValue::List(["x".into(), "y".into()].into()), None),
+                        ),
+                        ("z".to_string(), entry(EntryValue::SortedSet(zset), None)),
+                    ]),
+                ),
+                (
+                    3,
+                    HashMap::from([
+                        (
+                            "set".to_string(),
+                            entry(EntryValue::Set(["m".into(), "n".into()].into()), None),
+                        ),
+                        (
+                            "h".to_string(),
+                            entry(EntryValue::Hash([("k".into(), "v".into())].into()), None),
+                        ),
+                        ("st".to_string(), entry(EntryValue::Stream(stream), None)),
+                        (
+                            "gone".to_string(),
+                            Entry::new(
+                                EntryValue::String("x".into()),
+                                None,
+                                Some(UNIX_EPOCH + Duration::from_secs(1)),
+                                now,
+                            ),
+                        ),
+                    ]),
+                ),
+            ]),
+            functions: vec!["#!lua name=lib\nredis.register_function('f', function() end)".into()],
+        }
+    }
+
+    fn describe(contents: &RdbContents) -> Vec<String> {
+        let mut keys: Vec<_> = contents
+            .databases
+            .iter()
+            .flat_map(|(db, entries)| {
+                entries.iter().map(move |(key, entry)| {
+                    let value = value_json(entry.get_value());
+                    format!("{} {} {} {}", db, key, value, entry.expires_at().is_some())
+                })
+            })
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let contents = contents();
+        let data = rdb::write_rdb(&contents, false);
+        let mut lines = Vec::new();
+        rdb_to_json(&data[..], &mut lines).unwrap();
+        let lines = String::from_utf8(lines).unwrap();
+        assert_eq!(lines.lines().count(), 7);
+        assert!(lines.contains(r#"{"db":0,"key":"l","type":"list","ttl":null,"value":["x","y"]}"#));
+        assert!(lines.contains(r#""type":"zset","ttl":null,"value":[["low","-inf"],["one",1.5]]}"#));
+        assert!(lines.contains(r#""key":"s","type":"string","ttl":"#));
+        assert!(!lines.contains(r#""key":"gone""#));
+        assert!(lines
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with(r##"{"function":"#!lua name=lib\nredis"##));
+
+        let loaded = json_to_rdb(lines.as_bytes()).unwrap();
+        assert_eq!(loaded.functions, contents.functions);
+        let mut expected = describe(&contents);
+        expected.retain(|key| !key.starts_with("3 gone "));
+        assert_eq!(describe(&loaded), expected);
+        let ttl = loaded.databases[&0]["s"].expires_at().unwrap();
+        let left = ttl.duration_since(SystemTime::now()).unwrap();
+        assert!(left > Duration::from_secs(50) && left <= Duration::from_secs(60));
+
+        // The RDB written from JSON reads back the same.
+        let data = rdb::write_rdb(&loaded, true);
+        let (read, _) = rdb::parse_rdb_prefix(&data).unwrap();
+        assert_eq!(describe(&read), expected);
+
+        assert_eq!(
+            json_to_rdb(&b"\n{\"key\":\"k\",\"type\":\"list\",\"value\":\"x\"}\n"[..]).err(),
+            Some("Line 2: Expected an array".to_string())
+        );
+        assert_eq!(
+            json_to_rdb(&b"{\"key\":\"k\",\"type\":\"bitmap\",\"value\":1}"[..]).err(),
+            Some("Line 1: Unknown type 'bitmap'".to_string())
+        );
+        assert_eq!(
+            json_to_rdb(&b"{\"type\":\"string\",\"value\":\"x\"}"[..]).err(),
+            Some("Line 1: Missing \"key\"".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resp() {
+        let data = rdb::write_rdb(&contents(), false);
+        let mut out = Vec::new();
+        rdb_to_resp(&data[..], &mut out).unwrap();
+
+        // Split the stream back into commands.
+        let text = String::from_utf8(out).unwrap();
+        let mut commands = Vec::new();
+        let mut lines = text.split("\r\n");
+        while let Some(header) = lines.next().filter(|line| !line.is_empty()) {
+            let argc: usize = header[1..].parse().unwrap();
+            let args: Vec<_> = (0..argc)
+                .map(|_| {
+                    lines.next().unwrap();
+                    lines.next().unwrap()
+                })
+                .collect();
+            commands.push(args.join(" "));
+        }
+
+        assert_eq!(commands[0], "SELECT 0");
+        let db3 = commands.iter().position(|c| c == "SELECT 3").unwrap();
+        let in_db = |db: usize, command: &str| {
+            let position = commands.iter().position(|c| c == command);
+            assert!(position.is_some(), "missing {}", command);
+            assert_eq!(position.unwrap() > db3, db == 3, "{}", command);
+        };
+        in_db(0, "RPUSH l x y");
+        in_db(0, "ZADD z -inf low 1.5 one");
+        in_db(3, "HSET h k v");
+        in_db(3, "XADD st 1-0 f v g w");
+        in_db(3, "XSETID st 5-1 ENTRIESADDED 3 MAXDELETEDID 0-0");
+        in_db(3, "XGROUP CREATE st group 1-0 ENTRIESREAD 1");
+        in_db(
+            3,
+            "XCLAIM st group alice 0 1-0 TIME 1700000000000 RETRYCOUNT 2 JUSTID FORCE",
+        );
+        in_db(3, "XGROUP CREATECONSUMER st group bob");
+        assert!(commands
+            .iter()
+            .any(|c| c == "SADD set m n" || c == "SADD set n m"));
+        let set = commands
+            .iter()
+            .position(|c| c.starts_with("SET s "))
+            .unwrap();
+        assert!(commands[set + 1].starts_with("PEXPIREAT s "));
+        assert!(!commands.iter().any(|c| c.contains("gone")));
+        assert!(commands
+            .last()
+            .unwrap()
+            .starts_with("FUNCTION LOAD #!lua name=lib"));
+    }
+}
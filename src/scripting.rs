@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib, Variadic, VmState};
+
+use crate::config::Config;
+use crate::server::Server;
+use crate::store::Store;
+use crate::{arg_str, Command, Error, Value};
+
+/// How long a script may run before other clients get BUSY replies, as
+/// Redis' busy-reply-threshold.
+const BUSY_REPLY_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Lua instructions between two checks for SCRIPT KILL. The script also
+/// yields there, so other connections keep being served.
+const HOOK_INSTRUCTIONS: u32 = 1000;
+
+const SCRIPT_KILLED: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+/// Hex SHA1 digest of a script, the name EVALSHA knows it by.
+pub fn sha1hex(body: &str) -> String {
+    sha1_smol::Sha1::from(body).digest().to_string()
+}
+
+/// An error reply raised inside a script, by `redis.call` or SCRIPT KILL,
+/// which the script's caller receives as is.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct ReplyError(String);
+
+#[derive(Debug)]
+struct RunningScript {
+    started: Instant,
+    killed: AtomicBool,
+    /// Scripts that already wrote can't be killed without breaking
+    /// atomicity.
+    wrote: AtomicBool,
+}
+
+/// The script cache and the script currently running, if any.
+#[derive(Debug, Default)]
+pub struct Scripting {
+    scripts: Mutex<HashMap<String, String>>,
+    running: Mutex<Option<Arc<RunningScript>>>,
+}
+
+impl Scripting {
+    /// Compiles `body` and caches it, returning its SHA1 digest.
+    pub fn load(&self, body: &str) -> Result<String, Error> {
+        compile(&new_lua()?, body)?;
+        let sha = sha1hex(body);
+        self.cache(&sha, body);
+        Ok(sha)
+    }
+
+    fn cache(&self, sha: &str, body: &str) {
+        let mut scripts = self.scripts.lock().unwrap();
+        scripts.insert(sha.to_string(), body.to_string());
+    }
+
+    pub fn get(&self, sha: &str) -> Option<String> {
+        let scripts = self.scripts.lock().unwrap();
+        scripts.get(&sha.to_lowercase()).cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        let scripts = self.scripts.lock().unwrap();
+        scripts.contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.scripts.lock().unwrap().clear();
+    }
+
+    /// Whether a script has been running for longer than the busy
+    /// threshold, so other clients should be turned away.
+    pub fn is_busy(&self) -> bool {
+        let running = self.running.lock().unwrap();
+        running
+            .as_ref()
+            .is_some_and(|script| script.started.elapsed() >= BUSY_REPLY_THRESHOLD)
+    }
+
+    /// SCRIPT KILL: stops the running script unless it already wrote.
+    pub fn kill(&self) -> Result<(), Error> {
+        let running = self.running.lock().unwrap();
+        let script = running.as_ref().ok_or(Error::NotBusy)?;
+        if script.wrote.load(Ordering::Relaxed) {
+            return Err(Error::Unkillable);
+        }
+        script.killed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn start(&self) -> RunGuard<'_> {
+        let script = Arc::new(RunningScript {
+            started: Instant::now(),
+            killed: AtomicBool::new(false),
+            wrote: AtomicBool::new(false),
+        });
+        *self.running.lock().unwrap() = Some(script.clone());
+        RunGuard {
+            scripting: self,
+            script,
+        }
+    }
+}
+
+/// Marks a script as running for as long as it lives.
+struct RunGuard<'a> {
+    scripting: &'a Scripting,
+    script: Arc<RunningScript>,
+}
+
+impl Drop for RunGuard<'_> {
+    fn drop(&mut self) {
+        *self.scripting.running.lock().unwrap() = None;
+    }
+}
+
+/// A Lua state with only the libraries scripts may use.
+pub(crate) fn new_lua() -> Result<Lua, Error> {
+    let lua = Lua::new_with(
+        StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        LuaOptions::default(),
+    )
+    .map_err(|e| Error::Script(format!("ERR {}", e)))?;
+
+    let setup = || -> mlua::Result<()> {
+        let globals = lua.globals();
+        globals.set("dofile", mlua::Value::Nil)?;
+        globals.set("loadfile", mlua::Value::Nil)?;
+        // Redis embeds Lua 5.1, where unpack is still a global.
+        let table: mlua::Table = globals.get("table")?;
+        globals.set("unpack", table.get::<mlua::Function>("unpack")?)?;
+        Ok(())
+    };
+    setup().map_err(|e| Error::Script(format!("ERR {}", e)))?;
+    Ok(lua)
+}
+
+fn compile(lua: &Lua, body: &str) -> Result<mlua::Function, Error> {
+    lua.load(body)
+        .set_name("@user_script")
+        .into_function()
+        .map_err(|e| {
+            let message = match e {
+                mlua::Error::SyntaxError { message, .. } => message,
+                e => e.to_string(),
+            };
+            Error::Script(format!(
+                "ERR Error compiling script (new function): {}",
+                message
+            ))
+        })
+}
+
+/// Splits `numkeys [key ...] [arg ...]`, as EVAL and FCALL take them.
+pub(crate) fn split_keys(args: &[Value]) -> Result<(Vec<&str>, Vec<&str>), Error> {
+    let numkeys = arg_str(&args[0])?
+        .parse::<i64>()
+        .map_err(|_| Error::InvalidCommand("value is not an integer or out of range"))?;
+    if numkeys < 0 {
+        return Err(Error::InvalidCommand("Number of keys can't be negative"));
+    }
+    let args = &args[1..];
+    if numkeys as usize > args.len() {
+        return Err(Error::InvalidCommand(
+            "Number of keys can't be greater than number of args",
+        ));
+    }
+    let (keys, argv) = args.split_at(numkeys as usize);
+    let keys = keys.iter().map(arg_str).collect::<Result<Vec<_>, _>>()?;
+    let argv = argv.iter().map(arg_str).collect::<Result<Vec<_>, _>>()?;
+    Ok((keys, argv))
+}
+
+/// EVAL script numkeys [key ...] [arg ...], or EVALSHA with `by_sha`.
+pub async fn eval(
+    request_content: &[Value],
+    store: Arc<Store>,
+    server: &Arc<Server>,
+    config: &Config,
+    by_sha: bool,
+) -> Result<Value, Error> {
+    let script = arg_str(&request_content[1])?;
+    let (keys, argv) = split_keys(&request_content[2..])?;
+
+    let (sha, body) = if by_sha {
+        let body = server.scripting.get(script).ok_or(Error::NoScript)?;
+        (script.to_lowercase(), body)
+    } else {
+        (sha1hex(script), script.to_string())
+    };
+
+    let lua = new_lua()?;
+    let function = compile(&lua, &body)?;
+    if !by_sha {
+        server.scripting.cache(&sha, &body);
+    }
+
+    let globals = lua.globals();
+    let setup = || -> mlua::Result<()> {
+        globals.set("KEYS", lua.create_sequence_from(keys)?)?;
+        globals.set("ARGV", lua.create_sequence_from(argv)?)
+    };
+    setup().map_err(|e| Error::Script(format!("ERR {}", e)))?;
+
+    let args = mlua::MultiValue::new();
+    run(&lua, function, args, store, server, config, false, &sha).await
+}
+
+/// Runs `function` with the `redis` library at hand, as the running script
+/// that SCRIPT KILL and FUNCTION KILL stop. Errors name the script `name`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run(
+    lua: &Lua,
+    function: mlua::Function,
+    args: mlua::MultiValue,
+    store: Arc<Store>,
+    server: &Arc<Server>,
+    config: &Config,
+    read_only: bool,
+    name: &str,
+) -> Result<Value, Error> {
+    let running = server.scripting.start();
+    let setup = || -> mlua::Result<()> {
+        let redis = redis_lib(
+            lua,
+            store,
+            server.clone(),
+            config.clone(),
+            running.script.clone(),
+            read_only,
+        )?;
+        lua.globals().set("redis", redis)?;
+
+        let script = running.script.clone();
+        lua.set_global_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_, _| {
+                if script.killed.load(Ordering::Relaxed) {
+                    return Err(mlua::Error::external(ReplyError(SCRIPT_KILLED.to_string())));
+                }
+                Ok(VmState::Yield)
+            },
+        )
+    };
+    setup().map_err(|e| Error::Script(format!("ERR {}", e)))?;
+
+    // The script's writes are logged to the AOF inside MULTI ... EXEC.
+    server.aof.begin_atomic().await;
+    let result = function.call_async::<mlua::Value>(args).await;
+    server.aof.end_atomic().await;
+    match result {
+        Ok(value) => Ok(lua_to_resp(value)),
+        Err(e) => Err(script_error(e, name)),
+    }
+}
+
+/// The `redis` table scripts use to reach the server.
+fn redis_lib(
+    lua: &Lua,
+    store: Arc<Store>,
+    server: Arc<Server>,
+    config: Config,
+    script: Arc<RunningScript>,
+    read_only: bool,
+) -> mlua::Result<mlua::Table> {
+    let redis = lua.create_table()?;
+
+    // redis.call raises command errors, redis.pcall returns them.
+    for (name, raise) in [("call", true), ("pcall", false)] {
+        let (store, server, config, script) = (
+            store.clone(),
+            server.clone(),
+            config.clone(),
+            script.clone(),
+        );
+        let call = lua.create_async_function(move |lua, args: Variadic<mlua::Value>| {
+            let (store, server, config, script) = (
+                store.clone(),
+                server.clone(),
+                config.clone(),
+                script.clone(),
+            );
+            async move {
+                let reply = match command_args(args) {
+                    Ok(data) => {
+                        run_command(data, store, &server, &config, &script, read_only).await
+                    }
+                    Err(reply) => reply,
+                };
+                match reply {
+                    Value::Error(msg) if raise => Err(mlua::Error::external(ReplyError(msg))),
+                    reply => resp_to_lua(&lua, reply),
+                }
+            }
+        })?;
+        redis.set(name, call)?;
+    }
+
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_, body: mlua::LuaString| Ok(sha1hex(&body.to_str()?)))?,
+    )?;
+    redis.set(
+        "error_reply",
+        lua.create_function(|lua, msg: mlua::LuaString| {
+            let reply = lua.create_table()?;
+            reply.set("err", msg)?;
+            Ok(reply)
+        })?,
+    )?;
+    redis.set(
+        "status_reply",
+        lua.create_function(|lua, msg: mlua::LuaString| {
+            let reply = lua.create_table()?;
+            reply.set("ok", msg)?;
+            Ok(reply)
+        })?,
+    )?;
+    add_log(lua, &redis)?;
+
+    Ok(redis)
+}
+
+/// `redis.log` and its levels, which function libraries can use while
+/// loading too.
+pub(crate) fn add_log(lua: &Lua, redis: &mlua::Table) -> mlua::Result<()> {
+    redis.set(
+        "log",
+        lua.create_function(|_, (level, msgs): (i64, Variadic<String>)| {
+            eprintln!("[script:{}] {}", level, msgs.join(" "));
+            Ok(())
+        })?,
+    )?;
+    for (name, level) in [
+        ("LOG_DEBUG", 0),
+        ("LOG_VERBOSE", 1),
+        ("LOG_NOTICE", 2),
+        ("LOG_WARNING", 3),
+    ] {
+        redis.set(name, level)?;
+    }
+    Ok(())
+}
+
+/// Arguments of `redis.call`, or the error reply for bad ones.
+fn command_args(args: Variadic<mlua::Value>) -> Result<Vec<Value>, Value> {
+    if args.is_empty() {
+        return Err(Value::Error(
+            "ERR Please specify at least one argument for this redis lib call".to_string(),
+        ));
+    }
+    args.iter()
+        .map(|arg| {
+            let arg = match arg {
+                mlua::Value::String(s) => return Ok(lua_string_to_resp(s)),
+                mlua::Value::Integer(i) => Some(i.to_string()),
+                mlua::Value::Number(n) if n.fract() == 0.0 => Some((*n as i64).to_string()),
+                mlua::Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            };
+            arg.map(|arg| Value::BulkString(Some(arg)))
+                .ok_or(Value::Error(
+                    "ERR Lua redis lib command arguments must be strings or integers".to_string(),
+                ))
+        })
+        .collect()
+}
+
+async fn run_command(
+    data: Vec<Value>,
+    store: Arc<Store>,
+    server: &Arc<Server>,
+    config: &Config,
+    script: &RunningScript,
+    read_only: bool,
+) -> Value {
+    let Ok(command) = arg_str(&data[0]).and_then(Command::from_str) else {
+        return Value::Error("ERR Unknown Redis command called from script".to_string());
+    };
+    if !command.allowed_in_script() {
+        return Value::Error("ERR This Redis command is not allowed from script".to_string());
+    }
+    if command.is_write(&data) && read_only {
+        return Value::Error(
+            "ERR Write commands are not allowed from read-only scripts.".to_string(),
+        );
+    }
+    if command.is_write(&data) {
+        script.wrote.store(true, Ordering::Relaxed);
+    }
+
+    let result = match command.check_arity(&data) {
+        Ok(()) => construct_response_boxed(&command, data, store, server, config).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(reply) => reply,
+        Err(e) => e
+            .to_reply()
+            .unwrap_or_else(|| Value::Error(format!("ERR {}", e))),
+    }
+}
+
+/// Boxed, as scripts themselves run from inside `construct_response`.
+fn construct_response_boxed<'a>(
+    command: &'a Command,
+    data: Vec<Value>,
+    store: Arc<Store>,
+    server: &'a Arc<Server>,
+    config: &'a Config,
+) -> Pin<Box<dyn Future<Output = Result<Value, Error>> + Send + 'a>> {
+    Box::pin(command.construct_response(data, store, server, config))
+}
+
+fn script_error(error: mlua::Error, name: &str) -> Error {
+    if let Some(ReplyError(msg)) = error.downcast_ref::<ReplyError>() {
+        return Error::Script(msg.clone());
+    }
+    let message = error_message(&error);
+    Error::Script(format!("ERR {} script: {}", message, name))
+}
+
+/// The message of the Lua error behind `error`, without a traceback.
+pub(crate) fn error_message(mut error: &mlua::Error) -> String {
+    while let mlua::Error::CallbackError { cause, .. } = error {
+        error = cause;
+    }
+    let message = match error {
+        mlua::Error::RuntimeError(message) => message.clone(),
+        error => error.to_string(),
+    };
+    message
+        .split("\nstack traceback:")
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Lua strings are byte strings, so not every one is valid UTF-8.
+fn lua_string_to_resp(s: &mlua::LuaString) -> Value {
+    match s.to_str() {
+        Ok(s) => Value::BulkString(Some(s.to_string())),
+        Err(_) => Value::Binary(s.as_bytes().to_vec()),
+    }
+}
+
+/// Converts a command reply for Lua: nil replies become false, status and
+/// error replies become tables with a single `ok` or `err` field.
+fn resp_to_lua(lua: &Lua, value: Value) -> mlua::Result<mlua::Value> {
+    let single_field = |field: &str, msg: String| -> mlua::Result<mlua::Value> {
+        let table = lua.create_table()?;
+        table.set(field, msg)?;
+        Ok(mlua::Value::Table(table))
+    };
+    match value {
+        Value::Integer(i) => Ok(mlua::Value::Integer(i)),
+        Value::BulkString(Some(s)) => Ok(mlua::Value::String(lua.create_string(s)?)),
+        Value::Binary(bytes) => Ok(mlua::Value::String(lua.create_string(bytes)?)),
+        Value::BulkString(None) | Value::NullArray | Value::None => Ok(mlua::Value::Boolean(false)),
+        Value::SimpleString(s) => single_field("ok", s),
+        Value::Error(s) => single_field("err", s),
+        Value::Array(items) | Value::Push(items) => {
+            let items = items
+                .into_iter()
+                .map(|item| resp_to_lua(lua, item))
+                .collect::<mlua::Result<Vec<_>>>()?;
+            Ok(mlua::Value::Table(lua.create_sequence_from(items)?))
+        }
+        Value::Map(pairs) => resp_to_lua(
+            lua,
+            Value::Array(pairs.into_iter().flat_map(|(k, v)| [k, v]).collect()),
+        ),
+    }
+}
+
+/// Converts a script's result to a reply: numbers are truncated to
+/// integers, tables are read as arrays up to their first nil, unless they
+/// carry an `err` or `ok` field.
+fn lua_to_resp(value: mlua::Value) -> Value {
+    match value {
+        mlua::Value::Boolean(true) => Value::Integer(1),
+        mlua::Value::Integer(i) => Value::Integer(i),
+        mlua::Value::Number(n) => Value::Integer(n as i64),
+        mlua::Value::String(s) => lua_string_to_resp(&s),
+        mlua::Value::Table(table) => {
+            if let Ok(mlua::Value::String(err)) = table.raw_get("err") {
+                return Value::Error(err.to_string_lossy());
+            }
+            if let Ok(mlua::Value::String(ok)) = table.raw_get("ok") {
+                return Value::SimpleString(ok.to_string_lossy());
+            }
+            let mut items = Vec::new();
+            for i in 1.. {
+                match table.raw_get(i) {
+                    Ok(mlua::Value::Nil) | Err(_) => break,
+                    Ok(item) => items.push(lua_to_resp(item)),
+                }
+            }
+            Value::Array(items)
+        }
+        _ => Value::BulkString(None),
+    }
+}
+
+/// SCRIPT LOAD | EXISTS | FLUSH | KILL.
+pub fn script(request_content: &[Value], scripting: &Scripting) -> Result<Value, Error> {
+    let subcommand = arg_str(&request_content[1])?.to_lowercase();
+    let args = &request_content[2..];
+
+    match subcommand.as_str() {
+        "load" if args.len() == 1 => {
+            let sha = scripting.load(arg_str(&args[0])?)?;
+            Ok(Value::BulkString(Some(sha)))
+        }
+        "exists" if !args.is_empty() => {
+            let found = args
+                .iter()
+                .map(|sha| Ok(Value::Integer(scripting.exists(arg_str(sha)?) as i64)))
+                .collect::<Result<_, Error>>()?;
+            Ok(Value::Array(found))
+        }
+        "flush" if args.len() <= 1 => {
+            if let Some(mode) = args.first() {
+                let mode = arg_str(mode)?.to_lowercase();
+                if mode != "sync" && mode != "async" {
+                    return Err(Error::InvalidCommand(
+                        "SCRIPT FLUSH only support SYNC|ASYNC option",
+                    ));
+                }
+            }
+            scripting.flush();
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        "kill" if args.is_empty() => {
+            scripting.kill()?;
+            Ok(Value::SimpleString("OK".to_string()))
+        }
+        _ => Err(Error::InvalidCommand(
+            "unknown subcommand or wrong number of arguments for 'script' command",
+        )),
+    }
+}
@@ -1,16 +1,29 @@
-use crate::ARRAY_PREFIX;
-use crate::{Value, BULK_STRING_PREFIX, CRLF, INTEGER_PREFIX, SIMPLE_STRING_PREFIX};
+use crate::{
+    Value, ARRAY_PREFIX, BULK_STRING_PREFIX, CRLF, INTEGER_PREFIX, MAP_PREFIX, NULL_PREFIX,
+    PUSH_PREFIX, SIMPLE_ERROR_PREFIX, SIMPLE_STRING_PREFIX,
+};
+use std::future::Future;
 use std::io;
+use std::pin::Pin;
 use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt;
 
 pub struct StreamSerializer<S> {
     stream: S,
+    /// RESP version the peer speaks, 2 or 3.
+    protocol: u8,
 }
 
 impl<S> StreamSerializer<S> {
     pub fn new(stream: S) -> Self {
-        Self { stream }
+        Self {
+            stream,
+            protocol: 2,
+        }
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.protocol = protocol;
     }
 
     pub fn into_inner(self) -> S {
@@ -20,7 +33,7 @@ impl<S> StreamSerializer<S> {
 
 impl<S> StreamSerializer<S>
 where
-    S: AsyncWrite + Unpin,
+    S: AsyncWrite + Unpin + Send,
 {
     pub async fn send_term(&mut self) -> io::Result<()> {
         self.stream.write_all(CRLF.as_bytes()).await
@@ -33,8 +46,15 @@ where
         Ok(())
     }
 
-    async fn write_bulk_string(&mut self, s: String) -> io::Result<()> {
-        let content_bytes = s.as_bytes();
+    async fn write_error(&mut self, s: String) -> io::Result<()> {
+        self.stream.write_u8(SIMPLE_ERROR_PREFIX as u8).await?;
+        self.stream.write_all(s.as_bytes()).await?;
+        self.send_term().await?;
+        Ok(())
+    }
+
+    async fn write_bulk_string(&mut self, s: impl AsRef<[u8]>) -> io::Result<()> {
+        let content_bytes = s.as_ref();
         let content_bytes_len = content_bytes.len();
 
         self.stream.write_u8(BULK_STRING_PREFIX as u8).await?;
@@ -48,6 +68,9 @@ where
     }
 
     async fn write_empty_bulk_string(&mut self) -> io::Result<()> {
+        if self.protocol == 3 {
+            return self.write_null().await;
+        }
         self.stream.write_u8(BULK_STRING_PREFIX as u8).await?;
         let content = "-1";
         self.stream.write_all(content.as_bytes()).await?;
@@ -55,48 +78,87 @@ where
         Ok(())
     }
 
+    async fn write_null(&mut self) -> io::Result<()> {
+        self.stream.write_u8(NULL_PREFIX as u8).await?;
+        self.send_term().await
+    }
+
+    async fn write_aggregate_header(&mut self, prefix: char, len: usize) -> io::Result<()> {
+        self.stream.write_u8(prefix as u8).await?;
+        self.stream.write_all(len.to_string().as_bytes()).await?;
+        self.send_term().await
+    }
+
     async fn write_integer(&mut self, n: i64) -> io::Result<()> {
         self.stream.write_u8(INTEGER_PREFIX as u8).await?;
-        self.stream.write_i64(n).await?;
+        self.stream.write_all(n.to_string().as_bytes()).await?;
         self.send_term().await?;
         Ok(())
     }
 
     pub async fn write(&mut self, value: Value) -> io::Result<()> {
-        match value {
-            Value::SimpleString(s) => self.write_simple_string(s).await?,
-            Value::BulkString(opt_s) => match opt_s {
-                Some(s) => self.write_bulk_string(s).await?,
-                None => self.write_empty_bulk_string().await?,
-            },
-            Value::Integer(n) => self.write_integer(n).await?,
-            Value::Array(elements) => {
-                // * {len} CRLF [ <VALUE> CRLF ] ...
-                self.stream.write_u8(ARRAY_PREFIX as u8).await?;
-
-                self.stream
-                    .write_all(format!("{}", elements.len()).as_bytes())
-                    .await?;
-
-                self.send_term().await?;
-
-                for element in elements.into_iter() {
-                    match element {
-                        Value::BulkString(opt_s) => match opt_s {
-                            Some(s) => self.write_bulk_string(s).await?,
-                            None => self.write_empty_bulk_string().await?,
-                        },
-                        Value::SimpleString(s) => self.write_simple_string(s).await?,
-                        Value::Integer(n) => self.write_integer(n).await?,
-                        _ => panic!("Hasnt been implemented"),
+        self.write_value(value).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    // Boxed so that arrays can recurse into nested arrays.
+    fn write_value(
+        &mut self,
+        value: Value,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            match value {
+                Value::SimpleString(s) => self.write_simple_string(s).await?,
+                Value::BulkString(opt_s) => match opt_s {
+                    Some(s) => self.write_bulk_string(s).await?,
+                    None => self.write_empty_bulk_string().await?,
+                },
+                Value::Binary(bytes) => self.write_bulk_string(bytes).await?,
+                Value::Integer(n) => self.write_integer(n).await?,
+                Value::Error(s) => self.write_error(s).await?,
+                Value::NullArray if self.protocol == 3 => self.write_null().await?,
+                Value::NullArray => {
+                    self.stream.write_all(b"*-1").await?;
+                    self.send_term().await?;
+                }
+                Value::Array(elements) => {
+                    // * {len} CRLF [ <VALUE> CRLF ] ...
+                    self.write_aggregate_header(ARRAY_PREFIX, elements.len())
+                        .await?;
+                    for element in elements.into_iter() {
+                        self.write_value(element).await?;
+                    }
+                }
+                Value::Push(elements) => {
+                    let prefix = if self.protocol == 3 {
+                        PUSH_PREFIX
+                    } else {
+                        ARRAY_PREFIX
+                    };
+                    self.write_aggregate_header(prefix, elements.len()).await?;
+                    for element in elements.into_iter() {
+                        self.write_value(element).await?;
                     }
                 }
+                // RESP2 has no maps, so pairs are flattened into an array.
+                Value::Map(pairs) => {
+                    if self.protocol == 3 {
+                        self.write_aggregate_header(MAP_PREFIX, pairs.len()).await?;
+                    } else {
+                        self.write_aggregate_header(ARRAY_PREFIX, pairs.len() * 2)
+                            .await?;
+                    }
+                    for (key, value) in pairs.into_iter() {
+                        self.write_value(key).await?;
+                        self.write_value(value).await?;
+                    }
+                }
+                _ => todo!(),
             }
-            _ => todo!(),
-        }
 
-        self.stream.flush().await?;
-
-        Ok(())
+            Ok(())
+        })
     }
 }
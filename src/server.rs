@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use crate::aof::Aof;
+use crate::functions::Functions;
+use crate::persistence::Persistence;
+use crate::pubsub::PubSub;
+use crate::scripting::Scripting;
+use crate::store::Databases;
+
+/// Server-wide state shared by every connection, apart from the
+/// configuration.
+#[derive(Debug)]
+pub struct Server {
+    pub databases: Databases,
+    pub pubsub: Arc<PubSub>,
+    pub scripting: Scripting,
+    pub functions: Functions,
+    pub persistence: Persistence,
+    pub aof: Aof,
+}
+
+impl Server {
+    pub fn new(databases: Databases, pubsub: Arc<PubSub>) -> Self {
+        Self {
+            databases,
+            pubsub,
+            scripting: Scripting::default(),
+            functions: Functions::default(),
+            persistence: Persistence::default(),
+            aof: Aof::default(),
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::notify::NOTIFY_LIST;
+use crate::store::{EntryValue, KeyspaceView, Store};
+use crate::{arg_str, Error, Value};
+
+#[derive(Debug, Default)]
+struct SortOptions<'a> {
+    by: Option<&'a str>,
+    /// `BY` given without a `*`, meaning "keep the natural order".
+    dont_sort: bool,
+    limit: Option<(i64, i64)>,
+    get: Vec<&'a str>,
+    desc: bool,
+    alpha: bool,
+    store: Option<&'a str>,
+}
+
+fn parse_options(args: &[Value], read_only: bool) -> Result<SortOptions<'_>, Error> {
+    let mut options = SortOptions::default();
+
+    let mut idx = 0;
+    while idx < args.len() {
+        let remaining = args.len() - idx - 1;
+        match arg_str(&args[idx])?.to_lowercase().as_str() {
+            "asc" => options.desc = false,
+            "desc" => options.desc = true,
+            "alpha" => options.alpha = true,
+            "limit" if remaining >= 2 => {
+                let parse = |v: &Value| {
+                    arg_str(v)?.parse::<i64>().map_err(|_| {
+                        Error::InvalidCommand("value is not an integer or out of range")
+                    })
+                };
+                options.limit = Some((parse(&args[idx + 1])?, parse(&args[idx + 2])?));
+                idx += 2;
+            }
+            "store" if remaining >= 1 && !read_only => {
+                options.store = Some(arg_str(&args[idx + 1])?);
+                idx += 1;
+            }
+            "by" if remaining >= 1 => {
+                let pattern = arg_str(&args[idx + 1])?;
+                // Without a `*` every element maps to the same key, so
+                // there is nothing to sort by.
+                options.dont_sort = !pattern.contains('*');
+                options.by = Some(pattern);
+                idx += 1;
+            }
+            "get" if remaining >= 1 => {
+                options.get.push(arg_str(&args[idx + 1])?);
+                idx += 1;
+            }
+            _ => return Err(Error::InvalidCommand("syntax error")),
+        }
+        idx += 1;
+    }
+
+    Ok(options)
+}
+
+/// Resolves a BY / GET pattern for `element`: the first `*` is replaced by
+/// the element, and a trailing `->field` reads that field from a hash.
+/// `#` stands for the element itself.
+fn lookup_by_pattern(view: &KeyspaceView, pattern: &str, element: &str) -> Option<String> {
+    if pattern == "#" {
+        return Some(element.to_string());
+    }
+
+    let star = pattern.find('*')?;
+    let (key_pattern, field) = match pattern[star + 1..].find("->") {
+        Some(arrow) if star + 1 + arrow + 2 < pattern.len() => (
+            &pattern[..star + 1 + arrow],
+            Some(&pattern[star + 1 + arrow + 2..]),
+        ),
+        _ => (pattern, None),
+    };
+    let key = key_pattern.replacen('*', element, 1);
+
+    match (view.get(&key)?, field) {
+        (EntryValue::String(value), None) => Some(value.clone()),
+        (EntryValue::Hash(hash), Some(field)) => hash.get(field).cloned(),
+        _ => None,
+    }
+}
+
+/// What an element is compared by.
+#[derive(Debug)]
+enum SortKey {
+    Score(f64),
+    Alpha(Option<String>),
+}
+
+fn compare(a: &(String, SortKey), b: &(String, SortKey)) -> Ordering {
+    let ordering = match (&a.1, &b.1) {
+        (SortKey::Score(x), SortKey::Score(y)) => x.partial_cmp(y).unwrap_or(Ordering::Equal),
+        // Missing BY values sort first.
+        (SortKey::Alpha(x), SortKey::Alpha(y)) => x.cmp(y),
+        _ => Ordering::Equal,
+    };
+
+    // Ties are broken by the element itself so the output is deterministic.
+    ordering.then_with(|| a.0.cmp(&b.0))
+}
+
+/// Produces the elements of the value at `key`, already sorted and limited,
+/// followed by the GET lookups for each of them.
+fn sort_elements(
+    view: &KeyspaceView,
+    key: &str,
+    options: &SortOptions,
+) -> Result<Vec<Option<String>>, Error> {
+    let mut dont_sort = options.dont_sort;
+    let mut alpha = options.alpha;
+    let mut by = options.by;
+
+    let elements: Vec<String> = match view.get(key) {
+        None => Vec::new(),
+        Some(EntryValue::List(list)) => list.iter().cloned().collect(),
+        Some(EntryValue::Set(set)) => {
+            // Sets have no natural order, so a stored result would depend
+            // on hashing. Fall back to a lexicographic sort instead.
+            if dont_sort && options.store.is_some() {
+                dont_sort = false;
+                alpha = true;
+                by = None;
+            }
+            set.iter().cloned().collect()
+        }
+        Some(EntryValue::SortedSet(zset)) => zset.iter().map(|(m, _)| m.to_string()).collect(),
+        Some(_) => return Err(Error::WrongType),
+    };
+
+    let mut elements = if dont_sort {
+        // Natural order of lists and sorted sets, reversed for DESC.
+        let mut elements = elements;
+        if options.desc && matches!(view.get(key), Some(EntryValue::SortedSet(_))) {
+            elements.reverse();
+        }
+        elements
+    } else {
+        let mut keyed = elements
+            .into_iter()
+            .map(|element| {
+                let value = match by {
+                    Some(pattern) => lookup_by_pattern(view, pattern, &element),
+                    None => Some(element.clone()),
+                };
+
+                let sort_key = if alpha {
+                    SortKey::Alpha(value)
+                } else {
+                    let score = match value {
+                        Some(v) => v.trim().parse::<f64>().ok().filter(|f| !f.is_nan()).ok_or(
+                            Error::InvalidCommand(
+                                "One or more scores can't be converted into double",
+                            ),
+                        )?,
+                        None => 0.0,
+                    };
+                    SortKey::Score(score)
+                };
+                Ok((element, sort_key))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        keyed.sort_by(|a, b| {
+            let ordering = compare(a, b);
+            if options.desc {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+        keyed.into_iter().map(|(element, _)| element).collect()
+    };
+
+    if let Some((offset, count)) = options.limit {
+        let len = elements.len() as i64;
+        let start = offset.clamp(0, len);
+        let end = if count < 0 {
+            len
+        } else {
+            (start + count).min(len)
+        };
+        elements = elements.drain(start as usize..end as usize).collect();
+    }
+
+    if options.get.is_empty() {
+        return Ok(elements.into_iter().map(Some).collect());
+    }
+
+    Ok(elements
+        .iter()
+        .flat_map(|element| {
+            options
+                .get
+                .iter()
+                .map(move |pattern| lookup_by_pattern(view, pattern, element))
+        })
+        .collect())
+}
+
+/// Whether a SORT call stores its result, which is what makes it a write.
+pub fn stores(request_content: &[Value]) -> bool {
+    request_content.len() >= 2
+        && parse_options(&request_content[2..], false).is_ok_and(|options| options.store.is_some())
+}
+
+/// SORT key [BY pattern] [LIMIT offset count] [GET pattern [GET pattern ...]]
+///   [ASC | DESC] [ALPHA] [STORE destination]
+///
+/// SORT_RO takes the same options except STORE.
+pub async fn sort(
+    request_content: &[Value],
+    store: &Store,
+    read_only: bool,
+) -> Result<Value, Error> {
+    if request_content.len() < 2 {
+        return Err(Error::InvalidCommand(
+            "wrong number of arguments for 'sort' command",
+        ));
+    }
+    let key = arg_str(&request_content[1])?;
+    let options = parse_options(&request_content[2..], read_only)?;
+
+    let now = Instant::now();
+    match options.store {
+        // Sorted and stored under one lock, so the source can't change in
+        // between.
+        Some(destination) => {
+            let (count, stored) = store
+                .store_computed(destination, now, |view| {
+                    let list: VecDeque<String> = sort_elements(view, key, &options)?
+                        .into_iter()
+                        .map(Option::unwrap_or_default)
+                        .collect();
+                    let count = list.len() as i64;
+                    Ok((EntryValue::List(list), count))
+                })
+                .await?;
+            if stored {
+                store.notify(NOTIFY_LIST, "sortstore", destination);
+            }
+            Ok(Value::Integer(count))
+        }
+        None => {
+            let result = store
+                .view(now, |view| sort_elements(view, key, &options))
+                .await?;
+            Ok(Value::Array(
+                result.into_iter().map(Value::BulkString).collect(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::future::Future;
+
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::sorted_set::SortedSet;
+
+    fn run_async_tests<F: Future>(f: F) {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(f);
+    }
+
+    fn args(s: &str) -> Vec<Value> {
+        s.split_whitespace()
+            .map(|a| Value::BulkString(Some(a.to_string())))
+            .collect()
+    }
+
+    fn bulk(items: &[&str]) -> Value {
+        Value::Array(
+            items
+                .iter()
+                .map(|s| Value::BulkString(Some(s.to_string())))
+                .collect(),
+        )
+    }
+
+    async fn setup() -> Store {
+        let store = Store::new(None);
+        let list = ["3", "1", "2", "10"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        store
+            .set_value("list".to_string(), EntryValue::List(list))
+            .await;
+
+        for (id, weight, name) in [("1", "30", "one"), ("2", "10", "two"), ("3", "20", "three")] {
+            store
+                .insert(format!("weight_{}", id), weight.to_string(), None, None)
+                .await;
+            let hash = HashMap::from([
+                ("name".to_string(), name.to_string()),
+                ("rank".to_string(), weight.to_string()),
+            ]);
+            store
+                .set_value(format!("obj_{}", id), EntryValue::Hash(hash))
+                .await;
+        }
+        store
+    }
+
+    #[test]
+    fn test_sort_numeric_and_alpha() {
+        run_async_tests(async {
+            let store = setup().await;
+
+            assert_eq!(
+                sort(&args("SORT list"), &store, false).await.unwrap(),
+                bulk(&["1", "2", "3", "10"])
+            );
+            assert_eq!(
+                sort(&args("SORT list ALPHA DESC"), &store, false)
+                    .await
+                    .unwrap(),
+                bulk(&["3", "2", "10", "1"])
+            );
+            assert_eq!(
+                sort(&args("SORT list LIMIT 1 2"), &store, false)
+                    .await
+                    .unwrap(),
+                bulk(&["2", "3"])
+            );
+            assert_eq!(
+                sort(&args("SORT list BY nosort"), &store, false)
+                    .await
+                    .unwrap(),
+                bulk(&["3", "1", "2", "10"])
+            );
+        })
+    }
+
+    #[test]
+    fn test_sort_by_and_get_patterns() {
+        run_async_tests(async {
+            let store = setup().await;
+            let ids = ["1", "2", "3"].iter().map(|s| s.to_string()).collect();
+            store
+                .set_value("ids".to_string(), EntryValue::Set(ids))
+                .await;
+
+            assert_eq!(
+                sort(&args("SORT ids BY weight_*"), &store, true)
+                    .await
+                    .unwrap(),
+                bulk(&["2", "3", "1"])
+            );
+            assert_eq!(
+                sort(
+                    &args("SORT ids BY obj_*->rank DESC GET # GET obj_*->name"),
+                    &store,
+                    true
+                )
+                .await
+                .unwrap(),
+                bulk(&["1", "one", "3", "three", "2", "two"])
+            );
+            assert_eq!(
+                sort(&args("SORT ids GET missing_*"), &store, true)
+                    .await
+                    .unwrap(),
+                Value::Array(vec![
+                    Value::BulkString(None),
+                    Value::BulkString(None),
+                    Value::BulkString(None),
+                ])
+            );
+            assert!(sort(&args("SORT ids STORE dst"), &store, true)
+                .await
+                .is_err());
+        })
+    }
+
+    #[test]
+    fn test_stores() {
+        assert!(!stores(&args("SORT list")));
+        assert!(!stores(&args("SORT list BY store GET store LIMIT 0 1")));
+        assert!(stores(&args("SORT list ALPHA STORE dst")));
+        assert!(!stores(&args("SORT list STORE")));
+    }
+
+    #[test]
+    fn test_sort_store() {
+        run_async_tests(async {
+            let store = setup().await;
+            let mut zset = SortedSet::new();
+            zset.insert("b".to_string(), 1.0);
+            zset.insert("a".to_string(), 2.0);
+            store
+                .set_value("zset".to_string(), EntryValue::SortedSet(zset))
+                .await;
+
+            assert_eq!(
+                sort(&args("SORT zset BY nosort DESC STORE dst"), &store, false)
+                    .await
+                    .unwrap(),
+                Value::Integer(2)
+            );
+            assert_eq!(
+                sort(&args("SORT dst BY nosort"), &store, false)
+                    .await
+                    .unwrap(),
+                bulk(&["a", "b"])
+            );
+
+            assert!(matches!(
+                sort(&args("SORT weight_1"), &store, false).await,
+                Err(Error::WrongType)
+            ));
+            // Nothing is stored when sorting fails.
+            assert!(matches!(
+                sort(&args("SORT weight_1 STORE dst"), &store, false).await,
+                Err(Error::WrongType)
+            ));
+            assert_eq!(
+                sort(&args("SORT dst BY nosort"), &store, false)
+                    .await
+                    .unwrap(),
+                bulk(&["a", "b"])
+            );
+        })
+    }
+}
@@ -0,0 +1,130 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+/// f64 wrapper with a total order so scores can live inside a `BTreeSet`.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by (score, member), with O(1) score lookup by member.
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    ordered: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the score of `member`, returning `true` if the member is new.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old_score) => {
+                self.ordered.remove(&(Score(old_score), member.clone()));
+                self.ordered.insert((Score(score), member));
+                false
+            }
+            None => {
+                self.ordered.insert((Score(score), member));
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.ordered.remove(&(Score(score), member.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Iterates members in ascending (score, member) order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&str, f64)> {
+        self.ordered
+            .iter()
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+
+    /// Iterates members whose score lies in `[min, max)`.
+    pub fn range_by_score(&self, min: f64, max: f64) -> impl Iterator<Item = (&str, f64)> {
+        self.ordered
+            .range((
+                Bound::Included((Score(min), String::new())),
+                Bound::Unbounded,
+            ))
+            .take_while(move |(score, _)| score.0 < max)
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_order() {
+        let mut zset = SortedSet::new();
+        assert!(zset.insert("b".to_string(), 2.0));
+        assert!(zset.insert("a".to_string(), 2.0));
+        assert!(zset.insert("c".to_string(), 1.0));
+        assert!(!zset.insert("c".to_string(), 3.0));
+
+        let members: Vec<&str> = zset.iter().map(|(m, _)| m).collect();
+        assert_eq!(members, vec!["a", "b", "c"]);
+        assert_eq!(zset.score("c"), Some(3.0));
+        assert_eq!(zset.len(), 3);
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let mut zset = SortedSet::new();
+        for (i, m) in ["a", "b", "c", "d"].iter().enumerate() {
+            zset.insert(m.to_string(), i as f64);
+        }
+
+        let members: Vec<&str> = zset.range_by_score(1.0, 3.0).map(|(m, _)| m).collect();
+        assert_eq!(members, vec!["b", "c"]);
+
+        assert!(zset.remove("b"));
+        assert!(!zset.remove("b"));
+        let members: Vec<&str> = zset.range_by_score(1.0, 3.0).map(|(m, _)| m).collect();
+        assert_eq!(members, vec!["c"]);
+    }
+}
@@ -1,18 +1,69 @@
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     time::{Duration, Instant},
 };
 
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockMappedWriteGuard, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::notify::{
+    KeyspaceNotifier, NOTIFY_EXPIRED, NOTIFY_GENERIC, NOTIFY_KEY_MISS, NOTIFY_NEW,
+};
+use crate::pubsub::PubSub;
+use crate::sorted_set::SortedSet;
+use crate::stream::Stream;
+use crate::tracking::Tracking;
+use crate::Error;
 
 #[derive(Debug, Clone)]
 pub struct Entry {
-    value: String,
+    /// Shared with any fork of the keyspace still being saved, and copied
+    /// before it is changed.
+    value: Arc<EntryValue>,
     expires_at: Option<ExpiryTime>,
 }
 
+#[derive(Debug, Clone)]
+pub enum EntryValue {
+    String(String),
+    List(VecDeque<String>),
+    Set(HashSet<String>),
+    SortedSet(SortedSet),
+    Hash(HashMap<String, String>),
+    Stream(Stream),
+}
+
+impl EntryValue {
+    /// Name of the value's type, as Redis reports it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            EntryValue::String(_) => "string",
+            EntryValue::List(_) => "list",
+            EntryValue::Set(_) => "set",
+            EntryValue::SortedSet(_) => "zset",
+            EntryValue::Hash(_) => "hash",
+            EntryValue::Stream(_) => "stream",
+        }
+    }
+
+    /// Aggregate types are never stored empty, so callers use this to decide
+    /// whether a key should be deleted instead.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            EntryValue::String(_) => false,
+            EntryValue::List(list) => list.is_empty(),
+            EntryValue::Set(set) => set.is_empty(),
+            EntryValue::SortedSet(zset) => zset.is_empty(),
+            EntryValue::Hash(hash) => hash.is_empty(),
+            // Streams are kept empty, their last ID still matters.
+            EntryValue::Stream(_) => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum ExpiryTime {
     ExpiringInstant(Instant),
@@ -21,31 +72,48 @@ pub enum ExpiryTime {
 
 impl Entry {
     pub fn new(
-        value: String,
+        value: EntryValue,
         ttl: Option<u64>,
         expires_at_ts: Option<SystemTime>,
         now: Instant,
     ) -> Self {
         let expires_at = match expires_at_ts {
             Some(expiry_systime) => Some(ExpiryTime::ExpiringSystime(expiry_systime)),
-            None => match ttl {
-                Some(expires_in) => Some(ExpiryTime::ExpiringInstant(
+            None => ttl.map(|expires_in| {
+                ExpiryTime::ExpiringInstant(
                     now.checked_add(Duration::from_millis(expires_in))
                         .expect("Error in addition of expires_in and now"),
-                )),
-                None => None,
-            },
+                )
+            }),
         };
 
         // let expires_at = ttl.map(|expires_in| {
         //     now.checked_add(Duration::from_millis(expires_in))
         //         .expect("Error during adding ttl to now instant")
         // });
-        Entry { value, expires_at }
+        Entry {
+            value: Arc::new(value),
+            expires_at,
+        }
     }
 
-    pub fn get_value(&self) -> String {
-        self.value.clone()
+    pub fn get_value(&self) -> &EntryValue {
+        &self.value
+    }
+
+    /// When the entry expires, as wall-clock time.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at.as_ref().map(|expires_at| match expires_at {
+            ExpiryTime::ExpiringInstant(instant) => {
+                let (now, systime) = (Instant::now(), SystemTime::now());
+                if *instant >= now {
+                    systime + (*instant - now)
+                } else {
+                    systime - (now - *instant)
+                }
+            }
+            ExpiryTime::ExpiringSystime(systime) => *systime,
+        })
     }
 
     pub fn is_expired(&self, now: Instant) -> bool {
@@ -66,29 +134,165 @@ impl Entry {
 
 #[derive(Debug)]
 pub struct Store {
-    state: RwLock<HashMap<String, Entry>>,
+    /// Database number, as selected with SELECT.
+    index: usize,
+    /// Shared with any fork still being saved, and copied on the next write.
+    state: RwLock<Arc<HashMap<String, Entry>>>,
+    /// Held shared while a single command runs and exclusively while a
+    /// transaction runs, so transactions never interleave with other clients.
+    /// Shared by all databases.
+    exec_lock: Arc<RwLock<()>>,
+    /// Modification versions of keys that some client is WATCHing.
+    watched: Mutex<HashMap<String, WatchedKey>>,
+    notifier: KeyspaceNotifier,
+    tracking: Arc<Tracking>,
+    /// Modifications since the last save, counted across all databases.
+    dirty: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Default)]
+struct WatchedKey {
+    version: u64,
+    watchers: usize,
 }
 
 impl Store {
     pub fn new(rdb_kv_data: Option<HashMap<String, Entry>>) -> Self {
-        let hm = rdb_kv_data.map_or_else(|| HashMap::new(), |rdb_hm| rdb_hm.clone());
         Self {
-            state: RwLock::new(hm),
+            index: 0,
+            state: RwLock::new(Arc::new(rdb_kv_data.unwrap_or_default())),
+            exec_lock: Arc::new(RwLock::new(())),
+            watched: Mutex::new(HashMap::new()),
+            notifier: KeyspaceNotifier::default(),
+            tracking: Arc::new(Tracking::default()),
+            dirty: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Clients caching keys of any database, see CLIENT TRACKING.
+    pub fn tracking(&self) -> &Tracking {
+        &self.tracking
+    }
+
+    /// Enabled notify-keyspace-events classes.
+    pub fn notify_flags(&self) -> u32 {
+        self.notifier.flags()
+    }
+
+    pub fn set_notify_flags(&self, flags: u32) {
+        self.notifier.set_flags(flags);
+    }
+
+    /// Emits a keyspace event for `key`, for commands to report what they
+    /// did. Creation, deletion and expiry are reported by the store itself.
+    pub fn notify(&self, class: u32, event: &str, key: &str) {
+        self.notifier.notify(class, event, key);
+    }
+
+    /// Write access to the keyspace. A fork still being saved keeps the map
+    /// as it was, so it is copied first: keys and pointers, not values.
+    async fn write_state(&self) -> RwLockMappedWriteGuard<'_, HashMap<String, Entry>> {
+        RwLockWriteGuard::map(self.state.write().await, Arc::make_mut)
+    }
+
+    /// Drops the expired entry at `key` found by a lookup.
+    fn expire_key(&self, map: &mut HashMap<String, Entry>, key: &str) {
+        map.remove(key);
+        self.touch(key);
+        self.notify(NOTIFY_EXPIRED, "expired", key);
+    }
+
+    /// Drops `key` if it is still expired once the write lock is held, for
+    /// lookups that found it expired under the read lock.
+    async fn expire_stale(&self, key: &str, now: Instant) {
+        let mut guard = self.write_state().await;
+        if guard.get(key).is_some_and(|entry| entry.is_expired(now)) {
+            self.expire_key(&mut guard, key);
         }
     }
 
-    pub async fn get(&self, key: &str, now: Instant) -> Option<String> {
+    /// Starts tracking modifications of `key` and returns its current version.
+    pub fn watch(&self, key: &str) -> u64 {
+        let mut watched = self.watched.lock().unwrap();
+        let watched_key = watched.entry(key.to_string()).or_default();
+        watched_key.watchers += 1;
+        watched_key.version
+    }
+
+    pub fn unwatch(&self, key: &str) {
+        let mut watched = self.watched.lock().unwrap();
+        if let Some(watched_key) = watched.get_mut(key) {
+            watched_key.watchers -= 1;
+            if watched_key.watchers == 0 {
+                watched.remove(key);
+            }
+        }
+    }
+
+    /// Version of a watched key; it changes every time the key is modified.
+    pub fn key_version(&self, key: &str) -> u64 {
+        let watched = self.watched.lock().unwrap();
+        watched
+            .get(key)
+            .map_or(0, |watched_key| watched_key.version)
+    }
+
+    /// Marks `key` as modified for any client WATCHing or caching it.
+    fn touch(&self, key: &str) {
+        let mut watched = self.watched.lock().unwrap();
+        if let Some(watched_key) = watched.get_mut(key) {
+            watched_key.version += 1;
+        }
+        drop(watched);
+        self.tracking.invalidate_key(key);
+        self.dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks every watched key present in `map` as modified.
+    fn touch_existing(&self, map: &HashMap<String, Entry>) {
+        let mut watched = self.watched.lock().unwrap();
+        for (key, watched_key) in watched.iter_mut() {
+            if map.contains_key(key) {
+                watched_key.version += 1;
+            }
+        }
+    }
+
+    /// Whether `key` exists and has not expired yet.
+    pub async fn contains_key(&self, key: &str, now: Instant) -> bool {
+        let guard = self.state.read().await;
+        guard.get(key).is_some_and(|entry| !entry.is_expired(now))
+    }
+
+    pub async fn lock_shared(&self) -> RwLockReadGuard<'_, ()> {
+        self.exec_lock.read().await
+    }
+
+    pub async fn lock_exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.exec_lock.write().await
+    }
+
+    pub async fn get(&self, key: &str, now: Instant) -> Result<Option<String>, Error> {
         let guard = self.state.read().await;
         if let Some(entry) = guard.get(key) {
             if entry.is_expired(now) {
                 drop(guard);
-                self.state.write().await.remove(key);
-                None
+                self.expire_stale(key, now).await;
+                self.notify(NOTIFY_KEY_MISS, "keymiss", key);
+                Ok(None)
             } else {
-                Some(entry.get_value())
+                match entry.get_value() {
+                    EntryValue::String(value) => Ok(Some(value.clone())),
+                    _ => Err(Error::WrongType),
+                }
             }
         } else {
-            None
+            self.notify(NOTIFY_KEY_MISS, "keymiss", key);
+            Ok(None)
         }
     }
 
@@ -99,8 +303,213 @@ impl Store {
         ttl: Option<u64>,
         expires_at_ts: Option<SystemTime>,
     ) {
-        let entry = Entry::new(value, ttl, expires_at_ts, Instant::now());
-        self.state.write().await.insert(key, entry);
+        let entry = Entry::new(
+            EntryValue::String(value),
+            ttl,
+            expires_at_ts,
+            Instant::now(),
+        );
+        let previous = self.write_state().await.insert(key.clone(), entry);
+        self.touch(&key);
+        if previous.is_none() {
+            self.notify(NOTIFY_NEW, "new", &key);
+        }
+    }
+
+    /// Runs `f` against the sorted set stored at `key`, if there is one.
+    pub async fn read_sorted_set<R>(
+        &self,
+        key: &str,
+        now: Instant,
+        f: impl FnOnce(&SortedSet) -> R,
+    ) -> Result<Option<R>, Error> {
+        let guard = self.state.read().await;
+        match guard.get(key) {
+            Some(entry) if entry.is_expired(now) => {
+                drop(guard);
+                self.expire_stale(key, now).await;
+                self.notify(NOTIFY_KEY_MISS, "keymiss", key);
+                Ok(None)
+            }
+            Some(entry) => match entry.get_value() {
+                EntryValue::SortedSet(zset) => Ok(Some(f(zset))),
+                _ => Err(Error::WrongType),
+            },
+            None => {
+                self.notify(NOTIFY_KEY_MISS, "keymiss", key);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Runs `f` against the sorted set stored at `key`, creating an empty one
+    /// if the key does not exist. Sets left empty by `f` are removed. `f`
+    /// also tells whether it modified the set, which is passed back; only
+    /// then is the key marked as modified.
+    pub async fn write_sorted_set<R>(
+        &self,
+        key: &str,
+        now: Instant,
+        f: impl FnOnce(&mut SortedSet) -> (R, bool),
+    ) -> Result<(R, bool), Error> {
+        let mut guard = self.write_state().await;
+        if guard.get(key).is_some_and(|entry| entry.is_expired(now)) {
+            self.expire_key(&mut guard, key);
+        }
+        let existed = guard.contains_key(key);
+
+        let entry = guard.entry(key.to_string()).or_insert_with(|| {
+            Entry::new(EntryValue::SortedSet(SortedSet::new()), None, None, now)
+        });
+        let (result, modified) = match Arc::make_mut(&mut entry.value) {
+            EntryValue::SortedSet(zset) => f(zset),
+            _ => return Err(Error::WrongType),
+        };
+
+        let emptied = matches!(&*entry.value, EntryValue::SortedSet(zset) if zset.is_empty());
+        // A set created here and left empty never existed.
+        if modified && (existed || !emptied) {
+            self.touch(key);
+        }
+        if emptied {
+            guard.remove(key);
+            if existed {
+                self.notify(NOTIFY_GENERIC, "del", key);
+            }
+        } else if !existed {
+            self.notify(NOTIFY_NEW, "new", key);
+        }
+        Ok((result, modified))
+    }
+
+    /// Replaces whatever is stored at `key` with `value`, or deletes the key
+    /// when `value` is an empty aggregate. Returns whether a value was stored,
+    /// so the caller knows which event to report.
+    pub async fn set_value(&self, key: String, value: EntryValue) -> bool {
+        let now = Instant::now();
+        let mut guard = self.write_state().await;
+        self.replace_value(&mut guard, key, value, now)
+    }
+
+    /// Computes a value from a view of the keyspace with `f` and stores it
+    /// at `key` under the same lock, so nothing changes in between, for
+    /// commands storing what they read such as SORT ... STORE. `f` returns
+    /// the value and a result, which comes back with whether the value was
+    /// stored, as for `set_value`.
+    pub async fn store_computed<R>(
+        &self,
+        key: &str,
+        now: Instant,
+        f: impl FnOnce(&KeyspaceView) -> Result<(EntryValue, R), Error>,
+    ) -> Result<(R, bool), Error> {
+        let mut guard = self.write_state().await;
+        let (value, result) = f(&KeyspaceView { map: &guard, now })?;
+        let stored = self.replace_value(&mut guard, key.to_string(), value, now);
+        Ok((result, stored))
+    }
+
+    fn replace_value(
+        &self,
+        map: &mut HashMap<String, Entry>,
+        key: String,
+        value: EntryValue,
+        now: Instant,
+    ) -> bool {
+        if map.get(&key).is_some_and(|entry| entry.is_expired(now)) {
+            self.expire_key(map, &key);
+        }
+        self.touch(&key);
+
+        if value.is_empty() {
+            if map.remove(&key).is_some() {
+                self.notify(NOTIFY_GENERIC, "del", &key);
+            }
+            false
+        } else {
+            let entry = Entry::new(value, None, None, now);
+            if map.insert(key.clone(), entry).is_none() {
+                self.notify(NOTIFY_NEW, "new", &key);
+            }
+            true
+        }
+    }
+
+    /// Stores `entry` at `key` for RESTORE, where `None` stands for a value
+    /// that has already expired and only deletes the key. Unless `replace`,
+    /// an existing key is left alone and BUSYKEY returned.
+    pub async fn restore(
+        &self,
+        key: &str,
+        entry: Option<Entry>,
+        replace: bool,
+    ) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut guard = self.write_state().await;
+        if guard.get(key).is_some_and(|entry| entry.is_expired(now)) {
+            self.expire_key(&mut guard, key);
+        }
+        if !replace && guard.contains_key(key) {
+            return Err(Error::BusyKey);
+        }
+        match entry {
+            Some(entry) => {
+                let previous = guard.insert(key.to_string(), entry);
+                self.touch(key);
+                if previous.is_none() {
+                    self.notify(NOTIFY_NEW, "new", key);
+                }
+                self.notify(NOTIFY_GENERIC, "restore", key);
+            }
+            None => {
+                if guard.remove(key).is_some() {
+                    self.touch(key);
+                    self.notify(NOTIFY_GENERIC, "del", key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes `keys`, returning how many of them existed.
+    pub async fn remove(&self, keys: &[&str], now: Instant) -> usize {
+        let mut guard = self.write_state().await;
+        let mut removed = 0;
+        for key in keys {
+            if let Some(entry) = guard.remove(*key) {
+                self.touch(key);
+                if entry.is_expired(now) {
+                    self.notify(NOTIFY_EXPIRED, "expired", key);
+                } else {
+                    self.notify(NOTIFY_GENERIC, "del", key);
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    /// Deletes every key, for FLUSHDB. With `lazy` the old keyspace is freed
+    /// in the background.
+    pub async fn flush(&self, lazy: bool) {
+        self.clear(lazy).await;
+        self.tracking.invalidate_all();
+    }
+
+    async fn clear(&self, lazy: bool) {
+        let mut guard = self.state.write().await;
+        self.touch_existing(&guard);
+        self.dirty.fetch_add(guard.len() as u64, Ordering::Relaxed);
+        let old = std::mem::take(&mut *guard);
+        if lazy {
+            tokio::task::spawn_blocking(move || drop(old));
+        }
+    }
+
+    /// Runs `f` against a read-only view of the whole keyspace, for commands
+    /// that need to look at several keys consistently.
+    pub async fn view<R>(&self, now: Instant, f: impl FnOnce(&KeyspaceView) -> R) -> R {
+        let guard = self.state.read().await;
+        f(&KeyspaceView { map: &guard, now })
     }
 
     pub async fn get_all_keys(&self) -> Vec<String> {
@@ -109,8 +518,209 @@ impl Store {
     }
 }
 
+/// The numbered databases, each its own keyspace. They share the execution
+/// lock and the tracking table, so transactions and scripts can span several
+/// of them.
+#[derive(Debug)]
+pub struct Databases {
+    dbs: Vec<Arc<Store>>,
+}
+
+impl Databases {
+    /// Creates `count` databases, filled with the `loaded` keyspaces by
+    /// database number. Keyspaces past `count` are dropped.
+    pub fn new(
+        count: usize,
+        mut loaded: BTreeMap<usize, HashMap<String, Entry>>,
+        pubsub: Arc<PubSub>,
+    ) -> Self {
+        let exec_lock = Arc::new(RwLock::new(()));
+        let tracking = Arc::new(Tracking::default());
+        let dirty = Arc::new(AtomicU64::new(0));
+        let dbs = (0..count)
+            .map(|index| {
+                Arc::new(Store {
+                    index,
+                    state: RwLock::new(Arc::new(loaded.remove(&index).unwrap_or_default())),
+                    exec_lock: exec_lock.clone(),
+                    watched: Mutex::new(HashMap::new()),
+                    notifier: KeyspaceNotifier::new(pubsub.clone(), index),
+                    tracking: tracking.clone(),
+                    dirty: dirty.clone(),
+                })
+            })
+            .collect();
+        Self { dbs }
+    }
+
+    pub fn count(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Arc<Store>> {
+        self.dbs.get(index)
+    }
+
+    /// Takes the execution lock shared by all databases exclusively, for
+    /// work that doesn't come from a client such as scheduled saves.
+    pub async fn lock_exclusive(&self) -> RwLockWriteGuard<'_, ()> {
+        self.dbs[0].exec_lock.write().await
+    }
+
+    /// Number of modifications since the last save.
+    pub fn dirty(&self) -> u64 {
+        self.dbs[0].dirty.load(Ordering::Relaxed)
+    }
+
+    /// Forgets the `saved` modifications a finished save has written out,
+    /// keeping those made while it ran.
+    pub fn saved_dirty(&self, saved: u64) {
+        self.dbs[0].dirty.fetch_sub(saved, Ordering::Relaxed);
+    }
+
+    pub fn set_notify_flags(&self, flags: u32) {
+        for db in self.dbs.iter() {
+            db.set_notify_flags(flags);
+        }
+    }
+
+    /// Write-locks the keyspaces of databases `a` and `b`, always in index
+    /// order so that concurrent MOVE and SWAPDB can't deadlock.
+    async fn lock_pair(
+        &self,
+        a: usize,
+        b: usize,
+    ) -> (
+        RwLockWriteGuard<'_, Arc<HashMap<String, Entry>>>,
+        RwLockWriteGuard<'_, Arc<HashMap<String, Entry>>>,
+    ) {
+        if a < b {
+            let first = self.dbs[a].state.write().await;
+            (first, self.dbs[b].state.write().await)
+        } else {
+            let first = self.dbs[b].state.write().await;
+            (self.dbs[a].state.write().await, first)
+        }
+    }
+
+    /// Moves `key` from database `from` to `to`. Nothing is moved when the
+    /// key is missing from `from` or already exists in `to`.
+    pub async fn move_key(&self, key: &str, from: usize, to: usize, now: Instant) -> bool {
+        let (source, target) = (&self.dbs[from], &self.dbs[to]);
+        let (mut source_guard, mut target_guard) = self.lock_pair(from, to).await;
+        let source_map = Arc::make_mut(&mut source_guard);
+        let target_map = Arc::make_mut(&mut target_guard);
+        if source_map
+            .get(key)
+            .is_some_and(|entry| entry.is_expired(now))
+        {
+            source.expire_key(source_map, key);
+        }
+        if target_map
+            .get(key)
+            .is_some_and(|entry| entry.is_expired(now))
+        {
+            target.expire_key(target_map, key);
+        }
+        if target_map.contains_key(key) {
+            return false;
+        }
+        let Some(entry) = source_map.remove(key) else {
+            return false;
+        };
+        target_map.insert(key.to_string(), entry);
+
+        source.touch(key);
+        target.touch(key);
+        source.notify(NOTIFY_GENERIC, "move_from", key);
+        target.notify(NOTIFY_GENERIC, "move_to", key);
+        true
+    }
+
+    /// Exchanges the keyspaces of databases `a` and `b`. Both stay locked
+    /// throughout, so every client sees either the old or the new layout.
+    pub async fn swap(&self, a: usize, b: usize) {
+        if a == b {
+            return;
+        }
+        let (mut a_map, mut b_map) = self.lock_pair(a, b).await;
+        for db in [&self.dbs[a], &self.dbs[b]] {
+            db.touch_existing(&a_map);
+            db.touch_existing(&b_map);
+        }
+        std::mem::swap(&mut *a_map, &mut *b_map);
+        self.dbs[a].tracking.invalidate_all();
+        self.dbs[a].dirty.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The keyspaces of all databases as they are now, for saving. Taking it
+    /// copies nothing: the databases copy a map on their next write instead.
+    /// Callers hold the execution lock exclusively, so the databases agree
+    /// with each other.
+    pub async fn fork(&self) -> DatabasesFork {
+        let mut keyspaces = Vec::with_capacity(self.dbs.len());
+        for db in self.dbs.iter() {
+            keyspaces.push((db.index, db.state.read().await.clone()));
+        }
+        DatabasesFork(keyspaces)
+    }
+
+    /// Copies the live keys of every non-empty database, see `fork`.
+    pub async fn snapshot(&self, now: Instant) -> BTreeMap<usize, HashMap<String, Entry>> {
+        self.fork().await.live_keys(now)
+    }
+
+    /// Deletes every key of every database, for FLUSHALL.
+    pub async fn flush_all(&self, lazy: bool) {
+        for db in self.dbs.iter() {
+            db.clear(lazy).await;
+        }
+        if let Some(db) = self.dbs.first() {
+            db.tracking.invalidate_all();
+        }
+    }
+}
+
+/// The keyspaces of all databases at one point, see `Databases::fork`.
+#[derive(Debug)]
+pub struct DatabasesFork(Vec<(usize, Arc<HashMap<String, Entry>>)>);
+
+impl DatabasesFork {
+    /// The keys of every non-empty database that are live at `now`. Values
+    /// are shared rather than copied.
+    pub fn live_keys(&self, now: Instant) -> BTreeMap<usize, HashMap<String, Entry>> {
+        let mut live_keys = BTreeMap::new();
+        for (index, map) in self.0.iter() {
+            let live: HashMap<String, Entry> = map
+                .iter()
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect();
+            if !live.is_empty() {
+                live_keys.insert(*index, live);
+            }
+        }
+        live_keys
+    }
+}
+
+/// Read-only access to the keyspace that hides expired entries.
+pub struct KeyspaceView<'a> {
+    map: &'a HashMap<String, Entry>,
+    now: Instant,
+}
+
+impl KeyspaceView<'_> {
+    pub fn get(&self, key: &str) -> Option<&EntryValue> {
+        self.map
+            .get(key)
+            .filter(|entry| !entry.is_expired(self.now))
+            .map(|entry| entry.get_value())
+    }
+}
+
 impl Deref for Store {
-    type Target = RwLock<HashMap<String, Entry>>;
+    type Target = RwLock<Arc<HashMap<String, Entry>>>;
 
     fn deref(&self) -> &Self::Target {
         &self.state
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// A stream entry ID: milliseconds and a sequence number within them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// The 16 byte big-endian form RDB files use for listpack keys and PELs.
+    pub fn to_be_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.ms.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.seq.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_be_bytes(bytes: [u8; 16]) -> Self {
+        Self {
+            ms: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            seq: u64::from_be_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl FromStr for StreamId {
+    type Err = String;
+
+    /// Parses the `ms-seq` form IDs are displayed in.
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid stream ID '{}'", id);
+        let (ms, seq) = id.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            ms: ms.parse().map_err(|_| invalid())?,
+            seq: seq.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A stream: entries of field-value pairs ordered by ID, and the consumer
+/// groups reading them.
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(String, String)>>,
+    /// Greatest ID ever added, which new IDs must exceed even once deleted.
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    pub max_deleted_id: StreamId,
+    /// Entries ever added, deleted ones included.
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+/// A consumer group, with the entries delivered to it but not yet
+/// acknowledged.
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerGroup {
+    pub name: String,
+    /// Last entry delivered to the group.
+    pub last_id: StreamId,
+    /// Entries read by the group, when known.
+    pub entries_read: Option<u64>,
+    /// Pending entries list of the group.
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+/// An entry delivered to a consumer and not yet acknowledged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingEntry {
+    pub consumer: String,
+    /// Unix time in milliseconds of the last delivery.
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Consumer {
+    pub name: String,
+    /// Unix time in milliseconds the consumer was last seen.
+    pub seen_time: u64,
+    /// Unix time in milliseconds the consumer last read successfully.
+    pub active_time: u64,
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::Mutex;
+
+use crate::client::{ClientHandle, ClientId};
+use crate::{Error, Value};
+
+tokio::task_local! {
+    /// The client whose command is running, so NOLOOP can skip it.
+    static CURRENT_CLIENT: ClientId;
+}
+
+/// Runs `f` on behalf of client `id`.
+pub async fn with_current_client<F: Future>(id: ClientId, f: F) -> F::Output {
+    CURRENT_CLIENT.scope(id, f).await
+}
+
+fn current_client() -> Option<ClientId> {
+    CURRENT_CLIENT.try_with(|id| *id).ok()
+}
+
+/// Options given to CLIENT TRACKING ON.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrackingOptions {
+    pub redirect: Option<ClientId>,
+    /// Invalidate every key matching `prefixes` instead of the keys read.
+    pub bcast: bool,
+    pub prefixes: Vec<String>,
+    pub optin: bool,
+    pub optout: bool,
+    /// Skip invalidations for keys the client modified itself.
+    pub noloop: bool,
+}
+
+#[derive(Debug, Default)]
+struct TrackingState {
+    /// Every connected client, as possible invalidation targets.
+    connected: HashMap<ClientId, ClientHandle>,
+    tracking: HashMap<ClientId, TrackingOptions>,
+    /// Keys read by clients in default mode. Entries of clients that turned
+    /// tracking off are skipped and dropped when the key is invalidated.
+    keys: HashMap<String, HashSet<ClientId>>,
+    /// BCAST clients by prefix; the empty prefix matches every key.
+    prefixes: BTreeMap<String, HashSet<ClientId>>,
+}
+
+/// Server-assisted client side caching: remembers which clients may cache
+/// which keys and tells them when those keys change.
+#[derive(Debug, Default)]
+pub struct Tracking {
+    state: Mutex<TrackingState>,
+}
+
+impl Tracking {
+    pub fn connect(&self, client: &ClientHandle) {
+        let mut state = self.state.lock().unwrap();
+        state.connected.insert(client.id(), client.clone());
+    }
+
+    pub fn disconnect(&self, id: ClientId) {
+        let mut state = self.state.lock().unwrap();
+        state.connected.remove(&id);
+        Self::disable_locked(&mut state, id);
+    }
+
+    pub fn options(&self, id: ClientId) -> Option<TrackingOptions> {
+        let state = self.state.lock().unwrap();
+        state.tracking.get(&id).cloned()
+    }
+
+    /// CLIENT TRACKING ON. Calling it again while tracking adds prefixes
+    /// and updates the other options, but cannot switch BCAST on or off.
+    pub fn enable(&self, id: ClientId, mut options: TrackingOptions) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(redirect) = options.redirect {
+            if redirect != id && !state.connected.contains_key(&redirect) {
+                return Err(Error::InvalidCommand(
+                    "The client ID you want redirect to does not exist",
+                ));
+            }
+        }
+
+        let mut prefixes = Vec::new();
+        if let Some(current) = state.tracking.get(&id) {
+            if current.bcast != options.bcast {
+                return Err(Error::InvalidCommand(
+                    "You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.",
+                ));
+            }
+            prefixes = current.prefixes.clone();
+        }
+
+        if options.bcast && options.prefixes.is_empty() && prefixes.is_empty() {
+            options.prefixes.push(String::new());
+        }
+        for prefix in options.prefixes.drain(..) {
+            if prefixes.contains(&prefix) {
+                continue;
+            }
+            if prefixes
+                .iter()
+                .any(|other| other.starts_with(&prefix) || prefix.starts_with(other.as_str()))
+            {
+                return Err(Error::InvalidCommand(
+                    "Prefixes for a single client must not overlap.",
+                ));
+            }
+            prefixes.push(prefix);
+        }
+
+        for prefix in prefixes.iter() {
+            state.prefixes.entry(prefix.clone()).or_default().insert(id);
+        }
+        options.prefixes = prefixes;
+        state.tracking.insert(id, options);
+        Ok(())
+    }
+
+    /// CLIENT TRACKING OFF.
+    pub fn disable(&self, id: ClientId) {
+        let mut state = self.state.lock().unwrap();
+        Self::disable_locked(&mut state, id);
+    }
+
+    fn disable_locked(state: &mut TrackingState, id: ClientId) {
+        let Some(options) = state.tracking.remove(&id) else {
+            return;
+        };
+        for prefix in options.prefixes {
+            if let Some(clients) = state.prefixes.get_mut(&prefix) {
+                clients.remove(&id);
+                if clients.is_empty() {
+                    state.prefixes.remove(&prefix);
+                }
+            }
+        }
+    }
+
+    /// Records that client `id` read `keys` and may cache them.
+    pub fn remember_keys(&self, id: ClientId, keys: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            state.keys.entry(key.to_string()).or_default().insert(id);
+        }
+    }
+
+    /// Tells every client that may have cached `key` that it changed.
+    pub fn invalidate_key(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.tracking.is_empty() {
+            return;
+        }
+
+        let mut targets: HashSet<ClientId> = state.keys.remove(key).unwrap_or_default();
+        let mut end = 0;
+        loop {
+            if let Some(clients) = state.prefixes.get(&key[..end]) {
+                targets.extend(clients.iter().copied());
+            }
+            match key[end..].chars().next() {
+                Some(c) => end += c.len_utf8(),
+                None => break,
+            }
+        }
+
+        let keys = Value::Array(vec![Value::BulkString(Some(key.to_string()))]);
+        for id in targets {
+            Self::send(&state, id, keys.clone());
+        }
+    }
+
+    /// Tells every tracking client to drop its whole cache, after FLUSHALL.
+    pub fn invalidate_all(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.keys.clear();
+        let ids: Vec<ClientId> = state.tracking.keys().copied().collect();
+        for id in ids {
+            Self::send(&state, id, Value::BulkString(None));
+        }
+    }
+
+    /// Delivers an invalidation to client `id`, or to the client it
+    /// redirects to. RESP2 clients without a redirection get nothing.
+    fn send(state: &TrackingState, id: ClientId, keys: Value) {
+        let Some(options) = state.tracking.get(&id) else {
+            return;
+        };
+        if options.noloop && current_client() == Some(id) {
+            return;
+        }
+        let Some(client) = state.connected.get(&id) else {
+            return;
+        };
+
+        let (target, redirected) = match options.redirect {
+            Some(redirect) if redirect != id => match state.connected.get(&redirect) {
+                Some(target) => (target, true),
+                None => {
+                    if client.protocol() == 3 {
+                        client.push(Value::Push(vec![
+                            Value::BulkString(Some("tracking-redir-broken".to_string())),
+                            Value::Integer(redirect as i64),
+                        ]));
+                    }
+                    return;
+                }
+            },
+            _ => (client, false),
+        };
+
+        if target.protocol() == 3 {
+            target.push(Value::Push(vec![
+                Value::BulkString(Some("invalidate".to_string())),
+                keys,
+            ]));
+        } else if redirected {
+            target.push(Value::Push(vec![
+                Value::BulkString(Some("message".to_string())),
+                Value::BulkString(Some("__redis__:invalidate".to_string())),
+                keys,
+            ]));
+        }
+    }
+}